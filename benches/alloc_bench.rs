@@ -0,0 +1,51 @@
+// 统计一批病例分组过程中的堆分配次数和耗时
+// 运行方式: cargo bench --bench alloc_bench
+// 计数分配器只替换这个基准程序的全局分配器, 单元测试仍使用系统分配器; 程序单线程运行, 计数不受其他测试影响
+// 分组代码通过include!编译进drg模块, 与命令行程序使用相同的源码
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod drg {
+    // 基准程序不运行源码中的测试, 测试函数和只有测试使用的导入不再报警告
+    #![allow(dead_code, unused_imports)]
+
+    // 分组rounds遍测试病例, 返回(分组的病例数, 分组期间的分配次数, 耗时), 读取分组方案和病例的分配不计入
+    pub fn batch_allocations(rounds: usize, alloc_count: fn() -> usize) -> (usize, usize, std::time::Duration) {
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let cases_vec = read_csv_mapped("case_data/test_case_data.csv", &CliOptions::default()).unwrap();
+        let before = alloc_count();
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            for case in &cases_vec {
+                scheme.group(case, &GroupOptions::default()).unwrap();
+            }
+        }
+        let cost = start.elapsed();
+        (rounds * cases_vec.len(), alloc_count() - before, cost)
+    }
+
+    // 放在最后, 源码末尾的测试模块之后不能再有其他条目
+    include!("../src/main.rs");
+}
+
+// 统计堆分配次数的分配器
+struct CountingAlloc;
+static ALLOC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOC_COUNT.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn main() {
+    let (cases, allocs, cost) = drg::batch_allocations(200, || ALLOC_COUNT.load(Ordering::Relaxed));
+    println!("{} cases: {} allocations ({} per case), {:?}", cases, allocs, allocs / cases, cost);
+}
@@ -2,75 +2,101 @@ use core::str;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::{self, Write};
+use std::io::Write;
+use std::marker::PhantomData;
 use std::path::Path;
-use std::str::FromStr;
+use std::path::PathBuf;
 use std::string::String;
-use csv::Reader;
 use std::env;
 
-// 读取分组方案=======================================================================================
-// 读取JSON文件为字典(HashMap), 键为MDC编码, 值为MDC下的主诊断HashSet
-fn read_file_as_str_to_set<P: AsRef<Path>>(
-    path: P,
-) -> Result<HashMap<String, HashSet<String>>, Box<dyn Error>> {
-    // Open the file in read-only mode with buffer.
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of `User`.
-    let u = serde_json::from_reader(reader)?;
+#[cfg(feature = "zip")]
+mod zip_reader;
+#[cfg(feature = "zip")]
+use zip_reader::read_zip_members;
+#[cfg(feature = "parquet")]
+mod parquet_writer;
+#[cfg(feature = "parquet")]
+use parquet_writer::{encode_parquet, ParquetValues};
+
+// 分组方案数据所在的目录
+const DATA_DIR: &str = "data";
+
+// ADRG诊断手术表(嵌套格式), 键为ADRG编码, 值的键为表的类型, 值为诊断或手术操作编码HashSet
+type AdrgSheet = HashMap<String, HashMap<String, HashSet<String>>>;
+// ADRG => {DRG结尾数字 => DRG编码}
+type DrgLevelSheet = HashMap<String, HashMap<i32, String>>;
+
+// 扁平格式中不带表类型后缀的键(如"AA1")在嵌套格式中对应的表类型
+const DEFAULT_TABLE: &str = "default_list";
+// 命令行未提供人口学信息时的默认值: 性别未知, 成人年龄, 体重为0
+const UNKNOWN_SEX: i32 = -1;
+const DEFAULT_ADULT_AGE: f64 = 18.0;
+const DEFAULT_WEIGHT: i32 = 0;
 
-    Ok(u)
+// 读取分组方案=======================================================================================
+// 将扁平格式的ADRG诊断手术表转换为嵌套格式
+// 如"AH2_other_opt_list1" => adrg_dis_opt["AH2"]["other_opt_list1"], "AA1" => adrg_dis_opt["AA1"][DEFAULT_TABLE]
+fn flat_to_nested_adrg_sheet(flat: HashMap<String, HashSet<String>>) -> AdrgSheet {
+    let mut nested: AdrgSheet = HashMap::new();
+    for (key, codes) in flat {
+        // 第一个"_"之前为ADRG编码, 之后为表的类型
+        let (adrg_name, table_type) = match key.split_once('_') {
+            Some((adrg_name, table_type)) => (adrg_name.to_string(), table_type.to_string()),
+            None => (key, DEFAULT_TABLE.to_string()),
+        };
+        nested.entry(adrg_name).or_default().insert(table_type, codes);
+    }
+    nested
 }
 
-// 读取JSON文件为一个嵌套Hashmap, 键为ADRG编码, 值为一个Hashmap, 值的键为表的类型，值为诊断或手术操作编码Hashset
-fn read_file_as_str_nestring_hashmap<P: AsRef<Path>>(
-    path: P,
-) -> Result<HashMap<String, HashMap<String, HashSet<String>>>, Box<dyn Error>> {
-    // 打开文件加载到缓冲区
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // 读取缓冲区的内容并且序列化
-    let u = serde_json::from_reader(reader)?;
-    Ok(u)
+// 解析ADRG诊断手术表的JSON内容, 优先按嵌套格式解析, 失败时再按扁平格式解析并转换
+// 两种格式都解析失败时, 内容像嵌套格式(有值为对象的ADRG)就报出嵌套格式的错误, 否则同时报出两种格式的错误
+fn parse_adrg_dis_opt_sheet(data: &[u8]) -> Result<AdrgSheet, Box<dyn Error>> {
+    let nested_err = match serde_json::from_slice::<AdrgSheet>(data) {
+        Ok(nested) => return Ok(nested),
+        Err(e) => e,
+    };
+    match serde_json::from_slice(data) {
+        Ok(flat) => Ok(flat_to_nested_adrg_sheet(flat)),
+        Err(_) if looks_nested(data) => Err(format!("nested format: {}", nested_err).into()),
+        Err(flat_err) => Err(format!("neither nested format ({}) nor flat format ({})", nested_err, flat_err).into()),
+    }
 }
 
-// 读取JSON文件为一个字典Hashmap
-fn read_file_as_str_to_str<P: AsRef<Path>>(
-    path: P,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
-    // 打开文件加载到缓冲区
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // 读取缓冲区的内容并且序列化
-    let u = serde_json::from_reader(reader)?;
-    Ok(u)
+// ADRG诊断手术表是否为嵌套格式: 顶层为对象且有值为对象的ADRG
+fn looks_nested(data: &[u8]) -> bool {
+    serde_json::from_slice::<HashMap<String, serde_json::Value>>(data).is_ok_and(|x| x.values().any(|v| v.is_object()))
 }
 
-// 读取JSON文件为字典(HashMap), 键为MDC编码, 值为向量
-fn read_file_as_str_to_tuple<P: AsRef<Path>>(
-    path: P,
-) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
-    // Open the file in read-only mode with buffer.
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of `User`.
-    let u = serde_json::from_reader(reader)?;
-
-    Ok(u)
+// 取出ADRG下指定类型的诊断或手术操作表, 分组方案中没有这张表时返回错误
+fn adrg_table<'a>(
+    adrg_dis_opt: &'a AdrgSheet,
+    adrg_name: &str,
+    table_type: &str,
+) -> Result<&'a HashSet<String>, Box<dyn Error>> {
+    adrg_dis_opt
+        .get(adrg_name)
+        .and_then(|tables| tables.get(table_type))
+        .ok_or_else(|| format!("missing key {}/{} in adrg_dis_opt", adrg_name, table_type).into())
 }
 
+// 取出MDCZ下指定部位的诊断表, 分组方案中没有这张表时返回错误
+fn adrg_table_part<'a>(
+    mdcz_dis_opt: &'a HashMap<String, HashSet<String>>,
+    part_name: &str,
+) -> Result<&'a HashSet<String>, Box<dyn Error>> {
+    mdcz_dis_opt
+        .get(part_name)
+        .ok_or_else(|| format!("missing key {} in mdcz_dis_sheet", part_name).into())
+}
 
 // 泛形函数根据输入的数据类型来生成读取文件并序列化为指定的类型
 fn read_json_file<T, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn Error>>
@@ -83,1300 +109,5847 @@ where
     Ok(data)
 }
 
-// 读取所有手术或所有诊断列表
-fn read_icd9_to_vec<P: AsRef<Path>>(file_path: P) -> Result<HashSet<String>, Box<dyn Error>> {
-    let contents = fs::read_to_string(file_path)?;
-    let v: HashSet<String> = contents.split(',').map(|s| s.to_string()).collect();
-    Ok(v)
+// 解析以逗号分隔的编码列表
+fn parse_icd_list(contents: &str) -> HashSet<String> {
+    // 去掉文件开头的UTF-8 BOM和每个编码首尾的空白(包括换行), 否则第一个和最后一个编码无法匹配
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    contents.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
 }
 
-// 病例结构===========================================================================================
-#[derive(Debug, Deserialize)]
-struct DrgCase {
-    id: String,               // 病例ID
-    main_dis: String,         // 主诊断编码(必填)
-    main_opt: String,         // 主手术编码(手术病例必填)
-    other_dis: Vec<String>,   // 其他诊断编码(列表)
-    other_opt: Vec<String>,   // 其他手术编码(列表)
-    sex: i32,                 // 性别(0 => 女, 1 => 男)
-    age: f64,                 // 年龄(不足一岁以小于1小数表示, 出生天数/365)
-    weight: i32,              // 体重
-    all_dis: HashSet<String>, // 所有的诊断
-    all_opt: HashSet<String>, // 所有的手术
+// 分组方案的存储方式=================================================================================
+// 按文件名读取分组方案中的文件, 目录、zip压缩包和编译进程序的数据都通过它由GroupingScheme::load读取
+trait SchemeSource {
+    // 读取一个文件的全部内容, 文件不存在时返回错误
+    fn read_member(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    // 文件是否存在, 可选的文件(perinatal_dis.txt等)不存在时使用默认值
+    fn has_member(&self, name: &str) -> bool;
 }
 
-impl DrgCase {
-    // 初始化方法
-    fn new(
-        admission_number: String,
-        principal_diagnosis: String,
-        principal_operation: String,
-        other_diagnosis: Vec<String>,
-        other_operation: Vec<String>,
-        gender: i32,
-        old: f64,
-        mass: i32,
-    ) -> Self {
-        let mut tmp_other_dis = other_diagnosis.clone();
-        let mut tmp_other_opt = other_operation.clone();
-        tmp_other_dis.push(principal_diagnosis.clone());
-        tmp_other_opt.push(principal_operation.clone());
-        Self {
-            id: admission_number,
-            main_dis: principal_diagnosis,
-            main_opt: principal_operation,
-            other_dis: other_diagnosis,
-            other_opt: other_operation,
-            sex: gender,
-            age: old,
-            weight: mass,
-            all_dis: HashSet::from_iter(tmp_other_dis), // 初始化为主诊断+其他诊断
-            all_opt: HashSet::from_iter(tmp_other_opt), // 初始化为主手术操作+其他手术操作
-        }
-    }
+// 分组方案目录(默认data)
+struct DirSource {
+    dir: PathBuf,
+}
 
-    // 检查病例数是否有主诊断
-    fn no_main_diagnosis(&self) -> bool {
-        return self.main_dis == "";
+impl DirSource {
+    fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self { dir: dir.as_ref().to_path_buf() }
     }
+}
 
-    // 检查病例是否有主手术
-    fn no_surgery(&self) -> bool {
-        return self.main_opt == "";
+impl SchemeSource for DirSource {
+    fn read_member(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = self.dir.join(name);
+        fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e).into())
     }
 
-    // 检查病例是否有其他手术
-    fn no_other_surgery(&self) -> bool {
-        return self.other_opt.len() == 0;
+    fn has_member(&self, name: &str) -> bool {
+        self.dir.join(name).exists()
     }
+}
 
-    // 检查病例是否有其他诊断
-    fn no_other_diagnosis(&self) -> bool {
-        return self.other_dis.len() == 0;
+// zip压缩包中的分组方案, 文件与方案目录中的文件同名(可以在子目录中), 打开时全部解压到内存
+#[cfg(feature = "zip")]
+struct ZipSource {
+    members: HashMap<String, Vec<u8>>,
+}
+
+#[cfg(feature = "zip")]
+impl ZipSource {
+    fn open<P: AsRef<Path>>(zip_path: P) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(&zip_path)?;
+        Self::from_bytes(&data).map_err(|e| format!("{}: {}", zip_path.as_ref().display(), e).into())
     }
 
-    // 合并主诊断与其他诊断为一个set
-    fn concat_dis(&mut self) {
-        let mut temp_dis = self.other_dis.clone();
-        let principle_dis = self.main_dis.clone();
-        self.all_dis.insert(principle_dis);
-        temp_dis.retain(|r| self.all_dis.insert(r.to_string()))
+    fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Self { members: read_zip_members(data)? })
     }
+}
 
-    // 检查病例是否是有效的手术病例
-    fn is_vaild_surgrey(&self, all_dis_list: &HashSet<String>) -> bool {
-        all_dis_list.contains(&self.main_opt)
+#[cfg(feature = "zip")]
+impl SchemeSource for ZipSource {
+    fn read_member(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.members.get(name).cloned().ok_or_else(|| format!("missing scheme file in zip: {}", name).into())
     }
 
+    fn has_member(&self, name: &str) -> bool {
+        self.members.contains_key(name)
+    }
 }
 
-// 用于读取CSV文件并初始化结构体
-#[derive(Debug, Deserialize)]
-struct TempDrgCase {
-    id: String,               // 病例ID
-    main_dis: String,         // 主诊断编码(必填)
-    main_opt: String,         // 主手术编码(手术病例必填)
-    #[serde(deserialize_with = "custom_deserializer::deserialize_sep_str")]
-    other_dis: Vec<String>,   // 其他诊断编码(列表)
-    #[serde(deserialize_with = "custom_deserializer::deserialize_sep_str")]
-    other_opt: Vec<String>,   // 其他手术编码(列表)
-    #[serde(deserialize_with = "custom_deserializer::deserialize_i32")]
-    sex: i32,                 // 性别(0 => 女, 1 => 男)
-    #[serde(deserialize_with = "custom_deserializer::deserialize_f64")]
-    age: f64,                 // 年龄(不足一岁以小于1小数表示, 出生天数/365)
-    #[serde(deserialize_with = "custom_deserializer::deserialize_i32")]
-    weight: i32,              // 体重
+// 内存中的分组方案数据, 如[("main_dis_sheet.json", include_bytes!("../data/main_dis_sheet.json")), ...]
+// 用于编译进程序的分组方案(bundled特性, --bundled-scheme), 测试中也用于构造分组方案
+#[cfg(any(test, feature = "bundled"))]
+struct EmbeddedSource<'a> {
+    members: &'a [(&'a str, &'a [u8])],
 }
 
-// 用于存放分组完了以后的数据
-#[derive(Debug, Serialize)]
-struct DrgCaseGrouped {
-    id: String,               // 病例ID
-    main_dis: String,         // 主诊断编码(必填)
-    main_opt: String,         // 主手术编码(手术病例必填)
-    other_dis: String,        // 其他诊断编码(列表)
-    other_opt: String,        // 其他手术编码(列表)
-    sex: String,                 // 性别(0 => 女, 1 => 男)
-    age: String,                 // 年龄(不足一岁以小于1小数表示, 出生天数/365)
-    weight: String,              // 体重
-    code: String,             // 分组编码
+#[cfg(any(test, feature = "bundled"))]
+impl<'a> EmbeddedSource<'a> {
+    fn new(members: &'a [(&'a str, &'a [u8])]) -> Self {
+        Self { members }
+    }
 }
 
-impl DrgCaseGrouped {
-    // 重新定义一个初始化方法
-    fn new(drgcase: DrgCase, code: String) -> Self {
-        let other_dis_str = drgcase.other_dis.join("|");   // 合并其他诊断用"|"分隔
-        let other_opt_str = drgcase.other_opt.join("|");   // 合并其他诊断用"|"分隔
-        DrgCaseGrouped { 
-            id: drgcase.id, 
-            main_dis: drgcase.main_dis, 
-            main_opt: drgcase.main_opt, 
-            other_dis: other_dis_str, 
-            other_opt: other_opt_str, 
-            sex: drgcase.sex.to_string(), 
-            age: drgcase.age.to_string(), 
-            weight: drgcase.weight.to_string(), 
-            code,
-        }
+#[cfg(any(test, feature = "bundled"))]
+impl SchemeSource for EmbeddedSource<'_> {
+    fn read_member(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.members.iter()
+            .find(|(x, _)| *x == name)
+            .map(|(_, contents)| contents.to_vec())
+            .ok_or_else(|| format!("missing embedded scheme file: {}", name).into())
     }
-}
 
+    fn has_member(&self, name: &str) -> bool {
+        self.members.iter().any(|(x, _)| *x == name)
+    }
+}
 
+// 读取分组方案中的JSON文件, 解析失败时注明文件名
+fn read_member_json<T: DeserializeOwned>(source: &dyn SchemeSource, name: &str) -> Result<T, Box<dyn Error>> {
+    parse_member_json(name, &source.read_member(name)?)
+}
 
-// CSV读取的相关操作==================================================================
-// 自定义反序列化
-mod custom_deserializer {
-    use serde::{self, Deserialize, Deserializer};
+// 解析分组方案中JSON文件的内容, 解析失败时注明文件名
+fn parse_member_json<T: DeserializeOwned>(name: &str, data: &[u8]) -> Result<T, Box<dyn Error>> {
+    serde_json::from_slice(data).map_err(|e| format!("{}: {}", name, e).into())
+}
 
-    // i32类型的反序列化
-    pub fn deserialize_i32<'de, D>(deserializer: D) -> Result<i32, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        // 移除逗号、空格等
-        let clean_str = s.replace(",", "").trim().to_string();
-        
-        // 尝试转换为数字
-        clean_str.parse::<i32>()
-            .map_err(serde::de::Error::custom)
+// 读取分组方案中可选的JSON文件, 文件不存在时为默认值
+fn read_optional_member_json<T: DeserializeOwned + Default>(source: &dyn SchemeSource, name: &str) -> Result<T, Box<dyn Error>> {
+    if !source.has_member(name) {
+        return Ok(T::default());
     }
-    
-    // 以"|"为分隔符的文本的反序列化
-    pub fn deserialize_sep_str<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        Ok(s.split('|')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect())
+    read_member_json(source, name)
+}
+
+// 读取分组方案中以逗号分隔的编码列表文件, optional为true时文件不存在为空
+fn read_member_icd_list(source: &dyn SchemeSource, name: &str, optional: bool) -> Result<HashSet<String>, Box<dyn Error>> {
+    if optional && !source.has_member(name) {
+        return Ok(HashSet::new());
     }
-    
-    // f64类型的反序列化
-    pub fn deserialize_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        
-        // 处理空字符串或纯空白
-        if s.trim().is_empty() {
-            return Ok(0.0);
-        }
+    parse_member_icd_list(name, &source.read_member(name)?)
+}
 
-        // 清理字符串：移除空格和千位分隔符
-        let clean_str = s
-            .replace(" ", "")
-            .replace(",", "")
-            .trim()
-            .to_string();
+// 解析分组方案中编码列表文件的内容
+fn parse_member_icd_list(name: &str, data: &[u8]) -> Result<HashSet<String>, Box<dyn Error>> {
+    Ok(parse_icd_list(str::from_utf8(data).map_err(|e| format!("{}: {}", name, e))?))
+}
 
-        // 尝试解析数字
-        match clean_str.parse::<f64>() {
-            Ok(num) => Ok(num),
-            Err(e) => Err(serde::de::Error::custom(format!("Failed to parse float: {}", e)))
+// 按GroupingScheme::load_mdcs读取该文件的方式解析分组方案中的一个文件, 只检查能否解析, 不保留结果(benchmark-scheme-load)
+fn parse_scheme_member(name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    match name {
+        "adrg_dis_opt_sheet.json" => parse_adrg_dis_opt_sheet(data).map(|_| ()).map_err(|e| format!("{}: {}", name, e).into()),
+        "adrg_drg_name_sheet.json" | "main_dis_sheet.json" | "ccmcc_sheet.json" | "mdc_sub_adrg.json" => {
+            parse_member_json::<HashMap<String, Vec<String>>>(name, data).map(|_| ())
         }
+        "adrg_in_condition.json" | "exclude_sheet.json" => parse_member_json::<HashMap<String, String>>(name, data).map(|_| ()),
+        "mdcz_dis_sheet.json" => parse_member_json::<HashMap<String, HashSet<String>>>(name, data).map(|_| ()),
+        "all_dis_sheet.txt" | "all_opt_sheet.txt" | "mdcy_dis_sheet.txt" | "perinatal_dis.txt" => parse_member_icd_list(name, data).map(|_| ()),
+        "adrg_categories.json" => parse_member_json::<AdrgCategories>(name, data).map(|_| ()),
+        "severity_endings.json" => parse_member_json::<SeverityEndings>(name, data).map(|_| ()),
+        "local_rules.json" => parse_member_json::<Vec<LocalRule>>(name, data).map(|_| ()),
+        "drg_weights.json" => parse_member_json::<HashMap<String, f64>>(name, data).map(|_| ()),
+        "pre_mdc_order.json" => check_pre_mdc_order(parse_member_json(name, data)?)
+            .map(|_| ())
+            .map_err(|e| format!("{}: {}", name, e).into()),
+        _ => Err(format!("unknown scheme file: {}", name).into()),
     }
 }
 
-
-// 读取CSV数据
-fn read_csv(file_path: &str) -> Result<Vec<DrgCase>, Box<dyn Error>> {
-    let mut rdr = csv::Reader::from_path(file_path)?;
-    let mut case_vec: Vec<DrgCase> = Vec::new();    
-    for result in rdr.deserialize() {
-        let record: TempDrgCase = result?;
-        let case: DrgCase = DrgCase::new(
-            record.id, 
-            record.main_dis, 
-            record.main_opt, 
-            record.other_dis, 
-            record.other_opt, 
-            record.sex, 
-            record.age, 
-            record.weight
-        );
-        case_vec.push(case)
-    }
-    Ok(case_vec)
+// 分组方案压缩包读取=================================================================================
+// 分组方案压缩包中必须包含的文件(也是分组方案缓存检查的源文件)
+const SCHEME_FILES: [&str; 11] = [
+    "adrg_dis_opt_sheet.json",
+    "adrg_drg_name_sheet.json",
+    "adrg_in_condition.json",
+    "all_dis_sheet.txt",
+    "all_opt_sheet.txt",
+    "ccmcc_sheet.json",
+    "exclude_sheet.json",
+    "main_dis_sheet.json",
+    "mdc_sub_adrg.json",
+    "mdcy_dis_sheet.txt",
+    "mdcz_dis_sheet.json",
+];
+
+// 编译进程序的分组方案(bundled特性): 编译时data目录中的必需文件, 顺序与SCHEME_FILES相同
+#[cfg(feature = "bundled")]
+macro_rules! bundled_members {
+    ($($name:literal),* $(,)?) => {
+        [$(($name, include_bytes!(concat!("../data/", $name)) as &[u8])),*]
+    };
 }
 
+#[cfg(feature = "bundled")]
+const BUNDLED_SCHEME: [(&str, &[u8]); 11] = bundled_members!(
+    "adrg_dis_opt_sheet.json",
+    "adrg_drg_name_sheet.json",
+    "adrg_in_condition.json",
+    "all_dis_sheet.txt",
+    "all_opt_sheet.txt",
+    "ccmcc_sheet.json",
+    "exclude_sheet.json",
+    "main_dis_sheet.json",
+    "mdc_sub_adrg.json",
+    "mdcy_dis_sheet.txt",
+    "mdcz_dis_sheet.json",
+);
+
+// 分组方案中可以没有的文件
+const OPTIONAL_SCHEME_FILES: [&str; 6] = [
+    "perinatal_dis.txt", "adrg_categories.json", "severity_endings.json", "local_rules.json", "drg_weights.json", "pre_mdc_order.json",
+];
+
+// ADRG类型划分=======================================================================================
+// ADRG编码第二位字母对应的类型, 不同版本的分组方案边界不同
+// 可选的adrg_categories.json, 如{"surgery": "ABCDEFGHIJ", "operation": "KLMNOPQ", "internal": "RSTUVWXYZ"}
+#[derive(Debug, Clone, Deserialize)]
+struct AdrgCategories {
+    surgery: String,   // 外科手术组
+    operation: String, // 非手术室操作组
+    internal: String,  // 内科组
+}
 
-// 写入CSV数据
-fn write_csv(drgcases: Vec<DrgCaseGrouped>, file_path: &str) -> Result<(), Box<dyn Error>> {
-    let file = File::create(file_path)?;                       // 创建文件路径
-    let mut wrt = csv::Writer::from_writer(file); // 初始化写入模块
-    for d in drgcases {
-        // 逐行写入
-        wrt.serialize(d)?;
+impl Default for AdrgCategories {
+    fn default() -> Self {
+        Self {
+            surgery: String::from("ABCDEFGHIJ"),
+            operation: String::from("KLMNOPQ"),
+            internal: String::from("RSTUVWXYZ"),
+        }
     }
-    wrt.flush()?;         // 确保数据被写入
-    println!("Grouped data is write into your path");
-    Ok(())
 }
 
-// 判断病例所进入的MDC============================
-// 先期分组
-fn is_mdca(
-    record: &DrgCase,                                // 病例
-    adrg_dis_opt: &HashMap<String, HashSet<String>>, // ADRG诊断手术表
-    all_opt_list: &HashSet<String>,                  // 全部手术列表
-    adrg_type_dict: &HashMap<String, String>,        // ADRG类型及对应入组类型
-    mdc_name: String,
-) -> String {
-    if record.no_surgery() {
-        return String::from("KBBZ");
-    }
-    let mut pred = String::from("KBBZ");
-    // 因为MDCA没有主诊表，所以这里要判断病例是否进入MDCA下的ADRG
-    let adrg_list = vec![
-        "AA1", "AA2", "AB1", "AC1", "AD1", "AE1", "AF1", "AG1", "AG2", "AG3", "AH1", "AH2",
-    ];
-    // 遍历MDCA下的ADRG
-    for cate in adrg_list {
-        pred = process_adrg(
-            record,
-            adrg_dis_opt,
-            all_opt_list,
-            adrg_type_dict,
-            cate.to_string(),
-        );
-        if pred != "KBBZ" {
-            break;
+impl AdrgCategories {
+    // ADRG的类型: surgery, operation或internal, 无法判断(如KBBZ)时为None
+    fn drg_type(&self, adrg_name: &str) -> Option<&'static str> {
+        if adrg_name == "KBBZ" {
+            return None;
+        }
+        let c = adrg_name.chars().nth(1)?;
+        if self.surgery.contains(c) {
+            return Some("surgery");
+        } else if self.operation.contains(c) {
+            return Some("operation");
+        } else if self.internal.contains(c) {
+            return Some("internal");
         }
+        None
     }
-    return pred;
 }
 
-// MDCZ先期分组
-fn is_mdcz(
-    record: &DrgCase,                                // 病例
-    adrg_dis_opt: &HashMap<String, HashSet<String>>, // ADRG诊断手术表
-    all_opt_list: &HashSet<String>,                  // 全部手术列表
-    adrg_type_dict: &HashMap<String, String>,   // ADRG类型及对应入组类型
-    mdcz_dis_sheet: &HashMap<String, HashSet<String>>,   // MDC主诊表
-    mdc_name: String,
-) -> String {
-    let tmp_adrg = "ZZ1".to_string();
-    let pred = is_mdcz_dis(record, mdcz_dis_sheet, tmp_adrg);
-    if pred == "ZZ1" {
-        return String::from("MDCZ");
-    } else {
-        return String::from("KBBZ");
-    }
+// DRG结尾数字=======================================================================================
+// 并发症等级对应的DRG结尾数字, 不同版本的分组方案约定不同
+// 可选的severity_endings.json, 如{"mcc": 1, "cc": 3, "none": 5, "single": 9}, 没有给出的项取默认值
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SeverityEndings {
+    mcc: i32,    // 伴严重并发症或合并症
+    cc: i32,     // 伴一般并发症或合并症
+    none: i32,   // 不伴并发症或合并症
+    single: i32, // ADRG下只有一个DRG(不区分并发症)
 }
 
-// MDCP先期分组
-fn is_mdcp(
-    record: &DrgCase,                                // 病例
-    main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
-    mdc_name: String,
-) -> String {
-    // BUG 国家版的分组方案里面MDCP居然没有主诊表
-    if record.age <= 0.0795 {
-        // 新生儿要求为出生距今29天内的，29 / 365 ≈ 0.0795
-        return String::from("MDCP");
-    } else {
-        return String::from("KBBZ");
+impl Default for SeverityEndings {
+    fn default() -> Self {
+        Self { mcc: 1, cc: 3, none: 5, single: 9 }
     }
 }
 
-// MDCY先期分组
-fn is_mdcy(
-    record: &DrgCase,                               // 病例结构体
-    adrg_type_dict: &HashMap<String, String>,       // ADRG类型及对应入组类型
-    mdcy_dis_sheet: &HashSet<String>,
-    mdc_name: String,
-) -> String {
-    if mdcy_dis_sheet.is_disjoint(&record.all_dis) {
-        return String::from("KBBZ");
-    } else {
-        return String::from("MDCY");
+impl SeverityEndings {
+    // DRG是否因为CC/MCC进入了较高的等级
+    fn raised_by_ccmcc(&self, drg: &str) -> bool {
+        let ending = drg.chars().last().and_then(|c| c.to_digit(10)).map(|x| x as i32);
+        ending == Some(self.mcc) || ending == Some(self.cc)
     }
 }
 
-// 特殊的MDCN判断性别
-fn is_mdcn(
-    record: &DrgCase,                                // 病例
-    main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
-    mdc_name: String,
-) -> String {
-    // 判断性别为女sex为0
-    if (record.sex == 0) && (main_dis_sheet[&record.main_dis][0] == "MDCN") {
-        return String::from("MDCY");
-    } else {
-        return String::from("KBBZ");
-    }
+// 分组方案结构=======================================================================================
+// 一套完整的分组方案数据
+struct GroupingScheme {
+    adrg_dis_opt: AdrgSheet,                           // ADRG内涵诊断和手术操作表
+    all_opt_list: HashSet<String>,                     // 所有手术操作列表
+    all_dis_list: HashSet<String>,                     // 所有诊断列表
+    main_dis_sheet: HashMap<String, Vec<String>>,      // 各个MDC的主诊表
+    mdcy_dis_sheet: HashSet<String>,                   // MDCY的诊断表
+    mdcz_dis_sheet: HashMap<String, HashSet<String>>,  // MDCZ的诊断表
+    adrg_type_dict: HashMap<String, String>,           // 各个ADRG组进入的判断条件
+    mdc_sub_adrg: HashMap<String, Vec<String>>,        // MDC下的ADRG列表
+    ccmcc_sheet: HashMap<String, Vec<String>>,         // CCMCC列表
+    exclude_sheet: HashMap<String, String>,            // 主诊断排除表
+    adrg_drg_name_sheet: HashMap<String, Vec<String>>, // ADRG下的DRG
+    adrg_drg_levels: DrgLevelSheet,                    // ADRG下按结尾数字索引的DRG, 读取时预先计算
+    mdc_dis_index: HashMap<String, Vec<String>>,       // MDC下的主诊断(主诊表的反向索引), 读取时预先计算
+    age_thresholds: Vec<f64>,                          // 分组用到的年龄分界(岁), 读取时预先计算, 用于case_hash的年龄分段
+    perinatal_dis_list: HashSet<String>,               // 围产期主诊断列表(可选的perinatal_dis.txt), 用于--strict-age-for-mdcp
+    adrg_categories: AdrgCategories,                   // ADRG编码字母对应的外科/操作/内科类型(可选的adrg_categories.json)
+    severity_endings: SeverityEndings,                 // 并发症等级对应的DRG结尾数字(可选的severity_endings.json)
+    local_rules: Vec<LocalRule>,                       // 分组之后改写DRG的本地规则(可选的local_rules.json)
+    drg_weights: HashMap<String, f64>,                 // DRG的相对权重(RW)(可选的drg_weights.json), 如{"FR25": 0.85}
+    pre_mdc_order: Vec<String>,                        // 先期分组MDC的判断顺序(可选的pre_mdc_order.json), 为空时按默认顺序
+    warnings: Vec<String>,                             // 读取时的警告(如同时属于MDCM和MDCN的诊断), 不影响分组, 由调用方决定是否显示
 }
 
-// 特殊的MDCN判断性别
-fn is_mdcm(
-    record: &DrgCase,                                // 病例
-    main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
-    mdc_name: String,
-) -> String {
-    // 判断性别为男sex为1
-    if (record.sex == 1) && (main_dis_sheet[&record.main_dis][0] == "MDCM") {
-        return String::from("MDCY");
-    } else {
-        return String::from("KBBZ");
-    }
+// 分组方案中可选文件的内容, 文件不存在时为默认值
+// 解析源文件和读取分组方案缓存时都从SchemeSource读取(缓存中只保存必须的文件)
+struct OptionalTables {
+    perinatal_dis_list: HashSet<String>,
+    adrg_categories: AdrgCategories,
+    severity_endings: SeverityEndings,
+    local_rules: Vec<LocalRule>,
+    drg_weights: HashMap<String, f64>,
+    pre_mdc_order: Vec<String>,
 }
 
-// 普通MDC判断
-fn is_common_mdc(
-    record: &DrgCase,
-    main_dis_sheet: &HashMap<String, Vec<String>>,
-    mdc_name: String
-) -> String {
-    // 如果病例的主诊断在MDC主诊表中
-    if main_dis_sheet[&record.main_dis][0].to_string() == mdc_name {
-        return mdc_name
-    } else {
-        return String::from("KBBZ")
-    }
+// 读取分组方案中的可选文件, 并检查DRG结尾数字与并发症等级约定一致
+fn read_optional_tables(source: &dyn SchemeSource, adrg_drg_levels: &DrgLevelSheet) -> Result<OptionalTables, Box<dyn Error>> {
+    let severity_endings = read_optional_member_json(source, "severity_endings.json")?;
+    validate_drg_endings(adrg_drg_levels, &severity_endings)?;
+    Ok(OptionalTables {
+        perinatal_dis_list: read_member_icd_list(source, "perinatal_dis.txt", true)?,
+        adrg_categories: read_optional_member_json(source, "adrg_categories.json")?,
+        severity_endings,
+        local_rules: read_optional_member_json(source, "local_rules.json")?,
+        drg_weights: read_optional_member_json(source, "drg_weights.json")?,
+        pre_mdc_order: check_pre_mdc_order(read_optional_member_json(source, "pre_mdc_order.json")?)
+            .map_err(|e| format!("pre_mdc_order.json: {}", e))?,
+    })
 }
 
-// 各ADRG入组方式===================================
-// 包含主手术
-fn is_contain_main_opt(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    if record.no_surgery() {
-        // 无主手术的无法入组
-        return String::from("KBBZ");
+// 检查主诊表, 返回同时属于MDCM和MDCN的诊断的警告, 由调用方决定是否显示(解析源文件和读取分组方案缓存时都检查)
+fn check_main_dis_sheet(main_dis_sheet: &HashMap<String, Vec<String>>) -> Result<Vec<String>, Box<dyn Error>> {
+    validate_main_dis_sheet(main_dis_sheet)?;
+    Ok(sex_mdc_warning(main_dis_sheet).into_iter().collect())
+}
+
+impl GroupingScheme {
+    // 读取全部分组方案数据, 如GroupingScheme::load(&DirSource::new(DATA_DIR))
+    fn load(source: &dyn SchemeSource) -> Result<Self, Box<dyn Error>> {
+        Self::load_mdcs(source, None)
     }
-    if adrg_dis_opt[&adrg_name].contains(&record.main_opt) {
-        adrg_name
-    } else {
-        String::from("KBBZ")
+
+    // 只读取部分MDC的分组方案(开发调试用), 其他MDC(包括先期分组的MDCA等)的ADRG列表为空, 进入这些MDC的病例为KBBZ
+    // 只保留这些MDC下ADRG的诊断手术表, 主诊表等其他数据与完整方案相同
+    fn load_subset(source: &dyn SchemeSource, mdcs: &[&str]) -> Result<Self, Box<dyn Error>> {
+        Self::load_mdcs(source, Some(mdcs))
     }
-}
 
-// 同时有两手术
-fn is_contain_opt_simultaneously(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_opt1: String = adrg_name.to_string() + "_normal_list"; // 手术条件表1
-    let verb_opt2: String = adrg_name.to_string() + "_other_list"; // 手术条件表2
+    fn load_mdcs(source: &dyn SchemeSource, mdcs: Option<&[&str]>) -> Result<Self, Box<dyn Error>> {
+        let adrg_drg_name_sheet = read_member_json(source, "adrg_drg_name_sheet.json")?;
+        let adrg_drg_levels = drg_level_sheet(&adrg_drg_name_sheet)?;
+        let main_dis_sheet = read_member_json(source, "main_dis_sheet.json")?;
+        let warnings = check_main_dis_sheet(&main_dis_sheet)?;
+        let ccmcc_sheet = read_member_json(source, "ccmcc_sheet.json")?;
+        validate_ccmcc_sheet(&ccmcc_sheet)?;
+        let mut mdc_sub_adrg: HashMap<String, Vec<String>> = read_member_json(source, "mdc_sub_adrg.json")?;
+        validate_mdc_sub_adrg(&main_dis_sheet, &mdc_sub_adrg)?;
+        let mdc_dis_index = mdc_dis_index(&main_dis_sheet);
+        let adrg_type_dict = read_member_json(source, "adrg_in_condition.json")?;
+        validate_adrg_drg_lists(&adrg_type_dict, &adrg_drg_name_sheet)?;
+        let mut adrg_dis_opt = parse_adrg_dis_opt_sheet(&source.read_member("adrg_dis_opt_sheet.json")?)
+            .map_err(|e| format!("adrg_dis_opt_sheet.json: {}", e))?;
+        if let Some(mdcs) = mdcs {
+            // 保留MDC的键(ADRG列表为空), 不会被当作分组方案缺少ADRG列表
+            for (mdc, adrg_list) in mdc_sub_adrg.iter_mut() {
+                if !mdcs.contains(&mdc.as_str()) {
+                    adrg_list.clear();
+                }
+            }
+            let kept: HashSet<&String> = mdc_sub_adrg.values().flatten().collect();
+            adrg_dis_opt.retain(|adrg, _| kept.contains(adrg));
+        }
+        populate_main_dis_lists(&adrg_type_dict, &mut adrg_dis_opt);
+        let age_thresholds = age_thresholds(&adrg_dis_opt, &ccmcc_sheet);
+        let optional = read_optional_tables(source, &adrg_drg_levels)?;
+        Ok(Self {
+            adrg_dis_opt,
+            all_opt_list: read_member_icd_list(source, "all_opt_sheet.txt", false)?,
+            all_dis_list: read_member_icd_list(source, "all_dis_sheet.txt", false)?,
+            main_dis_sheet,
+            mdcy_dis_sheet: read_member_icd_list(source, "mdcy_dis_sheet.txt", false)?,
+            mdcz_dis_sheet: read_member_json(source, "mdcz_dis_sheet.json")?,
+            adrg_type_dict,
+            mdc_sub_adrg,
+            ccmcc_sheet,
+            exclude_sheet: read_member_json(source, "exclude_sheet.json")?,
+            adrg_drg_name_sheet,
+            adrg_drg_levels,
+            mdc_dis_index,
+            age_thresholds,
+            perinatal_dis_list: optional.perinatal_dis_list,
+            adrg_categories: optional.adrg_categories,
+            severity_endings: optional.severity_endings,
+            local_rules: optional.local_rules,
+            drg_weights: optional.drg_weights,
+            pre_mdc_order: optional.pre_mdc_order,
+            warnings,
+        })
+    }
 
-    if record.no_surgery() {
-        // 如果没有手术则为空白病组
-        return String::from("KBBZ");
+    // 从zip压缩包读取分组方案
+    #[cfg(feature = "zip")]
+    fn load_zip<P: AsRef<Path>>(zip_path: P) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(&zip_path)?;
+        Self::load_zip_bytes(&data).map_err(|e| format!("{}: {}", zip_path.as_ref().display(), e).into())
     }
-    if (!adrg_dis_opt[&verb_opt1].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt2].is_disjoint(&record.all_opt))
-    {
-        adrg_name
-    } else {
-        String::from("KBBZ")
+
+    // 从内存中的zip压缩包读取分组方案
+    #[cfg(feature = "zip")]
+    fn load_zip_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Self::load_zip_source(&ZipSource::from_bytes(data)?)
     }
-}
 
-// 其他诊断或手术或操作1+手术或操作2
-fn is_contain_other_dis_or_other_opt1_and_other_opt2(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_other_dis = adrg_name.to_string() + "_other_dis_list";
-    let verb_opt1 = adrg_name.to_string() + "_other_opt_list1";
-    let verb_opt2 = adrg_name.to_string() + "_other_opt_list2";
-    // 为了方便后续的对比, 需要将其他诊断列表转为HashSet
-    let tmp_other_dis_set = record
-        .other_dis
-        .iter()
-        .map(|x| x.clone())
-        .collect::<HashSet<String>>();
+    // 先一次列出压缩包中缺少的全部必须文件, 再读取
+    #[cfg(feature = "zip")]
+    fn load_zip_source(source: &ZipSource) -> Result<Self, Box<dyn Error>> {
+        let missing: Vec<&str> = SCHEME_FILES.iter().filter(|x| !source.has_member(x)).copied().collect();
+        if !missing.is_empty() {
+            return Err(format!("missing scheme files in zip: {}", missing.join(", ")).into());
+        }
+        Self::load(source)
+    }
 
-    if record.no_surgery() {
-        return String::from("KBBZ");
+    // DRG的相对权重, 分组方案中没有时为None
+    fn relative_weight(&self, drg: &str) -> Option<f64> {
+        self.drg_weights.get(drg).copied()
     }
-    if ((!adrg_dis_opt[&verb_other_dis].is_disjoint(&tmp_other_dis_set))
-        || (!adrg_dis_opt[&verb_opt1].is_disjoint(&record.all_opt)))
-        && (!adrg_dis_opt[&verb_opt2].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+
+    // 规范化分组方案中的全部诊断和手术编码(--normalize-codes), 并重新计算主诊表的反向索引
+    fn normalize_codes(&mut self) {
+        for tables in self.adrg_dis_opt.values_mut() {
+            for codes in tables.values_mut() {
+                *codes = normalize_code_set(codes);
+            }
+        }
+        self.all_opt_list = normalize_code_set(&self.all_opt_list);
+        self.all_dis_list = normalize_code_set(&self.all_dis_list);
+        self.main_dis_sheet = self.main_dis_sheet.drain().map(|(k, v)| (normalize_code(&k), v)).collect();
+        self.mdcy_dis_sheet = normalize_code_set(&self.mdcy_dis_sheet);
+        for codes in self.mdcz_dis_sheet.values_mut() {
+            *codes = normalize_code_set(codes);
+        }
+        self.ccmcc_sheet = self.ccmcc_sheet.drain().map(|(k, v)| (normalize_code(&k), v)).collect();
+        self.exclude_sheet = self.exclude_sheet.drain().map(|(k, v)| (normalize_code(&k), v)).collect();
+        self.perinatal_dis_list = normalize_code_set(&self.perinatal_dis_list);
+        self.mdc_dis_index = mdc_dis_index(&self.main_dis_sheet);
     }
-}
 
-// 同时包含主要诊断与主要手术
-fn is_contain_main_dis_and_main_opt_simultaneously(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_opt = adrg_name.to_string() + "_contain_main_opt_list";
-    let verb_main_dis = adrg_name.to_string() + "_contain_main_dis_list";
-    if record.no_surgery() {
-        // 无手术的病例无法入组
-        return String::from("KBBZ");
+    // 手术操作编码是否为有效手术(在所有手术操作列表all_opt_sheet中), 有效手术进入内科ADRG时判定为QY
+    fn is_valid_surgery(&self, opt_code: &str) -> bool {
+        self.all_opt_list.contains(opt_code.trim())
     }
-    if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+
+    // MDC下的全部主诊断(已排序), 分组方案中没有该MDC时为空
+    fn diagnoses_in_mdc(&self, mdc_name: &str) -> &[String] {
+        self.mdc_dis_index.get(mdc_name).map(|x| x.as_slice()).unwrap_or(&[])
     }
-}
 
-// 包含主要诊断
-fn is_contain_main_dis(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    if adrg_dis_opt[&adrg_name].contains(&record.main_dis) {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    // 年龄所在的分段: 不小于和大于各个年龄分界的个数, 分组对年龄的判断(<=, <, >=)在同一分段内结果相同
+    fn age_band(&self, age: f64) -> String {
+        let at_least = self.age_thresholds.iter().filter(|x| age >= **x).count();
+        let above = self.age_thresholds.iter().filter(|x| age > **x).count();
+        format!("{}.{}", at_least, above)
     }
-}
 
-// 同时包含CB4与CB5手术, CB2入组使用
-fn is_contain_cb4_opt_and_cb5_opt(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    if record.no_surgery() {
-        return String::from("KBBZ");
+    // 分组方案能产生的全部DRG编码(排序去重), 包括KBBZ以及内科组对应的QY编码
+    fn all_drg_codes(&self) -> Vec<String> {
+        let mut codes: Vec<String> = self.adrg_drg_name_sheet.values().flatten().cloned().collect();
+        for adrg_name in self.adrg_drg_name_sheet.keys() {
+            // 与qy_judge一致, 有效手术进入内科ADRG时判定为QY
+            if self.adrg_categories.drg_type(adrg_name) == Some("internal") {
+                codes.push(adrg_name[0..=0].to_string() + "QY");
+            }
+        }
+        codes.push(String::from("KBBZ"));
+        codes.sort();
+        codes.dedup();
+        codes
     }
-    if (!adrg_dis_opt["CB4"].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt["CB5"].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+
+    // 对单个病例分组, 返回包含MDC、ADRG和DRG的详细结果
+    fn group(&self, record: &DrgCase, options: &GroupOptions) -> Result<GroupResult, Box<dyn Error>> {
+        // 严格模式下的错误需要注明是哪个病例
+        self.group_inner(record, options, None, None)
+            .map_err(|e| format!("case {}: {}", record.id, e).into())
     }
-}
 
-// 同时包含CB5与CB6手术, CB3入组使用
-fn is_contain_cb5_opt_and_cb6_opt(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    if record.no_surgery() {
-        return String::from("KBBZ");
+    // 与group相同, issues为case_issues(record, options)的结果, 已经检查过的病例不必在分组时再检查一遍
+    fn group_with_issues(&self, record: &DrgCase, options: &GroupOptions, issues: &[CaseIssue]) -> Result<GroupResult, Box<dyn Error>> {
+        self.group_inner(record, options, None, Some(issues))
+            .map_err(|e| format!("case {}: {}", record.id, e).into())
     }
-    if (!adrg_dis_opt["CB4"].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt["CB5"].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+
+    // 分组时看到的病例问题: --normalize-codes时为规范化编码之后的病例
+    fn case_issues(&self, record: &DrgCase, options: &GroupOptions) -> Vec<CaseIssue> {
+        if options.normalize_codes {
+            record.normalized().validate(self)
+        } else {
+            record.validate(self)
+        }
     }
-}
 
-// 入组条件1：主要诊断+主要手术或操作1,
-// 入组条件2：主要手术或操作2
-// 入组条件3：手术或操作3+手术或操作4
-fn is_contain_multi_opt1(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_dis = adrg_name.to_string() + "_main_dis_list";
-    let verb_main_opt1 = adrg_name.to_string() + "_main_opt_list1";
-    let verb_main_opt2 = adrg_name.to_string() + "_main_opt_list2";
-    let verb_opt3 = adrg_name.to_string() + "_other_opt_list3";
-    let verb_opt4 = adrg_name.to_string() + "_other_opt_list4";
-    if record.no_surgery() {
-        return String::from("KBBZ");
+    // 对未入组的病例依次把每个其他诊断作为主诊断(原主诊断改为其他诊断)重新分组, 返回能入组的(其他诊断, DRG), 不改变原病例的分组结果
+    fn suggest_main_swap(&self, record: &DrgCase, options: &GroupOptions) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        let mut suggestions: Vec<(String, String)> = Vec::new();
+        // 交换主诊断和其他诊断不改变病例的编码, 问题只检查一次
+        let issues = self.case_issues(record, options);
+        for candidate in record.dis.secondary() {
+            if suggestions.iter().any(|(x, _)| x == candidate) {
+                continue;
+            }
+            let mut other_dis: Vec<String> = record.dis.secondary().iter().filter(|x| *x != candidate).cloned().collect();
+            other_dis.push(record.dis.principal().to_string());
+            let swapped = DrgCase::new(
+                record.id.to_string(),
+                candidate.to_string(),
+                record.opt.principal().to_string(),
+                other_dis,
+                record.opt.secondary().to_vec(),
+                record.sex,
+                record.age,
+                record.weight,
+            );
+            let result = self.group_with_issues(&swapped, options, &issues)?;
+            if result.drg != "KBBZ" {
+                suggestions.push((candidate.to_string(), result.drg));
+            }
+        }
+        Ok(suggestions)
     }
 
-    if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (adrg_dis_opt[&verb_main_opt1].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else if adrg_dis_opt[&verb_main_opt2].contains(&record.main_opt) {
-        return adrg_name;
-    } else if (!adrg_dis_opt[&verb_opt3].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt4].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    // 对QY病例依次把每个其他手术作为主手术(原主手术改为其他手术)重新分组, 返回第一个能进入外科或操作ADRG的(其他手术, DRG)
+    // 按其他手术的录入顺序尝试, 不改变原病例的分组结果, 不是QY的病例返回None
+    fn suggest_main_opt(&self, record: &DrgCase, options: &GroupOptions) -> Result<Option<(String, String)>, Box<dyn Error>> {
+        let issues = self.case_issues(record, options);
+        if !self.group_with_issues(record, options, &issues)?.adrg.ends_with("QY") {
+            return Ok(None);
+        }
+        for candidate in record.opt.secondary() {
+            let mut other_opt: Vec<String> = record.opt.secondary().iter().filter(|x| *x != candidate).cloned().collect();
+            other_opt.push(record.opt.principal().to_string());
+            let reselected = DrgCase::new(
+                record.id.to_string(),
+                record.dis.principal().to_string(),
+                candidate.to_string(),
+                record.dis.secondary().to_vec(),
+                other_opt,
+                record.sex,
+                record.age,
+                record.weight,
+            );
+            let result = self.group_with_issues(&reselected, options, &issues)?;
+            if result.drg != "KBBZ" && !result.adrg.ends_with("QY") {
+                return Ok(Some((candidate.to_string(), result.drg)));
+            }
+        }
+        Ok(None)
     }
-}
 
-// 入组条件1：主要诊断+手术或操作1+手术或操作2
-// 入组条件2：主要诊断+手术或操作1+手术或操作3+手术或操作4
-// 入组条件3：主要诊断+手术或操作4+手术或操作5
-fn is_contain_multi_opt2(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_dis = adrg_name.to_string() + "_main_dis_list";
-    let verb_opt1 = adrg_name.to_string() + "_other_opt_list1";
-    let verb_opt2 = adrg_name.to_string() + "_other_opt_list2";
-    let verb_opt3 = adrg_name.to_string() + "_other_opt_list3";
-    let verb_opt4 = adrg_name.to_string() + "_other_opt_list4";
-    let verb_opt5 = adrg_name.to_string() + "_other_opt_list5";
+    // 用备选主诊断代替主诊断(原主诊断改为其他诊断)重新分组, 按prefer在两个结果中选择(--main-dis-alt, --best-main-dis)
+    // main为按原主诊断分组的结果, 两者相同或无法区分时保留原主诊断
+    fn choose_main_dis(&self, record: &DrgCase, main: &GroupResult, alt_main_dis: &str, prefer: MainDisPreference, options: &GroupOptions) -> Result<MainDisChoice, Box<dyn Error>> {
+        let alt = self.group(&record.with_main_dis(alt_main_dis), options)?;
+        let ungrouped = |x: &GroupResult| x.drg == "KBBZ" || x.drg.ends_with("QY");
+        let alt_chosen = match prefer {
+            // 没有权重的DRG按0比较
+            MainDisPreference::Rw => {
+                self.relative_weight(&alt.drg).unwrap_or(0.0) > self.relative_weight(&main.drg).unwrap_or(0.0)
+            }
+            MainDisPreference::Grouped => ungrouped(main) && !ungrouped(&alt),
+        };
+        Ok(MainDisChoice { main: main.clone(), alt, alt_main_dis: alt_main_dis.to_string(), alt_chosen })
+    }
 
-    if record.no_surgery() {
-        return String::from("KBBZ");
+    // 进入MDCZ的病例在主诊断所在的MDC中能否进入外科ADRG, 返回第一个这样的(MDC, ADRG)
+    // MDCZ先于主诊断的MDC判断, 有本MDC外科手术的病例可能被过早分入MDCZ, 用于--warn-mdcz-override的人工复核
+    // 各MDC内按--adrg-priority的方式尝试全部ADRG, 外科ADRG优先
+    fn mdcz_surgical_alternative(&self, record: &DrgCase, options: &GroupOptions) -> Result<Option<(String, String)>, Box<dyn Error>> {
+        let dis_mdc_list = match self.main_dis_sheet.get(record.dis.principal()) {
+            Some(mdc_list) => mdc_list,
+            None => return Ok(None),
+        };
+        let options = GroupOptions { adrg_priority: Some(default_adrg_priority()), max_candidates: None, ..options.clone() };
+        let (mut n_tried, mut no_list_mdc) = (0, None);
+        for mdc in dis_mdc_list.iter().filter(|x| !is_pre_mdc(x)) {
+            let adrg = match_adrg_in_mdc(
+                record, &self.adrg_dis_opt, &self.all_opt_list, &self.adrg_type_dict, &self.mdc_sub_adrg, &self.adrg_categories, mdc, &options,
+                &mut n_tried, &mut no_list_mdc, &mut None,
+            )?;
+            if self.adrg_categories.drg_type(&adrg) == Some("surgery") {
+                return Ok(Some((mdc.to_string(), adrg)));
+            }
+        }
+        Ok(None)
     }
 
-    if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (!adrg_dis_opt[&verb_opt1].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt2].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (!adrg_dis_opt[&verb_opt1].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt3].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt4].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (!adrg_dis_opt[&verb_opt4].is_disjoint(&record.all_opt))
-        && (!adrg_dis_opt[&verb_opt5].is_disjoint(&record.all_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    // 病例的分组状态, 用于给每一行输出注明分组来源:
+    // ok => 正常入组, qy_adjusted => 经过QY调整, kbbz:<原因> => 未入组
+    // 未入组的原因: no_main_dis(主诊断为空), unknown_main_dis(主诊断不在主诊表中), no_adrg_match(没有满足条件的ADRG),
+    // 以及分组方案缺失导致的too_many_candidates, no_adrg_list_for_mdc, no_drg_list_for_adrg
+    // issues为case_issues的结果
+    fn group_status(&self, record: &DrgCase, result: &GroupResult, issues: &[CaseIssue]) -> String {
+        if result.drg != "KBBZ" {
+            if result.review_flags.iter().any(|x| x == "qy_adjusted") {
+                return String::from("qy_adjusted");
+            }
+            return String::from("ok");
+        }
+        if result.review_flags.iter().any(|x| x == "field_swap") {
+            // 被拒绝分组的原因为第一个填错列的编码, 如rejected:operation_as_diagnosis:96.7101
+            let issue = issues.iter().find(|x| x.is_field_swap()).map(|x| x.to_string()).unwrap_or_default();
+            let detail = issue.split_once(':').map(|(_, x)| x).unwrap_or("field_swap");
+            return format!("rejected:{}", detail);
+        }
+        let scheme_gap = ["too_many_candidates", "no_adrg_list_for_mdc", "no_drg_list_for_adrg"];
+        let reason = if let Some(flag) = result.review_flags.iter().find(|x| scheme_gap.contains(&x.as_str())) {
+            flag.as_str()
+        } else if record.no_main_diagnosis() {
+            "no_main_dis"
+        } else if self.main_dis_sheet.get(record.dis.principal()).is_none_or(|x| x.is_empty()) {
+            "unknown_main_dis"
+        } else {
+            "no_adrg_match"
+        };
+        format!("kbbz:{}", reason)
     }
-}
 
-// 入组条件1：主要诊断+主要手术或操作1
-// 入组条件2：主要诊断+手术或操作2+手术或操作3
-fn is_contain_multi_opt3(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_dis = adrg_name.to_string() + "_main_dis_list";
-    let verb_main_opt1 = adrg_name.to_string() + "_main_opt_list1";
-    let verb_main_opt2 = adrg_name.to_string() + "_other_opt_list2";
-    let verb_opt3 = adrg_name.to_string() + "_other_opt_list3";
+    // 对单个病例分组, 同时记录完整的判断路径
+    fn group_with_trace(&self, record: &DrgCase, options: &GroupOptions) -> Result<(GroupResult, CaseTrace), Box<dyn Error>> {
+        let mut trace = CaseTrace { id: record.id.to_string(), ..Default::default() };
+        let result = self.group_inner(record, options, Some(&mut trace), None)
+            .map_err(|e| format!("case {}: {}", record.id, e))?;
+        Ok((result, trace))
+    }
 
-    if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (adrg_dis_opt[&verb_main_opt1].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && ((adrg_dis_opt[&verb_main_opt2].contains(&record.main_opt))
-            || (!adrg_dis_opt[&verb_main_opt2].is_disjoint(&record.all_opt)))
+    // 惰性地逐个分组, 返回与输入顺序一致的分组结果迭代器, 可以与其他迭代器适配器串联而不必先收集为Vec
+    // 输入可以是DrgCase或&DrgCase的迭代器, 只有取出结果时才会对下一个病例分组
+    fn group_stream<'a, I>(
+        &'a self,
+        cases: I,
+        options: &'a GroupOptions,
+    ) -> impl Iterator<Item = Result<GroupResult, Box<dyn Error>>> + 'a
+    where
+        I: IntoIterator,
+        I::Item: Borrow<DrgCase>,
+        I::IntoIter: 'a,
     {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+        cases.into_iter().map(move |case| self.group(case.borrow(), options))
     }
-}
-
-// 入组条件1：主要诊断1+主要手术或操作
-// 入组条件2：主要诊断2+其他诊断+主要手术或操作
-fn is_contain_multi_opt4(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_dis1 = adrg_name.to_string() + "_main_dis_list1";
-    let verb_main_dis2 = adrg_name.to_string() + "_main_dis_list2";
-    let verb_main_opt = adrg_name.to_string() + "_main_opt_list";
-    let verb_other_dis = adrg_name.to_string() + "_other_dis_list";
 
-    // 将其他诊断转为HashSet
-    let tmp_other_dis_set = record
-        .other_dis
-        .iter()
-        .map(|x| x.clone())
-        .collect::<HashSet<String>>();
-
-    if (adrg_dis_opt[&verb_main_dis1].contains(&record.main_dis))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else if (adrg_dis_opt[&verb_main_dis2].contains(&record.main_dis))
-        && (!adrg_dis_opt[&verb_other_dis].is_disjoint(&tmp_other_dis_set))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    fn group_inner(
+        &self,
+        record: &DrgCase,
+        options: &GroupOptions,
+        mut trace: Option<&mut CaseTrace>,
+        issues: Option<&[CaseIssue]>,
+    ) -> Result<GroupResult, Box<dyn Error>> {
+        let normalized;
+        let record = if options.normalize_codes {
+            normalized = record.normalized();
+            &normalized
+        } else {
+            record
+        };
+        let mut review_flags: Vec<String> = Vec::new();
+        // 调用方没有给出病例问题时只在需要时检查
+        let field_swap = |issues: &[CaseIssue]| issues.iter().any(|x| x.is_field_swap());
+        let rejected = options.field_swap_policy == FieldSwapPolicy::Reject
+            && issues.map_or_else(|| field_swap(&record.validate(self)), field_swap);
+        if rejected {
+            // 诊断字段中有手术编码或手术字段中有诊断编码, 按--reject-field-swaps不分组
+            return Ok(GroupResult {
+                mdc: String::from("KBBZ"),
+                pre_qy_adrg: String::from("KBBZ"),
+                adrg: String::from("KBBZ"),
+                drg: String::from("KBBZ"),
+                severity: String::from("none"),
+                review_flags: vec![String::from("field_swap")],
+                local_override: None,
+            });
+        }
+        // 只有记录判断路径时才计时
+        let timer = trace.as_ref().map(|_| std::time::Instant::now());
+        // 先期分组MDC的顺序: --pre-mdc-order, 其次是分组方案的pre_mdc_order.json, 都没有时为默认顺序
+        let default_order = default_pre_mdc_order();
+        let pre_mdc_order = match &options.pre_mdc_order {
+            Some(order) => order,
+            None if !self.pre_mdc_order.is_empty() => &self.pre_mdc_order,
+            None => &default_order,
+        };
+        // 判断最终属于的MDC及ADRG
+        let mdc_adrg = which_mdc_adrg(
+            record,
+            &self.adrg_dis_opt,
+            &self.all_opt_list,
+            &self.all_dis_list,
+            &self.main_dis_sheet,
+            &self.adrg_type_dict,
+            &self.mdcz_dis_sheet,
+            &self.mdcy_dis_sheet,
+            &self.mdc_sub_adrg,
+            &self.perinatal_dis_list,
+            &self.adrg_categories,
+            pre_mdc_order,
+            options,
+            trace.as_deref_mut(),
+        );
+        let which_adrg_us = timer.map(|x| x.elapsed().as_micros() as u64).unwrap_or(0);
+        let (mdc, pre_qy_adrg) = match mdc_adrg {
+            Err(e) if e.is::<TooManyCandidates>() || e.is::<NoAdrgListForMdc>() => {
+                if let Some(t) = trace {
+                    t.which_adrg_us = which_adrg_us;
+                }
+                // 尝试的ADRG过多或分组方案缺少ADRG列表, 直接进入KBBZ并注明原因
+                let reason = if e.is::<TooManyCandidates>() { "too_many_candidates" } else { "no_adrg_list_for_mdc" };
+                return Ok(GroupResult {
+                    mdc: String::from("KBBZ"),
+                    pre_qy_adrg: String::from("KBBZ"),
+                    adrg: String::from("KBBZ"),
+                    drg: String::from("KBBZ"),
+                    severity: String::from("none"),
+                    review_flags: vec![String::from(reason)],
+                    local_override: None,
+                });
+            }
+            other => other?,
+        };
+        // QY判断
+        let adrg = if options.no_qy {
+            pre_qy_adrg.clone()
+        } else {
+            qy_judge(record, pre_qy_adrg.clone(), &self.all_opt_list, &self.adrg_categories, options)
+        };
+        if adrg != pre_qy_adrg {
+            review_flags.push(String::from("qy_adjusted"));
+        }
+        if options.warn_cross_mdc && mdc != "KBBZ" && !adrg_in_mdc(&pre_qy_adrg, &mdc) {
+            // 入组的ADRG编码首字母与判断时所在的MDC不一致, 分组方案的mdc_sub_adrg可能有误
+            eprintln!("warning: case {} grouped into ADRG {} while evaluating {}, which belongs to a different MDC", record.id, pre_qy_adrg, mdc);
+            review_flags.push(String::from("cross_mdc"));
+        }
+        if options.warn_mdcz_override && mdc == "MDCZ" {
+            if let Some((dis_mdc, surgical_adrg)) = self.mdcz_surgical_alternative(record, options)? {
+                eprintln!(
+                    "warning: case {} grouped into MDCZ ({}) but qualifies for surgical ADRG {} in {} of its main diagnosis",
+                    record.id, pre_qy_adrg, surgical_adrg, dis_mdc,
+                );
+                review_flags.push(String::from("mdcz_override"));
+            }
+        }
+        // 普通MDC的病例没有任何手术操作
+        if !is_pre_mdc(&mdc) && mdc != "KBBZ" && record.no_surgery() && record.no_other_surgery() {
+            review_flags.push(String::from("no_operation_support"));
+        }
+        // 判断最终属于的DRG
+        let timer = trace.as_ref().map(|_| std::time::Instant::now());
+        let drg = match process_drg(
+            record,
+            adrg.clone(),
+            &self.ccmcc_sheet,
+            &self.exclude_sheet,
+            &self.adrg_drg_levels,
+            &self.severity_endings,
+            options.ccmcc_scope,
+        ) {
+            Err(e) if e.is::<NoDrgListForAdrg>() && !options.strict => {
+                // 分组方案缺少入组ADRG的DRG列表, 进入KBBZ并注明原因
+                return Ok(GroupResult {
+                    mdc: String::from("KBBZ"),
+                    pre_qy_adrg: String::from("KBBZ"),
+                    adrg: String::from("KBBZ"),
+                    drg: String::from("KBBZ"),
+                    severity: String::from("none"),
+                    review_flags: vec![String::from("no_drg_list_for_adrg")],
+                    local_override: None,
+                });
+            }
+            other => other?,
+        };
+        let process_drg_us = timer.map(|x| x.elapsed().as_micros() as u64).unwrap_or(0);
+        // DRG的并发症等级(以MCC或CC对应的数字结尾)只来自于一个未被排除的CC/MCC
+        let ccmcc = valid_ccmcc(record, &self.ccmcc_sheet, &self.exclude_sheet, options.ccmcc_scope);
+        let severity = ccmcc_severity(&ccmcc);
+        let raised_by_ccmcc = self.severity_endings.raised_by_ccmcc(&drg);
+        if raised_by_ccmcc && ccmcc.len() == 1 {
+            review_flags.push(String::from("single_cc_only"));
+        }
+        // 按本地规则改写DRG
+        let mut drg = drg;
+        let mut local_override = None;
+        if let Some(rule) = self.local_rules.iter().find(|x| x.matches(record, &drg)) {
+            local_override = Some(LocalOverride { rule: rule.name.to_string(), original_drg: drg });
+            drg = rule.set_drg.to_string();
+            review_flags.push(String::from("local_override"));
+        }
+        if let Some(t) = trace {
+            t.severity = severity.to_string();
+            t.ccmcc = ccmcc;
+            t.mdc = mdc.to_string();
+            t.pre_qy_adrg = pre_qy_adrg.to_string();
+            t.adrg = adrg.to_string();
+            t.qy_adjusted = adrg != pre_qy_adrg;
+            t.drg = drg.to_string();
+            t.which_adrg_us = which_adrg_us;
+            t.process_drg_us = process_drg_us;
+            t.local_override = local_override.clone();
+            t.complications = complications(record, &self.ccmcc_sheet, &self.exclude_sheet, options.ccmcc_scope);
+            if pre_qy_adrg != "KBBZ" {
+                t.matched_tables = matched_tables(record, &self.adrg_dis_opt, &pre_qy_adrg);
+            }
+        }
+        Ok(GroupResult { mdc, pre_qy_adrg, adrg, drg, severity, review_flags, local_override })
     }
 }
 
-// 入组条件1：主要诊断+其他诊断1+主要手术或操作
-// 入组条件2：其他诊断2+主要手术或操作
-fn is_contain_multi_opt5(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_dis = adrg_name.to_string() + "_main_dis_list";
-    let verb_main_opt = adrg_name.to_string() + "_main_opt_list";
-    let verb_other_dis1 = adrg_name.to_string() + "_other_dis_list1";
-    let verb_other_dis2 = adrg_name.to_string() + "_other_dis_list2";
-    // 将其他诊断转为HashSet
-    let tmp_other_dis_set = record
-        .other_dis
-        .iter()
-        .map(|x| x.clone())
-        .collect::<HashSet<String>>();
+// 两个版本分组方案的差异, 用于diff-scheme输出
+#[derive(Debug, Default, Serialize)]
+struct SchemeDiff {
+    added_adrgs: Vec<String>,                                  // 新版增加的ADRG
+    removed_adrgs: Vec<String>,                                // 新版删除的ADRG
+    changed_conditions: Vec<(String, String, String)>,         // 入组条件变化的ADRG(ADRG, 旧条件, 新条件)
+    moved_diagnoses: Vec<(String, Vec<String>, Vec<String>)>,  // 所在MDC变化的主诊断(诊断, 旧MDC, 新MDC)
+    added_drgs: Vec<String>,                                   // 新版增加的DRG
+    removed_drgs: Vec<String>,                                 // 新版删除的DRG
+}
 
-    // 无主手术的病例进入空白组
-    if record.no_surgery() {
-        return String::from("KBBZ");
+// 对比两个版本的分组方案, 结果均已排序
+fn diff_schemes(old: &GroupingScheme, new: &GroupingScheme) -> SchemeDiff {
+    let mut diff = SchemeDiff::default();
+    for (adrg_name, new_condition) in &new.adrg_type_dict {
+        match old.adrg_type_dict.get(adrg_name) {
+            None => diff.added_adrgs.push(adrg_name.to_string()),
+            Some(old_condition) if old_condition != new_condition => {
+                diff.changed_conditions.push((adrg_name.to_string(), old_condition.to_string(), new_condition.to_string()));
+            }
+            Some(_) => {}
+        }
     }
-    // 入组判断
-    if (adrg_dis_opt[&verb_main_dis].contains(&record.main_dis))
-        && (!adrg_dis_opt[&verb_other_dis1].is_disjoint(&tmp_other_dis_set))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else if (!adrg_dis_opt[&verb_other_dis2].is_disjoint(&tmp_other_dis_set))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    diff.removed_adrgs = old.adrg_type_dict.keys()
+        .filter(|x| !new.adrg_type_dict.contains_key(*x))
+        .cloned()
+        .collect();
+    for (dis, new_mdc_list) in &new.main_dis_sheet {
+        if let Some(old_mdc_list) = old.main_dis_sheet.get(dis) {
+            if old_mdc_list != new_mdc_list {
+                diff.moved_diagnoses.push((dis.to_string(), old_mdc_list.clone(), new_mdc_list.clone()));
+            }
+        }
     }
+    let old_drgs: HashSet<String> = old.all_drg_codes().into_iter().collect();
+    let new_drgs: HashSet<String> = new.all_drg_codes().into_iter().collect();
+    diff.added_drgs = new_drgs.difference(&old_drgs).cloned().collect();
+    diff.removed_drgs = old_drgs.difference(&new_drgs).cloned().collect();
+
+    diff.added_adrgs.sort();
+    diff.removed_adrgs.sort();
+    diff.changed_conditions.sort();
+    diff.moved_diagnoses.sort();
+    diff.added_drgs.sort();
+    diff.removed_drgs.sort();
+    diff
 }
 
-// 包含 WB1、WB2、WB3的所有主要手术或操作
-fn is_contain_multi_wb_opt(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_main_opt1 = adrg_name.to_string() + "WB1_main_opt_list";
-    let verb_main_opt2 = adrg_name.to_string() + "WB2_main_opt_list";
-    let verb_main_opt3 = adrg_name.to_string() + "WB3_main_opt_list";
+// 分组方案的统计, 用于检查分组方案文件是否读取完整(scheme-stats)
+#[derive(Debug, Default, Serialize)]
+struct SchemeStats {
+    mdcs: usize,                              // 有ADRG列表的MDC数
+    adrgs: usize,                             // 有入组条件的ADRG数
+    adrg_tables: usize,                       // ADRG诊断手术表中的ADRG数
+    drgs: usize,                              // 能产生的DRG数(包括KBBZ和xQY)
+    diagnoses: usize,                         // 所有诊断列表中的诊断数
+    operations: usize,                        // 所有手术操作列表中的手术数
+    main_diagnoses: usize,                    // 主诊表中的诊断数
+    ccmcc_entries: usize,                     // CC/MCC表的条目数
+    exclude_entries: usize,                   // 主诊断排除表的条目数
+    local_rules: usize,                       // 本地规则数
+    adrgs_per_condition: BTreeMap<String, usize>, // 各入组条件类型的ADRG数
+    adrgs_per_mdc: BTreeMap<String, usize>,   // 各MDC下的ADRG数
+}
 
-    if record.no_surgery() {
-        return String::from("KBBZ");
+fn scheme_stats(scheme: &GroupingScheme) -> SchemeStats {
+    let mut stats = SchemeStats {
+        mdcs: scheme.mdc_sub_adrg.len(),
+        adrgs: scheme.adrg_type_dict.len(),
+        adrg_tables: scheme.adrg_dis_opt.len(),
+        drgs: scheme.all_drg_codes().len(),
+        diagnoses: scheme.all_dis_list.len(),
+        operations: scheme.all_opt_list.len(),
+        main_diagnoses: scheme.main_dis_sheet.len(),
+        ccmcc_entries: scheme.ccmcc_sheet.len(),
+        exclude_entries: scheme.exclude_sheet.len(),
+        local_rules: scheme.local_rules.len(),
+        ..Default::default()
+    };
+    for condition in scheme.adrg_type_dict.values() {
+        *stats.adrgs_per_condition.entry(condition.to_string()).or_default() += 1;
     }
-    if (adrg_dis_opt[&verb_main_opt1].contains(&record.main_opt))
-        || (adrg_dis_opt[&verb_main_opt2].contains(&record.main_opt))
-        || (adrg_dis_opt[&verb_main_opt3].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    for (mdc, adrg_list) in &scheme.mdc_sub_adrg {
+        stats.adrgs_per_mdc.insert(mdc.to_string(), adrg_list.len());
     }
+    stats
 }
 
-// 包含其他诊断
-fn is_contain_other_dis(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    // 将其他诊断转为HashSet
-    let tmp_other_dis_set = record
-        .other_dis
-        .iter()
-        .map(|x| x.clone())
-        .collect::<HashSet<String>>();
-    if !adrg_dis_opt[&adrg_name].is_disjoint(&tmp_other_dis_set) {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+impl SchemeStats {
+    // 两列的文本表格
+    fn to_table(&self) -> String {
+        let mut lines: Vec<String> = vec![
+            ("mdcs", self.mdcs), ("adrgs", self.adrgs), ("adrg_tables", self.adrg_tables), ("drgs", self.drgs),
+            ("diagnoses", self.diagnoses), ("operations", self.operations), ("main_diagnoses", self.main_diagnoses),
+            ("ccmcc_entries", self.ccmcc_entries), ("exclude_entries", self.exclude_entries), ("local_rules", self.local_rules),
+        ].into_iter().map(|(name, count)| format!("{:<60}{}", name, count)).collect();
+        lines.push(String::from("adrgs per condition"));
+        for (condition, count) in &self.adrgs_per_condition {
+            lines.push(format!("  {:<58}{}", condition, count));
+        }
+        lines.push(String::from("adrgs per mdc"));
+        for (mdc, count) in &self.adrgs_per_mdc {
+            lines.push(format!("  {:<58}{}", mdc, count));
+        }
+        lines.join("\n")
     }
 }
 
-// 包含主诊断或其他诊断
-fn is_contain_dis(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    // 将其他诊断转为HashSet
-    let tmp_other_dis_set = record
-        .other_dis
-        .iter()
-        .map(|x| x.clone())
-        .collect::<HashSet<String>>();
-    if (adrg_dis_opt[&adrg_name].contains(&record.main_dis))
-        && (!adrg_dis_opt[&adrg_name].is_disjoint(&tmp_other_dis_set))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+// 单个分组方案文件的大小和解析耗时(benchmark-scheme-load)
+#[derive(Debug, Serialize)]
+struct FileLoadTime {
+    file: String,   // 文件名
+    bytes: u64,     // 文件大小(字节)
+    parse_us: u64,  // 读取并解析的耗时(微秒)
+}
+
+// 逐个读取分组方案中的文件并计时, 用于找出冷启动时解析最慢的文件, 按耗时从大到小排列
+// 每个文件的耗时包括source.read_member和parse_scheme_member(与load_mdcs相同的解析), 可选的文件不存在时跳过
+fn benchmark_scheme_load(source: &dyn SchemeSource) -> Result<Vec<FileLoadTime>, Box<dyn Error>> {
+    let mut times: Vec<FileLoadTime> = Vec::new();
+    for name in SCHEME_FILES.iter().chain(OPTIONAL_SCHEME_FILES.iter()) {
+        if !source.has_member(name) {
+            continue;
+        }
+        let timer = std::time::Instant::now();
+        let data = source.read_member(name)?;
+        parse_scheme_member(name, &data)?;
+        times.push(FileLoadTime { file: name.to_string(), bytes: data.len() as u64, parse_us: timer.elapsed().as_micros() as u64 });
     }
+    times.sort_by_key(|x| std::cmp::Reverse(x.parse_us));
+    Ok(times)
 }
 
-// 包含所有手术()
-fn is_contain_all_opt(record: &DrgCase, all_opt: &HashSet<String>, adrg_name: String) -> String {
-    // 如果没有手术则进入空白病组
-    if record.no_surgery() {
-        return String::from("KBBZ");
+// 单个病例的分组判断路径, 用于--trace-file逐行输出JSON
+#[derive(Debug, Default, Serialize)]
+struct CaseTrace {
+    id: String,                       // 病例ID
+    pre_mdc_checks: Vec<TraceStep>,   // 先期分组MDC的判断结果
+    adrg_tried: Vec<AdrgTrial>,       // 依次尝试的ADRG
+    mdc: String,                      // 最终的MDC
+    pre_qy_adrg: String,              // QY判断之前的ADRG
+    adrg: String,                     // 最终的ADRG
+    qy_adjusted: bool,                // 是否经过QY调整
+    ccmcc: Vec<(String, String)>,     // 未被排除的CC/MCC(其他诊断编码, CC或MCC)
+    severity: String,                 // 并发症等级: MCC, CC或none
+    drg: String,                      // 最终的DRG
+    matched_tables: Vec<MatchedTable>, // 入组ADRG的各个诊断手术表中病例命中的编码
+    which_adrg_us: u64,               // 判断MDC及ADRG的耗时(微秒)
+    process_drg_us: u64,              // 判断DRG的耗时(微秒)
+    local_override: Option<LocalOverride>, // 本地规则对DRG的改写
+    complications: Vec<Complication>, // 其他诊断中每个CC/MCC是否计入并发症等级
+    adrg_alternatives: Vec<AdrgAlternative>, // 同一MDC内同时满足条件但按优先级未被选中的ADRG(--adrg-priority)
+    mdc_order: Vec<MdcStep>,          // MDC的完整判断顺序(先期分组MDC在前, 然后是主诊断所在的MDC)及各自的结果
+}
+
+// MDC判断顺序中的一步
+#[derive(Debug, Serialize)]
+struct MdcStep {
+    mdc: String,    // MDC编码
+    source: String, // pre_mdc(先期分组)或main_dis(主诊断所在的MDC)
+    result: String, // grouped(在此入组), overridden(满足了某个ADRG, 但被之后判断的MDC的结果覆盖), no_adrg_matched(进入但没有满足条件的ADRG), not_entered(不满足进入条件), not_evaluated(已经在MDCA入组)
+}
+
+// 其他诊断中的一个CC/MCC及其是否被主诊断排除
+#[derive(Debug, Serialize)]
+struct Complication {
+    code: String,            // 其他诊断编码
+    severity: String,        // CC或MCC
+    excluded: bool,          // 是否被主诊断排除(与主诊断在同一个排除表中)
+    exclusion_table: String, // CC/MCC所属的排除表编号, 如表6-3-1
+    condition_ok: bool,      // 是否满足CC/MCC条目的年龄性别条件, 不满足时同样不计入
+}
+
+impl CaseTrace {
+    // 供人阅读的文本格式判断路径, 按分组的步骤逐层缩进
+    fn to_text(&self) -> String {
+        let mut lines: Vec<String> = vec![format!("case {}", self.id)];
+        lines.push(String::from("  pre-MDC checks"));
+        for step in &self.pre_mdc_checks {
+            lines.push(format!("    {}: {}", step.mdc, step.result));
+        }
+        lines.push(String::from("  MDC order (pre-MDC first, then the main diagnosis MDCs)"));
+        for (i, step) in self.mdc_order.iter().enumerate() {
+            let source = if step.source == "pre_mdc" { "pre-MDC" } else { "main diagnosis" };
+            let result = match step.result.as_str() {
+                "grouped" => "<= grouped here",
+                "overridden" => "matched an ADRG, overridden by a later MDC",
+                "no_adrg_matched" => "entered, no ADRG matched",
+                "not_entered" => "not entered",
+                _ => "not evaluated",
+            };
+            lines.push(format!("    {}. {} ({}): {}", i + 1, step.mdc, source, result));
+        }
+        if self.mdc_order.is_empty() {
+            lines.push(String::from("    none evaluated: main diagnosis missing or not in main_dis_sheet"));
+        } else if !self.mdc_order.iter().any(|x| x.result == "grouped") {
+            lines.push(String::from("    all MDCs failed, case goes to KBBZ"));
+        }
+        lines.push(String::from("  ADRG tried"));
+        for trial in &self.adrg_tried {
+            let matched = if trial.matched { "matched" } else { "not matched" };
+            lines.push(format!("    {}/{} [{}]: {}", trial.mdc, trial.adrg, trial.condition, matched));
+        }
+        if !self.adrg_alternatives.is_empty() {
+            lines.push(String::from("  ADRG alternatives"));
+            for alt in &self.adrg_alternatives {
+                lines.push(format!("    {}/{} [{}]: not chosen, {} preferred", alt.mdc, alt.adrg, alt.adrg_type, alt.chosen));
+            }
+        }
+        lines.push(format!("  MDC: {}", self.mdc));
+        if self.qy_adjusted {
+            lines.push(format!("  ADRG: {} (QY adjusted from {})", self.adrg, self.pre_qy_adrg));
+        } else {
+            lines.push(format!("  ADRG: {}", self.adrg));
+        }
+        lines.push(String::from("  matched tables"));
+        for table in &self.matched_tables {
+            lines.push(format!("    {}: {}", table.key, table.codes.join(", ")));
+        }
+        lines.push(String::from("  CC/MCC"));
+        for (dis, level) in &self.ccmcc {
+            lines.push(format!("    {}: {}", dis, level));
+        }
+        lines.push(String::from("  complications"));
+        for c in &self.complications {
+            let status = if c.excluded {
+                "excluded"
+            } else if !c.condition_ok {
+                "condition not met"
+            } else {
+                "counted"
+            };
+            lines.push(format!("    {} {} [{}]: {}", c.code, c.severity, c.exclusion_table, status));
+        }
+        lines.push(format!("  severity: {}", self.severity));
+        match &self.local_override {
+            Some(x) => lines.push(format!("  DRG: {} (local rule {} overrode {})", self.drg, x.rule, x.original_drg)),
+            None => lines.push(format!("  DRG: {}", self.drg)),
+        }
+        lines.push(format!("  time: which_adrg {}us, process_drg {}us", self.which_adrg_us, self.process_drg_us));
+        lines.join("\n")
     }
 
-    if !all_opt.is_disjoint(&record.all_opt) {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+    // 按--explain-format输出判断路径, json为单行JSON
+    fn render(&self, format: &str) -> Result<String, Box<dyn Error>> {
+        match format {
+            "text" => Ok(self.to_text()),
+            _ => Ok(serde_json::to_string(self)?),
+        }
     }
 }
 
-// 包含诊断与主手术入组, WB3入组使用
-fn is_contain_dis_and_main_opt(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    let verb_dis = adrg_name.to_string() + "_main_dis_list";
-    let verb_main_opt = adrg_name.to_string() + "_main_opt_list";
-    if (!adrg_dis_opt[&verb_dis].is_disjoint(&record.all_dis))
-        && (adrg_dis_opt[&verb_main_opt].contains(&record.main_opt))
-    {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
+#[derive(Debug, Serialize)]
+struct MatchedTable {
+    key: String,        // 分组方案中的表名, 如AH1_main_opt_list1, 默认表为ADRG本身如AA1
+    codes: Vec<String>, // 病例的诊断或手术中在该表内的编码
+}
+
+// 入组ADRG的各个诊断手术表中病例命中的编码, 只列出有命中的表
+fn matched_tables(record: &DrgCase, adrg_dis_opt: &AdrgSheet, adrg_name: &str) -> Vec<MatchedTable> {
+    let mut matched: Vec<MatchedTable> = Vec::new();
+    let tables = match adrg_dis_opt.get(adrg_name) {
+        Some(tables) => tables,
+        None => return matched,
+    };
+    for (table_type, table) in tables {
+        let mut codes: Vec<String> = record.dis.all().iter()
+            .chain(record.opt.all().iter())
+            .filter(|x| x != &"" && table.contains(*x))
+            .cloned()
+            .collect();
+        if codes.is_empty() {
+            continue;
+        }
+        codes.sort();
+        codes.dedup();
+        let key = if table_type == DEFAULT_TABLE {
+            adrg_name.to_string()
+        } else {
+            format!("{}_{}", adrg_name, table_type)
+        };
+        matched.push(MatchedTable { key, codes });
     }
+    matched.sort_by(|a, b| a.key.cmp(&b.key));
+    matched
 }
 
-// 包含MDCZ的诊断，ZZ1入组使用
-fn is_mdcz_dis(
-    record: &DrgCase,
-    mdcz_dis_opt: &HashMap<String, HashSet<String>>,
-    adrg_name: String,
-) -> String {
-    // BUG 判断有问题
-    let mut counter = 0;
-    // 遍历不同部分的诊断表
-    for cate in vec![
-        "belly_dis_sheet",
-        "body_spine_dis_sheet",
-        "chest_dis_sheet",
-        "down_limb_dis_sheet",
-        "genital_dis_sheet",
-        "head_neck_dis_sheet",
-        "pelvis_dis_sheet",
-        "up_limb_dis_sheet",
-        "urinary_dis_sheet",
-    ] {
-        // 主诊断或其他诊断位于多个不同部分的诊断表中
-        if !mdcz_dis_opt[&cate.to_string()].is_disjoint(&record.all_dis)
-        {
-            counter += 1;
-        }
-    }
-    if counter > 1 {
-        return adrg_name;
-    } else {
-        return String::from("KBBZ");
-    }
+#[derive(Debug, Serialize)]
+struct TraceStep {
+    mdc: String,    // 先期分组的MDC
+    result: String, // 判断结果, KBBZ表示不满足
 }
 
-// 处理每个MDC大类
-fn process_mdc(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,
-    all_opt_list: &HashSet<String>,
-    main_dis_sheet: &HashMap<String, Vec<String>>,
-    mdcy_dis_sheet: &HashSet<String>,
-    mdcz_dis_sheet: &HashMap<String, HashSet<String>>,
+// 同一MDC内满足条件但未被选中的ADRG
+#[derive(Debug, Serialize)]
+struct AdrgAlternative {
+    mdc: String,       // 所在的MDC
+    adrg: String,      // 满足条件的ADRG
+    adrg_type: String, // ADRG的类型(DrgFunc::drg_type)
+    chosen: String,    // 按优先级选中的ADRG
+}
+
+#[derive(Debug, Serialize)]
+struct AdrgTrial {
+    mdc: String,       // 所在的MDC
+    adrg: String,      // 尝试的ADRG
+    condition: String, // ADRG的入组条件
+    matched: bool,     // 是否满足入组条件
+}
+
+// 记录一次ADRG尝试
+fn trace_adrg(
+    trace: &mut Option<&mut CaseTrace>,
+    mdc_name: &str,
+    adrg_name: &str,
     adrg_type_dict: &HashMap<String, String>,
-) -> String {
-    let mut pred_mdc = String::from("KBBZ");
-    // 逐个处理各MDC(优先处理:MDCA,MDCZ,MDCY,MDCP)
-    pred_mdc = is_mdca(record, adrg_dis_opt, all_opt_list, adrg_type_dict, String::from("MDCA"));
-    // 优先处理MDCA
-    if &pred_mdc == "MDCA" {
-        return pred_mdc
+    pred_adrg: &str,
+) {
+    if let Some(t) = trace {
+        t.adrg_tried.push(AdrgTrial {
+            mdc: mdc_name.to_string(),
+            adrg: adrg_name.to_string(),
+            condition: adrg_type_dict.get(adrg_name).cloned().unwrap_or_default(),
+            matched: pred_adrg != "KBBZ",
+        });
     }
+}
 
-    // 优先处理MDCZ
-    pred_mdc = is_mdcz(record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdcz_dis_sheet, String::from("MDCZ"));
-    if &pred_mdc == "MDCZ" {
-        return pred_mdc
+// 记录一次先期分组MDC的判断
+fn trace_pre_mdc(trace: &mut Option<&mut CaseTrace>, mdc_name: &str, result: &str) {
+    if let Some(t) = trace {
+        t.pre_mdc_checks.push(TraceStep { mdc: mdc_name.to_string(), result: result.to_string() });
     }
+}
 
-    // 优先处理MDCY
-    pred_mdc = is_mdcy(record, adrg_type_dict, mdcy_dis_sheet, String::from("MDCY"));
-    if &pred_mdc == "MDCY" {
-        return pred_mdc
-    }
+// 分组选项
+#[derive(Debug, Clone, Default)]
+struct GroupOptions {
+    strict: bool, // 严格模式, 分组方案中缺失的键直接报错而不是进入KBBZ
+    warn_cross_mdc: bool, // 入组的ADRG不属于判断时所在的MDC时在stderr提示(--warn-cross-mdc)
+    qy_all_ops: bool,       // QY判断时主手术或任一其他手术有效即可, 默认只看主手术
+    no_qy: bool,            // 跳过QY判断, 返回调整之前的ADRG/DRG
+    distinct_simultaneous_opt: bool, // 同时包含两类手术的条件要求由两个不同的手术满足
+    max_candidates: Option<usize>,   // 单个病例最多尝试的ADRG数, 超过时进入KBBZ
+    strict_age_for_mdcp: bool,       // 进入MDCP除年龄外还需要围产期主诊断或出生体重
+    normalize_codes: bool,           // 分组前规范化病例编码(全角转半角, 去首尾空白), 分组方案需要同样规范化
+    null_main_dis_error: bool,       // 主诊断为空时报错(--null-as-error), 默认进入KBBZ
+    ccmcc_scope: CcmccScope,         // 判断CC/MCC时检查的诊断范围(--ccmcc-scope)
+    adrg_priority: Option<Vec<String>>, // 同一MDC内多个ADRG满足条件时按类型的优先顺序选择(--adrg-priority), 默认取第一个
+    field_swap_policy: FieldSwapPolicy, // 诊断手术填错列的病例是照常分组并提示, 还是拒绝分组
+    warn_mdcz_override: bool,        // 进入MDCZ的病例在主诊断的MDC中也能进入外科ADRG时在stderr提示(--warn-mdcz-override)
+    pre_mdc_order: Option<Vec<String>>, // 先期分组MDC的判断顺序(--pre-mdc-order), 代替分组方案的pre_mdc_order.json
+}
+
+// 诊断字段中有手术编码或手术字段中有诊断编码时的处理
+// Warn => 照常分组, 批量分组结束时在stderr提示这类病例的数目(默认, --operations-as-diagnoses-ok)
+// Reject => 不分组, 进入KBBZ, review_flags为field_swap(--reject-field-swaps)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum FieldSwapPolicy {
+    #[default]
+    Warn,
+    Reject,
+}
+
+// 同一MDC内多个ADRG满足条件时默认的类型优先顺序: 外科手术 > 操作 > 内科
+fn default_adrg_priority() -> Vec<String> {
+    vec![String::from("surgery"), String::from("operation"), String::from("internal")]
+}
+
+// 判断CC/MCC时检查的诊断范围, 两者都不把主诊断(包括其他诊断中与主诊断相同的编码)算作自身的并发症
+// OtherDis => 按录入顺序逐个检查其他诊断, 重复录入的编码各算一次(默认)
+// AllDis => 检查病例全部诊断去重后除主诊断以外的编码, 重复录入的编码只算一次
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CcmccScope {
+    #[default]
+    OtherDis,
+    AllDis,
+}
+
+// 按主诊断和备选主诊断分别分组的结果
+#[derive(Debug, Clone)]
+struct MainDisChoice {
+    main: GroupResult,    // 按原主诊断分组
+    alt: GroupResult,     // 按备选主诊断分组
+    alt_main_dis: String, // 备选主诊断
+    alt_chosen: bool,     // 是否选择了备选主诊断
+}
 
-    // 优先处理MDCP
-    pred_mdc = is_mdcp(record, main_dis_sheet, String::from("MDCP"));
-    if &pred_mdc == "MDCY" {
-        return pred_mdc
+impl MainDisChoice {
+    fn chosen(&self) -> &GroupResult {
+        if self.alt_chosen { &self.alt } else { &self.main }
     }
 
-    // 优先处理MDCN
-    pred_mdc = is_mdcn(record, main_dis_sheet, String::from("MDCN"));
-    if &pred_mdc == "MDCN" {
-        return pred_mdc
+    // 选择的主诊断编码
+    fn chosen_main_dis<'a>(&'a self, main_dis: &'a str) -> &'a str {
+        if self.alt_chosen { &self.alt_main_dis } else { main_dis }
     }
+}
+
+// 两个候选主诊断的选择方式(--main-dis-prefer)
+// Rw => 选择相对权重较高的DRG(默认), 权重相同时保留原主诊断
+// Grouped => 原主诊断未入组(KBBZ或xQY)而备选主诊断能入组时选择备选主诊断
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum MainDisPreference {
+    #[default]
+    Rw,
+    Grouped,
+}
+
+// 病例分组的详细结果
+#[derive(Debug, Clone)]
+struct GroupResult {
+    mdc: String,  // 进入的MDC(未入组为KBBZ)
+    pre_qy_adrg: String, // QY判断之前的ADRG
+    adrg: String, // 进入的ADRG(已做QY判断, 未入组为KBBZ)
+    drg: String,  // 最终的DRG编码
+    severity: String, // 并发症等级: MCC, CC或none
+    review_flags: Vec<String>, // 需要人工复核的标记(single_cc_only, qy_adjusted, no_operation_support, cross_mdc, mdcz_override, too_many_candidates, no_adrg_list_for_mdc, no_drg_list_for_adrg, local_override)
+    local_override: Option<LocalOverride>, // 本地规则改写了DRG时的记录
+}
+
+// 本地规则对DRG的改写记录
+#[derive(Debug, Clone, Serialize)]
+struct LocalOverride {
+    rule: String,         // 规则名称
+    original_drg: String, // 改写之前的DRG
+}
+
+// 本地规则====================================================================================
+// 分组之后按医院本地政策改写DRG的规则, 读取自可选的local_rules.json(JSON数组), 按顺序匹配, 第一条匹配的规则生效
+// 如[{"name": "audit-1", "drg": "FR25", "main_dis": ["I50.900x018"], "set_drg": "FR23"}]
+// 各个条件都是可选的, 给出的条件需要同时满足:
+// drg => 原DRG等于该编码, main_dis => 主诊断在列表中, any_dis => 任一诊断在列表中, any_opt => 任一手术在列表中
+#[derive(Debug, Clone, Deserialize)]
+struct LocalRule {
+    name: String,
+    #[serde(default)]
+    drg: Option<String>,
+    #[serde(default)]
+    main_dis: Option<Vec<String>>,
+    #[serde(default)]
+    any_dis: Option<Vec<String>>,
+    #[serde(default)]
+    any_opt: Option<Vec<String>>,
+    set_drg: String,
+}
 
-    // 优先处理MDCM
-    pred_mdc = is_mdcm(record, main_dis_sheet, String::from("MDCM"));
-    if &pred_mdc == "MDCM" {
-        return pred_mdc
+impl LocalRule {
+    fn matches(&self, record: &DrgCase, drg: &str) -> bool {
+        self.drg.as_ref().is_none_or(|x| x == drg)
+            && self.main_dis.as_ref().is_none_or(|x| x.iter().any(|c| c == record.dis.principal()))
+            && self.any_dis.as_ref().is_none_or(|x| x.iter().any(|c| record.dis.all().contains(c)))
+            && self.any_opt.as_ref().is_none_or(|x| x.iter().any(|c| record.opt.all().contains(c)))
     }
-    // 遍历其他普通入组的MDC
-    for m in vec![
-        "MDCB", "MDCC", "MDCD", "MDCE", "MDCF", "MDCG",
-        "MDCH", "MDCI", "MDCJ", "MDCK", "MDCL", "MDCO",
-        "MDCQ", "MDCR", "MDCS", "MDCT", "MDCU", "MDCV",
-        "MDCW", "MDCX", "MDCY"
-    ] {
-        pred_mdc = is_common_mdc(record, main_dis_sheet, m.to_string());
-        if &pred_mdc == m {
-            break;
-        }
+}
+
+// 分组方案缓存=====================================================================================
+// 解析后的分组方案按简单的二进制格式保存, 之后启动时源文件没有变化就直接读取缓存, 不再解析JSON
+// 文件结构为"DRGCACHE1 <程序版本>\n" + 源文件指纹(文件名, 大小, 修改时间) + 各个表, 字符串和集合都以长度(u64小端)开头
+// 只缓存SCHEME_FILES中的表, 可选的小文件(perinatal_dis.txt等)每次都重新读取, 预先计算的索引读取缓存后重新计算
+// 文件头包含Cargo.toml中的版本, 其他版本的程序写入的缓存(格式可能不同)视为失效
+const SCHEME_CACHE_MAGIC: &[u8] = concat!("DRGCACHE1 ", env!("CARGO_PKG_VERSION"), "\n").as_bytes();
+
+// 源文件的指纹: (文件名, 大小, 修改时间纳秒), 任一文件的大小或修改时间变化后缓存失效
+type SchemeFingerprint = Vec<(String, u64, u64)>;
+
+fn scheme_fingerprint(data_dir: &Path) -> Result<SchemeFingerprint, Box<dyn Error>> {
+    let mut fingerprint = Vec::new();
+    for file in SCHEME_FILES {
+        let metadata = fs::metadata(data_dir.join(file)).map_err(|e| format!("{}: {}", file, e))?;
+        let mtime = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_nanos() as u64;
+        fingerprint.push((file.to_string(), metadata.len(), mtime));
     }
-    return pred_mdc;
+    Ok(fingerprint)
+}
 
+struct CacheWriter {
+    data: Vec<u8>,
 }
 
-// 处理每个ADRG入组
-fn process_adrg(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>, // ADRG诊断手术表
-    all_opt_list: &HashSet<String>,                  // 全部手术列表
-    adrg_type_dict: &HashMap<String, String>,   // ADRG类型及对应入组类型
-    // mdcz_dis_sheet: &HashMap<String, HashSet<String>>, // MDCZ诊断表
-    adrg_name: String,
-) -> String {
-    let pred_adrg = match adrg_type_dict[&adrg_name].as_str() {
-        "is_contain_main_dis" => is_contain_main_dis(record, adrg_dis_opt, adrg_name),
-        "is_contain_main_opt" => is_contain_main_opt(record, adrg_dis_opt, adrg_name),
-        "is_contain_main_dis_and_main_opt_simultaneously" => {
-            is_contain_main_dis_and_main_opt_simultaneously(record, adrg_dis_opt, adrg_name)
-        }
-        "is_contain_dis" => is_contain_dis(record, adrg_dis_opt, adrg_name),
-        "is_contain_opt_simultaneously" => {
-            is_contain_opt_simultaneously(record, adrg_dis_opt, adrg_name)
+impl CacheWriter {
+    fn u64(&mut self, x: u64) {
+        self.data.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn str(&mut self, x: &str) {
+        self.u64(x.len() as u64);
+        self.data.extend_from_slice(x.as_bytes());
+    }
+
+    fn strs<'a, I: ExactSizeIterator<Item = &'a String>>(&mut self, codes: I) {
+        self.u64(codes.len() as u64);
+        for x in codes {
+            self.str(x);
         }
-        "is_contain_all_opt" => is_contain_all_opt(record, all_opt_list, adrg_name),
-        "is_contain_multi_opt3" => is_contain_multi_opt3(record, adrg_dis_opt, adrg_name),
-        "is_contain_other_dis" => is_contain_other_dis(record, adrg_dis_opt, adrg_name),
-        "is_contain_multi_opt5" => is_contain_multi_opt5(record, adrg_dis_opt, adrg_name),
-        "is_contain_other_dis_or_other_opt1_and_other_opt2" => {
-            is_contain_other_dis_or_other_opt1_and_other_opt2(record, adrg_dis_opt, adrg_name)
+    }
+
+    fn str_map(&mut self, map: &HashMap<String, String>) {
+        self.u64(map.len() as u64);
+        for (k, v) in map {
+            self.str(k);
+            self.str(v);
         }
-        "is_contain_cb4_opt_and_cb5_opt" => {
-            is_contain_cb4_opt_and_cb5_opt(record, adrg_dis_opt, adrg_name)
+    }
+
+    fn vec_map(&mut self, map: &HashMap<String, Vec<String>>) {
+        self.u64(map.len() as u64);
+        for (k, v) in map {
+            self.str(k);
+            self.strs(v.iter());
         }
-        "is_contain_cb5_opt_and_cb6_opt" => {
-            is_contain_cb5_opt_and_cb6_opt(record, adrg_dis_opt, adrg_name)
+    }
+
+    fn set_map(&mut self, map: &HashMap<String, HashSet<String>>) {
+        self.u64(map.len() as u64);
+        for (k, v) in map {
+            self.str(k);
+            self.strs(v.iter());
         }
-        "is_contain_multi_opt1" => is_contain_multi_opt1(record, adrg_dis_opt, adrg_name),
-        "is_contain_multi_opt2" => is_contain_multi_opt2(record, adrg_dis_opt, adrg_name),
-        "is_contain_multi_opt4" => is_contain_multi_opt4(record, adrg_dis_opt, adrg_name),
-        "is_dis_and_main_opt" => is_contain_dis_and_main_opt(record, adrg_dis_opt, adrg_name),
-        "is_contain_multi_wb_opt" => is_contain_multi_wb_opt(record, adrg_dis_opt, adrg_name),
-        "is_mdcz_dis" => is_mdcz_dis(record, adrg_dis_opt, adrg_name), // 注意这里需要的是MDCZ的各部分诊断表
-        // 默认情况返回空白组
-        _ => String::from("KBBZ"),
-    };
+    }
+}
 
-    pred_adrg
+struct CacheReader<'a> {
+    data: &'a [u8],
+    pos: usize,
 }
 
+impl CacheReader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8], Box<dyn Error>> {
+        let end = self.pos.checked_add(n).ok_or("truncated scheme cache")?;
+        let b = self.data.get(self.pos..end).ok_or("truncated scheme cache")?;
+        self.pos += n;
+        Ok(b)
+    }
 
-struct DrgFunc {}
-impl DrgFunc {
-    // 判断是否为QY
-    fn is_qy(adrg_name: &String) -> bool {
-        &adrg_name[1..=2] == "QY"
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
     }
 
-    fn drg_type(adrg_name: String) -> String {
-        let surgery = vec!["A", "B", "C", "D", "E", "F", "G", "H", "I", "J"];
-        let operation = vec!["K", "L", "M", "N", "O", "P", "Q"];
-        let internal = vec!["R", "S", "T", "U", "V", "W", "X", "Y", "Z"];
-        if adrg_name == "KBBZ".to_string() {
-            "KBBZ".to_string()
-        } else if DrgFunc::is_qy(&adrg_name) {
-            "QY".to_string()
-        } else if surgery.contains(&&adrg_name[1..=1]) {
-            "surgery".to_string()
-        } else if operation.contains(&&adrg_name[1..=1]) {
-            "operation".to_string()
-        } else if internal.contains(&&adrg_name[1..=1]) {
-            "internal".to_string()
-        } else {
-            "other".to_string()
-        }
+    fn str(&mut self) -> Result<String, Box<dyn Error>> {
+        let n = self.u64()? as usize;
+        Ok(str::from_utf8(self.bytes(n)?)?.to_string())
     }
-}
 
+    fn strs<T: FromIterator<String>>(&mut self) -> Result<T, Box<dyn Error>> {
+        let n = self.u64()?;
+        (0..n).map(|_| self.str()).collect()
+    }
 
-fn qy_judge(record: &DrgCase, adrg_name: String, all_opt_list: &HashSet<String>) -> String {
-    // 判断QY
-    let internal = vec!["R", "S", "T", "U", "V", "W", "X", "Y", "Z"];
-    // 如果预测ADRG为KBBZ，则直接返回
-    if adrg_name == "KBBZ".to_string() {
-        return "KBBZ".to_string()
+    fn str_map(&mut self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let n = self.u64()?;
+        (0..n).map(|_| Ok((self.str()?, self.str()?))).collect()
     }
-    if record.is_vaild_surgrey(all_opt_list) {
-        if internal.contains(&&adrg_name[1..=1]) {
-            // 如果手术有效但是又进入了内科组，则判定为QY
-            let res_adrg = adrg_name[0..=0].to_string() + &("QY".to_string()); 
-            return res_adrg
-        } else {
-            return adrg_name
-        }
-    } else {
-        // 如果当前病例的手术无效，且预测的ADRG不为KBBZ，则返回当前预测ADRG
-        return adrg_name
-    }    
-}
 
-fn which_adrg(
-    record: &DrgCase,
-    adrg_dis_opt: &HashMap<String, HashSet<String>>,   // ADRG诊断手术表
-    all_opt_list: &HashSet<String>,                    // 全部手术列表
-    all_dis_list: &HashSet<String>,                    // 所有手术列表
-    main_dis_sheet: &HashMap<String, Vec<String>>,     // MDC主诊断列表
-    adrg_type_dict: &HashMap<String, String>,          // ADRG类型及对应入组类型
-    mdcz_dis_sheet: &HashMap<String, HashSet<String>>, // MDCZ诊断表
-    mdcy_dis_sheet: &HashSet<String>,                  // MDCY诊断表
-    mdc_sub_adrg: &HashMap<String, Vec<String>>,   // MDC下的各个ADRG
-) -> Result<String, Box<dyn std::error::Error>> {
-    // 决定进入哪个ADRG
-    let mut pred_adrg = "KBBZ".to_string();
-    let mut pred_mdc = "KBBZ".to_string();
+    fn vec_map(&mut self) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
+        let n = self.u64()?;
+        (0..n).map(|_| Ok((self.str()?, self.strs()?))).collect()
+    }
 
-    // 如果没有主诊断则无法入组，直接进入KBBZ
-    if record.no_main_diagnosis() {
-        return Ok(String::from("KBBZ"))
+    fn set_map(&mut self) -> Result<HashMap<String, HashSet<String>>, Box<dyn Error>> {
+        let n = self.u64()?;
+        (0..n).map(|_| Ok((self.str()?, self.strs()?))).collect()
     }
+}
 
-    // 主诊断所在的MDC
-    let mut target_mdc_list = main_dis_sheet[&record.main_dis].clone();
-    let pre_mdc = vec![
-        String::from("MDCA"),
-        String::from("MDCP"),
-        String::from("MDCY"),
-        String::from("MDCZ"),
-    ];
-    target_mdc_list = [pre_mdc, target_mdc_list].concat();
-    for mdc in target_mdc_list {
-        if mdc == String::from("MDCA") {
-            // 优先判断MDCA
-            pred_adrg = is_mdca(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, String::from("MDCA"));
-            if pred_adrg != String::from("KBBZ") {
-                // 如果在MDCA中找到ADRG入组
-                break
-            }
-        }        
-        else if mdc == String::from("MDCP") {
-            // 判断MDCP新生儿
-            pred_mdc = is_mdcp(&record, &main_dis_sheet, String::from("MDCP"));
-            if pred_mdc == String::from("MDCP") {
-                for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-                    pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                    if pred_adrg != "KBBZ".to_string() {
-                        break
-                    }
-                }
-            }
+impl GroupingScheme {
+    // 读取分组方案, 缓存有效时读取缓存, 否则解析源文件并写入缓存(写入失败只给出警告)
+    fn load_or_cache<P: AsRef<Path>, Q: AsRef<Path>>(data_dir: P, cache_path: Q) -> Result<Self, Box<dyn Error>> {
+        let (data_dir, cache_path) = (data_dir.as_ref(), cache_path.as_ref());
+        if let Some(scheme) = Self::read_cache(data_dir, cache_path)? {
+            return Ok(scheme);
         }
-        else if mdc == String::from("MDCY") {
-            // 判断MDCY
-            pred_mdc = is_mdcy(&record, &adrg_type_dict, &mdcy_dis_sheet, String::from("MDCY"));
-            if pred_mdc == String::from("MDCY") {
-                for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-                    pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                    if pred_adrg != "KBBZ".to_string() {
-                        break
-                    }
-                }
-            }
+        let scheme = Self::load(&DirSource::new(data_dir))?;
+        if let Err(e) = scheme.write_cache(data_dir, cache_path) {
+            eprintln!("warning: cannot write scheme cache {}: {}", cache_path.display(), e);
         }
-        else if mdc == String::from("MDCZ") {
-            // 判断MDCZ
-            pred_mdc = is_mdcz(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, &mdcz_dis_sheet, String::from("MDCZ"));
-            if pred_mdc == String::from("MDCZ") {
-                // 判断MDC内的ADRG入组
-                for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-                    pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                    if pred_adrg != "KBBZ".to_string() {
-                        break
-                    }
-                }
-            }
+        Ok(scheme)
+    }
+
+    // 缓存不存在, 格式不对或者源文件有变化时返回None
+    fn read_cache(data_dir: &Path, cache_path: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let data = match fs::read(cache_path) {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        // 版本(文件头)或源文件指纹不一致时缓存失效
+        if !data.starts_with(SCHEME_CACHE_MAGIC) {
+            return Ok(None);
         }
-        else if mdc == String::from("MDCN") {
-            // 需要判断性别的MDCN的处理
-            pred_mdc = is_mdcn(record, main_dis_sheet, "MDCN".to_string());
-            if pred_mdc == String::from("MDCN") {
-                // 判断MDC内的ADRG入组
-                for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-                    pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                    if pred_adrg != "KBBZ".to_string() {
-                        break
-                    }
-                }
+        let current_fingerprint = scheme_fingerprint(data_dir)?;
+        // 缓存损坏或被截断时同样视为失效, 重新解析源文件
+        let decoded = (|| -> Result<_, Box<dyn Error>> {
+            let mut rdr = CacheReader { data: &data, pos: SCHEME_CACHE_MAGIC.len() };
+            let n = rdr.u64()?;
+            let mut fingerprint: SchemeFingerprint = Vec::new();
+            for _ in 0..n {
+                fingerprint.push((rdr.str()?, rdr.u64()?, rdr.u64()?));
             }
-        }
-        else if mdc == String::from("MDCM") {
-            // 需要判断性别的MDCM的处理
-            pred_mdc = is_mdcm(record, main_dis_sheet, "MDCM".to_string());
-            if pred_mdc == String::from("MDCM") {
-                // 判断MDC内的ADRG入组
-                for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-                    pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                    if pred_adrg != "KBBZ".to_string() {
-                        break
-                    }
-                }
+            if fingerprint != current_fingerprint {
+                return Ok(None);
             }
-        }
-        else {
-            // 处理其他MDC
-            for adrg in mdc_sub_adrg[&mdc].clone() {
-                pred_adrg = process_adrg(&record, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg);
-                if pred_adrg != String::from("KBBZ") {
-                    break
-                }
+            let n = rdr.u64()?;
+            let mut adrg_dis_opt: AdrgSheet = HashMap::new();
+            for _ in 0..n {
+                adrg_dis_opt.insert(rdr.str()?, rdr.set_map()?);
             }
-        }
+            let tables = (
+                adrg_dis_opt,
+                rdr.strs()?,
+                rdr.strs()?,
+                rdr.vec_map()?,
+                rdr.strs()?,
+                rdr.set_map()?,
+                rdr.str_map()?,
+                rdr.vec_map()?,
+                rdr.vec_map()?,
+                rdr.str_map()?,
+                rdr.vec_map()?,
+            );
+            if rdr.pos != data.len() {
+                return Err("trailing bytes in scheme cache".into());
+            }
+            Ok(Some(tables))
+        })();
+        let (
+            adrg_dis_opt,
+            all_opt_list,
+            all_dis_list,
+            main_dis_sheet,
+            mdcy_dis_sheet,
+            mdcz_dis_sheet,
+            adrg_type_dict,
+            mdc_sub_adrg,
+            ccmcc_sheet,
+            exclude_sheet,
+            adrg_drg_name_sheet,
+        ) = match decoded {
+            Ok(Some(tables)) => tables,
+            Ok(None) | Err(_) => return Ok(None),
+        };
+        let warnings = check_main_dis_sheet(&main_dis_sheet)?;
+        let age_thresholds = age_thresholds(&adrg_dis_opt, &ccmcc_sheet);
+        let adrg_drg_levels = drg_level_sheet(&adrg_drg_name_sheet)?;
+        let optional = read_optional_tables(&DirSource::new(data_dir), &adrg_drg_levels)?;
+        Ok(Some(Self {
+            adrg_dis_opt,
+            all_opt_list,
+            all_dis_list,
+            mdc_dis_index: mdc_dis_index(&main_dis_sheet),
+            age_thresholds,
+            main_dis_sheet,
+            mdcy_dis_sheet,
+            mdcz_dis_sheet,
+            adrg_type_dict,
+            mdc_sub_adrg,
+            ccmcc_sheet,
+            exclude_sheet,
+            adrg_drg_levels,
+            adrg_drg_name_sheet,
+            perinatal_dis_list: optional.perinatal_dis_list,
+            adrg_categories: optional.adrg_categories,
+            severity_endings: optional.severity_endings,
+            local_rules: optional.local_rules,
+            drg_weights: optional.drg_weights,
+            pre_mdc_order: optional.pre_mdc_order,
+            warnings,
+        }))
+    }
 
+    fn write_cache(&self, data_dir: &Path, cache_path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut wrt = CacheWriter { data: SCHEME_CACHE_MAGIC.to_vec() };
+        let fingerprint = scheme_fingerprint(data_dir)?;
+        wrt.u64(fingerprint.len() as u64);
+        for (file, len, mtime) in &fingerprint {
+            wrt.str(file);
+            wrt.u64(*len);
+            wrt.u64(*mtime);
+        }
+        wrt.u64(self.adrg_dis_opt.len() as u64);
+        for (adrg, tables) in &self.adrg_dis_opt {
+            wrt.str(adrg);
+            wrt.set_map(tables);
+        }
+        wrt.strs(self.all_opt_list.iter());
+        wrt.strs(self.all_dis_list.iter());
+        wrt.vec_map(&self.main_dis_sheet);
+        wrt.strs(self.mdcy_dis_sheet.iter());
+        wrt.set_map(&self.mdcz_dis_sheet);
+        wrt.str_map(&self.adrg_type_dict);
+        wrt.vec_map(&self.mdc_sub_adrg);
+        wrt.vec_map(&self.ccmcc_sheet);
+        wrt.str_map(&self.exclude_sheet);
+        wrt.vec_map(&self.adrg_drg_name_sheet);
+        write_atomic(cache_path, &wrt.data)?;
+        Ok(())
     }
-    pred_adrg = qy_judge(record, pred_adrg, all_opt_list);
-    Ok(pred_adrg)
 }
 
+// 编码规范化=======================================================================================
+// 把全角ASCII字符(U+FF01-U+FF5E)和全角空格(U+3000)转为半角, 中文句号转为'.', 并去掉首尾的空白字符(包括全角空格)
+// 用于--normalize-codes, 病例编码和分组方案编码按同样的规则处理
+fn normalize_code(code: &str) -> String {
+    let converted: String = code.chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            '\u{3002}' => '.',
+            _ => c,
+        })
+        .collect();
+    converted.trim().to_string()
+}
 
-fn process_drg(
-    record: &DrgCase, 
-    adrg_name: String, 
-    ccmcc_sheet: &HashMap<String, Vec<String>>,
-    exclude_sheet: &HashMap<String,String>,
-    adrg_drg_name_sheet: &HashMap<String, Vec<String>>,
-) -> Result<String, Box<dyn Error>> {
-    if (&adrg_name == "KBBZ") || (&adrg_name[1..=2] == "QY") {
-        let res = adrg_name.clone();
-        return Ok(res)
+fn normalize_code_set(codes: &HashSet<String>) -> HashSet<String> {
+    codes.iter().map(|x| normalize_code(x)).collect()
+}
+
+// 编码集合=========================================================================================
+// 病例的诊断或手术编码: 一个主要编码加若干次要编码
+// principal() 主编码, 可能为空串(如无主手术)
+// secondary() 次要编码, 保持录入顺序
+// secondary_set() 次要编码的集合, 构造时缓存, 供与方案表做交集判断
+// all() 主编码+次要编码的集合, 空的主编码不计入
+#[derive(Debug, Clone)]
+struct CodeList<K> {
+    principal: String,
+    secondary: Vec<String>,
+    secondary_set: HashSet<String>,
+    all: HashSet<String>,
+    kind: PhantomData<K>,
+}
+
+#[derive(Debug, Clone)]
+struct DiagnosisKind;
+#[derive(Debug, Clone)]
+struct OperationKind;
+
+type Diagnoses = CodeList<DiagnosisKind>; // 诊断编码
+type Operations = CodeList<OperationKind>; // 手术操作编码
+
+impl<K> CodeList<K> {
+    fn new(principal: String, secondary: Vec<String>) -> Self {
+        let secondary_set: HashSet<String> = secondary.iter().cloned().collect();
+        let mut all = secondary_set.clone();
+        if !principal.is_empty() {
+            all.insert(principal.clone());
+        }
+        Self { principal, secondary, secondary_set, all, kind: PhantomData }
     }
-    // 判定CCMCC并决定进入哪个DRG
-    let mut pred_drg = "KBBZ".to_string();
-    let drg_wait_dict: HashMap<i32, String> = adrg_drg_name_sheet
-        .get(&adrg_name)
-        .unwrap()
-        .into_iter()
-        .map(|x| (x.chars().last().unwrap_or_default().to_digit(10).unwrap() as i32, x.to_string()))
-        .collect();
 
-    // 病例其他诊断与CCMMC列表的交集
-    let case_ccmcc = record.other_dis.iter()
-        .map(|x| ccmcc_sheet.get(x))
-        .filter(|x| !x.is_none())
-        .collect::<Vec<_>>();
+    fn principal(&self) -> &str {
+        &self.principal
+    }
 
-    // 如果没有CCMCC
-    if drg_wait_dict.len() == 1 {
-        // 如果当前ADRG下只有一个DRG那么DRG结尾必然只有9
-        pred_drg = drg_wait_dict[&9].clone();
+    fn secondary(&self) -> &[String] {
+        &self.secondary
     }
-    else if drg_wait_dict.len() == 2 {
-        // 如果当前ADRG下有两个DRG，
-        if case_ccmcc.is_empty() {
-            // 该病例无并发症，则DRG结尾为5
-            pred_drg = drg_wait_dict[&5].clone();
-        } else {
-            // 有并发症的情况需要考虑是否被主诊断排除
-            let mut exclude_label = "exclude";
-            for c in case_ccmcc {
-                if &c.unwrap()[0] == exclude_sheet.get(&record.main_dis).unwrap_or(&String::from("")) {
-                    // 如果被排除了则继续寻找下一个其他诊断
-                    continue;
-                } else {
-                    exclude_label = &c.unwrap()[1].as_str();
-                    // 如果找到了MCC就停止
-                    if exclude_label == "MCC" { break; } else { continue; }
-                }
-            }
-            if exclude_label == "MCC" {
-                // 如果存在MCC
-                // ADRG只分1和5的时候，有MCC进入1，没有MCC进入5
-                if drg_wait_dict.contains_key(&1) {
-                    pred_drg = drg_wait_dict[&1].clone();
-                } else {
-                    pred_drg = drg_wait_dict[&3].clone();
-                }
-            } 
-            else if exclude_label == "CC" {
-                // 如果只有CC
-                if drg_wait_dict.contains_key(&1) {
-                    // ADRG只分1和5的时候，有CC只能进入5
-                    pred_drg = drg_wait_dict[&5].clone();
-                } else {
-                    pred_drg = drg_wait_dict[&3].clone();
-                }
-            }
-            else {
-                // 没有有效CCMCC的情况下返回结尾为5的DRG
-                pred_drg = drg_wait_dict[&5].clone();
-            }
-        }
-    } 
-    else {
-        let mut high_ccmcc_label = "exclude";    // 默认
-        for c in case_ccmcc {
-           if &c.unwrap()[0] == exclude_sheet.get(&record.main_dis).unwrap_or(&String::from("")) {
-               // 如果并发症被排除了，则继续寻找
-               continue;
-           } 
-           else {
-               if c.unwrap()[1] == "MCC" {
-                   // 如果有MCC为被排除，则无需继续寻找，此时病例最高的并发症类型为MCC
-                   high_ccmcc_label = "MCC";
-                   break;
-               } 
-               else {
-                   // 并发症类型为CC，继续寻找是否有MCC
-                   high_ccmcc_label = c.unwrap()[0].as_str();  
-               }
-            }
-        }
-        if high_ccmcc_label == "MCC" {
-            // 如果并发症类型为MCC，则DRG以1结尾
-            pred_drg = drg_wait_dict[&1].clone();
-        } else if high_ccmcc_label == "CC" {
-            // 如果并发症类型为CC
-            if drg_wait_dict.len() == 3 {
-                // 当前ADRG下有3个DRG时，CC病例的DRG以3结尾
-                pred_drg = drg_wait_dict[&3].clone();
-            } else {
-                // 当前ADRG下有2个DRG时，CC病例的DRG以1结尾(意味着1与3合并了)
-                pred_drg = drg_wait_dict[&1].clone();
-            }
-        } else {
-            // 无CC和MCC，则DRG结尾为5
-            pred_drg = drg_wait_dict[&5].clone();
-        }
-    } 
-    Ok(pred_drg)
-}
 
+    fn secondary_set(&self) -> &HashSet<String> {
+        &self.secondary_set
+    }
 
-// 批量分组
-fn batch_drg_process(case_vec: Vec<DrgCase>, out_file_path: &str) -> Result<(), Box<dyn Error>> {
-    // 读取分组方案数据
-    // ADRG内涵诊断和手术操作表
-    let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    // 所有手术操作列表
-    let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    // 所有诊断列表
-    let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    // 各个MDC的主诊表
-    let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    // MDCY的诊断表
-    let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    // MDCZ的诊断表
-    let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    // 各个ADRG组进入的判断条件
-    let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-    // 读取MDC下的ADRG列表
-    let mdc_sub_adrg = read_file_as_str_to_tuple("data\\mdc_sub_adrg.json").unwrap();
-    // 读取CCMCC列表
-    let ccmcc_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\ccmcc_sheet.json").unwrap();
-    // 读取排除表
-    let exclude_sheet = read_file_as_str_to_str("D:\\MyScript\\rust\\DrgGrouper\\data\\exclude_sheet.json").unwrap();
-    // 读取ADRG下的DRG
-    let adrg_drg_name_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\adrg_drg_name_sheet.json").unwrap();
-    
-    let mut drg_grouped_vec: Vec<DrgCaseGrouped> = Vec::new();
-    // 批量分组
-    for case in case_vec {
-        // 判断最终属于的ADRG
-        let result_adrg = which_adrg(
-            &case, 
-            &adrg_dis_opt, 
-            &all_opt_list, 
-            &all_dis_list, 
-            &main_dis_sheet, 
-            &adrg_type_dict, 
-            &mdcz_dis_sheet, &mdcy_dis_sheet, 
-            &mdc_sub_adrg
-        ).unwrap();
-
-        // 判断属于的DRG
-        let result_drg = process_drg(
-            &case,
-            result_adrg,
-            &ccmcc_sheet,
-            &exclude_sheet,
-            &adrg_drg_name_sheet
-        ).unwrap();
-
-        // 初始化需要写入的病例类型结构
-        let c_wtr = DrgCaseGrouped::new(case, result_drg);
-        drg_grouped_vec.push(c_wtr);
-    }
-    // 写入为CSV文件到本地
-    write_csv(drg_grouped_vec, out_file_path)?;
+    fn all(&self) -> &HashSet<String> {
+        &self.all
+    }
 
-    Ok(())
+    // 是否没有主编码
+    fn no_principal(&self) -> bool {
+        self.principal.is_empty()
+    }
+
+    // 是否没有次要编码
+    fn no_secondary(&self) -> bool {
+        self.secondary.is_empty()
+    }
+
+    // 规范化每个编码, 规范化后为空的次要编码被去除
+    fn normalized(&self) -> Self {
+        let secondary: Vec<String> = self.secondary.iter()
+            .map(|x| normalize_code(x))
+            .filter(|x| !x.is_empty())
+            .collect();
+        Self::new(normalize_code(&self.principal), secondary)
+    }
+
+    // 全部编码与给定编码表是否有交集
+    // 病例的编码通常只有几个, 而方案中的编码表很大, 这里总是遍历较小的一方去查询较大的一方
+    fn intersects(&self, set: &HashSet<String>) -> bool {
+        if self.all.len() <= set.len() {
+            self.all.iter().any(|x| set.contains(x))
+        } else {
+            set.iter().any(|x| self.all.contains(x))
+        }
+    }
 }
 
+// 病例结构===========================================================================================
+#[derive(Debug)]
+struct DrgCase {
+    id: String,       // 病例ID
+    dis: Diagnoses,   // 诊断: 主诊断(必填)+其他诊断
+    opt: Operations,  // 手术: 主手术(手术病例必填)+其他手术
+    sex: i32,         // 性别(0 => 女, 1 => 男, -1 => 未知)
+    age: f64,         // 年龄(不足一岁以小于1小数表示, 出生天数/365)
+    weight: i32,      // 体重
+    main_dis_alt: String, // 备选主诊断(批量输入的main_dis_alt列), 为空时没有, 只用于--best-main-dis
+}
 
-// 单独分组
-fn single_drg_process(drgcase: DrgCase) -> Result<String, Box<dyn Error>> {
-    // 读取分组方案数据
-    // ADRG内涵诊断和手术操作表
-    let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    // 所有手术操作列表
-    let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    // 所有诊断列表
-    let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    // 各个MDC的主诊表
-    let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    // MDCY的诊断表
-    let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    // MDCZ的诊断表
-    let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    // 各个ADRG组进入的判断条件
-    let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-    // 读取MDC下的ADRG列表
-    let mdc_sub_adrg = read_file_as_str_to_tuple("data\\mdc_sub_adrg.json").unwrap();
-    // 读取CCMCC列表
-    let ccmcc_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\ccmcc_sheet.json").unwrap();
-    // 读取排除表
-    let exclude_sheet = read_file_as_str_to_str("D:\\MyScript\\rust\\DrgGrouper\\data\\exclude_sheet.json").unwrap();
-    // 读取ADRG下的DRG
-    let adrg_drg_name_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\adrg_drg_name_sheet.json").unwrap();
-    // 判断最终属于的ADRG
-    let result_adrg = which_adrg(
-        &drgcase, 
-        &adrg_dis_opt, 
-        &all_opt_list, 
-        &all_dis_list, 
-        &main_dis_sheet, 
-        &adrg_type_dict, 
-        &mdcz_dis_sheet, &mdcy_dis_sheet, 
-        &mdc_sub_adrg
-    ).unwrap();
-    // 判断最终属于的DRG
-    let result_drg = process_drg(
-        &drgcase,
-        result_adrg,
-        &ccmcc_sheet,
-        &exclude_sheet,
-        &adrg_drg_name_sheet
-    ).unwrap();
-
-    Ok(result_drg)
-    
+// 空病例: 没有诊断手术编码, 性别未知, 年龄和体重为0(与DrgCase::builder的成人年龄不同)
+impl Default for DrgCase {
+    fn default() -> Self {
+        Self::new(String::new(), String::new(), String::new(), Vec::new(), Vec::new(), UNKNOWN_SEX, 0.0, 0)
+    }
 }
 
+impl DrgCase {
+    // 逐项设置字段的构造器
+    fn builder() -> DrgCaseBuilder {
+        DrgCaseBuilder::default()
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // 收集命令行参数
-    let args: Vec<String> = env::args().collect();
-    match args[1].as_str() {
-        "--single" => {
+    // 初始化方法, 其他诊断和其他手术中与主诊断主手术重复的编码会被去除
+    #[allow(clippy::too_many_arguments)] // 字段与输入CSV的列一一对应
+    fn new(
+        admission_number: String,
+        principal_diagnosis: String,
+        principal_operation: String,
+        other_diagnosis: Vec<String>,
+        other_operation: Vec<String>,
+        gender: i32,
+        old: f64,
+        mass: i32,
+    ) -> Self {
+        Self::with_redundant(
+            admission_number,
+            principal_diagnosis,
+            principal_operation,
+            other_diagnosis,
+            other_operation,
+            gender,
+            old,
+            mass,
+            false,
+        )
+    }
+
+    // 初始化方法, keep_redundant为true时保留其他诊断和其他手术中重复的主诊断主手术编码
+    #[allow(clippy::too_many_arguments)] // 字段与输入CSV的列一一对应
+    fn with_redundant(
+        admission_number: String,
+        principal_diagnosis: String,
+        principal_operation: String,
+        mut other_diagnosis: Vec<String>,
+        mut other_operation: Vec<String>,
+        gender: i32,
+        old: f64,
+        mass: i32,
+        keep_redundant: bool,
+    ) -> Self {
+        if !keep_redundant {
+            other_diagnosis.retain(|x| x != &principal_diagnosis);
+            if !principal_operation.is_empty() {
+                other_operation.retain(|x| x != &principal_operation);
+            }
+        }
+        Self {
+            id: admission_number,
+            dis: Diagnoses::new(principal_diagnosis, other_diagnosis),
+            opt: Operations::new(principal_operation, other_operation),
+            sex: gender,
+            age: old,
+            weight: mass,
+            main_dis_alt: String::new(),
+        }
+    }
+
+    // 规范化诊断和手术编码后的病例(--normalize-codes)
+    fn normalized(&self) -> Self {
+        Self {
+            id: self.id.to_string(),
+            dis: self.dis.normalized(),
+            opt: self.opt.normalized(),
+            sex: self.sex,
+            age: self.age,
+            weight: self.weight,
+            main_dis_alt: normalize_code(&self.main_dis_alt),
+        }
+    }
+
+    // 用备选主诊断代替主诊断的病例, 原主诊断改为其他诊断(--main-dis-alt, --best-main-dis)
+    fn with_main_dis(&self, alt_main_dis: &str) -> Self {
+        let mut other_dis: Vec<String> = self.dis.secondary().iter().filter(|x| *x != alt_main_dis).cloned().collect();
+        other_dis.push(self.dis.principal().to_string());
+        DrgCase::new(
+            self.id.to_string(),
+            alt_main_dis.to_string(),
+            self.opt.principal().to_string(),
+            other_dis,
+            self.opt.secondary().to_vec(),
+            self.sex,
+            self.age,
+            self.weight,
+        )
+    }
+
+    // 检查病例数是否有主诊断
+    fn no_main_diagnosis(&self) -> bool {
+        self.dis.no_principal()
+    }
+
+    // 检查病例是否有主手术
+    fn no_surgery(&self) -> bool {
+        self.opt.no_principal()
+    }
+
+    // 检查病例是否有其他手术
+    fn no_other_surgery(&self) -> bool {
+        self.opt.no_secondary()
+    }
+
+    // 判断病例的手术操作与给定的手术操作表是否有交集
+    fn operations_intersect(&self, set: &HashSet<String>) -> bool {
+        self.opt.intersects(set)
+    }
+
+    // 出生体重分段, 用于新生儿病例的统计: <1000, 1000-1499, 1500-2499, >=2500(克), 体重未知(0)时为None
+    fn weight_band(&self) -> Option<&'static str> {
+        match self.weight {
+            w if w <= 0 => None,
+            w if w < 1000 => Some("<1000"),
+            w if w < 1500 => Some("1000-1499"),
+            w if w < 2500 => Some("1500-2499"),
+            _ => Some(">=2500"),
+        }
+    }
+
+    // 分组输入的哈希(16位十六进制), 包括主诊断、排序后的其他诊断、主手术、排序后的其他手术、性别、年龄分段和体重分段
+    // 年龄只按分组方案中用到的年龄分界(GroupingScheme::age_band)分段, 体重只区分有无出生体重, 与分组的判断一致
+    // best_main_dis(--best-main-dis)时分组结果还取决于备选主诊断, 有备选主诊断的病例把它也计入哈希
+    // 分组只依赖这些字段, 同一版本的分组方案和相同的分组选项下, 哈希相同的病例分组结果一定相同
+    // 使用FNV-1a而不是std的DefaultHasher, 保证不同版本编译的程序得到相同的哈希
+    fn case_hash(&self, scheme: &GroupingScheme, best_main_dis: bool) -> String {
+        let sorted = |set: &HashSet<String>| {
+            let mut codes: Vec<&str> = set.iter().map(|x| x.as_str()).collect();
+            codes.sort();
+            codes.join("|")
+        };
+        let mut key = format!(
+            "{}\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}\x1f{}",
+            self.dis.principal(),
+            sorted(self.dis.secondary_set()),
+            self.opt.principal(),
+            sorted(self.opt.secondary_set()),
+            self.sex,
+            scheme.age_band(self.age),
+            self.weight > 0,
+        );
+        // 没有备选主诊断时分组与不加--best-main-dis相同, 哈希也相同
+        if best_main_dis && !self.main_dis_alt.is_empty() {
+            key.push('\x1f');
+            key.push_str(&self.main_dis_alt);
+        }
+        format!("{:016x}", fnv1a(FNV_OFFSET, key.as_bytes()))
+    }
+
+    // 分组之前检查病例的问题, 不影响分组本身, 返回的问题按检查顺序排列
+    fn validate(&self, scheme: &GroupingScheme) -> Vec<CaseIssue> {
+        let mut issues: Vec<CaseIssue> = Vec::new();
+        if self.no_main_diagnosis() {
+            issues.push(CaseIssue::EmptyMainDiagnosis);
+        }
+        let mut dis_codes: Vec<&String> = self.dis.all().iter().collect();
+        dis_codes.sort();
+        for code in dis_codes {
+            if scheme.all_dis_list.contains(code) {
+                continue;
+            }
+            // ICD-10诊断编码以字母开头, ICD-9-CM-3手术编码以数字开头
+            if scheme.all_opt_list.contains(code) || code.starts_with(|c: char| c.is_ascii_digit()) {
+                issues.push(CaseIssue::OperationAsDiagnosis(code.to_string()));
+            } else {
+                issues.push(CaseIssue::UnknownDiagnosis(code.to_string()));
+            }
+        }
+        let mut opt_codes: Vec<&String> = self.opt.all().iter().collect();
+        opt_codes.sort();
+        for code in opt_codes {
+            if scheme.all_opt_list.contains(code) {
+                continue;
+            }
+            if scheme.all_dis_list.contains(code) || code.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                issues.push(CaseIssue::DiagnosisAsOperation(code.to_string()));
+            } else {
+                issues.push(CaseIssue::UnknownOperation(code.to_string()));
+            }
+        }
+        if ![0, 1, UNKNOWN_SEX].contains(&self.sex) {
+            issues.push(CaseIssue::InvalidSex(self.sex));
+        }
+        // 与is_mdcm/is_mdcn一致, 按主诊断进入的第一个MDC判断
+        match (first_mdc(&scheme.main_dis_sheet, self.dis.principal()), self.sex) {
+            (Some("MDCM"), 0) | (Some("MDCN"), 1) => issues.push(CaseIssue::SexConflict {
+                dis: self.dis.principal().to_string(),
+                mdc: first_mdc(&scheme.main_dis_sheet, self.dis.principal()).unwrap_or("").to_string(),
+            }),
+            _ => {}
+        }
+        // 与is_mdcp一致, 29天以内为新生儿
+        if self.age <= NEWBORN_MAX_AGE && self.weight > MAX_NEWBORN_WEIGHT {
+            issues.push(CaseIssue::NewbornWeight(self.weight));
+        }
+        issues
+    }
+
+    // 检查病例是否是有效的手术病例
+    fn is_vaild_surgrey(&self, all_dis_list: &HashSet<String>) -> bool {
+        all_dis_list.contains(self.opt.principal())
+    }
+
+    // 检查病例的主手术或任一其他手术是否在手术列表中
+    fn has_valid_operation(&self, all_opt_list: &HashSet<String>) -> bool {
+        self.opt.all().iter().any(|x| all_opt_list.contains(x))
+    }
+
+}
+
+// 新生儿体重(克)的合理上限, 超过时认为体重录入有误
+const MAX_NEWBORN_WEIGHT: i32 = 6000;
+
+// 问题的严重程度: error为病例无法正常分组, warning为可以分组但需要核对
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IssueSeverity {
+    Error,
+    Warning,
+}
+
+// DrgCase::validate检查出的病例问题
+#[derive(Debug, Clone, PartialEq)]
+enum CaseIssue {
+    EmptyMainDiagnosis,                     // 没有主诊断
+    UnknownDiagnosis(String),               // 诊断不在分组方案的诊断列表中
+    UnknownOperation(String),               // 手术不在分组方案的手术操作列表中
+    OperationAsDiagnosis(String),           // 诊断字段中是手术编码, 可能填错了列
+    DiagnosisAsOperation(String),           // 手术字段中是诊断编码, 可能填错了列
+    InvalidSex(i32),                        // 性别不是0, 1或-1
+    SexConflict { dis: String, mdc: String }, // 男性的主诊断只属于女性MDC, 或者相反
+    NewbornWeight(i32),                     // 新生儿的体重超过合理上限
+}
+
+impl CaseIssue {
+    // 诊断手术填错列的问题(operation_as_diagnosis, diagnosis_as_operation)
+    fn is_field_swap(&self) -> bool {
+        matches!(self, CaseIssue::OperationAsDiagnosis(_) | CaseIssue::DiagnosisAsOperation(_))
+    }
+
+    fn severity(&self) -> IssueSeverity {
+        match self {
+            CaseIssue::EmptyMainDiagnosis | CaseIssue::InvalidSex(_) => IssueSeverity::Error,
+            _ => IssueSeverity::Warning,
+        }
+    }
+
+    // 问题类型的名称, 用于输出
+    fn kind(&self) -> &'static str {
+        match self {
+            CaseIssue::EmptyMainDiagnosis => "empty_main_diagnosis",
+            CaseIssue::UnknownDiagnosis(_) => "unknown_diagnosis",
+            CaseIssue::UnknownOperation(_) => "unknown_operation",
+            CaseIssue::OperationAsDiagnosis(_) => "operation_as_diagnosis",
+            CaseIssue::DiagnosisAsOperation(_) => "diagnosis_as_operation",
+            CaseIssue::InvalidSex(_) => "invalid_sex",
+            CaseIssue::SexConflict { .. } => "sex_conflict",
+            CaseIssue::NewbornWeight(_) => "newborn_weight",
+        }
+    }
+}
+
+// 输出格式为"severity:kind[:detail]", 如"warning:unknown_diagnosis:X99.999"
+impl std::fmt::Display for CaseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let severity = match self.severity() {
+            IssueSeverity::Error => "error",
+            IssueSeverity::Warning => "warning",
+        };
+        match self {
+            CaseIssue::EmptyMainDiagnosis => write!(f, "{}:{}", severity, self.kind()),
+            CaseIssue::UnknownDiagnosis(code)
+            | CaseIssue::UnknownOperation(code)
+            | CaseIssue::OperationAsDiagnosis(code)
+            | CaseIssue::DiagnosisAsOperation(code) => write!(f, "{}:{}:{}", severity, self.kind(), code),
+            CaseIssue::InvalidSex(sex) => write!(f, "{}:{}:{}", severity, self.kind(), sex),
+            CaseIssue::SexConflict { dis, mdc } => write!(f, "{}:{}:{}/{}", severity, self.kind(), dis, mdc),
+            CaseIssue::NewbornWeight(weight) => write!(f, "{}:{}:{}", severity, self.kind(), weight),
+        }
+    }
+}
+
+// 病例构造器, 未设置的字段使用默认值: 其他诊断手术为空, 性别未知, 成人年龄, 体重为0
+#[derive(Debug, Clone)]
+struct DrgCaseBuilder {
+    id: String,
+    main_dis: String,
+    main_opt: String,
+    other_dis: Vec<String>,
+    other_opt: Vec<String>,
+    sex: i32,
+    age: f64,
+    weight: i32,
+    keep_redundant: bool,
+}
+
+impl Default for DrgCaseBuilder {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            main_dis: String::new(),
+            main_opt: String::new(),
+            other_dis: Vec::new(),
+            other_opt: Vec::new(),
+            sex: UNKNOWN_SEX,
+            age: DEFAULT_ADULT_AGE,
+            weight: DEFAULT_WEIGHT,
+            keep_redundant: false,
+        }
+    }
+}
+
+impl DrgCaseBuilder {
+    fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = id.into();
+        self
+    }
+
+    fn main_dis(mut self, main_dis: impl Into<String>) -> Self {
+        self.main_dis = main_dis.into();
+        self
+    }
+
+    fn main_opt(mut self, main_opt: impl Into<String>) -> Self {
+        self.main_opt = main_opt.into();
+        self
+    }
+
+    fn other_dis<I: IntoIterator<Item = S>, S: Into<String>>(mut self, other_dis: I) -> Self {
+        self.other_dis = other_dis.into_iter().map(|x| x.into()).collect();
+        self
+    }
+
+    fn other_opt<I: IntoIterator<Item = S>, S: Into<String>>(mut self, other_opt: I) -> Self {
+        self.other_opt = other_opt.into_iter().map(|x| x.into()).collect();
+        self
+    }
+
+    fn sex(mut self, sex: i32) -> Self {
+        self.sex = sex;
+        self
+    }
+
+    fn age(mut self, age: f64) -> Self {
+        self.age = age;
+        self
+    }
+
+    fn weight(mut self, weight: i32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    fn keep_redundant(mut self, keep_redundant: bool) -> Self {
+        self.keep_redundant = keep_redundant;
+        self
+    }
+
+    // 生成病例结构, all_dis和all_opt在这里重新计算
+    fn build(self) -> DrgCase {
+        DrgCase::with_redundant(
+            self.id,
+            self.main_dis,
+            self.main_opt,
+            self.other_dis,
+            self.other_opt,
+            self.sex,
+            self.age,
+            self.weight,
+            self.keep_redundant,
+        )
+    }
+}
+
+// 用于读取CSV文件并初始化结构体
+#[derive(Debug, Deserialize)]
+struct TempDrgCase {
+    id: String,               // 病例ID
+    main_dis: String,         // 主诊断编码(必填)
+    main_opt: String,         // 主手术编码(手术病例必填)
+    #[serde(deserialize_with = "custom_deserializer::deserialize_sep_str")]
+    other_dis: Vec<String>,   // 其他诊断编码(列表)
+    #[serde(deserialize_with = "custom_deserializer::deserialize_sep_str")]
+    other_opt: Vec<String>,   // 其他手术编码(列表)
+    // 人口学信息可以缺列或为空: 性别为未知(-1), 年龄为成人(18), 体重为0, 与JSON和group模式相同
+    // 年龄在换算单位(case_from_record)以后才取成人年龄, 缺少的年龄不会按天数换算成新生儿
+    #[serde(default = "default_sex", deserialize_with = "custom_deserializer::deserialize_sex")]
+    sex: i32,                 // 性别(0 => 女, 1 => 男, -1 => 未知)
+    #[serde(default, deserialize_with = "custom_deserializer::deserialize_age")]
+    age: Option<f64>,         // 年龄, 单位按--age-unit(缺列或空白为None)
+    #[serde(default, deserialize_with = "custom_deserializer::deserialize_weight")]
+    weight: i32,              // 体重
+    #[serde(default)]
+    main_dis_alt: String,     // 备选主诊断, 可以没有这一列
+}
+
+// CSV表头中各个病例字段所在的列, 用于--fast-csv按列号直接读取
+#[derive(Debug)]
+struct ColumnIndex {
+    id: usize,
+    main_dis: usize,
+    main_opt: usize,
+    other_dis: usize,
+    other_opt: usize,
+    sex: Option<usize>, // 人口学信息的列可以没有
+    age: Option<usize>,
+    weight: Option<usize>,
+    main_dis_alt: Option<usize>,
+}
+
+impl ColumnIndex {
+    // 按列名查找各字段的列号(表头已按--column-map重命名), 缺少列时报错
+    fn from_headers(headers: &csv::StringRecord) -> Result<Self, Box<dyn Error>> {
+        let find = |name: &str| {
+            headers.iter().position(|h| h.trim() == name).ok_or_else(|| format!("missing column {} in csv header", name))
+        };
+        Ok(Self {
+            id: find("id")?,
+            main_dis: find("main_dis")?,
+            main_opt: find("main_opt")?,
+            other_dis: find("other_dis")?,
+            other_opt: find("other_opt")?,
+            sex: find("sex").ok(),
+            age: find("age").ok(),
+            weight: find("weight").ok(),
+            main_dis_alt: find("main_dis_alt").ok(),
+        })
+    }
+}
+
+// 按预先计算的列号直接读取一行, 不经过serde, 字段解析规则与custom_deserializer相同
+// 年龄单位和重复编码的处理仍由case_from_record完成, 因此这里构造的是TempDrgCase
+impl TryFrom<(&csv::StringRecord, &ColumnIndex)> for TempDrgCase {
+    type Error = Box<dyn Error>;
+
+    fn try_from((record, index): (&csv::StringRecord, &ColumnIndex)) -> Result<Self, Self::Error> {
+        let field = |i: usize| record.get(i).unwrap_or("");
+        let optional_field = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or("");
+        Ok(Self {
+            id: field(index.id).to_string(),
+            main_dis: field(index.main_dis).to_string(),
+            main_opt: field(index.main_opt).to_string(),
+            other_dis: custom_deserializer::parse_sep_str(field(index.other_dis)),
+            other_opt: custom_deserializer::parse_sep_str(field(index.other_opt)),
+            sex: custom_deserializer::parse_i32_or(optional_field(index.sex), UNKNOWN_SEX)?,
+            age: custom_deserializer::parse_f64_opt(optional_field(index.age))?,
+            weight: custom_deserializer::parse_i32_or(optional_field(index.weight), 0)?,
+            main_dis_alt: optional_field(index.main_dis_alt).to_string(),
+        })
+    }
+}
+
+// 用于存放分组完了以后的数据
+#[derive(Debug, Serialize)]
+struct DrgCaseGrouped {
+    id: String,               // 病例ID
+    main_dis: String,         // 主诊断编码(必填)
+    main_opt: String,         // 主手术编码(手术病例必填)
+    other_dis: String,        // 其他诊断编码(列表)
+    other_opt: String,        // 其他手术编码(列表)
+    sex: String,                 // 性别(0 => 女, 1 => 男)
+    age: String,                 // 年龄(不足一岁以小于1小数表示, 出生天数/365)
+    weight: String,              // 体重
+    code: String,             // 分组编码
+    #[serde(skip_serializing_if = "Option::is_none")]
+    review_flags: Option<String>, // 需要人工复核的标记(用"|"分隔), --emit-review-flags时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n_dis: Option<usize>,     // 诊断数(主诊断+其他诊断), --emit-counts时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n_opt: Option<usize>,     // 手术数(主手术+其他手术), --emit-counts时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    case_hash: Option<String>, // 分组输入的哈希, --emit-hash时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight_band: Option<String>, // 出生体重分段(体重未知时为空), --emit-weight-band时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage_mdc: Option<String>, // 进入的MDC, --emit-stages时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage_adrg_pre_qy: Option<String>, // QY判断之前的ADRG, --emit-stages时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage_adrg_post_qy: Option<String>, // QY判断之后的ADRG, --emit-stages时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage_drg: Option<String>, // 本地规则改写之前的DRG, --emit-stages时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_swap_suggestion: Option<String>, // 未入组病例换用其他诊断作为主诊断后能入组的DRG, --suggest-main-swap时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_opt_suggestion: Option<String>, // QY病例换用其他手术作为主手术后能进入的DRG, --suggest-main-opt时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_dis_alt: Option<String>, // 备选主诊断, --best-main-dis时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_dis_drg: Option<String>, // 按原主诊断分组的DRG, --best-main-dis时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    main_dis_alt_drg: Option<String>, // 按备选主诊断分组的DRG(没有备选主诊断时为空), --best-main-dis时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chosen_main_dis: Option<String>, // code列的DRG使用的主诊断, --best-main-dis时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>, // 分组状态(ok, qy_adjusted, kbbz:<原因>), --emit-reason-always时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    case_issues: Option<String>, // DrgCase::validate检查出的问题(用"|"分隔), --validate时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_code: Option<String>, // 基线文件中的分组编码, --baseline时输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_code: Option<String>, // 本次的分组编码, --baseline时输出
+}
+
+impl DrgCaseGrouped {
+    // 重新定义一个初始化方法
+    fn new(drgcase: DrgCase, code: String) -> Self {
+        let other_dis_str = drgcase.dis.secondary().join("|");   // 合并其他诊断用"|"分隔
+        let other_opt_str = drgcase.opt.secondary().join("|");   // 合并其他诊断用"|"分隔
+        DrgCaseGrouped { 
+            id: drgcase.id, 
+            main_dis: drgcase.dis.principal, 
+            main_opt: drgcase.opt.principal, 
+            other_dis: other_dis_str, 
+            other_opt: other_opt_str, 
+            sex: drgcase.sex.to_string(), 
+            age: drgcase.age.to_string(), 
+            weight: drgcase.weight.to_string(), 
+            code,
+            review_flags: None,
+            n_dis: None,
+            n_opt: None,
+            case_hash: None,
+            weight_band: None,
+            stage_mdc: None,
+            stage_adrg_pre_qy: None,
+            stage_adrg_post_qy: None,
+            stage_drg: None,
+            main_swap_suggestion: None,
+            main_opt_suggestion: None,
+            main_dis_alt: None,
+            main_dis_drg: None,
+            main_dis_alt_drg: None,
+            chosen_main_dis: None,
+            status: None,
+            case_issues: None,
+            old_code: None,
+            new_code: None,
+        }
+    }
+}
+
+
+
+// CSV读取的相关操作==================================================================
+// 自定义反序列化
+mod custom_deserializer {
+    use serde::{self, Deserialize, Deserializer};
+
+    // 解析整数, 允许千分位逗号(如"2,288")和小数部分为0的数字(如"2288.0")
+    // 带有小数或超出i32范围的数字给出带原始值的错误
+    pub fn parse_i32(s: &str) -> Result<i32, String> {
+        // 移除逗号、空格等
+        let clean_str = s.replace(",", "").trim().to_string();
+
+        // 尝试转换为数字
+        if let Ok(v) = clean_str.parse::<i32>() {
+            return Ok(v);
+        }
+        let v = clean_str.parse::<f64>().map_err(|_| format!("invalid integer: {:?}", s))?;
+        if !v.is_finite() || v.fract() != 0.0 {
+            return Err(format!("not an integer: {:?}", s));
+        }
+        if v < i32::MIN as f64 || v > i32::MAX as f64 {
+            return Err(format!("integer out of range ({}..={}): {:?}", i32::MIN, i32::MAX, s));
+        }
+        Ok(v as i32)
+    }
+    
+    // 空字符串或纯空白为默认值, 其余与parse_i32相同
+    pub fn parse_i32_or(s: &str, default: i32) -> Result<i32, String> {
+        if s.trim().is_empty() {
+            return Ok(default);
+        }
+        parse_i32(s)
+    }
+
+    // 性别的反序列化, 空白为未知(-1)
+    pub fn deserialize_sex<'de, D>(deserializer: D) -> Result<i32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        parse_i32_or(&s, super::UNKNOWN_SEX).map_err(serde::de::Error::custom)
+    }
+
+    // 体重的反序列化, 空白为0
+    pub fn deserialize_weight<'de, D>(deserializer: D) -> Result<i32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        parse_i32_or(&s, 0).map_err(serde::de::Error::custom)
+    }
+
+    // 以"|"为分隔符的文本的反序列化
+    pub fn deserialize_sep_str<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(parse_sep_str(&s))
+    }
+
+    // 以"|"为分隔符的文本拆分为编码列表, 去除空白和空编码
+    pub fn parse_sep_str(s: &str) -> Vec<String> {
+        s.split('|')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+    
+    // 年龄的反序列化, 空白为None, 换算单位以后再取成人年龄, 不按新生儿判断MDCP
+    pub fn deserialize_age<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        parse_f64_opt(&s).map_err(serde::de::Error::custom)
+    }
+
+    // 空字符串或纯空白为None, 其余与parse_f64相同
+    pub fn parse_f64_opt(s: &str) -> Result<Option<f64>, String> {
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+        parse_f64(s).map(Some)
+    }
+
+    // 解析小数, 空字符串为0, 允许空格和千位分隔符
+    pub fn parse_f64(s: &str) -> Result<f64, String> {
+        // 处理空字符串或纯空白
+        if s.trim().is_empty() {
+            return Ok(0.0);
+        }
+
+        // 清理字符串：移除空格和千位分隔符
+        let clean_str = s
+            .replace(" ", "")
+            .replace(",", "")
+            .trim()
+            .to_string();
+
+        // 尝试解析数字
+        match clean_str.parse::<f64>() {
+            Ok(num) => Ok(num),
+            Err(e) => Err(format!("Failed to parse float: {}", e))
+        }
+    }
+}
+
+
+// 读取CSV数据(测试使用, 命令行通过read_csv_mapped读取)
+#[cfg(test)]
+fn read_csv(file_path: &str) -> Result<Vec<DrgCase>, Box<dyn Error>> {
+    read_csv_mapped(file_path, &CliOptions::default())
+}
+
+// 读取CSV数据, 先按列名映射表(源列名 => 内部字段名)重命名表头再反序列化
+fn read_csv_mapped(
+    file_path: &str,
+    options: &CliOptions,
+) -> Result<Vec<DrgCase>, Box<dyn Error>> {
+    read_csv_records(csv::Reader::from_path(file_path)?, options)
+}
+
+// 从CSV/TSV读取器中读取病例, 列名映射和--fast-csv与read_csv_mapped相同
+fn read_csv_records<R: std::io::Read>(
+    rdr: csv::Reader<R>,
+    options: &CliOptions,
+) -> Result<Vec<DrgCase>, Box<dyn Error>> {
+    let mut case_vec: Vec<DrgCase> = Vec::new();
+    for_each_csv_case(rdr, options, |case| {
+        case_vec.push(case);
+        Ok(())
+    })?;
+    Ok(case_vec)
+}
+
+// 逐行读取CSV/TSV中的病例并交给f处理, 不在内存中保留已经处理过的病例
+fn for_each_csv_case<R: std::io::Read, F: FnMut(DrgCase) -> Result<(), Box<dyn Error>>>(
+    mut rdr: csv::Reader<R>,
+    options: &CliOptions,
+    mut f: F,
+) -> Result<(), Box<dyn Error>> {
+    let column_map = &options.column_map;
+    if !column_map.is_empty() {
+        let headers: csv::StringRecord = rdr
+            .headers()?
+            .iter()
+            .map(|h| column_map.get(h.trim()).map(|x| x.as_str()).unwrap_or(h))
+            .collect();
+        rdr.set_headers(headers);
+    }
+    let mut bad_rows = BadRows::new(options);
+    if options.fast_csv {
+        // 按列号直接读取, 跳过serde
+        let index = ColumnIndex::from_headers(rdr.headers()?)?;
+        for result in rdr.records() {
+            let row = result?;
+            let record = TempDrgCase::try_from((&row, &index))
+                .map_err(|e| format!("line {}: {}", row.position().map(|x| x.line()).unwrap_or(0), e));
+            match record {
+                Ok(record) => f(case_from_record(record, options))?,
+                Err(e) => bad_rows.push(e)?,
+            }
+        }
+        return Ok(());
+    }
+    for result in rdr.deserialize() {
+        match result {
+            Ok(record) => f(case_from_record(record, options))?,
+            Err(e) => bad_rows.push(e.to_string())?,
+        }
+    }
+    Ok(())
+}
+
+// 读取病例时解析失败的行, --skip-bad-rows时跳过并在stderr提示, 否则第一个错误即失败
+// --max-errors N时失败的行达到N行就停止读取, 报错中列出已经失败的行
+struct BadRows {
+    skip: bool,
+    max_errors: Option<usize>,
+    errors: Vec<String>,
+}
+
+impl BadRows {
+    fn new(options: &CliOptions) -> Self {
+        Self { skip: options.skip_bad_rows || options.max_errors.is_some(), max_errors: options.max_errors, errors: Vec::new() }
+    }
+
+    fn push(&mut self, error: String) -> Result<(), Box<dyn Error>> {
+        if !self.skip {
+            return Err(error.into());
+        }
+        eprintln!("warning: skipped bad row: {}", error);
+        self.errors.push(error);
+        if self.max_errors.is_some_and(|n| self.errors.len() >= n) {
+            return Err(format!("aborted after {} bad rows (--max-errors):\n{}", self.errors.len(), self.errors.join("\n")).into());
+        }
+        Ok(())
+    }
+}
+
+
+// 用于从JSON字符串初始化单个病例, 其他字段缺省时与group模式的默认值一致
+#[derive(Debug, Deserialize)]
+struct JsonDrgCase {
+    #[serde(default)]
+    id: String,               // 病例ID
+    main_dis: String,         // 主诊断编码(必填)
+    #[serde(default)]
+    main_opt: String,         // 主手术编码
+    #[serde(default)]
+    other_dis: Vec<String>,   // 其他诊断编码(列表)
+    #[serde(default)]
+    other_opt: Vec<String>,   // 其他手术编码(列表)
+    #[serde(default = "default_sex")]
+    sex: i32,                 // 性别(0 => 女, 1 => 男, -1 => 未知)
+    #[serde(default)]
+    age: Option<f64>,         // 年龄, 单位按--age-unit(缺少或为null时为成人年龄)
+    #[serde(default)]
+    weight: i32,              // 体重
+    #[serde(default)]
+    main_dis_alt: String,     // 备选主诊断, 只用于--best-main-dis
+}
+
+fn default_sex() -> i32 {
+    UNKNOWN_SEX
+}
+
+// 从JSON字符串解析单个病例, 如'{"id":"0001","main_dis":"I50.900x018","other_dis":["E16.200"]}'
+fn parse_json_case(json: &str, options: &CliOptions) -> Result<DrgCase, Box<dyn Error>> {
+    let record: JsonDrgCase = serde_json::from_str(json)?;
+    Ok(case_from_json(record, options))
+}
+
+fn case_from_json(record: JsonDrgCase, options: &CliOptions) -> DrgCase {
+    let age = resolve_age(record.age, options.age_unit.as_deref());
+    let mut case = DrgCase::builder()
+        .id(record.id)
+        .main_dis(record.main_dis)
+        .main_opt(record.main_opt)
+        .other_dis(record.other_dis)
+        .other_opt(record.other_opt)
+        .sex(record.sex)
+        .age(age)
+        .weight(record.weight)
+        .keep_redundant(options.keep_redundant)
+        .build();
+    case.main_dis_alt = record.main_dis_alt.trim().to_string();
+    case
+}
+
+// 单个字符串表示的病例(旧系统的定长格式)中各字段的位置, 位置从0开始, 没有的字段为None
+// 默认为"id|main_dis|main_opt|other_dis|other_opt|sex|age|weight", 其他诊断和其他手术的多个编码用";"分隔
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct FlatLayout {
+    separator: char,     // 字段分隔符
+    sub_separator: char, // 其他诊断和其他手术中多个编码的分隔符
+    id: Option<usize>,
+    main_dis: usize,
+    main_opt: Option<usize>,
+    other_dis: Option<usize>,
+    other_opt: Option<usize>,
+    sex: Option<usize>,
+    age: Option<usize>,
+    weight: Option<usize>,
+}
+
+impl Default for FlatLayout {
+    fn default() -> Self {
+        FlatLayout {
+            separator: '|',
+            sub_separator: ';',
+            id: Some(0),
+            main_dis: 1,
+            main_opt: Some(2),
+            other_dis: Some(3),
+            other_opt: Some(4),
+            sex: Some(5),
+            age: Some(6),
+            weight: Some(7),
+        }
+    }
+}
+
+// 按FlatLayout解析单个字符串表示的病例, 如"0001|I50.900x018||E16.200;I10.x00||1|60|0"
+// 缺少的字段或空白的字段取与group模式相同的默认值(性别未知, 成人年龄, 体重为0)
+fn parse_flat_case(line: &str, layout: &FlatLayout, options: &CliOptions) -> Result<DrgCase, Box<dyn Error>> {
+    let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split(layout.separator).map(|x| x.trim()).collect();
+    let field = |pos: Option<usize>| pos.and_then(|x| fields.get(x)).copied().filter(|x| !x.is_empty());
+    let codes = |pos: Option<usize>| -> Vec<String> {
+        field(pos)
+            .map(|x| x.split(layout.sub_separator).map(|c| c.trim()).filter(|c| !c.is_empty()).map(|c| c.to_string()).collect())
+            .unwrap_or_default()
+    };
+    let id = field(layout.id).unwrap_or("0").to_string();
+    let main_dis = field(Some(layout.main_dis)).ok_or_else(|| format!("flat case {} has no main_dis at field {}", id, layout.main_dis))?;
+    // 数字字段与CSV输入一样允许千分位逗号和小数部分为0的整数(如"2,288", "2288.0")
+    let sex = custom_deserializer::parse_i32_or(field(layout.sex).unwrap_or(""), UNKNOWN_SEX)
+        .map_err(|e| format!("flat case {} sex: {}", id, e))?;
+    let age = field(layout.age).map(custom_deserializer::parse_f64).transpose().map_err(|e| format!("flat case {} age: {}", id, e))?;
+    let weight = custom_deserializer::parse_i32_or(field(layout.weight).unwrap_or(""), DEFAULT_WEIGHT)
+        .map_err(|e| format!("flat case {} weight: {}", id, e))?;
+    let age = resolve_age(age, options.age_unit.as_deref());
+    Ok(DrgCase::builder()
+        .id(id.as_str())
+        .main_dis(main_dis)
+        .main_opt(field(layout.main_opt).unwrap_or(""))
+        .other_dis(codes(layout.other_dis))
+        .other_opt(codes(layout.other_opt))
+        .sex(sex)
+        .age(age)
+        .weight(weight)
+        .keep_redundant(options.keep_redundant)
+        .build())
+}
+
+// 批量分组支持的输入格式, auto为按扩展名判断, 没有扩展名或从stdin读取时按内容判断
+const INPUT_FORMATS: [&str; 6] = ["auto", "csv", "tsv", "ndjson", "json", "xlsx"];
+
+// 按文件扩展名判断输入格式, 无法判断时为None
+fn input_format_by_extension(file_path: &str) -> Option<&'static str> {
+    let ext = Path::new(file_path).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "csv" => Some("csv"),
+        "tsv" => Some("tsv"),
+        "xlsx" => Some("xlsx"),
+        "ndjson" | "jsonl" => Some("ndjson"),
+        "json" => Some("json"),
+        _ => None,
+    }
+}
+
+// 按开头的内容判断输入格式: "["为JSON数组, "{"为NDJSON(每行一个对象), 第一行有制表符没有逗号为TSV, 其他为CSV
+fn sniff_input_format(head: &[u8]) -> &'static str {
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    if text.starts_with('[') {
+        return "json";
+    } else if text.starts_with('{') {
+        return "ndjson";
+    }
+    let first_line = text.lines().next().unwrap_or("");
+    if first_line.contains('\t') && !first_line.contains(',') {
+        return "tsv";
+    }
+    "csv"
+}
+
+// 按--input-format读取批量分组的病例, file_path为-时从stdin读取
+// 没有指定--age-unit时先按整个文件的年龄分布推断年龄的单位, 推断为天数时给出警告并按--age-unit days构造病例
+// 缺少或空白的年龄不参与推断, 构造病例时仍为成人年龄
+fn read_input_cases(file_path: &str, options: &CliOptions) -> Result<Vec<DrgCase>, Box<dyn Error>> {
+    let format = options.input_format.as_deref().unwrap_or("auto");
+    let data = if file_path == "-" {
+        let mut data: Vec<u8> = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut data)?;
+        data
+    } else {
+        let by_extension = if format == "auto" { input_format_by_extension(file_path) } else { None };
+        if format == "csv" || by_extension == Some("csv") {
+            // CSV文件直接按路径读取
+            if csv_ages_in_days(file_path, options)? {
+                return read_csv_mapped(file_path, &age_unit_days_options(options));
+            }
+            return read_csv_mapped(file_path, options);
+        }
+        fs::read(file_path)?
+    };
+    let format = match format {
+        "auto" => input_format_by_extension(file_path).unwrap_or_else(|| sniff_input_format(&data[..data.len().min(4096)])),
+        format => format,
+    };
+    let in_days = options.age_unit.is_none() && ages_in_days(file_path, input_ages(&data, format, options));
+    let days_options;
+    let options = if in_days {
+        days_options = age_unit_days_options(options);
+        &days_options
+    } else {
+        options
+    };
+    read_cases_from_bytes(&data, format, options).map_err(|e| format!("{} ({}): {}", file_path, format, e).into())
+}
+
+// 年龄按天数处理时的警告
+fn warn_ages_in_days<I: Iterator<Item = f64>>(file_path: &str, ages: I) {
+    let (mut total, mut over) = (0, 0);
+    for age in ages {
+        total += 1;
+        if age > 150.0 {
+            over += 1;
+        }
+    }
+    eprintln!(
+        "warning: {}: {} of {} ages exceed 150, the age column is treated as days (use --age-unit years to override)",
+        file_path, over, total
+    );
+}
+
+// 按推断的单位判断年龄列是否为天数, 是天数时给出警告
+fn ages_in_days(file_path: &str, ages: Vec<f64>) -> bool {
+    if infer_age_unit(ages.iter().copied()) != "days" {
+        return false;
+    }
+    warn_ages_in_days(file_path, ages.into_iter());
+    true
+}
+
+// 逐行分组(--max-memory, --join-input)时先只读取一遍CSV文件的age列, 与read_input_cases一样推断年龄的单位
+// 指定了--age-unit时不推断, 返回true时病例需要按--age-unit days构造(age_unit_days_options), 推断为天数时给出同样的警告
+fn csv_ages_in_days(file_path: &str, options: &CliOptions) -> Result<bool, Box<dyn Error>> {
+    if options.age_unit.is_some() {
+        return Ok(false);
+    }
+    let ages = csv_ages(csv::Reader::from_path(file_path)?, options)?;
+    Ok(ages_in_days(file_path, ages))
+}
+
+// 只读取CSV/TSV的age列, 缺少或空白的年龄和无法解析的行(分组时按--skip-bad-rows处理)跳过
+fn csv_ages<R: std::io::Read>(mut rdr: csv::Reader<R>, options: &CliOptions) -> Result<Vec<f64>, Box<dyn Error>> {
+    let age_col = rdr.headers()?
+        .iter()
+        .position(|h| options.column_map.get(h.trim()).map(|x| x.as_str()).unwrap_or(h) == "age");
+    let age_col = match age_col {
+        Some(age_col) => age_col,
+        None => return Ok(Vec::new()),
+    };
+    Ok(rdr.records()
+        .filter_map(|record| record.ok().and_then(|x| custom_deserializer::parse_f64_opt(x.get(age_col).unwrap_or("")).ok().flatten()))
+        .collect())
+}
+
+// 只取JSON/NDJSON病例的年龄, 用于推断单位
+#[derive(Deserialize)]
+struct JsonAge {
+    #[serde(default)]
+    age: Option<f64>,
+}
+
+// 读取内存中输入数据的年龄(不构造病例), 缺少的年龄和无法解析的病例跳过
+fn input_ages(data: &[u8], format: &str, options: &CliOptions) -> Vec<f64> {
+    match format {
+        "csv" => csv_ages(csv::Reader::from_reader(data), options).unwrap_or_default(),
+        "tsv" => csv_ages(csv::ReaderBuilder::new().delimiter(b'\t').from_reader(data), options).unwrap_or_default(),
+        "ndjson" => String::from_utf8_lossy(data)
+            .lines()
+            .filter_map(|line| serde_json::from_str::<JsonAge>(line).ok().and_then(|x| x.age))
+            .collect(),
+        "json" => serde_json::from_slice::<Vec<JsonAge>>(data)
+            .map(|x| x.into_iter().filter_map(|x| x.age).collect())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// 推断一个文件中年龄的单位: 非零年龄中超过一半大于150时为days(150岁以上不合理), 否则为years
+fn infer_age_unit<I: Iterator<Item = f64>>(ages: I) -> &'static str {
+    let (mut nonzero, mut over) = (0, 0);
+    for age in ages.filter(|x| *x > 0.0) {
+        nonzero += 1;
+        if age > 150.0 {
+            over += 1;
+        }
+    }
+    if over * 2 > nonzero {
+        return "days";
+    }
+    "years"
+}
+
+fn read_cases_from_bytes(data: &[u8], format: &str, options: &CliOptions) -> Result<Vec<DrgCase>, Box<dyn Error>> {
+    match format {
+        "csv" => read_csv_records(csv::Reader::from_reader(data), options),
+        "tsv" => read_csv_records(csv::ReaderBuilder::new().delimiter(b'\t').from_reader(data), options),
+        "ndjson" => {
+            let mut case_vec: Vec<DrgCase> = Vec::new();
+            let mut bad_rows = BadRows::new(options);
+            for (n, line) in str::from_utf8(data)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_json_case(line, options) {
+                    Ok(case) => case_vec.push(case),
+                    Err(e) => bad_rows.push(format!("line {}: {}", n + 1, e))?,
+                }
+            }
+            Ok(case_vec)
+        }
+        "json" => {
+            let records: Vec<JsonDrgCase> = serde_json::from_slice(data)?;
+            Ok(records.into_iter().map(|x| case_from_json(x, options)).collect())
+        }
+        // Excel读取(以及按批分块读取)尚未实现, 见文件末尾的TODO
+        "xlsx" => Err("xlsx input is not supported in this build, please save the sheet as CSV".into()),
+        _ => Err(format!("unsupported input format: {}", format).into()),
+    }
+}
+
+// 输入文件必须包含的列(病例结构中的字段名)
+const REQUIRED_COLUMNS: [&str; 8] = ["id", "main_dis", "main_opt", "other_dis", "other_opt", "sex", "age", "weight"];
+
+// 输入文件列名的检查结果
+#[derive(Debug, Default)]
+struct ColumnCheck {
+    matched: Vec<(String, String)>, // 找到的列(原始列名, 对应的字段名)
+    missing: Vec<String>,           // 缺少的字段
+    extra: Vec<String>,             // 多余的列(分组时忽略)
+}
+
+// 只读取表头, 按列名映射检查输入文件是否包含所有必须的列
+fn check_columns(file_path: &str, column_map: &HashMap<String, String>) -> Result<ColumnCheck, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(file_path)?;
+    let mut check = ColumnCheck::default();
+    let mut found: HashSet<String> = HashSet::new();
+    for h in rdr.headers()?.iter() {
+        let field = column_map.get(h.trim()).map(|x| x.as_str()).unwrap_or(h);
+        if REQUIRED_COLUMNS.contains(&field) {
+            check.matched.push((h.to_string(), field.to_string()));
+            found.insert(field.to_string());
+        } else {
+            check.extra.push(h.to_string());
+        }
+    }
+    check.missing = REQUIRED_COLUMNS.iter()
+        .filter(|x| !found.contains(**x))
+        .map(|x| x.to_string())
+        .collect();
+    Ok(check)
+}
+
+// 打印列名检查表, 缺少必须的列时报错
+fn require_columns(file_path: &str, column_map: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    let check = check_columns(file_path, column_map)?;
+    println!("{:<10}{:<20}field", "status", "column");
+    for (column, field) in &check.matched {
+        println!("{:<10}{:<20}{}", "matched", column, field);
+    }
+    for field in &check.missing {
+        println!("{:<10}{:<20}{}", "missing", "", field);
+    }
+    for column in &check.extra {
+        println!("{:<10}{}", "extra", column);
+    }
+    if !check.missing.is_empty() {
+        return Err(format!("{} is missing required columns: {}", file_path, check.missing.join(", ")).into());
+    }
+    Ok(())
+}
+
+// 把CSV中读取的一行转为病例结构
+fn case_from_record(record: TempDrgCase, options: &CliOptions) -> DrgCase {
+    let age = resolve_age(record.age, options.age_unit.as_deref());
+    let mut case = DrgCase::with_redundant(
+        record.id, 
+        record.main_dis, 
+        record.main_opt, 
+        record.other_dis, 
+        record.other_opt, 
+        record.sex, 
+        age, 
+        record.weight,
+        options.keep_redundant,
+    );
+    case.main_dis_alt = record.main_dis_alt.trim().to_string();
+    case
+}
+
+// 输出文件先写入同一目录下的临时文件(文件名后加.<进程号>.<序号>.tmp), commit时再改名替换目标文件
+// 写入中途出错时临时文件在drop时删除, 目标文件(如果已有)保持不变
+// 临时文件名包含进程号和进程内的序号, 只新建不覆盖, 多个进程(或同一进程内)同时写入同一目标文件时不会互相覆盖临时文件
+// append为true时先把已有的目标文件复制到临时文件, 再在末尾追加
+// 注意: 每次追加都会完整复制一遍已有文件, 耗时和临时磁盘占用随文件大小增长, 多次追加到很大的输出文件时代价明显
+struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    committed: bool,
+}
+
+// 本进程已经使用的临时文件序号
+static ATOMIC_FILE_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+impl AtomicFile {
+    fn create<P: AsRef<Path>>(path: P, append: bool) -> Result<AtomicFile, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let (file, tmp_path) = loop {
+            let seq = ATOMIC_FILE_SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(format!(".{}.{}.tmp", std::process::id(), seq));
+            let tmp_path = PathBuf::from(tmp_path);
+            // 同名文件(如之前崩溃的进程留下的)已经存在时换下一个序号
+            match fs::OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+                Ok(file) => break (file, tmp_path),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let mut atomic = AtomicFile { file, path, tmp_path, committed: false };
+        if append && atomic.path.exists() {
+            // 复制失败时在drop时删除临时文件
+            std::io::copy(&mut File::open(&atomic.path)?, &mut atomic.file)?;
+        }
+        Ok(atomic)
+    }
+
+    fn commit(mut self) -> Result<(), Box<dyn Error>> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+// 一次写入整个文件的内容, 同样经过临时文件替换
+fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut file = AtomicFile::create(path, false)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+// 写完CSV后取出AtomicFile并替换目标文件
+fn commit_csv(wrt: csv::Writer<AtomicFile>) -> Result<(), Box<dyn Error>> {
+    let file = wrt.into_inner().map_err(|e| e.error().to_string())?;
+    file.commit()
+}
+
+// 写入CSV数据, append为true时追加到已有文件末尾, 已有内容时不再写表头
+// 写入失败时不改动已有的输出文件
+fn write_csv<T: Serialize>(drgcases: Vec<T>, file_path: &str, append: bool) -> Result<(), Box<dyn Error>> {
+    let has_content = append && fs::metadata(file_path).map(|m| m.len() > 0).unwrap_or(false);
+    let file = AtomicFile::create(file_path, append)?;
+    let mut wrt = csv::WriterBuilder::new()
+        .has_headers(!has_content)
+        .from_writer(file); // 初始化写入模块
+    for d in drgcases {
+        // 逐行写入
+        wrt.serialize(d)?;
+    }
+    commit_csv(wrt)?;     // 确保数据被写入并替换目标文件
+    println!("Grouped data is write into your path");
+    Ok(())
+}
+
+// 写入JSON数据(缩进格式), 写入失败时不改动已有的文件
+fn write_json<T: Serialize + ?Sized>(value: &T, file_path: &str) -> Result<(), Box<dyn Error>> {
+    let mut wrt = std::io::BufWriter::new(AtomicFile::create(file_path, false)?);
+    serde_json::to_writer_pretty(&mut wrt, value)?;
+    let file = wrt.into_inner().map_err(|e| e.error().to_string())?;
+    file.commit()
+}
+
+// --drg-only时输出的一行, 只有病例ID和分组编码
+#[derive(Debug, Serialize)]
+struct DrgOnlyRow {
+    id: String,
+    code: String,
+}
+
+// 按--output-format写入分组结果, 默认为CSV, --drg-only时只写入id和code两列
+fn write_output(drgcases: Vec<DrgCaseGrouped>, file_path: &str, options: &CliOptions) -> Result<(), Box<dyn Error>> {
+    match options.output_format.as_deref() {
+        #[cfg(feature = "parquet")]
+        Some("parquet") => {
+            if options.append {
+                return Err("--append is not supported for parquet output".into());
+            }
+            let mut columns = grouped_parquet_columns(&drgcases)?;
+            if options.drg_only {
+                columns.retain(|(name, _)| name == "id" || name == "code");
+            }
+            write_parquet(&columns, drgcases.len(), file_path)?;
+            println!("Grouped data is write into your path");
+            Ok(())
+        }
+        #[cfg(not(feature = "parquet"))]
+        Some("parquet") => Err("--output-format parquet needs the parquet feature".into()),
+        _ if options.drg_only => {
+            let rows: Vec<DrgOnlyRow> = drgcases.into_iter().map(|x| DrgOnlyRow { id: x.id, code: x.code }).collect();
+            write_csv(rows, file_path, options.append)
+        }
+        _ => write_csv(drgcases, file_path, options.append),
+    }
+}
+
+// Parquet输出=====================================================================================
+// 写入Parquet文件, columns为(列名, 数据), 各列的行数必须都等于num_rows, 编码见parquet_writer
+#[cfg(feature = "parquet")]
+fn write_parquet(columns: &[(String, ParquetValues)], num_rows: usize, file_path: &str) -> Result<(), Box<dyn Error>> {
+    write_atomic(file_path, &encode_parquet(columns, num_rows)?)?;
+    Ok(())
+}
+
+// 把分组结果转为Parquet的列, 性别和体重为INT32, 年龄为DOUBLE, 其他为字符串, 可选的列只在有值时输出
+#[cfg(feature = "parquet")]
+fn grouped_parquet_columns(drgcases: &[DrgCaseGrouped]) -> Result<Vec<(String, ParquetValues)>, Box<dyn Error>> {
+    let strings = |f: &dyn Fn(&DrgCaseGrouped) -> String| ParquetValues::Utf8(drgcases.iter().map(f).collect());
+    let mut columns: Vec<(String, ParquetValues)> = vec![
+        (String::from("id"), strings(&|x| x.id.to_string())),
+        (String::from("main_dis"), strings(&|x| x.main_dis.to_string())),
+        (String::from("main_opt"), strings(&|x| x.main_opt.to_string())),
+        (String::from("other_dis"), strings(&|x| x.other_dis.to_string())),
+        (String::from("other_opt"), strings(&|x| x.other_opt.to_string())),
+        (String::from("sex"), ParquetValues::Int32(drgcases.iter().map(|x| x.sex.parse()).collect::<Result<_, _>>()?)),
+        (String::from("age"), ParquetValues::Double(drgcases.iter().map(|x| x.age.parse()).collect::<Result<_, _>>()?)),
+        (String::from("weight"), ParquetValues::Int32(drgcases.iter().map(|x| x.weight.parse()).collect::<Result<_, _>>()?)),
+        (String::from("code"), strings(&|x| x.code.to_string())),
+    ];
+    if drgcases.iter().any(|x| x.n_dis.is_some()) {
+        columns.push((String::from("n_dis"), ParquetValues::Int32(drgcases.iter().map(|x| x.n_dis.unwrap_or(0) as i32).collect())));
+        columns.push((String::from("n_opt"), ParquetValues::Int32(drgcases.iter().map(|x| x.n_opt.unwrap_or(0) as i32).collect())));
+    }
+    let optional = [
+        ("review_flags", drgcases.iter().map(|x| &x.review_flags).collect::<Vec<_>>()),
+        ("case_hash", drgcases.iter().map(|x| &x.case_hash).collect()),
+        ("weight_band", drgcases.iter().map(|x| &x.weight_band).collect()),
+        ("stage_mdc", drgcases.iter().map(|x| &x.stage_mdc).collect()),
+        ("stage_adrg_pre_qy", drgcases.iter().map(|x| &x.stage_adrg_pre_qy).collect()),
+        ("stage_adrg_post_qy", drgcases.iter().map(|x| &x.stage_adrg_post_qy).collect()),
+        ("stage_drg", drgcases.iter().map(|x| &x.stage_drg).collect()),
+        ("main_swap_suggestion", drgcases.iter().map(|x| &x.main_swap_suggestion).collect()),
+        ("main_opt_suggestion", drgcases.iter().map(|x| &x.main_opt_suggestion).collect()),
+        ("main_dis_alt", drgcases.iter().map(|x| &x.main_dis_alt).collect()),
+        ("main_dis_drg", drgcases.iter().map(|x| &x.main_dis_drg).collect()),
+        ("main_dis_alt_drg", drgcases.iter().map(|x| &x.main_dis_alt_drg).collect()),
+        ("chosen_main_dis", drgcases.iter().map(|x| &x.chosen_main_dis).collect()),
+        ("status", drgcases.iter().map(|x| &x.status).collect()),
+        ("case_issues", drgcases.iter().map(|x| &x.case_issues).collect()),
+        ("old_code", drgcases.iter().map(|x| &x.old_code).collect()),
+        ("new_code", drgcases.iter().map(|x| &x.new_code).collect()),
+    ];
+    for (name, values) in optional {
+        if values.iter().any(|x| x.is_some()) {
+            columns.push((name.to_string(), ParquetValues::Utf8(values.into_iter().map(|x| x.clone().unwrap_or_default()).collect())));
+        }
+    }
+    Ok(columns)
+}
+
+// 判断病例所进入的MDC============================
+// 先期分组
+fn is_mdca(
+    record: &DrgCase,                                // 病例
+    adrg_dis_opt: &AdrgSheet,                        // ADRG诊断手术表
+    all_opt_list: &HashSet<String>,                  // 全部手术列表
+    adrg_type_dict: &HashMap<String, String>,        // ADRG类型及对应入组类型
+    mdc_sub_adrg: &HashMap<String, Vec<String>>,     // MDC下的各个ADRG
+    mdc_name: String,
+    options: &GroupOptions,                          // 分组选项
+) -> Result<String, Box<dyn Error>> {
+    // MDCA的ADRG都以主手术(或手术组合)入组, 与主诊断无关, 没有主手术时不可能进入MDCA
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    let mut pred = String::from("KBBZ");
+    // 因为MDCA没有主诊表，所以这里要判断病例是否进入MDCA下的ADRG
+    // ADRG列表与其他MDC一样来自mdc_sub_adrg, 读取时已检查存在
+    let adrg_list = mdc_sub_adrg.get(mdc_name.as_str()).map(|x| x.as_slice()).unwrap_or(&[]);
+    // 遍历MDCA下的ADRG
+    for cate in adrg_list {
+        pred = process_adrg(
+            record,
+            adrg_dis_opt,
+            all_opt_list,
+            adrg_type_dict,
+            cate.to_string(),
+            options,
+        )?;
+        if pred != "KBBZ" {
+            break;
+        }
+    }
+    Ok(pred)
+}
+
+// MDCZ先期分组
+fn is_mdcz(
+    record: &DrgCase,                                // 病例
+    _adrg_dis_opt: &AdrgSheet,                        // ADRG诊断手术表
+    _all_opt_list: &HashSet<String>,                  // 全部手术列表
+    _adrg_type_dict: &HashMap<String, String>,   // ADRG类型及对应入组类型
+    mdcz_dis_sheet: &HashMap<String, HashSet<String>>,   // MDC主诊表
+    _mdc_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let tmp_adrg = "ZZ1".to_string();
+    let pred = is_mdcz_dis(record, mdcz_dis_sheet, tmp_adrg)?;
+    if pred == "ZZ1" {
+        Ok(String::from("MDCZ"))
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 新生儿要求为出生距今29天内的，29 / 365 ≈ 0.0795
+const NEWBORN_MAX_AGE: f64 = 0.0795;
+
+// MDCP先期分组
+fn is_mdcp(
+    record: &DrgCase,                                // 病例
+    _main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
+    _mdc_name: String,
+) -> String {
+    // BUG 国家版的分组方案里面MDCP居然没有主诊表
+    if record.age <= NEWBORN_MAX_AGE {
+        String::from("MDCP")
+    } else {
+        String::from("KBBZ")
+    }
+}
+
+// 新生儿的佐证: 主诊断为围产期诊断或有出生体重
+// 分组方案没有perinatal_dis.txt时, 以ICD-10第十六章(P00-P96)的编码作为围产期诊断
+fn has_birth_evidence(record: &DrgCase, perinatal_dis_list: &HashSet<String>) -> bool {
+    let perinatal = if perinatal_dis_list.is_empty() {
+        record.dis.principal().starts_with('P')
+    } else {
+        perinatal_dis_list.contains(record.dis.principal())
+    };
+    perinatal || record.weight > 0
+}
+
+// MDCY先期分组
+fn is_mdcy(
+    record: &DrgCase,                               // 病例结构体
+    _adrg_type_dict: &HashMap<String, String>,       // ADRG类型及对应入组类型
+    mdcy_dis_sheet: &HashSet<String>,
+    _mdc_name: String,
+) -> String {
+    if mdcy_dis_sheet.is_disjoint(record.dis.all()) {
+        String::from("KBBZ")
+    } else {
+        String::from("MDCY")
+    }
+}
+
+// 主诊断在主诊表中的第一个MDC, 主诊断不在表中或对应的MDC列表为空时为None
+fn first_mdc<'a>(main_dis_sheet: &'a HashMap<String, Vec<String>>, main_dis: &str) -> Option<&'a str> {
+    main_dis_sheet.get(main_dis).and_then(|x| x.first()).map(|x| x.as_str())
+}
+
+// 主诊断所在的MDC中是否有mdc_name
+fn main_dis_in_mdc(main_dis_sheet: &HashMap<String, Vec<String>>, main_dis: &str, mdc_name: &str) -> bool {
+    main_dis_sheet.get(main_dis).is_some_and(|x| x.iter().any(|mdc| mdc == mdc_name))
+}
+
+// 特殊的MDCN判断性别
+// 同时属于MDCM和MDCN的主诊断(如Q56.000)按性别进入其中一个
+fn is_mdcn(
+    record: &DrgCase,                                // 病例
+    main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
+    _mdc_name: String,
+) -> String {
+    // 判断性别为女sex为0
+    if (record.sex == 0) && main_dis_in_mdc(main_dis_sheet, record.dis.principal(), "MDCN") {
+        String::from("MDCN")
+    } else {
+        String::from("KBBZ")
+    }
+}
+
+// 特殊的MDCM判断性别
+fn is_mdcm(
+    record: &DrgCase,                                // 病例
+    main_dis_sheet: &HashMap<String, Vec<String>>,   // MDC主诊表
+    _mdc_name: String,
+) -> String {
+    // 判断性别为男sex为1
+    if (record.sex == 1) && main_dis_in_mdc(main_dis_sheet, record.dis.principal(), "MDCM") {
+        String::from("MDCM")
+    } else {
+        String::from("KBBZ")
+    }
+}
+
+// 普通MDC判断, 普通MDC已由which_mdc_adrg统一判断, 只在测试中单独检查主诊断是否属于某MDC
+#[cfg(test)]
+fn is_common_mdc(
+    record: &DrgCase,
+    main_dis_sheet: &HashMap<String, Vec<String>>,
+    mdc_name: String
+) -> String {
+    // 如果病例的主诊断在MDC主诊表中
+    if first_mdc(main_dis_sheet, record.dis.principal()) == Some(mdc_name.as_str()) {
+        mdc_name
+    } else {
+        String::from("KBBZ")
+    }
+}
+
+// 各ADRG入组方式===================================
+// 包含主手术
+fn is_contain_main_opt(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    if record.no_surgery() {
+        // 无主手术的无法入组
+        return Ok(String::from("KBBZ"));
+    }
+    if adrg_table(adrg_dis_opt, &adrg_name, DEFAULT_TABLE)?.contains(record.opt.principal()) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 同时有两手术
+fn is_contain_opt_simultaneously(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+    options: &GroupOptions,
+) -> Result<String, Box<dyn Error>> {
+    let verb_opt1 = "normal_list"; // 手术条件表1
+    let verb_opt2 = "other_list"; // 手术条件表2
+
+    if record.no_surgery() {
+        // 如果没有手术则为空白病组
+        return Ok(String::from("KBBZ"));
+    }
+    if options.distinct_simultaneous_opt {
+        // 要求两个表分别由不同的手术满足, 同时在两个表中的一个手术不能同时满足两个条件
+        let opt_table1 = adrg_table(adrg_dis_opt, &adrg_name, verb_opt1)?;
+        let opt_table2 = adrg_table(adrg_dis_opt, &adrg_name, verb_opt2)?;
+        let hits1: HashSet<&String> = record.opt.all().iter().filter(|x| opt_table1.contains(*x)).collect();
+        let hits2: HashSet<&String> = record.opt.all().iter().filter(|x| opt_table2.contains(*x)).collect();
+        // 两个表都有手术, 并且不是只有同一个手术
+        let same_single_opt = hits1.len() == 1 && hits1 == hits2;
+        if !hits1.is_empty() && !hits2.is_empty() && !same_single_opt {
+            return Ok(adrg_name);
+        } else {
+            return Ok(String::from("KBBZ"));
+        }
+    }
+    if (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt1)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt2)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 其他诊断或手术或操作1+手术或操作2
+fn is_contain_other_dis_or_other_opt1_and_other_opt2(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_other_dis = "other_dis_list";
+    let verb_opt1 = "other_opt_list1";
+    let verb_opt2 = "other_opt_list2";
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    if ((!adrg_table(adrg_dis_opt, &adrg_name, verb_other_dis)?.is_disjoint(record.dis.secondary_set()))
+        || (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt1)?)))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt2)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 同时包含主要诊断与主要手术
+fn is_contain_main_dis_and_main_opt_simultaneously(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_opt = "contain_main_opt_list";
+    let verb_main_dis = "contain_main_dis_list";
+    if record.no_surgery() {
+        // 无手术的病例无法入组
+        return Ok(String::from("KBBZ"));
+    }
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含主要诊断
+fn is_contain_main_dis(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    if adrg_table(adrg_dis_opt, &adrg_name, DEFAULT_TABLE)?.contains(record.dis.principal()) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含主要诊断, 使用专门的主诊断表(如"BR1_main_dis_list"), 而不是与其他入组条件共用的ADRG默认表
+fn is_contain_main_dis_list(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis = "main_dis_list";
+    if adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含主要诊断且年龄在ADRG的年龄范围内, 用于普通MDC中按年龄区分儿童与成人的ADRG
+// 主诊断表与is_contain_main_dis相同(没有主诊断表时使用ADRG默认表), 年龄范围见adrg_age_range
+fn is_contain_main_dis_with_age(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let (min_age, max_age) = adrg_age_range(adrg_dis_opt, &adrg_name)?;
+    let table_type = match adrg_dis_opt.get(&adrg_name) {
+        Some(tables) if tables.contains_key("main_dis_list") => "main_dis_list",
+        _ => DEFAULT_TABLE,
+    };
+    let in_range = min_age.is_none_or(|x| record.age >= x) && max_age.is_none_or(|x| record.age < x);
+    if in_range && adrg_table(adrg_dis_opt, &adrg_name, table_type)?.contains(record.dis.principal()) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// ADRG的年龄范围(岁), 读取自只有一项的"age_range"表(如"BR3_age_range": ["0-18"]), 表示0 <= 年龄 < 18
+// 任一端可以省略表示不限, 如"18-"为成人, "-0.08"为一个月以内
+fn adrg_age_range(adrg_dis_opt: &AdrgSheet, adrg_name: &str) -> Result<(Option<f64>, Option<f64>), Box<dyn Error>> {
+    let table = adrg_table(adrg_dis_opt, adrg_name, "age_range")?;
+    let range = match table.iter().next() {
+        Some(range) if table.len() == 1 => range,
+        _ => return Err(format!("{}/age_range needs exactly one entry like 0-18", adrg_name).into()),
+    };
+    let (min_age, max_age) = range.split_once('-').ok_or_else(|| format!("invalid age range {} of {}", range, adrg_name))?;
+    let bound = |x: &str| -> Result<Option<f64>, Box<dyn Error>> {
+        match x.trim() {
+            "" => Ok(None),
+            x => Ok(Some(x.parse::<f64>().map_err(|_| format!("invalid age range {} of {}", range, adrg_name))?)),
+        }
+    };
+    Ok((bound(min_age)?, bound(max_age)?))
+}
+
+// 同时包含CB4与CB5手术, CB2入组使用
+fn is_contain_cb4_opt_and_cb5_opt(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    if (record.operations_intersect(adrg_table(adrg_dis_opt, "CB4", DEFAULT_TABLE)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, "CB5", DEFAULT_TABLE)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 同时包含CB5与CB6手术, CB3入组使用
+fn is_contain_cb5_opt_and_cb6_opt(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    if (record.operations_intersect(adrg_table(adrg_dis_opt, "CB4", DEFAULT_TABLE)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, "CB5", DEFAULT_TABLE)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 入组条件1：主要诊断+主要手术或操作1,
+// 入组条件2：主要手术或操作2
+// 入组条件3：手术或操作3+手术或操作4
+#[allow(clippy::if_same_then_else)] // 每个分支对应一个入组条件
+fn is_contain_multi_opt1(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis = "main_dis_list";
+    let verb_main_opt1 = "main_opt_list1";
+    let verb_main_opt2 = "main_opt_list2";
+    let verb_opt3 = "other_opt_list3";
+    let verb_opt4 = "other_opt_list4";
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt1)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else if adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt2)?.contains(record.opt.principal()) {
+        Ok(adrg_name)
+    } else if (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt3)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt4)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 入组条件1：主要诊断+手术或操作1+手术或操作2
+// 入组条件2：主要诊断+手术或操作1+手术或操作3+手术或操作4
+// 入组条件3：主要诊断+手术或操作4+手术或操作5
+#[allow(clippy::if_same_then_else)] // 每个分支对应一个入组条件
+fn is_contain_multi_opt2(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis = "main_dis_list";
+    let verb_opt1 = "other_opt_list1";
+    let verb_opt2 = "other_opt_list2";
+    let verb_opt3 = "other_opt_list3";
+    let verb_opt4 = "other_opt_list4";
+    let verb_opt5 = "other_opt_list5";
+
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt1)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt2)?))
+    {
+        Ok(adrg_name)
+    } else if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt1)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt3)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt4)?))
+    {
+        Ok(adrg_name)
+    } else if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt4)?))
+        && (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_opt5)?))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 入组条件1：主要诊断+主要手术或操作1
+// 入组条件2：主要诊断+手术或操作2+手术或操作3
+#[allow(clippy::if_same_then_else)] // 每个分支对应一个入组条件
+fn is_contain_multi_opt3(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis = "main_dis_list";
+    let verb_main_opt1 = "main_opt_list1";
+    let verb_main_opt2 = "other_opt_list2";
+
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt1)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && ((adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt2)?.contains(record.opt.principal()))
+            || (record.operations_intersect(adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt2)?)))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 入组条件1：主要诊断1+主要手术或操作
+// 入组条件2：主要诊断2+其他诊断+主要手术或操作
+#[allow(clippy::if_same_then_else)] // 每个分支对应一个入组条件
+fn is_contain_multi_opt4(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis1 = "main_dis_list1";
+    let verb_main_dis2 = "main_dis_list2";
+    let verb_main_opt = "main_opt_list";
+    let verb_other_dis = "other_dis_list";
+
+    // 将其他诊断转为HashSet
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis1)?.contains(record.dis.principal()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis2)?.contains(record.dis.principal()))
+        && (!adrg_table(adrg_dis_opt, &adrg_name, verb_other_dis)?.is_disjoint(record.dis.secondary_set()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 入组条件1：主要诊断+其他诊断1+主要手术或操作
+// 入组条件2：其他诊断2+主要手术或操作
+#[allow(clippy::if_same_then_else)] // 每个分支对应一个入组条件
+fn is_contain_multi_opt5(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_dis = "main_dis_list";
+    let verb_main_opt = "main_opt_list";
+    let verb_other_dis1 = "other_dis_list1";
+    let verb_other_dis2 = "other_dis_list2";
+    // 将其他诊断转为HashSet
+    // 无主手术的病例进入空白组
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    // 入组判断
+    if (adrg_table(adrg_dis_opt, &adrg_name, verb_main_dis)?.contains(record.dis.principal()))
+        && (!adrg_table(adrg_dis_opt, &adrg_name, verb_other_dis1)?.is_disjoint(record.dis.secondary_set()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else if (!adrg_table(adrg_dis_opt, &adrg_name, verb_other_dis2)?.is_disjoint(record.dis.secondary_set()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含 WB1、WB2、WB3的所有主要手术或操作
+fn is_contain_multi_wb_opt(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_main_opt = "main_opt_list";
+
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+    if (adrg_table(adrg_dis_opt, "WB1", verb_main_opt)?.contains(record.opt.principal()))
+        || (adrg_table(adrg_dis_opt, "WB2", verb_main_opt)?.contains(record.opt.principal()))
+        || (adrg_table(adrg_dis_opt, "WB3", verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含其他诊断
+fn is_contain_other_dis(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    if !adrg_table(adrg_dis_opt, &adrg_name, DEFAULT_TABLE)?.is_disjoint(record.dis.secondary_set()) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含主诊断或其他诊断
+// 国家版方案中该类ADRG(如PS1-PS4, YR1, YR2)的条件为"主要诊断或其他诊断包括", 主诊断或任一其他诊断在表中即可
+fn is_contain_dis(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let dis_table = adrg_table(adrg_dis_opt, &adrg_name, DEFAULT_TABLE)?;
+    if dis_table.contains(record.dis.principal()) || record.dis.secondary().iter().any(|x| dis_table.contains(x)) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含所有手术()
+fn is_contain_all_opt(
+    record: &DrgCase,
+    all_opt: &HashSet<String>,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    // 如果没有手术则进入空白病组
+    if record.no_surgery() {
+        return Ok(String::from("KBBZ"));
+    }
+
+    if record.operations_intersect(all_opt) {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含诊断与主手术入组, WB3入组使用
+fn is_contain_dis_and_main_opt(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    let verb_dis = "main_dis_list";
+    let verb_main_opt = "main_opt_list";
+    if (!adrg_table(adrg_dis_opt, &adrg_name, verb_dis)?.is_disjoint(record.dis.all()))
+        && (adrg_table(adrg_dis_opt, &adrg_name, verb_main_opt)?.contains(record.opt.principal()))
+    {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// 包含MDCZ的诊断，ZZ1入组使用
+fn is_mdcz_dis(
+    record: &DrgCase,
+    mdcz_dis_opt: &HashMap<String, HashSet<String>>,
+    adrg_name: String,
+) -> Result<String, Box<dyn Error>> {
+    // BUG 判断有问题
+    let mut counter = 0;
+    // 遍历不同部分的诊断表
+    for cate in vec![
+        "belly_dis_sheet",
+        "body_spine_dis_sheet",
+        "chest_dis_sheet",
+        "down_limb_dis_sheet",
+        "genital_dis_sheet",
+        "head_neck_dis_sheet",
+        "pelvis_dis_sheet",
+        "up_limb_dis_sheet",
+        "urinary_dis_sheet",
+    ] {
+        // 主诊断或其他诊断位于多个不同部分的诊断表中
+        if !adrg_table_part(mdcz_dis_opt, cate)?.is_disjoint(record.dis.all())
+        {
+            counter += 1;
+        }
+    }
+    if counter > 1 {
+        Ok(adrg_name)
+    } else {
+        Ok(String::from("KBBZ"))
+    }
+}
+
+// process_adrg能处理的全部入组条件
+const ENTRY_CONDITIONS: [&str; 20] = [
+    "is_contain_main_dis",
+    "is_contain_main_dis_list",
+    "is_contain_main_dis_with_age",
+    "is_contain_main_opt",
+    "is_contain_main_dis_and_main_opt_simultaneously",
+    "is_contain_dis",
+    "is_contain_opt_simultaneously",
+    "is_contain_all_opt",
+    "is_contain_multi_opt3",
+    "is_contain_other_dis",
+    "is_contain_multi_opt5",
+    "is_contain_other_dis_or_other_opt1_and_other_opt2",
+    "is_contain_cb4_opt_and_cb5_opt",
+    "is_contain_cb5_opt_and_cb6_opt",
+    "is_contain_multi_opt1",
+    "is_contain_multi_opt2",
+    "is_contain_multi_opt4",
+    "is_dis_and_main_opt",
+    "is_contain_multi_wb_opt",
+    "is_mdcz_dis",
+];
+
+// 处理每个ADRG入组
+fn process_adrg(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,                        // ADRG诊断手术表
+    all_opt_list: &HashSet<String>,                  // 全部手术列表
+    adrg_type_dict: &HashMap<String, String>,   // ADRG类型及对应入组类型
+    // mdcz_dis_sheet: &HashMap<String, HashSet<String>>, // MDCZ诊断表
+    adrg_name: String,
+    options: &GroupOptions,                          // 分组选项
+) -> Result<String, Box<dyn Error>> {
+    // ADRG的入组条件
+    let adrg_type = match adrg_type_dict.get(&adrg_name) {
+        Some(adrg_type) => adrg_type.as_str(),
+        None => {
+            let err = format!("missing key {} in adrg_type_dict", adrg_name);
+            return lenient_kbbz(Err(err.into()), options);
+        }
+    };
+    let pred_adrg = match adrg_type {
+        // 读取分组方案时已经为is_contain_main_dis的ADRG补充了主诊断表, 没有主诊断表时使用ADRG默认表
+        "is_contain_main_dis" => match adrg_dis_opt.get(&adrg_name) {
+            Some(tables) if tables.contains_key("main_dis_list") => is_contain_main_dis_list(record, adrg_dis_opt, adrg_name),
+            _ => is_contain_main_dis(record, adrg_dis_opt, adrg_name),
+        },
+        "is_contain_main_dis_list" => is_contain_main_dis_list(record, adrg_dis_opt, adrg_name),
+        "is_contain_main_dis_with_age" => is_contain_main_dis_with_age(record, adrg_dis_opt, adrg_name),
+        "is_contain_main_opt" => is_contain_main_opt(record, adrg_dis_opt, adrg_name),
+        "is_contain_main_dis_and_main_opt_simultaneously" => {
+            is_contain_main_dis_and_main_opt_simultaneously(record, adrg_dis_opt, adrg_name)
+        }
+        "is_contain_dis" => is_contain_dis(record, adrg_dis_opt, adrg_name),
+        "is_contain_opt_simultaneously" => {
+            is_contain_opt_simultaneously(record, adrg_dis_opt, adrg_name, options)
+        }
+        "is_contain_all_opt" => is_contain_all_opt(record, all_opt_list, adrg_name),
+        "is_contain_multi_opt3" => is_contain_multi_opt3(record, adrg_dis_opt, adrg_name),
+        "is_contain_other_dis" => is_contain_other_dis(record, adrg_dis_opt, adrg_name),
+        "is_contain_multi_opt5" => is_contain_multi_opt5(record, adrg_dis_opt, adrg_name),
+        "is_contain_other_dis_or_other_opt1_and_other_opt2" => {
+            is_contain_other_dis_or_other_opt1_and_other_opt2(record, adrg_dis_opt, adrg_name)
+        }
+        "is_contain_cb4_opt_and_cb5_opt" => {
+            is_contain_cb4_opt_and_cb5_opt(record, adrg_dis_opt, adrg_name)
+        }
+        "is_contain_cb5_opt_and_cb6_opt" => {
+            is_contain_cb5_opt_and_cb6_opt(record, adrg_dis_opt, adrg_name)
+        }
+        "is_contain_multi_opt1" => is_contain_multi_opt1(record, adrg_dis_opt, adrg_name),
+        "is_contain_multi_opt2" => is_contain_multi_opt2(record, adrg_dis_opt, adrg_name),
+        "is_contain_multi_opt4" => is_contain_multi_opt4(record, adrg_dis_opt, adrg_name),
+        "is_dis_and_main_opt" => is_contain_dis_and_main_opt(record, adrg_dis_opt, adrg_name),
+        "is_contain_multi_wb_opt" => is_contain_multi_wb_opt(record, adrg_dis_opt, adrg_name),
+        "is_mdcz_dis" => match adrg_dis_opt.get(&adrg_name) {
+            // 注意这里需要的是MDCZ的各部分诊断表
+            Some(mdcz_dis_opt) => is_mdcz_dis(record, mdcz_dis_opt, adrg_name.clone()),
+            None => Err(format!("missing key {} in adrg_dis_opt", adrg_name).into()),
+        },
+        // 未知的入组条件返回空白组
+        _ => Err(format!("unknown entry condition {} of {}", adrg_type, adrg_name).into()),
+    };
+
+    lenient_kbbz(pred_adrg, options)
+}
+
+// 宽松模式下分组方案缺失的键按空白组(KBBZ)处理, 严格模式(--strict)下直接返回错误
+fn lenient_kbbz(
+    pred: Result<String, Box<dyn Error>>,
+    options: &GroupOptions,
+) -> Result<String, Box<dyn Error>> {
+    match pred {
+        Err(_) if !options.strict => Ok(String::from("KBBZ")),
+        pred => pred,
+    }
+}
+
+
+// ADRG编码的首字母是否与MDC编码的最后一个字母相同, 如FR2属于MDCF
+fn adrg_in_mdc(adrg_name: &str, mdc_name: &str) -> bool {
+    adrg_name.chars().next().is_some_and(|x| mdc_name.strip_prefix("MDC") == Some(x.to_string().as_str()))
+}
+
+// 是否为先期分组的MDC
+fn is_pre_mdc(mdc_name: &str) -> bool {
+    ["MDCA", "MDCP", "MDCY", "MDCZ"].contains(&mdc_name)
+}
+
+// 默认的先期分组MDC判断顺序, MDCN和MDCM在主诊断所在的MDC中按顺序判断
+fn default_pre_mdc_order() -> Vec<String> {
+    ["MDCA", "MDCP", "MDCY", "MDCZ"].iter().map(|x| x.to_string()).collect()
+}
+
+// 检查先期分组MDC的顺序(--pre-mdc-order, pre_mdc_order.json), 只能包含有单独进入条件的MDC, 不能重复
+fn check_pre_mdc_order(order: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let allowed = ["MDCA", "MDCP", "MDCY", "MDCZ", "MDCN", "MDCM"];
+    for (i, mdc) in order.iter().enumerate() {
+        if !allowed.contains(&mdc.as_str()) {
+            return Err(format!("unsupported pre-MDC {}, expected one of {}", mdc, allowed.join(",")).into());
+        }
+        if order[..i].contains(mdc) {
+            return Err(format!("duplicate pre-MDC {}", mdc).into());
+        }
+    }
+    Ok(order)
+}
+
+struct DrgFunc {}
+impl DrgFunc {
+    // 判断是否为QY
+    fn is_qy(adrg_name: &str) -> bool {
+        &adrg_name[1..=2] == "QY"
+    }
+
+    // ADRG的类型: 按分组方案的ADRG类型划分为surgery, operation或internal, 另有KBBZ, QY和无法划分的other
+    fn drg_type(adrg_name: &str, adrg_categories: &AdrgCategories) -> String {
+        if adrg_name == "KBBZ" {
+            "KBBZ".to_string()
+        } else if DrgFunc::is_qy(adrg_name) {
+            "QY".to_string()
+        } else {
+            adrg_categories.drg_type(adrg_name).unwrap_or("other").to_string()
+        }
+    }
+}
+
+
+fn qy_judge(
+    record: &DrgCase,
+    adrg_name: String,
+    all_opt_list: &HashSet<String>,
+    adrg_categories: &AdrgCategories,
+    options: &GroupOptions,
+) -> String {
+    // 判断QY
+    // 如果预测ADRG为KBBZ，则直接返回
+    if adrg_name == "KBBZ" {
+        return "KBBZ".to_string()
+    }
+    // 默认只看主手术是否在手术列表中, --qy-all-ops时主手术或任一其他手术在手术列表中即为有效手术
+    let valid_surgery = if options.qy_all_ops {
+        record.has_valid_operation(all_opt_list)
+    } else {
+        record.is_vaild_surgrey(all_opt_list)
+    };
+    if valid_surgery {
+        if adrg_categories.drg_type(&adrg_name) == Some("internal") {
+            // 如果手术有效但是又进入了内科组，则判定为QY
+            adrg_name[0..=0].to_string() + "QY"
+        } else {
+            adrg_name
+        }
+    } else {
+        // 如果当前病例的手术无效，且预测的ADRG不为KBBZ，则返回当前预测ADRG
+        adrg_name
+    }    
+}
+
+// 决定进入哪个MDC及ADRG, 返回(MDC, ADRG), ADRG为QY判断之前的结果, 未能入组时两者都为KBBZ
+#[allow(clippy::too_many_arguments)] // 与各MDC/ADRG判断函数保持统一的参数签名
+fn which_mdc_adrg(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,                          // ADRG诊断手术表
+    all_opt_list: &HashSet<String>,                    // 全部手术列表
+    _all_dis_list: &HashSet<String>,                    // 所有手术列表
+    main_dis_sheet: &HashMap<String, Vec<String>>,     // MDC主诊断列表
+    adrg_type_dict: &HashMap<String, String>,          // ADRG类型及对应入组类型
+    mdcz_dis_sheet: &HashMap<String, HashSet<String>>, // MDCZ诊断表
+    mdcy_dis_sheet: &HashSet<String>,                  // MDCY诊断表
+    mdc_sub_adrg: &HashMap<String, Vec<String>>,   // MDC下的各个ADRG
+    perinatal_dis_list: &HashSet<String>,              // 围产期主诊断列表
+    adrg_categories: &AdrgCategories,                  // ADRG类型划分
+    pre_mdc_order: &[String],                          // 先期分组MDC的判断顺序
+    options: &GroupOptions,                            // 分组选项
+    mut trace: Option<&mut CaseTrace>,                 // 判断路径记录(--trace-file)
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let mut pred_adrg = "KBBZ".to_string();
+    let mut n_tried: usize = 0; // 已经尝试的ADRG数
+    let mut no_list_mdc: Option<String> = None; // 进入了但分组方案中没有ADRG列表的MDC
+
+    // 如果没有主诊断则无法入组，直接进入KBBZ
+    if record.no_main_diagnosis() {
+        if options.null_main_dis_error {
+            // 通常是上游导出数据时主诊断列为空
+            return Err("empty main diagnosis (--null-as-error)".into())
+        }
+        return Ok((String::from("KBBZ"), String::from("KBBZ")))
+    }
+
+    // 主诊断所在的MDC
+    let dis_mdc_list = match main_dis_sheet.get(record.dis.principal()) {
+        Some(mdc_list) if mdc_list.is_empty() => {
+            // 分组方案有误, 主诊断没有对应的MDC, 按不在主诊表中处理
+            if options.strict {
+                return Err(format!("empty MDC list for {} in main_dis_sheet", record.dis.principal()).into())
+            }
+            return Ok((String::from("KBBZ"), String::from("KBBZ")))
+        }
+        Some(mdc_list) => mdc_list,
+        None if options.strict => {
+            return Err(format!("missing key {} in main_dis_sheet", record.dis.principal()).into())
+        }
+        // 主诊断不在任何MDC的主诊表中时无法入组
+        None => return Ok((String::from("KBBZ"), String::from("KBBZ"))),
+    };
+    // 先期分组的MDC在前, 然后是主诊断所在的MDC(已经作为先期分组判断过的MDCN/MDCM不再重复判断)
+    let target_mdc_list: Vec<&str> = pre_mdc_order.iter().map(|x| x.as_str())
+        .chain(dis_mdc_list.iter().filter(|x| !pre_mdc_order.contains(x)).map(|x| x.as_str()))
+        .collect();
+    if let Some(t) = trace.as_mut() {
+        // 先记录完整的判断顺序, 之后逐个更新结果, MDCA入组之后的MDC保持not_evaluated
+        t.mdc_order = target_mdc_list.iter().enumerate().map(|(i, mdc)| MdcStep {
+            mdc: mdc.to_string(),
+            source: String::from(if i < pre_mdc_order.len() { "pre_mdc" } else { "main_dis" }),
+            result: String::from("not_evaluated"),
+        }).collect();
+    }
+    let mut adrg_mdc = String::from("KBBZ"); // 给出pred_adrg的MDC
+    let mut adrg_step: Option<usize> = None; // adrg_mdc在判断顺序中的位置
+    for (step, mdc) in target_mdc_list.into_iter().enumerate() {
+        let last_adrg = pred_adrg.clone();
+        // 先期分组和需要判断性别的MDC只有满足进入条件时才判断其中的ADRG
+        let mut entered = true;
+        if mdc == "MDCA" {
+            // 优先判断MDCA
+            pred_adrg = is_mdca(record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, String::from("MDCA"), options)?;
+            trace_pre_mdc(&mut trace, "MDCA", &pred_adrg);
+            // MDCA没有单独的进入条件, 满足其中某个ADRG的条件即为进入
+            entered = pred_adrg != "KBBZ";
+        }        
+        else if mdc == "MDCP" {
+            // 判断MDCP新生儿
+            let mut pred_mdc = is_mdcp(record, main_dis_sheet, String::from("MDCP"));
+            if pred_mdc == "MDCP" && options.strict_age_for_mdcp && !has_birth_evidence(record, perinatal_dis_list) {
+                // 年龄符合新生儿但没有其他佐证, 可能是录入错误的成人年龄
+                pred_mdc = String::from("KBBZ");
+            }
+            trace_pre_mdc(&mut trace, "MDCP", &pred_mdc);
+            entered = pred_mdc == "MDCP";
+            if entered {
+                pred_adrg = match_adrg_in_mdc(
+                    record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, &pred_mdc, options,
+                    &mut n_tried, &mut no_list_mdc, &mut trace,
+                )?;
+            }
+        }
+        else if mdc == "MDCY" {
+            // 判断MDCY
+            let pred_mdc = is_mdcy(record, adrg_type_dict, mdcy_dis_sheet, String::from("MDCY"));
+            trace_pre_mdc(&mut trace, "MDCY", &pred_mdc);
+            entered = pred_mdc == "MDCY";
+            if entered {
+                pred_adrg = match_adrg_in_mdc(
+                    record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, &pred_mdc, options,
+                    &mut n_tried, &mut no_list_mdc, &mut trace,
+                )?;
+            }
+        }
+        else if mdc == "MDCZ" {
+            // 判断MDCZ
+            let pred_mdc = lenient_kbbz(
+                is_mdcz(record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdcz_dis_sheet, String::from("MDCZ")),
+                options,
+            )?;
+            trace_pre_mdc(&mut trace, "MDCZ", &pred_mdc);
+            entered = pred_mdc == "MDCZ";
+            if entered {
+                // 判断MDC内的ADRG入组
+                pred_adrg = match_adrg_in_mdc(
+                    record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, &pred_mdc, options,
+                    &mut n_tried, &mut no_list_mdc, &mut trace,
+                )?;
+            }
+        }
+        else if mdc == "MDCN" {
+            // 需要判断性别的MDCN的处理
+            let pred_mdc = is_mdcn(record, main_dis_sheet, "MDCN".to_string());
+            if step < pre_mdc_order.len() {
+                trace_pre_mdc(&mut trace, "MDCN", &pred_mdc);
+            }
+            entered = pred_mdc == "MDCN";
+            if entered {
+                // 判断MDC内的ADRG入组
+                pred_adrg = match_adrg_in_mdc(
+                    record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, &pred_mdc, options,
+                    &mut n_tried, &mut no_list_mdc, &mut trace,
+                )?;
+            }
+        }
+        else if mdc == "MDCM" {
+            // 需要判断性别的MDCM的处理
+            let pred_mdc = is_mdcm(record, main_dis_sheet, "MDCM".to_string());
+            if step < pre_mdc_order.len() {
+                trace_pre_mdc(&mut trace, "MDCM", &pred_mdc);
+            }
+            entered = pred_mdc == "MDCM";
+            if entered {
+                // 判断MDC内的ADRG入组
+                pred_adrg = match_adrg_in_mdc(
+                    record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, &pred_mdc, options,
+                    &mut n_tried, &mut no_list_mdc, &mut trace,
+                )?;
+            }
+        }
+        else {
+            // 处理其他MDC
+            pred_adrg = match_adrg_in_mdc(
+                record, adrg_dis_opt, all_opt_list, adrg_type_dict, mdc_sub_adrg, adrg_categories, mdc, options,
+                &mut n_tried, &mut no_list_mdc, &mut trace,
+            )?;
+        }
+
+        if let Some(t) = trace.as_mut() {
+            // 没有进入的MDC不改变pred_adrg, 此时pred_adrg是之前的MDC的结果
+            t.mdc_order[step].result = String::from(match (entered, pred_adrg != "KBBZ") {
+                (true, true) => "grouped",
+                (true, false) => "no_adrg_matched",
+                (false, _) => "not_entered",
+            });
+        }
+        if mdc == "MDCA" && pred_adrg != "KBBZ" {
+            // 如果在MDCA中找到ADRG入组
+            adrg_mdc = mdc.to_string();
+            adrg_step = Some(step);
+            break
+        }
+        if pred_adrg != last_adrg {
+            // 之后进入的MDC的判断结果覆盖之前的结果
+            adrg_mdc = mdc.to_string();
+            adrg_step = Some(step);
+        }
+    }
+    if let Some(t) = trace.as_mut() {
+        // 只有给出最终结果的MDC是grouped, 之前满足了ADRG的MDC被覆盖
+        for (step, mdc_step) in t.mdc_order.iter_mut().enumerate() {
+            if mdc_step.result == "grouped" && (pred_adrg == "KBBZ" || adrg_step != Some(step)) {
+                mdc_step.result = String::from("overridden");
+            }
+        }
+    }
+    if pred_adrg == "KBBZ" {
+        if let Some(mdc) = no_list_mdc {
+            // 未入组是因为分组方案缺少ADRG列表, 而不是病例不符合条件
+            return Err(Box::new(NoAdrgListForMdc { mdc }));
+        }
+        return Ok((String::from("KBBZ"), String::from("KBBZ")))
+    }
+    Ok((adrg_mdc, pred_adrg))
+}
+
+// 在一个MDC内判断ADRG入组, 返回入组的ADRG, 都不满足时为KBBZ
+// 默认按mdc_sub_adrg中的顺序取第一个满足条件的ADRG; 给出options.adrg_priority时尝试该MDC的全部ADRG,
+// 多个ADRG同时满足时按分组方案的ADRG类型划分(DrgFunc::drg_type)选择类型优先级高的(默认外科手术 > 操作 > 内科), 同一类型按列表顺序,
+// 未被选中的ADRG记录在判断路径的adrg_alternatives中
+#[allow(clippy::too_many_arguments)] // 与各MDC/ADRG判断函数保持统一的参数签名
+fn match_adrg_in_mdc(
+    record: &DrgCase,
+    adrg_dis_opt: &AdrgSheet,
+    all_opt_list: &HashSet<String>,
+    adrg_type_dict: &HashMap<String, String>,
+    mdc_sub_adrg: &HashMap<String, Vec<String>>,
+    adrg_categories: &AdrgCategories,
+    mdc_name: &str,
+    options: &GroupOptions,
+    n_tried: &mut usize,
+    no_list_mdc: &mut Option<String>,
+    trace: &mut Option<&mut CaseTrace>,
+) -> Result<String, Box<dyn Error>> {
+    let mut matched: Vec<String> = Vec::new();
+    for adrg in sub_adrg_list(mdc_sub_adrg, mdc_name, options, no_list_mdc)? {
+        count_candidate(n_tried, options)?;
+        let pred_adrg = process_adrg(record, adrg_dis_opt, all_opt_list, adrg_type_dict, adrg.clone(), options)?;
+        trace_adrg(trace, mdc_name, adrg, adrg_type_dict, &pred_adrg);
+        if pred_adrg != "KBBZ" {
+            matched.push(pred_adrg);
+            if options.adrg_priority.is_none() {
+                break
+            }
+        }
+    }
+    let priority = match &options.adrg_priority {
+        Some(priority) if matched.len() > 1 => priority,
+        _ => return Ok(matched.into_iter().next().unwrap_or_else(|| String::from("KBBZ"))),
+    };
+    // 不在优先级列表中的类型排在最后
+    let rank = |adrg: &String| {
+        let adrg_type = DrgFunc::drg_type(adrg, adrg_categories);
+        priority.iter().position(|x| *x == adrg_type).unwrap_or(priority.len())
+    };
+    let chosen = matched.iter().min_by_key(|x| rank(x)).cloned().unwrap();
+    if let Some(t) = trace {
+        for adrg in matched.iter().filter(|x| **x != chosen) {
+            t.adrg_alternatives.push(AdrgAlternative {
+                mdc: mdc_name.to_string(),
+                adrg: adrg.clone(),
+                adrg_type: DrgFunc::drg_type(adrg, adrg_categories),
+                chosen: chosen.clone(),
+            });
+        }
+    }
+    Ok(chosen)
+}
+
+// 病例进入的MDC在分组方案中没有ADRG列表
+#[derive(Debug)]
+struct NoAdrgListForMdc {
+    mdc: String,
+}
+
+impl std::fmt::Display for NoAdrgListForMdc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NoAdrgListForMdc: no ADRG list for {} in mdc_sub_adrg", self.mdc)
+    }
+}
+
+impl Error for NoAdrgListForMdc {}
+
+
+// 病例进入的ADRG在分组方案中没有DRG列表
+#[derive(Debug)]
+struct NoDrgListForAdrg {
+    adrg: String,
+}
+
+impl std::fmt::Display for NoDrgListForAdrg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "NoDrgListForAdrg: no DRG list for {} in adrg_drg_name_sheet", self.adrg)
+    }
+}
+
+impl Error for NoDrgListForAdrg {}
+
+// 单个病例尝试的ADRG数超过上限
+#[derive(Debug)]
+struct TooManyCandidates {
+    limit: usize,
+}
+
+impl std::fmt::Display for TooManyCandidates {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "TooManyCandidates: more than {} ADRGs evaluated", self.limit)
+    }
+}
+
+impl Error for TooManyCandidates {}
+
+// 记录一次ADRG尝试, 超过--limit-mdc-loop的上限时返回TooManyCandidates
+fn count_candidate(n_tried: &mut usize, options: &GroupOptions) -> Result<(), Box<dyn Error>> {
+    *n_tried += 1;
+    match options.max_candidates {
+        Some(limit) if *n_tried > limit => Err(Box::new(TooManyCandidates { limit })),
+        _ => Ok(()),
+    }
+}
+
+// MDC下的ADRG列表, 分组方案中没有该MDC的ADRG列表时按没有ADRG处理并记入no_list_mdc(严格模式下报错)
+fn sub_adrg_list<'a>(
+    mdc_sub_adrg: &'a HashMap<String, Vec<String>>,
+    mdc_name: &str,
+    options: &GroupOptions,
+    no_list_mdc: &mut Option<String>,
+) -> Result<&'a [String], Box<dyn Error>> {
+    match mdc_sub_adrg.get(mdc_name) {
+        Some(adrg_list) => Ok(adrg_list),
+        None if options.strict => Err(format!("missing key {} in mdc_sub_adrg", mdc_name).into()),
+        None => {
+            no_list_mdc.get_or_insert_with(|| mdc_name.to_string());
+            Ok(&[])
+        }
+    }
+}
+
+
+// CC/MCC条目的年龄性别条件, 条目第三项起为可选条件, 如["表6-3-1", "MCC", "age_max:1", "sex:0"]
+// age_min:X => 年龄不小于X, age_max:X => 年龄小于X, sex:X => 性别为X(性别未知时不限制)
+fn ccmcc_condition_ok(record: &DrgCase, ccmcc: &[String]) -> bool {
+    for condition in ccmcc.iter().skip(2) {
+        let (key, value) = match condition.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let ok = match (key.trim(), value.trim().parse::<f64>()) {
+            ("age_min", Ok(v)) => record.age >= v,
+            ("age_max", Ok(v)) => record.age < v,
+            ("sex", Ok(v)) => record.sex == UNKNOWN_SEX || record.sex == v as i32,
+            _ => true,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+// 按范围列出需要判断CC/MCC的诊断, 主诊断不算作自身的并发症
+fn ccmcc_candidates(record: &DrgCase, scope: CcmccScope) -> Vec<&String> {
+    let principal = record.dis.principal();
+    match scope {
+        CcmccScope::OtherDis => record.dis.secondary().iter().filter(|x| *x != principal).collect(),
+        CcmccScope::AllDis => {
+            let mut codes: Vec<&String> = record.dis.all().iter().filter(|x| *x != principal).collect();
+            codes.sort();
+            codes
+        }
+    }
+}
+
+// 病例其他诊断中未被主诊断排除的CC/MCC, 返回(其他诊断编码, CC或MCC)
+fn valid_ccmcc(
+    record: &DrgCase,
+    ccmcc_sheet: &HashMap<String, Vec<String>>,
+    exclude_sheet: &HashMap<String, String>,
+    scope: CcmccScope,
+) -> Vec<(String, String)> {
+    let exclude_table = exclude_sheet.get(record.dis.principal());
+    ccmcc_candidates(record, scope).into_iter()
+        .filter_map(|x| ccmcc_sheet.get(x).map(|c| (x, c)))
+        .filter(|(_, c)| c.len() >= 2)
+        .filter(|(_, c)| ccmcc_condition_ok(record, c))
+        .filter(|(_, c)| Some(&c[0]) != exclude_table)
+        .map(|(x, c)| (x.to_string(), c[1].to_string()))
+        .collect()
+}
+
+// 其他诊断中每个CC/MCC的排除判断, 与valid_ccmcc的判断一致, 用于判断路径
+fn complications(
+    record: &DrgCase,
+    ccmcc_sheet: &HashMap<String, Vec<String>>,
+    exclude_sheet: &HashMap<String, String>,
+    scope: CcmccScope,
+) -> Vec<Complication> {
+    let exclude_table = exclude_sheet.get(record.dis.principal());
+    ccmcc_candidates(record, scope).into_iter()
+        .filter_map(|x| ccmcc_sheet.get(x).map(|c| (x, c)))
+        .filter(|(_, c)| c.len() >= 2)
+        .map(|(x, c)| Complication {
+            code: x.to_string(),
+            severity: c[1].to_string(),
+            excluded: Some(&c[0]) == exclude_table,
+            exclusion_table: c[0].to_string(),
+            condition_ok: ccmcc_condition_ok(record, c),
+        })
+        .collect()
+}
+
+// 检查主诊表中没有对应MDC为空列表的诊断
+fn validate_main_dis_sheet(main_dis_sheet: &HashMap<String, Vec<String>>) -> Result<(), Box<dyn Error>> {
+    let mut empty: Vec<&String> = main_dis_sheet.iter().filter(|(_, v)| v.is_empty()).map(|(k, _)| k).collect();
+    if !empty.is_empty() {
+        empty.sort();
+        return Err(format!("main_dis_sheet has diagnoses without any MDC: {:?}", empty).into());
+    }
+    Ok(())
+}
+
+// 检查主诊表中同时属于MDCM(男性)和MDCN(女性)的诊断, 这类诊断的入组结果取决于性别, 通常是分组方案的错误
+// 只给出警告而不报错, 返回警告内容, 没有这类诊断时为None
+fn sex_mdc_warning(main_dis_sheet: &HashMap<String, Vec<String>>) -> Option<String> {
+    let mut both: Vec<&String> = main_dis_sheet.iter()
+        .filter(|(_, v)| v.iter().any(|x| x == "MDCM") && v.iter().any(|x| x == "MDCN"))
+        .map(|(k, _)| k)
+        .collect();
+    if both.is_empty() {
+        return None;
+    }
+    both.sort();
+    Some(format!("{} diagnoses in main_dis_sheet are mapped to both MDCM and MDCN: {:?}", both.len(), both))
+}
+
+// 分组方案读取时的警告, 与GroupingScheme的warnings相同, 读取失败的主诊表已经由validate_scheme记录为问题, 这里按空表处理
+fn scheme_warnings(source: &dyn SchemeSource) -> Vec<String> {
+    let main_dis_sheet: HashMap<String, Vec<String>> = read_member_json(source, "main_dis_sheet.json").unwrap_or_default();
+    sex_mdc_warning(&main_dis_sheet).into_iter().collect()
+}
+
+// 检查主诊表和先期分组中用到的每个MDC在mdc_sub_adrg中都有ADRG列表
+fn validate_mdc_sub_adrg(
+    main_dis_sheet: &HashMap<String, Vec<String>>,
+    mdc_sub_adrg: &HashMap<String, Vec<String>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut missing: Vec<&str> = ["MDCA", "MDCP", "MDCY", "MDCZ"].into_iter()
+        .chain(main_dis_sheet.values().flatten().map(|x| x.as_str()))
+        .filter(|x| !mdc_sub_adrg.contains_key(*x))
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(format!("mdc_sub_adrg has no ADRG list for: {:?}", missing).into());
+    }
+    Ok(())
+}
+
+// 检查有入组条件的ADRG在ADRG下的DRG表中都有DRG列表
+fn validate_adrg_drg_lists(
+    adrg_type_dict: &HashMap<String, String>,
+    adrg_drg_name_sheet: &HashMap<String, Vec<String>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut missing: Vec<&String> = adrg_type_dict.keys().filter(|x| !adrg_drg_name_sheet.contains_key(*x)).collect();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(format!("adrg_drg_name_sheet has no DRG list for: {:?}", missing).into());
+    }
+    Ok(())
+}
+
+// 入组条件为is_contain_main_dis(或is_contain_main_dis_with_age)的ADRG没有专门的主诊断表(main_dis_list)时, 用ADRG默认表补充
+// 分组方案可以给出与默认表不同的"ADRG_main_dis_list", 主诊断条件只看主诊断表
+fn populate_main_dis_lists(adrg_type_dict: &HashMap<String, String>, adrg_dis_opt: &mut AdrgSheet) {
+    for (adrg_name, adrg_type) in adrg_type_dict {
+        if adrg_type != "is_contain_main_dis" && adrg_type != "is_contain_main_dis_with_age" {
+            continue;
+        }
+        if let Some(tables) = adrg_dis_opt.get_mut(adrg_name) {
+            if !tables.contains_key("main_dis_list") {
+                if let Some(default) = tables.get(DEFAULT_TABLE).cloned() {
+                    tables.insert(String::from("main_dis_list"), default);
+                }
+            }
+        }
+    }
+}
+
+// 检查CC/MCC表的每个条目都有排除表编号和CC/MCC两项
+fn validate_ccmcc_sheet(ccmcc_sheet: &HashMap<String, Vec<String>>) -> Result<(), Box<dyn Error>> {
+    let mut malformed: Vec<&String> = ccmcc_sheet.iter().filter(|(_, v)| v.len() < 2).map(|(k, _)| k).collect();
+    if !malformed.is_empty() {
+        malformed.sort();
+        return Err(format!("ccmcc_sheet entries need [exclude table, CC/MCC]: {:?}", malformed).into());
+    }
+    Ok(())
+}
+
+// 不对病例分组, 只检查分组方案的一致性, 返回找到的全部问题(而不是读取时遇到的第一个)
+// 与GroupingScheme::load使用相同的读取函数和读取时的检查(包括可选文件), 以及入组条件是否已知, MDC下的ADRG是否有入组条件和诊断手术表, 列表是否为空
+fn validate_scheme(source: &dyn SchemeSource) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    // 读取失败的文件记录问题后按空表继续检查
+    let main_dis_sheet: HashMap<String, Vec<String>> = or_problem(read_member_json(source, "main_dis_sheet.json"), "main_dis_sheet.json", &mut problems);
+    let ccmcc_sheet: HashMap<String, Vec<String>> = or_problem(read_member_json(source, "ccmcc_sheet.json"), "ccmcc_sheet.json", &mut problems);
+    let mdc_sub_adrg: HashMap<String, Vec<String>> = or_problem(read_member_json(source, "mdc_sub_adrg.json"), "mdc_sub_adrg.json", &mut problems);
+    let adrg_drg_name_sheet: HashMap<String, Vec<String>> =
+        or_problem(read_member_json(source, "adrg_drg_name_sheet.json"), "adrg_drg_name_sheet.json", &mut problems);
+    let adrg_type_dict: HashMap<String, String> = or_problem(read_member_json(source, "adrg_in_condition.json"), "adrg_in_condition.json", &mut problems);
+    let adrg_dis_opt = or_problem(
+        source.read_member("adrg_dis_opt_sheet.json").and_then(|data| parse_adrg_dis_opt_sheet(&data)),
+        "adrg_dis_opt_sheet.json",
+        &mut problems,
+    );
+    or_problem(read_member_json::<HashMap<String, String>>(source, "exclude_sheet.json"), "exclude_sheet.json", &mut problems);
+    or_problem(read_member_json::<HashMap<String, HashSet<String>>>(source, "mdcz_dis_sheet.json"), "mdcz_dis_sheet.json", &mut problems);
+    for name in ["all_dis_sheet.txt", "all_opt_sheet.txt", "mdcy_dis_sheet.txt"] {
+        or_problem(read_member_icd_list(source, name, false), name, &mut problems);
+    }
+    // 可选的文件
+    or_problem(read_member_icd_list(source, "perinatal_dis.txt", true), "perinatal_dis.txt", &mut problems);
+    or_problem(read_optional_member_json::<AdrgCategories>(source, "adrg_categories.json"), "adrg_categories.json", &mut problems);
+    or_problem(read_optional_member_json::<Vec<LocalRule>>(source, "local_rules.json"), "local_rules.json", &mut problems);
+    or_problem(read_optional_member_json::<HashMap<String, f64>>(source, "drg_weights.json"), "drg_weights.json", &mut problems);
+    or_problem(
+        read_optional_member_json(source, "pre_mdc_order.json").and_then(check_pre_mdc_order),
+        "pre_mdc_order.json",
+        &mut problems,
+    );
+    let severity_endings: SeverityEndings =
+        or_problem(read_optional_member_json(source, "severity_endings.json"), "severity_endings.json", &mut problems);
+
+    // 读取时的检查
+    or_problem(validate_main_dis_sheet(&main_dis_sheet), "main_dis_sheet.json", &mut problems);
+    or_problem(validate_ccmcc_sheet(&ccmcc_sheet), "ccmcc_sheet.json", &mut problems);
+    or_problem(validate_mdc_sub_adrg(&main_dis_sheet, &mdc_sub_adrg), "mdc_sub_adrg.json", &mut problems);
+    or_problem(validate_adrg_drg_lists(&adrg_type_dict, &adrg_drg_name_sheet), "adrg_drg_name_sheet.json", &mut problems);
+    let adrg_drg_levels = or_problem(drg_level_sheet(&adrg_drg_name_sheet), "adrg_drg_name_sheet.json", &mut problems);
+    or_problem(validate_drg_endings(&adrg_drg_levels, &severity_endings), "adrg_drg_name_sheet.json", &mut problems);
+
+    let mut sorted = |mut found: Vec<String>, message: &str| {
+        if !found.is_empty() {
+            found.sort();
+            found.dedup();
+            problems.push(format!("{}: {:?}", message, found));
+        }
+    };
+    sorted(
+        adrg_type_dict.iter().filter(|(_, v)| !ENTRY_CONDITIONS.contains(&v.as_str())).map(|(k, v)| format!("{} => {}", k, v)).collect(),
+        "adrg_in_condition.json has unknown entry conditions",
+    );
+    sorted(
+        mdc_sub_adrg.values().flatten().filter(|x| !adrg_type_dict.contains_key(*x)).cloned().collect(),
+        "mdc_sub_adrg has ADRGs without an entry condition",
+    );
+    // 只用全部手术列表判断的入组条件不需要诊断手术表
+    sorted(
+        adrg_type_dict.iter().filter(|(k, v)| *v != "is_contain_all_opt" && !adrg_dis_opt.contains_key(*k)).map(|(k, _)| k.to_string()).collect(),
+        "adrg_dis_opt_sheet has no tables for",
+    );
+    sorted(
+        mdc_sub_adrg.iter().filter(|(_, v)| v.is_empty()).map(|(k, _)| k.to_string()).collect(),
+        "mdc_sub_adrg has empty ADRG lists for",
+    );
+    sorted(
+        adrg_drg_name_sheet.iter().filter(|(_, v)| v.is_empty()).map(|(k, _)| k.to_string()).collect(),
+        "adrg_drg_name_sheet has empty DRG lists for",
+    );
+    sorted(
+        adrg_dis_opt.iter()
+            .flat_map(|(adrg, tables)| tables.iter().filter(|(_, v)| v.is_empty()).map(move |(table, _)| format!("{}/{}", adrg, table)))
+            .collect(),
+        "adrg_dis_opt_sheet has empty tables",
+    );
+    sorted(
+        adrg_type_dict.iter()
+            .filter(|(k, v)| *v == "is_contain_main_dis_with_age" && adrg_age_range(&adrg_dis_opt, k).is_err())
+            .map(|(k, _)| k.to_string())
+            .collect(),
+        "adrg_dis_opt_sheet has no valid age_range for",
+    );
+    problems
+}
+
+// 检查结果有错误时记入problems并返回默认值, 使检查可以继续
+// 错误信息以文件名开头(如read_member_json的错误)时不再重复文件名
+fn or_problem<T: Default>(result: Result<T, Box<dyn Error>>, context: &str, problems: &mut Vec<String>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            let message = e.to_string();
+            if message.starts_with(context) {
+                problems.push(message);
+            } else {
+                problems.push(format!("{}: {}", context, message));
+            }
+            T::default()
+        }
+    }
+}
+
+// 分组用到的年龄分界(已排序去重): 新生儿的年龄上限, ADRG的年龄范围(age_range)和CC/MCC条目的age_min/age_max
+fn age_thresholds(adrg_dis_opt: &AdrgSheet, ccmcc_sheet: &HashMap<String, Vec<String>>) -> Vec<f64> {
+    let mut thresholds = vec![NEWBORN_MAX_AGE];
+    for (adrg_name, tables) in adrg_dis_opt {
+        if let (true, Ok((min_age, max_age))) = (tables.contains_key("age_range"), adrg_age_range(adrg_dis_opt, adrg_name)) {
+            thresholds.extend(min_age.into_iter().chain(max_age));
+        }
+    }
+    for condition in ccmcc_sheet.values().flat_map(|x| x.iter().skip(2)) {
+        if let Some(("age_min" | "age_max", value)) = condition.split_once(':').map(|(k, v)| (k.trim(), v.trim())) {
+            thresholds.extend(value.parse::<f64>().ok());
+        }
+    }
+    thresholds.sort_by(f64::total_cmp);
+    thresholds.dedup();
+    thresholds
+}
+
+// 主诊表的反向索引: MDC => 主诊断列表(排序)
+fn mdc_dis_index(main_dis_sheet: &HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
+    let mut index: HashMap<String, Vec<String>> = HashMap::new();
+    for (dis, mdc_list) in main_dis_sheet {
+        for mdc in mdc_list {
+            index.entry(mdc.to_string()).or_default().push(dis.to_string());
+        }
+    }
+    for dis_list in index.values_mut() {
+        dis_list.sort();
+    }
+    index
+}
+
+// 把ADRG下的DRG列表按DRG结尾数字索引, 分组方案读取时计算一次
+fn drg_level_sheet(adrg_drg_name_sheet: &HashMap<String, Vec<String>>) -> Result<DrgLevelSheet, Box<dyn Error>> {
+    let mut levels: DrgLevelSheet = HashMap::new();
+    for (adrg_name, drg_list) in adrg_drg_name_sheet {
+        let mut drg_wait_dict: HashMap<i32, String> = HashMap::new();
+        for drg in drg_list {
+            let level = drg.chars().last().and_then(|c| c.to_digit(10))
+                .ok_or(format!("invalid DRG code {} under ADRG {}", drg, adrg_name))?;
+            drg_wait_dict.insert(level as i32, drg.to_string());
+        }
+        levels.insert(adrg_name.to_string(), drg_wait_dict);
+    }
+    Ok(levels)
+}
+
+// 检查各ADRG下DRG的结尾数字与分组方案的并发症等级约定(SeverityEndings)一致, 否则分组时选不出DRG
+// 只有一个DRG时不限结尾; 有多个DRG时结尾只能是MCC、CC和无的数字, 必须有无并发症的DRG, 三个以上时必须有MCC的DRG
+fn validate_drg_endings(adrg_drg_levels: &DrgLevelSheet, endings: &SeverityEndings) -> Result<(), Box<dyn Error>> {
+    let allowed = [endings.mcc, endings.cc, endings.none];
+    let mut bad: Vec<String> = adrg_drg_levels.iter()
+        .filter(|(_, levels)| levels.len() > 1)
+        .filter(|(_, levels)| {
+            levels.keys().any(|x| !allowed.contains(x))
+                || !levels.contains_key(&endings.none)
+                || (levels.len() > 2 && !levels.contains_key(&endings.mcc))
+        })
+        .map(|(adrg_name, levels)| {
+            let mut drgs: Vec<&str> = levels.values().map(|x| x.as_str()).collect();
+            drgs.sort();
+            format!("{} => {}", adrg_name, drgs.join("|"))
+        })
+        .collect();
+    if !bad.is_empty() {
+        bad.sort();
+        return Err(format!(
+            "adrg_drg_name_sheet has DRG endings that do not match severity_endings (mcc {}, cc {}, none {}): {:?}",
+            endings.mcc, endings.cc, endings.none, bad,
+        ).into());
+    }
+    Ok(())
+}
+
+// 并发症等级, 有MCC为MCC, 只有CC为CC, 都没有为none
+fn ccmcc_severity(ccmcc: &[(String, String)]) -> String {
+    if ccmcc.iter().any(|(_, level)| level == "MCC") {
+        String::from("MCC")
+    } else if ccmcc.is_empty() {
+        String::from("none")
+    } else {
+        String::from("CC")
+    }
+}
+
+fn process_drg(
+    record: &DrgCase, 
+    adrg_name: String, 
+    ccmcc_sheet: &HashMap<String, Vec<String>>,
+    exclude_sheet: &HashMap<String,String>,
+    adrg_drg_levels: &DrgLevelSheet,
+    severity_endings: &SeverityEndings,
+    scope: CcmccScope,
+) -> Result<String, Box<dyn Error>> {
+    if (&adrg_name == "KBBZ") || (&adrg_name[1..=2] == "QY") {
+        let res = adrg_name.clone();
+        return Ok(res)
+    }
+    // 判定CCMCC并决定进入哪个DRG
+    let drg_wait_dict: &HashMap<i32, String> = match adrg_drg_levels.get(&adrg_name) {
+        Some(x) => x,
+        None => return Err(Box::new(NoDrgListForAdrg { adrg: adrg_name })),
+    };
+
+    // CC/MCC条目至少需要排除表编号和CC/MCC两项
+    if let Some(x) = record.dis.secondary().iter().find(|x| ccmcc_sheet.get(*x).is_some_and(|c| c.len() < 2)) {
+        return Err(format!("ccmcc_sheet entry for {} needs [exclude table, CC/MCC]", x).into());
+    }
+    // 病例的并发症等级: 未被主诊断排除且满足年龄性别条件的其他诊断中最高的等级(MCC > CC > none)
+    let severity = ccmcc_severity(&valid_ccmcc(record, ccmcc_sheet, exclude_sheet, scope));
+    resolve_drg_ending(&adrg_name, &severity, drg_wait_dict, severity_endings)
+}
+
+// 按并发症等级在ADRG下的DRG中选择, 结尾数字按分组方案的约定(默认MCC => 1, CC => 3, 无 => 5, 不区分 => 9)
+// 分组方案读取时已检查过结尾数字(validate_drg_endings), ADRG下仍然没有需要的结尾时返回错误而不会panic
+fn resolve_drg_ending(adrg_name: &str, severity: &str, drg_wait_dict: &HashMap<i32, String>, endings: &SeverityEndings) -> Result<String, Box<dyn Error>> {
+    let (mcc, cc, none) = (endings.mcc, endings.cc, endings.none);
+    let ending = if drg_wait_dict.len() == 1 {
+        // 如果当前ADRG下只有一个DRG那么DRG结尾必然为不区分并发症的数字
+        return Ok(drg_wait_dict.get(&endings.single).or_else(|| drg_wait_dict.values().next()).unwrap().clone());
+    }
+    else if drg_wait_dict.len() == 2 {
+        // 当前ADRG下有两个DRG时为MCC和无或者CC和无
+        match severity {
+            // ADRG只分MCC和无的时候，有MCC进入MCC，没有MCC进入无
+            "MCC" if drg_wait_dict.contains_key(&mcc) => mcc,
+            "MCC" => cc,
+            // ADRG只分MCC和无的时候，有CC只能进入无
+            "CC" if drg_wait_dict.contains_key(&mcc) => none,
+            "CC" => cc,
+            // 没有有效CCMCC的情况下返回不伴并发症的DRG
+            _ => none,
+        }
+    }
+    else {
+        match severity {
+            // 如果并发症类型为MCC，则进入伴严重并发症的DRG
+            "MCC" => mcc,
+            // 如果并发症类型为CC，则进入伴一般并发症的DRG, 没有时进入MCC的DRG(意味着MCC与CC合并了)
+            "CC" if drg_wait_dict.contains_key(&cc) => cc,
+            "CC" => mcc,
+            // 无CC和MCC，则进入不伴并发症的DRG
+            _ => none,
+        }
+    };
+    match drg_wait_dict.get(&ending) {
+        Some(drg) => Ok(drg.clone()),
+        None => {
+            let mut drgs: Vec<&str> = drg_wait_dict.values().map(|x| x.as_str()).collect();
+            drgs.sort();
+            Err(format!("ADRG {} has no DRG ending in {} for severity {} (DRGs: {})", adrg_name, ending, severity, drgs.join("|")).into())
+        }
+    }
+}
+
+
+// 单个病例的分组结果、判断路径(不记录判断路径时为None)和检查出的病例问题
+type GroupedCase = (GroupResult, Option<CaseTrace>, Vec<CaseIssue>);
+
+// 逐行读取NDJSON病例(每行一个JSON对象, 空行跳过)并分组, 不把全部病例读入内存
+// count_only为true时只按DRG计数, 读完之后按DRG编码排序输出直方图; 否则每个病例输出一行JSON结果
+fn stream_ndjson<R: std::io::BufRead, W: Write>(
+    reader: R,
+    out: &mut W,
+    scheme: &GroupingScheme,
+    options: &CliOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let case = parse_json_case(&line, options).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        let result = scheme.group(&case, &options.group_options)?;
+        if options.count_only {
+            *counts.entry(result.drg).or_default() += 1;
+        } else {
+            writeln!(out, "{}", result_json(&case.id, result))?;
+        }
+    }
+    if options.count_only {
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort();
+        for (drg, count) in counts {
+            writeln!(out, "{}\t{}", drg, count)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// NDJSON和repl模式输出的一行分组结果
+fn result_json(id: &str, result: GroupResult) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "mdc": result.mdc,
+        "adrg": result.adrg,
+        "drg": result.drg,
+        "severity": result.severity,
+        "review_flags": result.review_flags,
+    })
+}
+
+// 常驻的交互模式, 分组方案只读取一次, 之后逐行读取JSON病例并立即输出一行JSON结果, 直到输入结束
+// 供桌面程序等长期运行的调用方使用, 单个病例解析或分组失败时输出{"line": 行号, "error": 错误信息}并继续, 不会退出进程
+fn run_repl<R: std::io::BufRead, W: Write>(
+    reader: R,
+    out: &mut W,
+    scheme: &GroupingScheme,
+    options: &CliOptions,
+) -> Result<(), Box<dyn Error>> {
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = match parse_json_case(&line, options).and_then(|case| {
+            let result = scheme.group(&case, &options.group_options)?;
+            Ok(result_json(&case.id, result))
+        }) {
+            Ok(row) => row,
+            Err(e) => serde_json::json!({"line": n + 1, "error": e.to_string()}),
+        };
+        writeln!(out, "{}", row)?;
+        // 每个结果都立即输出, 调用方不必等到输入结束
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// 读取基线分组结果文件中每个病例ID的分组编码(code列)
+fn read_baseline_codes(file_path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(file_path).map_err(|e| format!("{}: {}", file_path, e))?;
+    let headers = rdr.headers()?.clone();
+    let id_col = headers.iter().position(|x| x == "id").ok_or_else(|| format!("{}: baseline needs an id column", file_path))?;
+    let code_col = headers.iter().position(|x| x == "code").ok_or_else(|| format!("{}: baseline needs a code column", file_path))?;
+    let mut codes = HashMap::new();
+    for record in rdr.records() {
+        let record = record?;
+        codes.insert(record[id_col].to_string(), record[code_col].to_string());
+    }
+    Ok(codes)
+}
+
+// 对单个病例分组, with_trace为true时同时返回判断路径
+// 病例问题只检查一次, 分组、--validate、--emit-reason-always和填错列的计数都使用这一次的结果
+fn group_one(
+    scheme: &GroupingScheme,
+    case: &DrgCase,
+    options: &GroupOptions,
+    with_trace: bool,
+) -> Result<GroupedCase, Box<dyn Error>> {
+    let issues = scheme.case_issues(case, options);
+    let mut trace = with_trace.then(|| CaseTrace { id: case.id.to_string(), ..Default::default() });
+    let result = scheme.group_inner(case, options, trace.as_mut(), Some(&issues))
+        .map_err(|e| format!("case {}: {}", case.id, e))?;
+    Ok((result, trace, issues))
+}
+
+// 多线程分组, 病例按顺序分成threads段, 每个线程处理一段, 结果按输入顺序拼接
+fn group_parallel(
+    scheme: &GroupingScheme,
+    cases: &[DrgCase],
+    options: &GroupOptions,
+    with_trace: bool,
+    threads: usize,
+) -> Result<Vec<GroupedCase>, Box<dyn Error>> {
+    let chunk_size = cases.len().div_ceil(threads.max(1)).max(1);
+    let chunks: Vec<Result<Vec<GroupedCase>, String>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = cases
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter()
+                        .map(|case| group_one(scheme, case, options, with_trace).map_err(|e| e.to_string()))
+                        .collect()
+                })
+            })
+            .collect();
+        handles.into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(String::from("grouping thread panicked"))))
+            .collect()
+    });
+    let mut results = Vec::with_capacity(cases.len());
+    for chunk in chunks {
+        results.extend(chunk?);
+    }
+    Ok(results)
+}
+
+// 随机抽取n个病例(不放回), 保持病例在输入中的顺序, 相同的种子总是抽到相同的病例
+fn sample_cases(cases: Vec<DrgCase>, n: usize, seed: u64) -> Vec<DrgCase> {
+    // splitmix64, 不依赖外部的随机数库
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    // 部分Fisher-Yates洗牌, 前n个为抽中的病例
+    let mut index: Vec<usize> = (0..cases.len()).collect();
+    let n = n.min(cases.len());
+    for i in 0..n {
+        let j = i + (next() % (cases.len() - i) as u64) as usize;
+        index.swap(i, j);
+    }
+    let chosen: HashSet<usize> = index[..n].iter().copied().collect();
+    cases.into_iter().enumerate().filter(|(i, _)| chosen.contains(i)).map(|(_, x)| x).collect()
+}
+
+// 输出的年龄: 整岁为整数, 不足一岁时总是保留全部精度(可以还原出生天数, 如29天为0.07945205479452055)
+// 给出decimals(--age-decimals)时一岁以上的年龄四舍五入到decimals位小数, 并去掉末尾的0
+fn format_age(age: f64, decimals: Option<usize>) -> String {
+    match decimals {
+        Some(n) if age >= 1.0 && age.fract() != 0.0 => {
+            let rounded = format!("{:.*}", n, age);
+            if !rounded.contains('.') {
+                return rounded;
+            }
+            rounded.trim_end_matches('0').trim_end_matches('.').to_string()
+        }
+        _ => age.to_string(),
+    }
+}
+
+// 一个病例的批量输出行, 按选项增加各个可选列
+// choice为--best-main-dis时两个主诊断的分组结果, result为其中选择的结果, issues为分组时检查出的病例问题
+fn grouped_row(scheme: &GroupingScheme, case: DrgCase, result: &GroupResult, choice: Option<&MainDisChoice>, issues: &[CaseIssue], options: &CliOptions) -> Result<DrgCaseGrouped, Box<dyn Error>> {
+    let case_dis_count = case.dis.all().len();
+    let case_opt_count = case.opt.all().len();
+    let case_hash = case.case_hash(scheme, options.best_main_dis);
+    let weight_band = case.weight_band().unwrap_or_default().to_string();
+    let age = format_age(case.age, options.age_decimals);
+    let (main_dis, main_dis_alt) = (case.dis.principal().to_string(), case.main_dis_alt.to_string());
+    // 只对未入组的病例给出建议, 格式为"其他诊断:DRG", 多个用"|"分隔
+    let main_swap_suggestion = if options.suggest_main_swap && result.drg == "KBBZ" {
+        scheme.suggest_main_swap(&case, &options.group_options)?
+            .iter()
+            .map(|(dis, drg)| format!("{}:{}", dis, drg))
+            .collect::<Vec<String>>()
+            .join("|")
+    } else {
+        String::new()
+    };
+    // 只对QY病例给出建议, 格式为"其他手术:DRG"
+    let main_opt_suggestion = if options.suggest_main_opt && result.adrg.ends_with("QY") {
+        scheme.suggest_main_opt(&case, &options.group_options)?
+            .map(|(opt, drg)| format!("{}:{}", opt, drg))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let status = if options.emit_reason_always {
+        Some(scheme.group_status(&case, result, issues))
+    } else {
+        None
+    };
+    let case_issues = if options.validate {
+        Some(issues.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("|"))
+    } else {
+        None
+    };
+    // 初始化需要写入的病例类型结构
+    let mut c_wtr = DrgCaseGrouped::new(case, result.drg.clone());
+    c_wtr.age = age;
+    c_wtr.case_issues = case_issues;
+    c_wtr.status = status;
+    if options.emit_review_flags {
+        c_wtr.review_flags = Some(result.review_flags.join("|"));
+    }
+    if options.emit_counts {
+        c_wtr.n_dis = Some(case_dis_count);
+        c_wtr.n_opt = Some(case_opt_count);
+    }
+    if options.emit_hash {
+        c_wtr.case_hash = Some(case_hash);
+    }
+    if options.emit_weight_band {
+        c_wtr.weight_band = Some(weight_band);
+    }
+    if options.emit_stages {
+        // 分组各阶段的结果, 便于比较两个版本的分组结果从哪一步开始不同
+        c_wtr.stage_mdc = Some(result.mdc.to_string());
+        c_wtr.stage_adrg_pre_qy = Some(result.pre_qy_adrg.to_string());
+        c_wtr.stage_adrg_post_qy = Some(result.adrg.to_string());
+        c_wtr.stage_drg = Some(result.local_override.as_ref().map(|x| x.original_drg.to_string()).unwrap_or_else(|| result.drg.to_string()));
+    }
+    if options.suggest_main_swap {
+        c_wtr.main_swap_suggestion = Some(main_swap_suggestion);
+    }
+    if options.suggest_main_opt {
+        c_wtr.main_opt_suggestion = Some(main_opt_suggestion);
+    }
+    if options.best_main_dis {
+        c_wtr.main_dis_drg = Some(choice.map(|x| x.main.drg.to_string()).unwrap_or_else(|| result.drg.to_string()));
+        c_wtr.main_dis_alt_drg = Some(choice.map(|x| x.alt.drg.to_string()).unwrap_or_default());
+        c_wtr.chosen_main_dis = Some(choice.map(|x| x.chosen_main_dis(&main_dis).to_string()).unwrap_or(main_dis));
+        c_wtr.main_dis_alt = Some(main_dis_alt);
+    }
+    Ok(c_wtr)
+}
+
+// 与基线分组结果相比是否变化(基线中没有的病例也算作变化), 变化时填入old_code和new_code列
+fn baseline_changed(baseline: &HashMap<String, String>, c_wtr: &mut DrgCaseGrouped) -> bool {
+    let old_code = baseline.get(&c_wtr.id).cloned().unwrap_or_default();
+    if old_code == c_wtr.code {
+        return false;
+    }
+    c_wtr.old_code = Some(old_code);
+    c_wtr.new_code = Some(c_wtr.code.clone());
+    true
+}
+
+// 批量分组
+fn batch_drg_process(
+    case_vec: Vec<DrgCase>,
+    out_file_path: &str,
+    options: &CliOptions,
+) -> Result<(), Box<dyn Error>> {
+    // 读取分组方案数据
+    let scheme = load_scheme(options)?;
+
+    let mut trace_writer = match &options.trace_file {
+        Some(trace_file) => Some(std::io::BufWriter::new(File::create(trace_file)?)),
+        None => None,
+    };
+    let mut progress = if options.progress && !options.quiet {
+        Some(Progress::new(case_vec.len()))
+    } else {
+        None
+    };
+    let with_trace = trace_writer.is_some();
+    // 多线程时先并行分组, 结果按输入顺序排列, 之后与单线程一样逐个输出, 保证输出完全相同
+    let mut parallel_results = if options.threads > 1 {
+        Some(group_parallel(&scheme, &case_vec, &options.group_options, with_trace, options.threads)?.into_iter())
+    } else {
+        None
+    };
+    let mut drg_grouped_vec: Vec<(GroupResult, DrgCaseGrouped)> = Vec::new();
+    let mut n_field_swaps: usize = 0; // 诊断手术填错列的病例数
+    // 批量分组
+    for case in case_vec {
+        let (result, trace, issues) = match parallel_results.as_mut().and_then(|x| x.next()) {
+            Some(grouped) => grouped,
+            None => group_one(&scheme, &case, &options.group_options, with_trace)?,
+        };
+        if let (Some(wrt), Some(trace)) = (trace_writer.as_mut(), trace) {
+            // 每个病例的判断路径写为一行JSON
+            writeln!(wrt, "{}", trace.render(options.explain_format.as_deref().unwrap_or("json"))?)?;
+        }
+        if issues.iter().any(|x| x.is_field_swap()) {
+            n_field_swaps += 1;
+        }
+        let (result, choice) = best_main_dis(&scheme, &case, result, options)?;
+        let c_wtr = grouped_row(&scheme, case, &result, choice.as_ref(), &issues, options)?;
+        drg_grouped_vec.push((result, c_wtr));
+        if let Some(p) = progress.as_mut() {
+            p.tick();
+        }
+    }
+    if let Some(p) = progress.as_ref() {
+        p.finish();
+    }
+    warn_field_swaps(n_field_swaps, options);
+
+    if let Some(wrt) = trace_writer.as_mut() {
+        wrt.flush()?;
+    }
+
+    if let Some(coverage_path) = &options.coverage_out {
+        // 按全部病例统计, 不受--baseline筛选的影响
+        write_adrg_coverage(&adrg_coverage(&scheme, drg_grouped_vec.iter().map(|(result, _)| result)), coverage_path)?;
+    }
+
+    if let Some(cmi_path) = &options.cmi_out {
+        // 与--coverage-out一样按全部病例计算
+        let mut drg_counts: BTreeMap<String, usize> = BTreeMap::new();
+        for (result, _) in drg_grouped_vec.iter() {
+            *drg_counts.entry(result.drg.to_string()).or_default() += 1;
+        }
+        write_cmi(&casemix_index(&scheme, &drg_counts, options.cmi_exclude_ungrouped), cmi_path, options)?;
+    }
+
+    if let Some(baseline_path) = &options.baseline {
+        // 只输出与基线分组结果不同的病例(基线中没有的病例也算作变化)
+        let baseline = read_baseline_codes(baseline_path)?;
+        drg_grouped_vec.retain_mut(|(_, c_wtr)| baseline_changed(&baseline, c_wtr));
+    }
+
+    match options.split_by.as_deref() {
+        Some(split_by) => {
+            // 按MDC或ADRG拆分写入不同的文件, 未入组的病例写入*_KBBZ.csv
+            let mut split_map: BTreeMap<String, Vec<DrgCaseGrouped>> = BTreeMap::new();
+            for (result, c_wtr) in drg_grouped_vec {
+                let key = if split_by == "adrg" { result.adrg } else { result.mdc };
+                split_map.entry(key).or_default().push(c_wtr);
+            }
+            for (key, drgcases) in split_map {
+                let split_path = split_output_path(out_file_path, &key);
+                write_output(drgcases, split_path.to_str().ok_or("invalid output path")?, options)?;
+            }
+        }
+        None => {
+            // 写入为CSV(或--output-format指定的格式)文件到本地
+            let drgcases = drg_grouped_vec.into_iter().map(|(_, c_wtr)| c_wtr).collect();
+            write_output(drgcases, out_file_path, options)?;
+        }
+    }
+
+    Ok(())
+}
+
+// --best-main-dis时对有备选主诊断的病例按两个主诊断分别分组, 返回选择的结果和两个结果
+fn best_main_dis(scheme: &GroupingScheme, case: &DrgCase, result: GroupResult, options: &CliOptions) -> Result<(GroupResult, Option<MainDisChoice>), Box<dyn Error>> {
+    if !options.best_main_dis || case.main_dis_alt.is_empty() {
+        return Ok((result, None));
+    }
+    let choice = scheme.choose_main_dis(case, &result, &case.main_dis_alt, options.main_dis_prefer, &options.group_options)?;
+    Ok((choice.chosen().clone(), Some(choice)))
+}
+
+// 批量分组结束后提示诊断手术填错列的病例数
+fn warn_field_swaps(n_field_swaps: usize, options: &CliOptions) {
+    if n_field_swaps == 0 || options.quiet {
+        return;
+    }
+    let action = match options.group_options.field_swap_policy {
+        FieldSwapPolicy::Warn => "grouped anyway, use --reject-field-swaps to reject them",
+        FieldSwapPolicy::Reject => "rejected as KBBZ with review flag field_swap",
+    };
+    eprintln!(
+        "warning: {} cases have operation codes in diagnosis fields or diagnosis codes in operation fields ({})",
+        n_field_swaps, action,
+    );
+}
+
+// 估计读入一个CSV文件的全部病例需要的内存与文件大小的倍数(编码字符串、集合和输出行)
+const CASE_MEMORY_FACTOR: u64 = 8;
+
+// --max-memory时按输入文件大小估计读入全部病例需要的内存, 超过限制时改为逐行的流式分组
+// 流式分组只支持CSV文件输入和CSV输出, 不支持时给出警告并仍然全部读入内存
+fn use_streaming(in_file_path: &str, options: &CliOptions) -> Result<bool, Box<dyn Error>> {
+    let max_mb = match options.max_memory_mb {
+        Some(max_mb) => max_mb,
+        None => return Ok(false),
+    };
+    if in_file_path == "-" {
+        // stdin的大小未知, 不做估计
+        return Ok(false);
+    }
+    let estimated_mb = fs::metadata(in_file_path)?.len().saturating_mul(CASE_MEMORY_FACTOR) / (1024 * 1024);
+    if estimated_mb < max_mb {
+        return Ok(false);
+    }
+    let format = options.input_format.as_deref().unwrap_or("auto");
+    let csv_input = format == "csv" || (format == "auto" && input_format_by_extension(in_file_path) == Some("csv"));
+    let unsupported = if !csv_input {
+        Some("input is not a CSV file")
+    } else if options.output_format.as_deref() == Some("parquet") {
+        Some("--output-format parquet")
+    } else if options.split_by.is_some() {
+        Some("--split-by")
+    } else if options.sample_random.is_some() {
+        Some("--sample-random")
+    } else {
+        None
+    };
+    if let Some(reason) = unsupported {
+        eprintln!("warning: estimated {} MB exceeds --max-memory {}, but streaming does not support {}; reading all cases into memory", estimated_mb, max_mb, reason);
+        return Ok(false);
+    }
+    if !options.quiet {
+        eprintln!("estimated {} MB exceeds --max-memory {}, grouping {} row by row", estimated_mb, max_mb, in_file_path);
+    }
+    Ok(true)
+}
+
+// 流式批量分组: 逐行读取、分组并写入, 输出与batch_drg_process相同, 返回病例数
+// 没有--age-unit时先读一遍age列推断年龄的单位(csv_ages_in_days), 不显示进度
+fn batch_stream_process(in_file_path: &str, out_file_path: &str, options: &CliOptions) -> Result<usize, Box<dyn Error>> {
+    let scheme = load_scheme(options)?;
+    let baseline = match &options.baseline {
+        Some(baseline_path) => Some(read_baseline_codes(baseline_path)?),
+        None => None,
+    };
+    let mut trace_writer = match &options.trace_file {
+        Some(trace_file) => Some(std::io::BufWriter::new(File::create(trace_file)?)),
+        None => None,
+    };
+    let mut coverage: BTreeMap<String, usize> = scheme.mdc_sub_adrg.values().flatten().map(|x| (x.to_string(), 0)).collect();
+    let mut drg_counts: BTreeMap<String, usize> = BTreeMap::new();
+    let has_content = options.append && fs::metadata(out_file_path).map(|m| m.len() > 0).unwrap_or(false);
+    let file = AtomicFile::create(out_file_path, options.append)?;
+    let mut wrt = csv::WriterBuilder::new().has_headers(!has_content).from_writer(file);
+    let (mut rows, mut n_field_swaps) = (0, 0);
+    let days_options = csv_ages_in_days(in_file_path, options)?.then(|| age_unit_days_options(options));
+    let rdr = csv::Reader::from_path(in_file_path)?;
+    for_each_csv_case(rdr, days_options.as_ref().unwrap_or(options), |case| {
+        let (result, trace, issues) = group_one(&scheme, &case, &options.group_options, trace_writer.is_some())?;
+        if let (Some(wrt), Some(trace)) = (trace_writer.as_mut(), trace) {
+            writeln!(wrt, "{}", trace.render(options.explain_format.as_deref().unwrap_or("json"))?)?;
+        }
+        if issues.iter().any(|x| x.is_field_swap()) {
+            n_field_swaps += 1;
+        }
+        let (result, choice) = best_main_dis(&scheme, &case, result, options)?;
+        *coverage.entry(result.adrg.to_string()).or_default() += 1;
+        *drg_counts.entry(result.drg.to_string()).or_default() += 1;
+        rows += 1;
+        let mut c_wtr = grouped_row(&scheme, case, &result, choice.as_ref(), &issues, options)?;
+        if baseline.as_ref().is_some_and(|baseline| !baseline_changed(baseline, &mut c_wtr)) {
+            return Ok(());
+        }
+        if options.drg_only {
+            wrt.serialize(DrgOnlyRow { id: c_wtr.id, code: c_wtr.code })?;
+        } else {
+            wrt.serialize(c_wtr)?;
+        }
+        Ok(())
+    })?;
+    commit_csv(wrt)?;
+    if let Some(wrt) = trace_writer.as_mut() {
+        wrt.flush()?;
+    }
+    warn_field_swaps(n_field_swaps, options);
+    if let Some(coverage_path) = &options.coverage_out {
+        write_adrg_coverage(&coverage, coverage_path)?;
+    }
+    if let Some(cmi_path) = &options.cmi_out {
+        write_cmi(&casemix_index(&scheme, &drg_counts, options.cmi_exclude_ungrouped), cmi_path, options)?;
+    }
+    println!("Grouped data is write into your path");
+    Ok(rows)
+}
+
+// 各ADRG的入组病例数, 包括mdc_sub_adrg中的全部ADRG(没有病例的ADRG为0), 以及病例进入的xQY和KBBZ
+fn adrg_coverage<'a, I: Iterator<Item = &'a GroupResult>>(scheme: &GroupingScheme, results: I) -> BTreeMap<String, usize> {
+    let mut coverage: BTreeMap<String, usize> = scheme.mdc_sub_adrg.values().flatten().map(|x| (x.to_string(), 0)).collect();
+    for result in results {
+        *coverage.entry(result.adrg.to_string()).or_default() += 1;
+    }
+    coverage
+}
+
+// 把各ADRG的入组病例数写为CSV(adrg,cases), 按ADRG编码排序
+fn write_adrg_coverage(coverage: &BTreeMap<String, usize>, file_path: &str) -> Result<(), Box<dyn Error>> {
+    let mut wrt = csv::Writer::from_writer(AtomicFile::create(file_path, false)?);
+    wrt.write_record(["adrg", "cases"])?;
+    for (adrg, count) in coverage {
+        wrt.write_record([adrg.as_str(), count.to_string().as_str()])?;
+    }
+    commit_csv(wrt)?;
+    Ok(())
+}
+
+// 一批病例的病例组合指数(CMI), 即计入的病例的平均相对权重
+#[derive(Debug, Default)]
+struct CmiReport {
+    cases: usize,                                 // 计入CMI的病例数
+    total_weight: f64,                            // 计入的病例的权重合计
+    missing_weight: usize,                        // DRG没有权重(按0计入)的病例数, 不包括KBBZ和xQY
+    drgs: BTreeMap<String, (usize, Option<f64>)>, // 各DRG计入的病例数和权重
+}
+
+impl CmiReport {
+    fn cmi(&self) -> f64 {
+        if self.cases == 0 {
+            return 0.0;
+        }
+        self.total_weight / self.cases as f64
+    }
+}
+
+// 按各DRG的病例数计算CMI, 没有权重的DRG按0计入, exclude_ungrouped时不计入KBBZ和xQY的病例
+fn casemix_index(scheme: &GroupingScheme, drg_counts: &BTreeMap<String, usize>, exclude_ungrouped: bool) -> CmiReport {
+    let mut report = CmiReport::default();
+    for (drg, &count) in drg_counts {
+        let ungrouped = drg == "KBBZ" || drg.ends_with("QY");
+        if exclude_ungrouped && ungrouped {
+            continue;
+        }
+        let rw = scheme.relative_weight(drg);
+        if rw.is_none() && !ungrouped {
+            report.missing_weight += count;
+        }
+        report.cases += count;
+        report.total_weight += rw.unwrap_or(0.0) * count as f64;
+        report.drgs.insert(drg.to_string(), (count, rw));
+    }
+    report
+}
+
+// 在stdout输出CMI, 并把各DRG的病例数、权重和权重合计写为CSV(drg,cases,rw,weighted), 没有权重的DRG的rw为空
+fn write_cmi(report: &CmiReport, file_path: &str, options: &CliOptions) -> Result<(), Box<dyn Error>> {
+    let mut wrt = csv::Writer::from_writer(AtomicFile::create(file_path, false)?);
+    wrt.write_record(["drg", "cases", "rw", "weighted"])?;
+    for (drg, (count, rw)) in &report.drgs {
+        let rw_str = rw.map(|x| x.to_string()).unwrap_or_default();
+        let weighted = (rw.unwrap_or(0.0) * *count as f64).to_string();
+        wrt.write_record([drg.as_str(), count.to_string().as_str(), rw_str.as_str(), weighted.as_str()])?;
+    }
+    commit_csv(wrt)?;
+    if report.missing_weight > 0 && !options.quiet {
+        eprintln!("warning: {} cases are in DRGs without a relative weight and count as 0", report.missing_weight);
+    }
+    println!("CMI: {:.4} ({} cases, total weight {:.4})", report.cmi(), report.cases, report.total_weight);
+    Ok(())
+}
+
+// 批量分组的汇总统计
+#[derive(Debug, Default, Serialize)]
+struct BatchStats {
+    total: usize,                          // 病例总数
+    kbbz_count: usize,                     // 未入组(KBBZ)的病例数
+    qy_count: usize,                       // 歧义组(xQY)的病例数
+    drg_counts: BTreeMap<String, usize>,   // 各DRG的病例数
+    mdc_counts: BTreeMap<String, usize>,   // 各MDC的病例数
+}
+
+// 对已经解析好的一批病例分组, 同时计算汇总统计(batch-stats)
+fn group_batch_with_stats(
+    cases: &[DrgCase],
+    scheme: &GroupingScheme,
+    options: &GroupOptions,
+) -> Result<(Vec<GroupResult>, BatchStats), Box<dyn Error>> {
+    let mut results: Vec<GroupResult> = Vec::with_capacity(cases.len());
+    let mut stats = BatchStats::default();
+    for result in scheme.group_stream(cases, options) {
+        let result = result?;
+        stats.total += 1;
+        if result.drg == "KBBZ" {
+            stats.kbbz_count += 1;
+        }
+        if result.drg.ends_with("QY") {
+            stats.qy_count += 1;
+        }
+        *stats.drg_counts.entry(result.drg.to_string()).or_default() += 1;
+        *stats.mdc_counts.entry(result.mdc.to_string()).or_default() += 1;
+        results.push(result);
+    }
+    Ok((results, stats))
+}
+
+// 批量分组并输出原始行, 原始的列和单元格内容保持不变, 末尾追加分组结果列
+fn batch_join_process(
+    in_file_path: &str,
+    out_file_path: &str,
+    options: &CliOptions,
+) -> Result<usize, Box<dyn Error>> {
+    // 读取分组方案数据
+    let scheme = load_scheme(options)?;
+
+    let mut rdr = csv::Reader::from_path(in_file_path)?;
+    let original_headers = rdr.headers()?.clone();
+    // 列名映射只用于解析病例, 输出时使用原始列名
+    let mapped_headers: csv::StringRecord = original_headers
+        .iter()
+        .map(|h| options.column_map.get(h.trim()).map(|x| x.as_str()).unwrap_or(h))
+        .collect();
+    let has_content = options.append && fs::metadata(out_file_path).map(|m| m.len() > 0).unwrap_or(false);
+    let file = AtomicFile::create(out_file_path, options.append)?;
+    let mut wrt = csv::Writer::from_writer(file);
+    if !has_content {
+        let mut headers = original_headers.clone();
+        for h in ["mdc", "adrg", "drg", "rw", "severity"] {
+            headers.push_field(h);
+        }
+        wrt.write_record(&headers)?;
+    }
+    let mut rows: usize = 0;
+    // 与--batch一样, 没有--age-unit时按整个文件推断年龄的单位, 输出的原始行不变
+    let days_options = csv_ages_in_days(in_file_path, options)?.then(|| age_unit_days_options(options));
+    // 解析失败的行与--batch一样按--skip-bad-rows/--max-errors处理, 跳过的行不输出
+    let mut bad_rows = BadRows::new(options);
+    for raw in rdr.records() {
+        let parsed = raw.map_err(|e| e.to_string()).and_then(|raw| {
+            let record: TempDrgCase = raw.deserialize(Some(&mapped_headers)).map_err(|e| e.to_string())?;
+            Ok((raw, record))
+        });
+        let (raw, record) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                bad_rows.push(e)?;
+                continue;
+            }
+        };
+        let case = case_from_record(record, days_options.as_ref().unwrap_or(options));
+        let result = scheme.group(&case, &options.group_options)?;
+        let mut row = raw.clone();
+        row.push_field(&result.mdc);
+        row.push_field(&result.adrg);
+        row.push_field(&result.drg);
+        // 分组方案中没有该DRG的权重时为空
+        row.push_field(&scheme.relative_weight(&result.drg).map(|x| x.to_string()).unwrap_or_default());
+        row.push_field(&result.severity);
+        wrt.write_record(&row)?;
+        rows += 1;
+    }
+    commit_csv(wrt)?;
+    Ok(rows)
+}
+
+// 批量分组的运行记录====================================================================================
+// 批量分组成功后写入<输出文件>.meta.json, 记录输出文件是如何产生的, 便于审计时追溯
+// 哈希都使用FNV-1a, 不同版本编译的程序得到相同的结果
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// 逐块计算文件内容的哈希, 不把整个文件读入内存
+fn fnv1a_file<P: AsRef<Path>>(hash: u64, path: P) -> Result<u64, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; 64 * 1024];
+    let mut hash = hash;
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a(hash, &buf[..n]);
+    }
+}
+
+// 分组方案的版本: 方案目录中各文件(包括存在的可选文件)的文件名和内容的哈希, 指定--scheme-zip时为压缩包内容的哈希
+// --local-rules和--drg-weights指定的文件代替方案中的同名文件, 其内容以及--only-mdcs同样计入哈希
+fn scheme_version(options: &CliOptions) -> Result<String, Box<dyn Error>> {
+    let mut hash = FNV_OFFSET;
+    match &options.scheme_zip {
+        Some(zip_path) => hash = fnv1a_file(hash, zip_path)?,
+        // 编译进程序的分组方案与同样内容的data目录得到相同的版本
+        #[cfg(feature = "bundled")]
+        None if options.bundled_scheme => {
+            for (file, contents) in BUNDLED_SCHEME.iter() {
+                hash = fnv1a(hash, file.as_bytes());
+                hash = fnv1a(hash, contents);
+            }
+        }
+        None => {
+            let dir = Path::new(DATA_DIR);
+            for file in SCHEME_FILES.iter().chain(OPTIONAL_SCHEME_FILES.iter()) {
+                let path = dir.join(file);
+                if !path.exists() {
+                    continue;
+                }
+                hash = fnv1a(hash, file.as_bytes());
+                hash = fnv1a_file(hash, &path)?;
+            }
+        }
+    }
+    for (flag, path) in [("--local-rules", &options.local_rules), ("--drg-weights", &options.drg_weights)] {
+        if let Some(path) = path {
+            hash = fnv1a(hash, flag.as_bytes());
+            hash = fnv1a_file(hash, path).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    if let Some(mdcs) = &options.only_mdcs {
+        hash = fnv1a(hash, b"--only-mdcs");
+        hash = fnv1a(hash, mdcs.join(",").as_bytes());
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+// UNIX时间戳(秒)转为UTC时间, 如2026-10-14T08:30:00Z, 与运行机器的时区无关
+fn utc_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+    // 由1970-01-01起的天数推算公历日期
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs / 3600, secs % 3600 / 60, secs % 60,
+    )
+}
+
+// 一次批量分组的运行记录
+#[derive(Debug, Serialize, Deserialize)]
+struct RunMeta {
+    run_id: String,         // 运行ID: 输入文件内容、分组方案版本、命令行选项和运行时间的哈希
+    created_at: String,     // 运行时间(UTC)
+    created_at_unix: u64,   // 运行时间(UNIX时间戳, 秒)
+    scheme_version: String, // 分组方案版本
+    options: Vec<String>,   // 命令行中的选项及其取值, 按给出的顺序
+    input: String,          // 输入文件
+    output: String,         // 输出文件(--split-by时为拆分前的文件名)
+    rows: usize,            // 分组的病例数
+}
+
+impl RunMeta {
+    // 相同的输入文件、分组方案、选项和运行时间得到相同的运行ID
+    fn new(in_file_path: &str, out_file_path: &str, rows: usize, options: &CliOptions, unix_secs: u64) -> Result<Self, Box<dyn Error>> {
+        let scheme_version = scheme_version(options)?;
+        // 从stdin读取(-)时已经无法再读取输入内容, 只计入其余部分
+        let mut hash = if in_file_path == "-" { FNV_OFFSET } else { fnv1a_file(FNV_OFFSET, in_file_path)? };
+        hash = fnv1a(hash, scheme_version.as_bytes());
+        for option in &options.flags {
+            hash = fnv1a(hash, b"\x1f");
+            hash = fnv1a(hash, option.as_bytes());
+        }
+        hash = fnv1a(hash, &unix_secs.to_le_bytes());
+        Ok(Self {
+            run_id: format!("{:016x}", hash),
+            created_at: utc_timestamp(unix_secs),
+            created_at_unix: unix_secs,
+            scheme_version,
+            options: options.flags.clone(),
+            input: in_file_path.to_string(),
+            output: out_file_path.to_string(),
+            rows,
+        })
+    }
+}
+
+// 运行记录的文件名: 输出文件名后加.meta.json, 如out.csv => out.csv.meta.json
+fn run_meta_path(out_file_path: &str) -> String {
+    format!("{}.meta.json", out_file_path)
+}
+
+fn write_run_meta(meta: &RunMeta, out_file_path: &str) -> Result<(), Box<dyn Error>> {
+    write_json(meta, &run_meta_path(out_file_path))
+}
+
+// 批量分组的进度显示, 写到stderr以免混入stdout的输出
+// 只需要一行计数、速度和剩余时间, 用\r原地刷新即可, 不引入进度条依赖, 按--progress开启
+struct Progress {
+    total: usize,
+    done: usize,
+    start: std::time::Instant,
+    last_draw: std::time::Instant,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        let now = std::time::Instant::now();
+        Self { total, done: 0, start: now, last_draw: now }
+    }
+
+    // 完成一个病例, 每200毫秒最多刷新一次
+    fn tick(&mut self) {
+        self.done += 1;
+        if self.done == self.total || self.last_draw.elapsed().as_millis() >= 200 {
+            self.last_draw = std::time::Instant::now();
+            eprint!("\r{}", self.line());
+        }
+    }
+
+    // 进度行: 已完成/总数, 百分比, 速度, 预计剩余时间
+    fn line(&self) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.done as f64 / elapsed } else { 0.0 };
+        let eta = if rate > 0.0 { (self.total - self.done) as f64 / rate } else { 0.0 };
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        format!("{}/{} ({}%) {:.0} cases/s ETA {:.0}s", self.done, self.total, percent, rate, eta)
+    }
+
+    fn finish(&self) {
+        eprintln!("\r{}", self.line());
+    }
+}
+
+// 合并多个分组输出文件(如按科室或月份分片的结果), 各文件的列必须相同(顺序可以不同), 按第一个文件的列顺序输出
+// 病例ID重复时默认报错, keep_last为true时保留最后出现的行(位置不变), 返回被覆盖的病例ID
+fn merge_outputs(inputs: &[String], output: &str, keep_last: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut headers: Option<csv::StringRecord> = None;
+    let mut rows: Vec<csv::StringRecord> = Vec::new();
+    let mut row_index: HashMap<String, usize> = HashMap::new();
+    let mut collisions: Vec<String> = Vec::new();
+    for input in inputs {
+        let mut rdr = csv::Reader::from_path(input).map_err(|e| format!("{}: {}", input, e))?;
+        let file_headers = rdr.headers()?.clone();
+        let base = headers.get_or_insert_with(|| file_headers.clone());
+        // 当前文件每一列在输出中的位置
+        let order: Vec<usize> = base.iter()
+            .map(|h| file_headers.iter().position(|x| x == h))
+            .collect::<Option<Vec<usize>>>()
+            .filter(|_| file_headers.len() == base.len())
+            .ok_or_else(|| format!(
+                "{}: columns {:?} do not match {:?}",
+                input, file_headers.iter().collect::<Vec<_>>(), base.iter().collect::<Vec<_>>(),
+            ))?;
+        let id_col = base.iter().position(|x| x == "id").ok_or("merge needs an id column")?;
+        for record in rdr.records() {
+            let record = record?;
+            let row: csv::StringRecord = order.iter().map(|&i| record.get(i).unwrap_or("")).collect();
+            let id = row.get(id_col).unwrap_or("").to_string();
+            match row_index.get(&id) {
+                Some(&i) if keep_last => {
+                    rows[i] = row;
+                    collisions.push(id);
+                }
+                Some(_) => return Err(format!("{}: duplicate case id {}", input, id).into()),
+                None => {
+                    row_index.insert(id, rows.len());
+                    rows.push(row);
+                }
+            }
+        }
+    }
+    let mut wtr = csv::Writer::from_writer(AtomicFile::create(output, false)?);
+    if let Some(h) = headers {
+        wtr.write_record(&h)?;
+    }
+    for row in rows {
+        wtr.write_record(&row)?;
+    }
+    commit_csv(wtr)?;
+    Ok(collisions)
+}
+
+// 拆分输出时各个文件的路径, 如out.csv => out_MDCA.csv
+fn split_output_path(out_file_path: &str, key: &str) -> PathBuf {
+    let path = Path::new(out_file_path);
+    let stem = path.file_stem().and_then(|x| x.to_str()).unwrap_or("out");
+    let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("csv");
+    path.with_file_name(format!("{}_{}.{}", stem, key, ext))
+}
+
+
+// 单独分组
+fn single_drg_process(drgcase: DrgCase, options: &CliOptions) -> Result<String, Box<dyn Error>> {
+    // 读取分组方案数据
+    let scheme = load_scheme(options)?;
+    if let Some(n) = options.repeat {
+        // 分组方案只读取一次, 重复分组同一个病例测量稳定状态下的耗时
+        let (mean_us, p99_us) = repeat_latency(&scheme, &drgcase, &options.group_options, n)?;
+        eprintln!("repeat {}: mean {:.2}us, p99 {:.2}us", n, mean_us, p99_us);
+    }
+    // 判断最终属于的DRG
+    let result = scheme.group(&drgcase, &options.group_options)?;
+    let (drgcase, drg) = match &options.main_dis_alt {
+        Some(alt_main_dis) => {
+            // 两个主诊断分别分组, 输出两个结果, 返回选择的DRG
+            let choice = scheme.choose_main_dis(&drgcase, &result, alt_main_dis, options.main_dis_prefer, &options.group_options)?;
+            let rw = |drg: &str| scheme.relative_weight(drg).map(|x| x.to_string()).unwrap_or_else(|| String::from("-"));
+            println!("main diagnosis {} => {} (rw {})", drgcase.dis.principal(), choice.main.drg, rw(&choice.main.drg));
+            println!("alternate main diagnosis {} => {} (rw {})", alt_main_dis, choice.alt.drg, rw(&choice.alt.drg));
+            println!("chose {}", choice.chosen_main_dis(drgcase.dis.principal()));
+            let chosen = if choice.alt_chosen { drgcase.with_main_dis(alt_main_dis) } else { drgcase };
+            (chosen, choice.chosen().drg.to_string())
+        }
+        None => (drgcase, result.drg),
+    };
+    if options.explain {
+        // 输出完整的判断路径及入组ADRG命中的表, 有--main-dis-alt时为选择的主诊断的判断路径
+        let (result, trace) = scheme.group_with_trace(&drgcase, &options.group_options)?;
+        match options.explain_format.as_deref().unwrap_or("text") {
+            "json" => println!("{}", serde_json::to_string_pretty(&trace)?),
+            format => println!("{}", trace.render(format)?),
+        }
+        return Ok(result.drg);
+    }
+    Ok(drg)
+}
+
+
+// 对同一个病例重复分组n次, 返回每次分组耗时的(平均值, p99), 单位为微秒
+fn repeat_latency(scheme: &GroupingScheme, record: &DrgCase, options: &GroupOptions, n: usize) -> Result<(f64, f64), Box<dyn Error>> {
+    if n == 0 {
+        return Err("--repeat needs a positive number".into());
+    }
+    let mut elapsed_us: Vec<f64> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let start = std::time::Instant::now();
+        scheme.group(record, options)?;
+        elapsed_us.push(start.elapsed().as_secs_f64() * 1e6);
+    }
+    elapsed_us.sort_by(|a, b| a.total_cmp(b));
+    let mean = elapsed_us.iter().sum::<f64>() / n as f64;
+    let p99 = elapsed_us[((n as f64 * 0.99).ceil() as usize).clamp(1, n) - 1];
+    Ok((mean, p99))
+}
+
+// 命令行选项
+#[derive(Debug, Clone, Default)]
+struct CliOptions {
+    split_by: Option<String>,             // 批量分组时按mdc或adrg拆分输出文件
+    column_map: HashMap<String, String>,  // 输入文件的列名映射(源列名 => 内部字段名)
+    flat_layout: FlatLayout,              // --single --flat病例字符串的字段位置和分隔符
+    keep_redundant: bool,                 // 保留其他诊断/其他手术中重复的主诊断/主手术编码
+    append: bool,                         // 批量分组时追加到已有的输出文件
+    trace_file: Option<String>,           // 批量分组时逐行写入每个病例判断路径的JSON文件
+    coverage_out: Option<String>,         // 批量分组后写入各ADRG入组病例数的CSV文件
+    join_input: bool,                     // 输出原始行加分组结果列
+    emit_counts: bool,                    // 批量输出中增加n_dis和n_opt列
+    emit_hash: bool,                      // 批量输出中增加case_hash列
+    emit_weight_band: bool,               // 批量输出中增加weight_band列
+    emit_review_flags: bool,              // 批量输出中增加review_flags列
+    emit_stages: bool,                    // 批量输出中增加stage_mdc, stage_adrg_pre_qy, stage_adrg_post_qy和stage_drg列
+    drg_only: bool,                       // 批量输出只有id和code两列, 不输出诊断手术和人口学信息
+    suggest_main_swap: bool,              // 批量输出中增加main_swap_suggestion列
+    suggest_main_opt: bool,               // 批量输出中增加main_opt_suggestion列
+    emit_reason_always: bool,             // 批量输出中增加status列, 每一行都注明分组状态
+    validate: bool,                       // 批量输出中增加case_issues列
+    threads: usize,                       // 批量分组的线程数, 0或1为单线程
+    baseline: Option<String>,             // 基线分组结果文件, 只输出分组结果变化的病例
+    count_only: bool,                     // NDJSON模式下只输出各DRG的病例数
+    fast_csv: bool,                       // 按列号直接读取CSV, 不经过serde
+    sample_random: Option<usize>,         // 批量分组时随机抽取的病例数
+    seed: Option<u64>,                    // 随机抽样的种子, 默认取当前时间
+    require_columns: bool,                // 分组之前检查输入文件的列名
+    progress: bool,                       // 批量分组时在stderr显示进度
+    quiet: bool,                          // 不显示进度和读取分组方案时的警告
+    explain: bool,                        // 单病例分组时输出判断路径
+    repeat: Option<usize>,                // 单病例重复分组的次数, 用于测量耗时(不在用法中列出)
+    explain_format: Option<String>,       // 判断路径的格式: json或text, 单病例默认text, --trace-file默认json
+    skip_bad_rows: bool,                  // 跳过解析失败的行, 默认第一个错误即失败
+    max_errors: Option<usize>,            // 跳过的行达到该数目时停止, 默认不限
+    output_format: Option<String>,        // 批量分组的输出格式: csv或parquet, 默认csv
+    input_format: Option<String>,         // 批量分组的输入格式, 默认auto(按扩展名或内容判断)
+    age_unit: Option<String>,             // 输入年龄的单位: days, years或auto, 默认为years(不足一岁以小数表示)
+    scheme_zip: Option<String>,           // 从zip压缩包读取分组方案, 默认读取data目录
+    bundled_scheme: bool,                 // 读取编译进程序的分组方案(bundled特性), 不读取data目录
+    scheme_cache: Option<String>,         // 解析后的分组方案缓存文件, 源文件没有变化时直接读取
+    local_rules: Option<String>,          // 本地规则文件, 默认为分组方案目录中的local_rules.json
+    drg_weights: Option<String>,          // DRG相对权重文件, 默认为分组方案目录中的drg_weights.json
+    cmi_out: Option<String>,              // 批量分组后输出CMI, 并把各DRG的病例数和权重写入的CSV文件
+    cmi_exclude_ungrouped: bool,          // 计算CMI时不计入KBBZ和xQY的病例
+    flags: Vec<String>,                   // 命令行中的选项及其取值(不包括位置参数), 写入运行记录
+    main_dis_alt: Option<String>,         // 单病例分组的备选主诊断, 两个主诊断分别分组后按main_dis_prefer选择
+    best_main_dis: bool,                  // 批量分组时按输入的main_dis_alt列选择主诊断, 并增加两个主诊断的分组结果列
+    main_dis_prefer: MainDisPreference,   // 两个候选主诊断的选择方式, 默认选择相对权重较高的DRG
+    age_decimals: Option<usize>,          // 批量输出中一岁以上的年龄保留的小数位数, 默认保留全部精度
+    max_memory_mb: Option<u64>,           // 批量分组时估计的内存(MB)超过该值就改为逐行读取、分组和写入
+    only_mdcs: Option<Vec<String>>,       // 只读取这些MDC的分组方案(开发调试用), 进入其他MDC的病例为KBBZ
+    group_options: GroupOptions,          // 分组选项
+}
+
+// 读取分组方案数据, 指定--scheme-zip时从压缩包读取, 指定--only-mdcs时只读取data目录中这些MDC的分组方案
+fn load_scheme(options: &CliOptions) -> Result<GroupingScheme, Box<dyn Error>> {
+    if options.only_mdcs.is_some() && (options.scheme_zip.is_some() || options.scheme_cache.is_some()) {
+        return Err("--only-mdcs cannot be combined with --scheme-zip or --scheme-cache".into());
+    }
+    if options.bundled_scheme && (options.scheme_zip.is_some() || options.scheme_cache.is_some()) {
+        return Err("--bundled-scheme cannot be combined with --scheme-zip or --scheme-cache".into());
+    }
+    let mut scheme = match &options.scheme_zip {
+        #[cfg(feature = "zip")]
+        Some(zip_path) => GroupingScheme::load_zip(zip_path)?,
+        #[cfg(not(feature = "zip"))]
+        Some(_) => return Err("--scheme-zip needs the zip feature".into()),
+        None if options.bundled_scheme => load_bundled_scheme(options.only_mdcs.as_deref())?,
+        None => match (&options.only_mdcs, &options.scheme_cache) {
+            (Some(mdcs), _) => {
+                let mdcs: Vec<&str> = mdcs.iter().map(|x| x.as_str()).collect();
+                GroupingScheme::load_subset(&DirSource::new(DATA_DIR), &mdcs)?
+            }
+            (None, Some(cache_path)) => GroupingScheme::load_or_cache(DATA_DIR, cache_path)?,
+            (None, None) => GroupingScheme::load(&DirSource::new(DATA_DIR))?,
+        },
+    };
+    if !options.quiet {
+        for warning in &scheme.warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    if let Some(rules_path) = &options.local_rules {
+        // 指定的本地规则文件代替分组方案目录中的local_rules.json
+        scheme.local_rules = read_json_file(rules_path).map_err(|e| format!("{}: {}", rules_path, e))?;
+    }
+    if let Some(weights_path) = &options.drg_weights {
+        // 指定的权重文件代替分组方案目录中的drg_weights.json
+        scheme.drg_weights = read_json_file(weights_path).map_err(|e| format!("{}: {}", weights_path, e))?;
+    }
+    if options.group_options.normalize_codes {
+        scheme.normalize_codes();
+    }
+    Ok(scheme)
+}
+
+// 本次运行中auto模式无法区分天数和年数的年龄个数, 运行结束时汇总提示一次
+static AMBIGUOUS_AGES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// 读取编译进程序的分组方案(--bundled-scheme), 可以与--only-mdcs同时使用
+#[cfg(feature = "bundled")]
+fn load_bundled_scheme(only_mdcs: Option<&[String]>) -> Result<GroupingScheme, Box<dyn Error>> {
+    let source = EmbeddedSource::new(&BUNDLED_SCHEME);
+    match only_mdcs {
+        Some(mdcs) => GroupingScheme::load_subset(&source, &mdcs.iter().map(|x| x.as_str()).collect::<Vec<&str>>()),
+        None => GroupingScheme::load(&source),
+    }
+}
+
+#[cfg(not(feature = "bundled"))]
+fn load_bundled_scheme(_only_mdcs: Option<&[String]>) -> Result<GroupingScheme, Box<dyn Error>> {
+    Err("--bundled-scheme needs the bundled feature".into())
+}
+
+// 按--age-unit把输入年龄换算为分组使用的年(不足一岁以小数表示)
+fn normalize_age(age: f64, age_unit: Option<&str>) -> f64 {
+    match age_unit {
+        Some("days") => age / 365.0,
+        Some("auto") => {
+            if age > 150.0 {
+                // 超过合理的年龄, 只可能是天数
+                return age / 365.0;
+            }
+            if (1.0..=28.0).contains(&age) && age.fract() == 0.0 {
+                // 可能是新生儿的天数也可能是年龄, 按年处理并计数
+                AMBIGUOUS_AGES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            age
+        }
+        _ => age,
+    }
+}
+
+// 输入的年龄按--age-unit换算为年, 缺少或空白的年龄在换算以后才取成人年龄(不会被当作天数变成新生儿)
+fn resolve_age(age: Option<f64>, age_unit: Option<&str>) -> f64 {
+    age.map_or(DEFAULT_ADULT_AGE, |x| normalize_age(x, age_unit))
+}
+
+// 推断年龄列为天数时使用的选项: 与指定--age-unit days相同, 构造病例时换算年龄
+fn age_unit_days_options(options: &CliOptions) -> CliOptions {
+    CliOptions { age_unit: Some(String::from("days")), ..options.clone() }
+}
+
+// 取出并清零本次运行中无法区分天数和年数的年龄个数
+fn take_ambiguous_ages() -> usize {
+    AMBIGUOUS_AGES.swap(0, std::sync::atomic::Ordering::Relaxed)
+}
+
+// 读取列名映射表, 参数可以是JSON字符串也可以是JSON文件的路径
+fn read_column_map(arg: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    if arg.trim_start().starts_with('{') {
+        Ok(serde_json::from_str(arg)?)
+    } else {
+        read_json_file(arg)
+    }
+}
+
+// 从命令行参数中取出选项, 返回剩下的位置参数和选项
+fn parse_cli_options(args: &[String]) -> Result<(Vec<String>, CliOptions), Box<dyn Error>> {
+    let mut positional: Vec<String> = Vec::new();
+    let mut options = CliOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--split-by" => {
+                // 目前支持按mdc或adrg拆分
+                let split_by = iter.next().ok_or("--split-by needs a value: mdc or adrg")?;
+                if split_by != "mdc" && split_by != "adrg" {
+                    return Err(format!("unsupported --split-by value: {}", split_by).into());
+                }
+                options.split_by = Some(split_by.to_string());
+            }
+            "--column-map" => {
+                let column_map = iter.next().ok_or("--column-map needs a JSON object or a JSON file path")?;
+                options.column_map = read_column_map(column_map)?;
+            }
+            "--flat-layout" => {
+                let layout = iter.next().ok_or("--flat-layout needs a JSON object or a JSON file path")?;
+                options.flat_layout = if layout.trim_start().starts_with('{') {
+                    serde_json::from_str(layout)?
+                } else {
+                    read_json_file(layout)?
+                };
+            }
+            "--strict" => options.group_options.strict = true,
+            "--warn-cross-mdc" => options.group_options.warn_cross_mdc = true,
+            "--qy-all-ops" => options.group_options.qy_all_ops = true,
+            "--no-qy" => options.group_options.no_qy = true,
+            "--normalize-codes" => options.group_options.normalize_codes = true,
+            "--null-as-kbbz" => options.group_options.null_main_dis_error = false,
+            "--null-as-error" => options.group_options.null_main_dis_error = true,
+            "--ccmcc-scope" => {
+                let scope = iter.next().ok_or("--ccmcc-scope needs a value: other_dis or all_dis")?;
+                options.group_options.ccmcc_scope = match scope.as_str() {
+                    "other_dis" => CcmccScope::OtherDis,
+                    "all_dis" => CcmccScope::AllDis,
+                    _ => return Err(format!("unsupported --ccmcc-scope value: {}", scope).into()),
+                };
+            }
+            "--adrg-priority" => {
+                if options.group_options.adrg_priority.is_none() {
+                    options.group_options.adrg_priority = Some(default_adrg_priority());
+                }
+            }
+            "--adrg-priority-order" => {
+                let order = iter.next().ok_or("--adrg-priority-order needs a comma separated list, e.g. surgery,operation,internal")?;
+                let order: Vec<String> = order.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+                if let Some(x) = order.iter().find(|x| !["surgery", "operation", "internal"].contains(&x.as_str())) {
+                    return Err(format!("unsupported ADRG type in --adrg-priority-order: {}", x).into());
+                }
+                options.group_options.adrg_priority = Some(order);
+            }
+            "--pre-mdc-order" => {
+                let order = iter.next().ok_or("--pre-mdc-order needs a comma separated list, e.g. MDCA,MDCN,MDCP,MDCY,MDCZ")?;
+                let order: Vec<String> = order.split(',').map(|x| x.trim().to_uppercase()).filter(|x| !x.is_empty()).collect();
+                options.group_options.pre_mdc_order = Some(check_pre_mdc_order(order).map_err(|e| format!("--pre-mdc-order: {}", e))?);
+            }
+            "--operations-as-diagnoses-ok" => options.group_options.field_swap_policy = FieldSwapPolicy::Warn,
+            "--reject-field-swaps" => options.group_options.field_swap_policy = FieldSwapPolicy::Reject,
+            "--warn-mdcz-override" => options.group_options.warn_mdcz_override = true,
+            "--strict-age-for-mdcp" => options.group_options.strict_age_for_mdcp = true,
+            "--limit-mdc-loop" => {
+                let limit = iter.next().ok_or("--limit-mdc-loop needs a number")?;
+                options.group_options.max_candidates = Some(limit.parse::<usize>()?);
+            }
+            "--distinct-simultaneous-opt" => options.group_options.distinct_simultaneous_opt = true,
+            "--keep-redundant" => options.keep_redundant = true,
+            "--append" => options.append = true,
+            "--join-input" => options.join_input = true,
+            "--emit-counts" => options.emit_counts = true,
+            "--emit-hash" => options.emit_hash = true,
+            "--emit-weight-band" => options.emit_weight_band = true,
+            "--emit-review-flags" => options.emit_review_flags = true,
+            "--emit-stages" => options.emit_stages = true,
+            "--drg-only" => options.drg_only = true,
+            "--suggest-main-swap" => options.suggest_main_swap = true,
+            "--suggest-main-opt" => options.suggest_main_opt = true,
+            "--emit-reason-always" => options.emit_reason_always = true,
+            "--validate" => options.validate = true,
+            "--count-only" => options.count_only = true,
+            "--fast-csv" => options.fast_csv = true,
+            "--explain-format" => {
+                let format = iter.next().ok_or("--explain-format needs a value: json or text")?;
+                if format != "json" && format != "text" {
+                    return Err(format!("unsupported --explain-format value: {}", format).into());
+                }
+                options.explain_format = Some(format.to_string());
+            }
+            "--coverage-out" => {
+                let coverage_out = iter.next().ok_or("--coverage-out needs an output csv file")?;
+                options.coverage_out = Some(coverage_out.to_string());
+            }
+            "--baseline" => {
+                let baseline = iter.next().ok_or("--baseline needs a grouped csv file")?;
+                options.baseline = Some(baseline.to_string());
+            }
+            // 调试用的隐藏选项: 单病例分组重复N次并在stderr输出平均和p99耗时
+            "--repeat" => {
+                let n = iter.next().ok_or("--repeat needs a number")?;
+                options.repeat = Some(n.parse().map_err(|_| format!("invalid --repeat value: {}", n))?);
+            }
+            "--threads" => {
+                let threads = iter.next().ok_or("--threads needs a number")?;
+                options.threads = threads.parse().map_err(|_| format!("invalid --threads value: {}", threads))?;
+            }
+            "--sample-random" => {
+                let n = iter.next().ok_or("--sample-random needs a number")?;
+                options.sample_random = Some(n.parse().map_err(|_| format!("invalid --sample-random value: {}", n))?);
+            }
+            "--main-dis-alt" => {
+                let main_dis_alt = iter.next().ok_or("--main-dis-alt needs a diagnosis code")?;
+                options.main_dis_alt = Some(main_dis_alt.trim().to_string());
+            }
+            "--best-main-dis" => options.best_main_dis = true,
+            "--main-dis-prefer" => {
+                options.main_dis_prefer = match iter.next().map(|x| x.as_str()) {
+                    Some("rw") => MainDisPreference::Rw,
+                    Some("grouped") => MainDisPreference::Grouped,
+                    Some(other) => return Err(format!("unsupported --main-dis-prefer value: {}", other).into()),
+                    None => return Err("--main-dis-prefer needs a value: rw or grouped".into()),
+                };
+            }
+            "--age-decimals" => {
+                let n = iter.next().ok_or("--age-decimals needs a number")?;
+                options.age_decimals = Some(n.parse().map_err(|_| format!("invalid --age-decimals value: {}", n))?);
+            }
+            "--max-memory" => {
+                let mb = iter.next().ok_or("--max-memory needs a size in MB")?;
+                options.max_memory_mb = Some(mb.parse().map_err(|_| format!("invalid --max-memory value: {}", mb))?);
+            }
+            "--only-mdcs" => {
+                let mdcs = iter.next().ok_or("--only-mdcs needs a comma separated list, e.g. MDCB,MDCC")?;
+                let mdcs: Vec<String> = mdcs.split(',').map(|x| x.trim().to_uppercase()).filter(|x| !x.is_empty()).collect();
+                if mdcs.is_empty() {
+                    return Err("--only-mdcs needs at least one MDC".into());
+                }
+                options.only_mdcs = Some(mdcs);
+            }
+            "--seed" => {
+                let seed = iter.next().ok_or("--seed needs a number")?;
+                options.seed = Some(seed.parse().map_err(|_| format!("invalid --seed value: {}", seed))?);
+            }
+            "--require-columns" => options.require_columns = true,
+            "--progress" => options.progress = true,
+            "--quiet" => options.quiet = true,
+            "--explain" => options.explain = true,
+            "--trace-file" => {
+                let trace_file = iter.next().ok_or("--trace-file needs a file path")?;
+                options.trace_file = Some(trace_file.to_string());
+            }
+            "--local-rules" => {
+                let local_rules = iter.next().ok_or("--local-rules needs a json file path")?;
+                options.local_rules = Some(local_rules.to_string());
+            }
+            "--drg-weights" => {
+                let drg_weights = iter.next().ok_or("--drg-weights needs a json file path")?;
+                options.drg_weights = Some(drg_weights.to_string());
+            }
+            "--cmi" => {
+                let cmi_out = iter.next().ok_or("--cmi needs an output csv file")?;
+                options.cmi_out = Some(cmi_out.to_string());
+            }
+            "--cmi-exclude-ungrouped" => options.cmi_exclude_ungrouped = true,
+            "--scheme-zip" => {
+                let scheme_zip = iter.next().ok_or("--scheme-zip needs a zip file path")?;
+                options.scheme_zip = Some(scheme_zip.to_string());
+            }
+            "--bundled-scheme" => options.bundled_scheme = true,
+            "--scheme-cache" => {
+                let scheme_cache = iter.next().ok_or("--scheme-cache needs a cache file path")?;
+                options.scheme_cache = Some(scheme_cache.to_string());
+            }
+            "--skip-bad-rows" => options.skip_bad_rows = true,
+            "--max-errors" => {
+                let n = iter.next().ok_or("--max-errors needs a number")?;
+                options.max_errors = Some(n.parse().map_err(|_| format!("invalid --max-errors value: {}", n))?);
+            }
+            "--output-format" => {
+                let format = iter.next().ok_or("--output-format needs a value: csv or parquet")?;
+                if format != "csv" && format != "parquet" {
+                    return Err(format!("unsupported --output-format value: {}", format).into());
+                }
+                options.output_format = Some(format.to_string());
+            }
+            "--input-format" => {
+                let format = iter.next().ok_or("--input-format needs a value: auto, csv, tsv, ndjson, json or xlsx")?;
+                if !INPUT_FORMATS.contains(&format.as_str()) {
+                    return Err(format!("unsupported --input-format value: {}", format).into());
+                }
+                options.input_format = Some(format.to_string());
+            }
+            "--age-unit" => {
+                let age_unit = iter.next().ok_or("--age-unit needs a value: days, years or auto")?;
+                if !["days", "years", "auto"].contains(&age_unit.as_str()) {
+                    return Err(format!("unsupported --age-unit value: {}", age_unit).into());
+                }
+                options.age_unit = Some(age_unit.to_string());
+            }
+            _ => positional.push(arg.to_string()),
+        }
+    }
+    // 除去位置参数后剩下的就是选项及其取值
+    let mut remaining = positional.iter().peekable();
+    for arg in args {
+        if remaining.peek() == Some(&arg) {
+            remaining.next();
+        } else {
+            options.flags.push(arg.to_string());
+        }
+    }
+    Ok((positional, options))
+}
+
+// 检查--single和--batch的位置参数个数(无法识别的选项也留在位置参数中), 个数不对时给出准确的用法, 而不是按固定位置取值时越界
+fn check_positional_args(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mode = args.get(1).ok_or("missing mode: use --single, --batch, group or another mode in the usage notes")?;
+    let (expected, usage) = match mode.as_str() {
+        "--single" if matches!(args.get(2).map(|x| x.as_str()), Some("--flat") | Some("--json")) => {
+            (2, "--single --json <json> | --single --flat <case>")
+        }
+        "--single" => (8, "--single <id> <main_dis> <main_opt> <other_dis> <other_opt> <sex> <age> <weight>"),
+        "--batch" => (2, "--batch <in_file.csv> <out_file.csv>"),
+        _ => return Ok(()),
+    };
+    let given = &args[2..];
+    if given.len() == expected {
+        return Ok(());
+    }
+    let mut message = format!("{} expects {} arguments, got {}", mode, expected, given.len());
+    let unknown: Vec<&str> = given.iter()
+        .map(|x| x.as_str())
+        .filter(|x| x.starts_with("--") && *x != "--flat" && *x != "--json")
+        .collect();
+    if !unknown.is_empty() {
+        message.push_str(&format!(" (unknown options: {})", unknown.join(", ")));
+    }
+    Err(format!("{}; usage: {} [options]", message, usage).into())
+}
+
+// 解析scheme-stats模式的参数, 返回输出格式table或json, 默认table
+fn scheme_stats_format(args: &[String]) -> Result<&'static str, Box<dyn Error>> {
+    let mut format = "table";
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match (arg.as_str(), iter.next().map(|x| x.as_str())) {
+            ("--format", Some("table")) => format = "table",
+            ("--format", Some("json")) => format = "json",
+            _ => return Err(format!("unknown scheme-stats argument: {}", arg).into()),
+        }
+    }
+    Ok(format)
+}
+
+// 解析group模式的命名参数, 只有--main-dis为必填, 其余缺省时使用默认值
+fn parse_group_case(args: &[String], options: &CliOptions) -> Result<DrgCase, Box<dyn Error>> {
+    let mut id = String::from("0");
+    let mut main_dis: Option<String> = None;
+    let mut main_opt = String::new();
+    let mut other_dis: Vec<String> = Vec::new();
+    let mut other_opt: Vec<String> = Vec::new();
+    let mut sex = UNKNOWN_SEX;
+    let mut age = DEFAULT_ADULT_AGE;
+    let mut weight = DEFAULT_WEIGHT;
+    let split_codes = |x: &str| x.split("|").filter(|c| c != &"").map(|c| c.to_string()).collect::<Vec<String>>();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = iter.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--id" => id = value.to_string(),
+            "--main-dis" => main_dis = Some(value.to_string()),
+            "--main-opt" => main_opt = value.to_string(),
+            "--other-dis" => other_dis = split_codes(value),
+            "--other-opt" => other_opt = split_codes(value),
+            "--sex" => sex = value.parse::<i32>()?,
+            "--age" => age = normalize_age(value.parse::<f64>()?, options.age_unit.as_deref()),
+            "--weight" => weight = value.parse::<i32>()?,
+            _ => return Err(format!("unknown group argument: {}", arg).into()),
+        }
+    }
+    let main_dis = main_dis.ok_or("group needs --main-dis")?;
+    Ok(DrgCase::with_redundant(id, main_dis, main_opt, other_dis, other_opt, sex, age, weight, options.keep_redundant))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // 收集命令行参数
+    let (args, options) = parse_cli_options(&env::args().collect::<Vec<String>>())?;
+    check_positional_args(&args)?;
+    match args[1].as_str() {
+        "--single" if args.get(2).map(|x| x.as_str()) == Some("--flat") => {
+            // 单病例模式, 病例以一个按--flat-layout分隔的字符串给出
+            let case = parse_flat_case(args.get(3).ok_or("--flat needs a delimited case string")?, &options.flat_layout, &options)?;
+            let drg_code = single_drg_process(case, &options)?;
+            println!("result drg code is {}", drg_code);
+        }
+        "--single" if args.get(2).map(|x| x.as_str()) == Some("--json") => {
+            // 单病例模式, 病例以一个JSON字符串给出
+            let case = parse_json_case(args.get(3).ok_or("--json needs a JSON object")?, &options)?;
+            let drg_code = single_drg_process(case, &options)?;
+            println!("result drg code is {}", drg_code);
+        }
+        "--single" => {
             // 单病例模式
             let id = args[2].to_string();
             let main_dis = args[3].to_string();
@@ -1384,10 +5957,10 @@ fn main() -> Result<(), Box<dyn Error>> {
             let other_dis = args[5].split("|").map(|x| x.to_string()).collect::<Vec<String>>();
             let other_opt = args[6].split("|").map(|x| x.to_string()).collect::<Vec<String>>();
             let sex = args[7].parse::<i32>()?;
-            let age = args[8].parse::<f64>()?;
+            let age = normalize_age(args[8].parse::<f64>()?, options.age_unit.as_deref());
             let weight = args[9].parse::<i32>()?;
             // 初始化病例结构
-            let case = DrgCase::new(
+            let case = DrgCase::with_redundant(
                 id, 
                 main_dis, 
                 main_opt, 
@@ -1396,552 +5969,3665 @@ fn main() -> Result<(), Box<dyn Error>> {
                 sex, 
                 age, 
                 weight,
+                options.keep_redundant,
             );
-            let drg_code = single_drg_process(case)?;
+            let drg_code = single_drg_process(case, &options)?;
+            println!("result drg code is {}", drg_code);
+        }
+        "scheme-stats" => {
+            // 分组方案的统计: scheme-stats [--format table|json]
+            let format = scheme_stats_format(&args[2..])?;
+            let stats = scheme_stats(&load_scheme(&options)?);
+            if format == "json" {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                println!("{}", stats.to_table());
+            }
+        }
+        "validate-scheme" => {
+            // 只检查分组方案的一致性: validate-scheme [--dir <dir>] [--scheme-zip <zip>], 列出全部问题, 有问题时以非零状态退出
+            let mut data_dir = DATA_DIR;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--dir" => data_dir = iter.next().ok_or("--dir needs a scheme directory")?,
+                    // --scheme-zip已经由parse_cli_options读取
+                    _ => return Err(format!("unknown validate-scheme argument: {}", arg).into()),
+                }
+            }
+            let (source, scheme_name): (Box<dyn SchemeSource>, &str) = match &options.scheme_zip {
+                #[cfg(feature = "zip")]
+                Some(zip_path) => (Box::new(ZipSource::open(zip_path)?), zip_path.as_str()),
+                #[cfg(not(feature = "zip"))]
+                Some(_) => return Err("--scheme-zip needs the zip feature".into()),
+                None => (Box::new(DirSource::new(data_dir)), data_dir),
+            };
+            let problems = validate_scheme(source.as_ref());
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            // 警告不算作问题, 不影响检查结果
+            for warning in scheme_warnings(source.as_ref()) {
+                println!("warning: {}", warning);
+            }
+            if !problems.is_empty() {
+                return Err(format!("{} problems found in {}", problems.len(), scheme_name).into());
+            }
+            println!("{}: no problems found", scheme_name);
+        }
+        "benchmark-scheme-load" => {
+            // 逐个文件统计分组方案的读取耗时: benchmark-scheme-load [--dir <dir>] [--scheme-zip <zip>]
+            let mut data_dir = DATA_DIR;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--dir" => data_dir = iter.next().ok_or("--dir needs a scheme directory")?,
+                    // --scheme-zip已经由parse_cli_options读取
+                    _ => return Err(format!("unknown benchmark-scheme-load argument: {}", arg).into()),
+                }
+            }
+            let times = match &options.scheme_zip {
+                #[cfg(feature = "zip")]
+                Some(zip_path) => benchmark_scheme_load(&ZipSource::open(zip_path)?)?,
+                #[cfg(not(feature = "zip"))]
+                Some(_) => return Err("--scheme-zip needs the zip feature".into()),
+                None => benchmark_scheme_load(&DirSource::new(data_dir))?,
+            };
+            println!("{:<30}{:>12}{:>12}", "file", "bytes", "parse_us");
+            for time in &times {
+                println!("{:<30}{:>12}{:>12}", time.file, time.bytes, time.parse_us);
+            }
+            let total: u64 = times.iter().map(|x| x.parse_us).sum();
+            println!("{:<30}{:>12}{:>12}", "total", times.iter().map(|x| x.bytes).sum::<u64>(), total);
+        }
+        "list-drgs" => {
+            // 输出分组方案能产生的全部DRG编码
+            let scheme = load_scheme(&options)?;
+            for code in scheme.all_drg_codes() {
+                println!("{}", code);
+            }
+        }
+        "list-diagnoses" => {
+            // 输出MDC下的全部主诊断: list-diagnoses --mdc <MDC>
+            let mdc = match (args.get(2).map(|x| x.as_str()), args.get(3)) {
+                (Some("--mdc"), Some(mdc)) if args.len() == 4 => mdc.trim().to_uppercase(),
+                _ => return Err("usage: list-diagnoses --mdc <MDC>".into()),
+            };
+            let scheme = load_scheme(&options)?;
+            if !scheme.mdc_sub_adrg.contains_key(&mdc) {
+                return Err(format!("unknown MDC: {}", mdc).into());
+            }
+            for code in scheme.diagnoses_in_mdc(&mdc) {
+                println!("{}", code);
+            }
+        }
+        "batch-stats" => {
+            // 对病例文件分组, 只输出汇总统计: batch-stats <in_file>
+            let in_file_path = match &args[2..] {
+                [in_file_path] => in_file_path.as_str(),
+                _ => return Err("usage: batch-stats <in_file>".into()),
+            };
+            let scheme = load_scheme(&options)?;
+            let cases = read_input_cases(in_file_path, &options)?;
+            let (_, stats) = group_batch_with_stats(&cases, &scheme, &options.group_options)?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        "diff-scheme" => {
+            // 对比两个版本的分组方案: diff-scheme --old <dir> --new <dir>
+            let mut old_dir: Option<&str> = None;
+            let mut new_dir: Option<&str> = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--old" => old_dir = iter.next().map(|x| x.as_str()),
+                    "--new" => new_dir = iter.next().map(|x| x.as_str()),
+                    _ => return Err(format!("unknown diff-scheme argument: {}", arg).into()),
+                }
+            }
+            let old = GroupingScheme::load(&DirSource::new(old_dir.ok_or("diff-scheme needs --old <dir>")?))?;
+            let new = GroupingScheme::load(&DirSource::new(new_dir.ok_or("diff-scheme needs --new <dir>")?))?;
+            println!("{}", serde_json::to_string_pretty(&diff_schemes(&old, &new))?);
+        }
+        "merge" => {
+            // 合并分组输出文件: merge --inputs a.csv b.csv --output all.csv [--on-conflict fail|keep-last]
+            let mut inputs: Vec<String> = Vec::new();
+            let mut output: Option<&str> = None;
+            let mut keep_last = false;
+            let mut iter = args[2..].iter().peekable();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--inputs" => {
+                        while let Some(x) = iter.next_if(|x| !x.starts_with("--")) {
+                            inputs.push(x.to_string());
+                        }
+                    }
+                    "--output" => output = iter.next().map(|x| x.as_str()),
+                    "--on-conflict" => match iter.next().map(|x| x.as_str()) {
+                        Some("fail") => keep_last = false,
+                        Some("keep-last") => keep_last = true,
+                        other => return Err(format!("unsupported --on-conflict value: {:?}", other).into()),
+                    },
+                    _ => return Err(format!("unknown merge argument: {}", arg).into()),
+                }
+            }
+            if inputs.is_empty() {
+                return Err("merge needs --inputs <file>...".into());
+            }
+            let collisions = merge_outputs(&inputs, output.ok_or("merge needs --output <file>")?, keep_last)?;
+            if !collisions.is_empty() {
+                eprintln!("warning: {} duplicate case ids kept from the last file: {}", collisions.len(), collisions.join(", "));
+            }
+        }
+        "--ndjson" => {
+            // NDJSON流式分组: --ndjson <in_file.ndjson|-> [--count-only], 结果写到stdout
+            let in_file_path = args.get(2).ok_or("--ndjson needs an input file or - for stdin")?;
+            let scheme = load_scheme(&options)?;
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            if in_file_path == "-" {
+                stream_ndjson(std::io::stdin().lock(), &mut out, &scheme, &options)?;
+            } else {
+                stream_ndjson(BufReader::new(File::open(in_file_path)?), &mut out, &scheme, &options)?;
+            }
+        }
+        "repl" => {
+            // 常驻的交互模式: 从stdin逐行读取JSON病例, 在stdout逐行输出JSON结果, 直到EOF
+            let scheme = load_scheme(&options)?;
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            run_repl(std::io::stdin().lock(), &mut out, &scheme, &options)?;
+        }
+        "classify-operation" => {
+            // 查询手术操作是否为有效手术: classify-operation --opt <code> [--opt <code>]
+            let mut codes: Vec<&str> = Vec::new();
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--opt" => codes.push(iter.next().ok_or("--opt needs an operation code")?),
+                    _ => return Err(format!("unknown classify-operation argument: {}", arg).into()),
+                }
+            }
+            if codes.is_empty() {
+                return Err("classify-operation needs --opt <code>".into());
+            }
+            let scheme = load_scheme(&options)?;
+            for code in codes {
+                if scheme.is_valid_surgery(code) {
+                    println!("{}: valid surgery (counts for QY adjustment)", code);
+                } else {
+                    println!("{}: not a valid surgery", code);
+                }
+            }
+        }
+        "group" => {
+            // 只提供编码的快速分组, 性别年龄体重可省略
+            let case = parse_group_case(&args[2..], &options)?;
+            let drg_code = single_drg_process(case, &options)?;
             println!("result drg code is {}", drg_code);
         }
-        "--batch" => {
-            // 批量分组
-            let in_file_path = args[2].as_str();
-            let out_file_path = args[3].as_str();
-            // 读取需要分组的病案数据
-            let cases_vec = read_csv(in_file_path).unwrap();
-            // 批量分组
-            batch_drg_process(cases_vec, out_file_path).unwrap();
-            println!("Batch group is done, save at {}", out_file_path);
+        "--batch" => {
+            // 批量分组(--split-by mdc|adrg 按MDC或ADRG拆分输出文件)
+            let in_file_path = args[2].as_str();
+            let out_file_path = args[3].as_str();
+            if options.require_columns {
+                // 先检查表头, 缺少必须的列时不开始分组
+                require_columns(in_file_path, &options.column_map)?;
+            }
+            let rows: usize;
+            if options.join_input {
+                if options.split_by.is_some() || options.trace_file.is_some() || options.sample_random.is_some() || options.coverage_out.is_some() || options.cmi_out.is_some() || options.drg_only {
+                    return Err("--join-input cannot be combined with --split-by, --trace-file, --sample-random, --coverage-out, --cmi or --drg-only".into());
+                }
+                rows = batch_join_process(in_file_path, out_file_path, &options)?;
+            } else if use_streaming(in_file_path, &options)? {
+                rows = batch_stream_process(in_file_path, out_file_path, &options)?;
+            } else {
+                // 读取需要分组的病案数据
+                let mut cases_vec = read_input_cases(in_file_path, &options)?;
+                if let Some(n) = options.sample_random {
+                    // 没有指定种子时取当前时间, 并输出种子以便复现
+                    let seed = match options.seed {
+                        Some(seed) => seed,
+                        None => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos() as u64,
+                    };
+                    eprintln!("sampling {} of {} cases with --seed {}", n.min(cases_vec.len()), cases_vec.len(), seed);
+                    cases_vec = sample_cases(cases_vec, n, seed);
+                }
+                // 批量分组
+                rows = cases_vec.len();
+                batch_drg_process(cases_vec, out_file_path, &options)?;
+            }
+            // 分组成功后写入运行记录
+            let unix_secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            let meta = RunMeta::new(in_file_path, out_file_path, rows, &options, unix_secs)?;
+            write_run_meta(&meta, out_file_path)?;
+            println!("Batch group is done, save at {} (run {})", out_file_path, meta.run_id);
+        }
+        _ => { println!("wrong input please check your input!!!") }
+    }
+    let ambiguous = take_ambiguous_ages();
+    if ambiguous > 0 {
+        eprintln!("warning: {} case(s) with --age-unit auto had an age between 1 and 28 that is ambiguous between days and years, treated as years", ambiguous);
+    }
+    Ok(())
+}
+
+// 功能测试=======================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "parquet")]
+    use super::parquet_writer::tests::read_parquet;
+    #[cfg(feature = "zip")]
+    use super::zip_reader::tests::stored_zip;
+
+    // #[test]
+    // fn read_adrg_dis_opt() {
+    //     // 测试读取ADRG诊断手术表是否正常
+    //     let res: HashMap<String, HashSet<String>> =
+    //         read_json_file("data\\adrg_dis_opt_sheet.json").unwrap();
+    //     // res.expect("Reading File wrong???");
+    //     // println!("{:?}", &res["K85.001"]);
+    //     assert_eq!(true, res["AA1"].contains("33.6x00"));
+    // }
+
+    // #[test]
+    // fn read_adrg_to_drg() {
+    //     // 测试读取ADRG下的DRG分组列表
+    //     let res: HashMap<String, HashSet<String>> = read_json_file("data\\adrg_drg_name_sheet.json").unwrap();
+    //     assert_eq!(true, res["AA2"].contains("AA29"));
+    // }
+
+    // #[test]
+    // fn read_all_icd9_and_10() {
+    //     // 测试读取所有诊断表或手术表是否正常
+    //     let res: HashSet<String> = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
+    //     println!("length of the file is {}", res.len());
+    //     let shit: Vec<String> = res.clone().iter().map(|x| x.to_string()).collect();
+    //     println!("the second element is {}", shit[1]);
+    //     // let test_verb = &shit[2];
+    //     let test_verb = String::from("A84.000x001");
+    //     assert_eq!(true, res.contains(&test_verb));
+    // }
+
+    // #[test]
+    // fn read_mdc_main_dis() {
+    //     // 测试读取MDC主诊断表
+    //     let res = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
+    //     let test_verb = "A00.100x001";
+    //     println!("target mdc is {}", res[test_verb][0]);
+    //     assert_eq!(true, res[test_verb][0] == "MDCG");
+    // }
+
+    // #[test]
+    // fn read_exclude_sheet() {
+    //     // 读取主诊断排除表
+    //     let res = read_file_as_str_to_str("data\\exclude_sheet.json").unwrap();
+    //     assert_eq!(true, res["A01.000x014"] == "表6-3-1");
+    // }
+
+    // 一个小的合成分组方案, 规则函数的测试不依赖正式的分组方案数据
+    // 方案包括MDCA(AA1), MDCP(PR1), MDCY(YR1), MDCZ(ZB1)和普通的MDCF(FB2, FR2), 编码都是示例
+    const FIXTURE_FILES: [(&str, &str); 11] = [
+        ("main_dis_sheet.json", r#"{"I50.900": ["MDCF"], "P22.000": ["MDCP"]}"#),
+        ("mdc_sub_adrg.json", r#"{"MDCA": ["AA1"], "MDCP": ["PR1"], "MDCY": ["YR1"], "MDCZ": ["ZB1"], "MDCF": ["FB2", "FR2"]}"#),
+        ("adrg_in_condition.json", r#"{"AA1": "is_contain_main_opt", "PR1": "is_contain_main_dis", "YR1": "is_contain_dis", "ZB1": "is_contain_main_opt", "FB2": "is_contain_main_opt", "FR2": "is_contain_main_dis"}"#),
+        ("adrg_dis_opt_sheet.json", r#"{"AA1": ["33.6x00"], "PR1": ["P22.000"], "YR1": ["B20.000"], "ZB1": ["79.3500"], "FB2": ["35.0101"], "FR2": ["I50.900"]}"#),
+        ("adrg_drg_name_sheet.json", r#"{"AA1": ["AA19"], "PR1": ["PR19"], "YR1": ["YR11", "YR15"], "ZB1": ["ZB19"], "FB2": ["FB21", "FB25"], "FR2": ["FR21", "FR23", "FR25"]}"#),
+        ("ccmcc_sheet.json", r#"{"E87.100": ["表6-3-2", "CC"], "J96.000": ["表6-3-3", "MCC"]}"#),
+        ("exclude_sheet.json", r#"{"I50.900": "表6-3-1", "P22.000": "表6-3-3"}"#),
+        ("mdcz_dis_sheet.json", r#"{"belly_dis_sheet": ["S36.000"], "body_spine_dis_sheet": [], "chest_dis_sheet": ["S22.000"], "down_limb_dis_sheet": [], "genital_dis_sheet": [], "head_neck_dis_sheet": [], "pelvis_dis_sheet": [], "up_limb_dis_sheet": [], "urinary_dis_sheet": []}"#),
+        ("mdcy_dis_sheet.txt", "B20.000"),
+        ("all_dis_sheet.txt", "I50.900,P22.000,B20.000,S36.000,S22.000,E87.100,J96.000"),
+        ("all_opt_sheet.txt", "33.6x00,79.3500,35.0101"),
+    ];
+
+    fn fixture_members() -> Vec<(&'static str, &'static [u8])> {
+        FIXTURE_FILES.iter().map(|(name, contents)| (*name, contents.as_bytes())).collect()
+    }
+
+    // 每个测试自己的临时目录(按进程号和测试名区分), 测试并行运行时不会互相覆盖, 离开作用域时删除
+    struct TestDir {
+        path: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(test_name: &str) -> Self {
+            let path = env::temp_dir().join(format!("drg_test_{}_{}", std::process::id(), test_name));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn join<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+            self.path.join(file)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.path).ok();
+        }
+    }
+
+    // 从内存中读取合成分组方案
+    fn fixture_scheme() -> GroupingScheme {
+        let members = fixture_members();
+        GroupingScheme::load(&EmbeddedSource::new(&members)).unwrap()
+    }
+
+    #[test]
+    fn mdcz_group_test() {
+        // 其他诊断位于两个不同部位的诊断表中时进入MDCZ
+        let scheme = fixture_scheme();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900").main_opt("79.3500").other_dis(["S36.000", "S22.000"]).sex(1).age(20.0).build();
+        let res = is_mdcz(&case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdcz_dis_sheet, String::from("MDCZ")).unwrap();
+        assert_eq!("MDCZ", res);
+        // 进入MDCZ的ZB1之后, 主诊断所在的MDCF的结果(主手术不是MDCF的手术, 内科ADRG有手术为QY)覆盖ZB1
+        let (result, trace) = scheme.group_with_trace(&case, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCF", "FQY", "FQY"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+        assert_eq!("overridden", trace.mdc_order.iter().find(|x| x.mdc == "MDCZ").unwrap().result);
+        // 主诊断只属于MDCZ时没有之后的MDC, 在MDCZ入组
+        let mut mdcz_only = fixture_scheme();
+        mdcz_only.main_dis_sheet.insert(String::from("I50.900"), vec![String::from("MDCZ")]);
+        let result = mdcz_only.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCZ", "ZB1", "ZB19"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+
+        // 只有一个部位时不进入MDCZ, 按主诊断进入MDCF, 主手术属于本MDC的外科手术
+        let one_part = DrgCase::builder().id("0002").main_dis("I50.900").main_opt("35.0101").other_dis(["S36.000"]).sex(1).age(20.0).build();
+        let res = is_mdcz(&one_part, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdcz_dis_sheet, String::from("MDCZ")).unwrap();
+        assert_eq!("KBBZ", res);
+        assert_eq!("FB2", scheme.group(&one_part, &GroupOptions::default()).unwrap().adrg);
+    }
+
+    #[test]
+    fn mdcy_group_test() {
+        // 任一诊断在MDCY诊断表中时进入MDCY
+        let scheme = fixture_scheme();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900").other_dis(["B20.000"]).sex(1).age(20.0).build();
+        assert_eq!("MDCY", is_mdcy(&case, &scheme.adrg_type_dict, &scheme.mdcy_dis_sheet, String::from("MDCY")));
+        // 主诊断所在的MDCF在MDCY之后判断, 结果覆盖YR1
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCF", "FR2"), (result.mdc.as_str(), result.adrg.as_str()));
+        let mut mdcy_only = fixture_scheme();
+        mdcy_only.main_dis_sheet.insert(String::from("I50.900"), vec![String::from("MDCY")]);
+        let result = mdcy_only.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCY", "YR1", "YR15"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+
+        let other = DrgCase::builder().id("0002").main_dis("I50.900").other_dis(["E87.100"]).sex(1).age(20.0).build();
+        assert_eq!("KBBZ", is_mdcy(&other, &scheme.adrg_type_dict, &scheme.mdcy_dis_sheet, String::from("MDCY")));
+        let result = scheme.group(&other, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCF", "FR2", "FR23"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+    }
+
+    #[test]
+    fn mdcp_group_test() {
+        // 年龄不超过29天时进入MDCP
+        let scheme = fixture_scheme();
+        let case = DrgCase::builder().id("0001").main_dis("P22.000").sex(1).age(0.05).weight(2288).build();
+        assert_eq!("MDCP", is_mdcp(&case, &scheme.main_dis_sheet, String::from("MDCP")));
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCP", "PR1", "PR19"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+
+        // 超过29天的患者没有对应的MDC
+        let older = DrgCase::builder().id("0002").main_dis("P22.000").sex(1).age(0.1).weight(2288).build();
+        assert_eq!("KBBZ", is_mdcp(&older, &scheme.main_dis_sheet, String::from("MDCP")));
+        assert_eq!("KBBZ", scheme.group(&older, &GroupOptions::default()).unwrap().drg);
+    }
+
+    #[test]
+    fn mdca_group_test() {
+        // 主手术满足MDCA下ADRG的条件时进入MDCA, 与主诊断无关
+        let scheme = fixture_scheme();
+        let options = GroupOptions::default();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900").main_opt("33.6x00").other_dis(["B20.000"]).sex(1).age(20.0).build();
+        let res = is_mdca(&case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdc_sub_adrg, String::from("MDCA"), &options).unwrap();
+        assert_eq!("AA1", res);
+        let result = scheme.group(&case, &options).unwrap();
+        assert_eq!(("MDCA", "AA1", "AA19"), (result.mdc.as_str(), result.adrg.as_str(), result.drg.as_str()));
+
+        // 没有主手术时不进入MDCA
+        let no_opt = DrgCase::builder().id("0002").main_dis("I50.900").other_dis(["J96.000"]).sex(1).age(20.0).build();
+        let res = is_mdca(&no_opt, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdc_sub_adrg, String::from("MDCA"), &options).unwrap();
+        assert_eq!("KBBZ", res);
+        assert_eq!("FR21", scheme.group(&no_opt, &options).unwrap().drg);
+    }
+
+    // #[test]
+    // fn test_adrg() {
+    //     // 读取分组方案数据
+
+    //     // ADRG内涵诊断和手术操作表
+    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
+    //     // 所有手术操作列表
+    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
+    //     // 所有诊断列表
+    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
+    //     // 各个MDC的主诊表
+    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
+    //     // MDCY的诊断表
+    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
+    //     // MDCZ的诊断表
+    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
+    //     // 各个ADRG组进入的判断条件
+    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    //     // 读取MDC下的ADRG列表
+    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
+
+    //     // 初始化病例结构
+    //     let case = DrgCase::new(
+    //         String::from("0001"),
+    //         String::from("G12.900"),
+    //         String::from("03.9202"),
+    //         vec![String::from("M41.900")],
+    //         vec![],
+    //         1,
+    //         20.0,
+    //         2288
+    //     );
+    //     // MDC列表
+    //     let mdc_list = vec![
+    //         "MDCA", "MDCP", "MDCY", "MDCZ", "MDCB", "MDCC", "MDCD", 
+    //         "MDCE", "MDCF", "MDCG", "MDCH", "MDCI", "MDCJ", "MDCK", "MDCL", 
+    //         "MDCM", "MDCN", "MDCO", "MDCQ", "MDCR", "MDCS", "MDCT", "MDCU", 
+    //         "MDCV", "MDCW", "MDCX"].iter_mut().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    //     // 无效主诊断，病例进入KBBZ
+    //     if case.no_main_diagnosis() {
+    //         println!("No main dis no adrg group in result is {}", "KBBZ");
+    //     }
+
+    //     // 主诊断所在的MDC
+    //     let mut target_mdc_list = main_dis_sheet[case.dis.principal()].clone();
+    //     let pre_mdc = vec![String::from("MDCA"), String::from("MDCP"), String::from("MDCY"), String::from("MDCZ")];
+    //     target_mdc_list = [pre_mdc, target_mdc_list].concat();
+    //     println!("{:?}", target_mdc_list);
+        
+    //     let mut pred_adrg = String::from("KBBZ");
+    //     let mut pred_mdc = String::from("KBBZ");
+    //     for mdc in target_mdc_list {
+    //         if mdc == String::from("MDCA") {
+    //             // 优先判断MDCA
+    //             pred_adrg = is_mdca( &case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, String::from("MDCA"));
+    //             if pred_adrg != String::from("KBBZ") {
+    //                 // 如果在MDCA中找到ADRG入组
+    //                 println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
+    //                 break
+    //             }
+    //         }
+    //         else if mdc == String::from("MDCP") {
+    //             // 判断MDCP
+    //             pred_mdc = is_mdcp(&case, &main_dis_sheet, String::from("MDCP"));
+    //             if pred_mdc == String::from("MDCP") {
+    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
+    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
+    //                     if pred_adrg != "KBBZ".to_string() {
+    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
+    //                         break
+    //                     }
+    //                 }
+            
+    //             }
+    //         }
+    //         else if mdc == String::from("MDCY") {
+    //             // 判断MDCY
+    //             pred_mdc = is_mdcy(&case, &adrg_type_dict, &mdcy_dis_sheet, String::from("MDCY"));
+    //             if pred_mdc == String::from("MDCY") {
+    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
+    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
+    //                     if pred_adrg != "KBBZ".to_string() {
+    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
+    //                         break
+    //                     }
+    //                 }
+                
+    //             }
+    //         }
+    //         else if mdc == String::from("MDCZ") {
+    //             // 判断MDCZ
+    //             pred_mdc = is_mdcz(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, &mdcz_dis_sheet, String::from("MDCZ"));
+    //             if pred_mdc == String::from("MDCZ") {
+    //                 // 判断MDC内的ADRG入组
+    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
+    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
+    //                     if pred_adrg != "KBBZ".to_string() {
+    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
+    //                         break
+    //                     }
+    //                 }
+    //             }
+    //         }
+    //         else {
+    //             // 处理其他MDC
+    //             for adrg in mdc_sub_adrg[&mdc].clone() {
+    //                 pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
+    //                 if pred_adrg != String::from("KBBZ") {
+    //                     println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
+    //                     break
+    //                 }
+    //             }
+    //         }
+    //     }
+    //     println!("The final predict adrg is {}", pred_adrg);
+    //     assert_eq!(true, pred_adrg == "AH1");
+    // }
+
+    // #[test]
+    // fn test_adrg() {        
+    //     // ADRG内涵诊断和手术操作表
+    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
+    //     // 所有手术操作列表
+    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
+    //     // 所有诊断列表
+    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
+    //     // 各个MDC的主诊表
+    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
+    //     // MDCY的诊断表
+    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
+    //     // MDCZ的诊断表
+    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
+    //     // 各个ADRG组进入的判断条件
+    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    //     // 读取MDC下的ADRG列表
+    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
+    //     // 初始化病例结构
+
+    //     // 初始化病例结构
+    //     let case = DrgCase::new(
+    //         String::from("0001"),
+    //         String::from("G12.900"),
+    //         String::from("31.7400x0001"),
+    //         vec![String::from("M41.900")],
+    //         vec![],
+    //         1,
+    //         20.0,
+    //         2288
+    //     );
+
+    //     // 判断最终属于的ADRG
+    //     let result_adrg = which_adrg(
+    //         &case, 
+    //         &adrg_dis_opt, 
+    //         &all_opt_list, 
+    //         &all_dis_list, 
+    //         &main_dis_sheet, 
+    //         &adrg_type_dict, 
+    //         &mdcz_dis_sheet, &mdcy_dis_sheet, 
+    //         &mdc_sub_adrg
+    //     ).unwrap();
+    //     println!("result adrg is {}", result_adrg);
+    //     assert_eq!(true, result_adrg == String::from("BU2"));
+    // }
+
+    // #[test]
+    // fn test_drg() {        
+    //     // ADRG内涵诊断和手术操作表
+    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
+    //     // 所有手术操作列表
+    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
+    //     // 所有诊断列表
+    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
+    //     // 各个MDC的主诊表
+    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
+    //     // MDCY的诊断表
+    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
+    //     // MDCZ的诊断表
+    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
+    //     // 各个ADRG组进入的判断条件
+    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    //     // 读取MDC下的ADRG列表
+    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
+    //     // 读取CCMCC列表
+    //     let ccmcc_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\ccmcc_sheet.json").unwrap();
+    //     // 读取排除表
+    //     let exclude_sheet = read_file_as_str_to_str("D:\\MyScript\\rust\\DrgGrouper\\data\\exclude_sheet.json").unwrap();
+    //     // 读取ADRG下的DRG
+    //     let adrg_drg_name_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\adrg_drg_name_sheet.json").unwrap();
+
+
+    //     // 初始化病例结构
+    //     let case = DrgCase::new(
+    //         String::from("0001"),
+    //         String::from("G12.900"),
+    //         String::from("31.7400x0001"),
+    //         vec![String::from("M41.900")],
+    //         vec![],
+    //         1,
+    //         20.0,
+    //         2288
+    //     );
+
+    //     // 判断最终属于的ADRG
+    //     let result_adrg = which_adrg(
+    //         &case, 
+    //         &adrg_dis_opt, 
+    //         &all_opt_list, 
+    //         &all_dis_list, 
+    //         &main_dis_sheet, 
+    //         &adrg_type_dict, 
+    //         &mdcz_dis_sheet, &mdcy_dis_sheet, 
+    //         &mdc_sub_adrg
+    //     ).unwrap();
+    //     println!("result adrg is {}", result_adrg);
+
+    //     let result_drg = process_drg(
+    //         &case,
+    //         result_adrg,
+    //         &ccmcc_sheet,
+    //         &exclude_sheet,
+    //         &adrg_drg_name_sheet
+    //     ).unwrap();
+
+    //     println!("result drg is {}", result_drg);
+    //     assert_eq!(true, result_drg == String::from("BU25"));
+    // }
+
+    // #[test]
+    // fn test_read_csv() {
+        // 测试读取CSV文件
+        // let cases_vec = read_csv("D:\\MyScript\\rust\\DrgGrouper\\case_data\\test_case_data.csv").unwrap();
+        // for drg_case in &cases_vec {
+            // println!("{:?}", drg_case);
+        // }
+        // assert_eq!(true, cases_vec[0].main_dis == "I50.900x08".to_string());
+    // }
+
+    #[test]
+    fn test_flat_to_nested_adrg_sheet() {
+        // 测试扁平格式的ADRG诊断手术表转换为嵌套格式
+        let mut flat: HashMap<String, HashSet<String>> = HashMap::new();
+        flat.insert(String::from("AA1"), HashSet::from([String::from("33.6x00")]));
+        flat.insert(String::from("AH2_other_opt_list1"), HashSet::from([String::from("31.1x00")]));
+        flat.insert(String::from("AH2_other_dis_list"), HashSet::from([String::from("J96.000")]));
+        let nested = flat_to_nested_adrg_sheet(flat);
+        assert_eq!(2, nested.len());
+        assert!(nested["AA1"][DEFAULT_TABLE].contains("33.6x00"));
+        assert!(nested["AH2"]["other_opt_list1"].contains("31.1x00"));
+        assert!(nested["AH2"]["other_dis_list"].contains("J96.000"));
+    }
+
+    #[test]
+    fn test_adrg_dis_opt_parse_errors() {
+        // 测试两种格式都解析失败时的错误: 像嵌套格式时报出嵌套格式的错误, 否则同时报出两种格式的错误
+        let nested = br#"{"AA1": {"main_opt_list": ["33.6x00", 33.61]}}"#;
+        let err = parse_adrg_dis_opt_sheet(nested).unwrap_err().to_string();
+        assert!(err.starts_with("nested format: invalid type: floating point `33.61`"), "{}", err);
+        let neither = br#"{"AA1": "33.6x00"}"#;
+        let err = parse_adrg_dis_opt_sheet(neither).unwrap_err().to_string();
+        assert!(err.starts_with("neither nested format (invalid type: string"), "{}", err);
+        assert!(err.contains("flat format (invalid type: string"), "{}", err);
+        assert!(parse_adrg_dis_opt_sheet(b"").unwrap_err().to_string().contains("EOF"));
+    }
+
+    #[test]
+    fn test_read_flat_adrg_dis_opt() {
+        // 测试读取扁平格式的ADRG诊断手术表
+        let res = parse_adrg_dis_opt_sheet(&fs::read("data/adrg_dis_opt_sheet.json").unwrap()).unwrap();
+        assert!(res["AA1"][DEFAULT_TABLE].contains("33.6x00"));
+        assert!(res["AC1"]["normal_list"].contains("52.8000"));
+        assert!(res["AC1"]["other_list"].contains("55.6100"));
+    }
+
+    #[test]
+    fn test_read_nested_adrg_dis_opt() {
+        // 测试读取嵌套格式的ADRG诊断手术表, 结果应与扁平格式转换后的一致
+        let tmp = TestDir::new("test_read_nested_adrg_dis_opt");
+        let flat = parse_adrg_dis_opt_sheet(&fs::read("data/adrg_dis_opt_sheet.json").unwrap()).unwrap();
+        let nested_path = tmp.join("adrg_dis_opt_nested_sheet.json");
+        fs::write(&nested_path, serde_json::to_string(&flat).unwrap()).unwrap();
+        let nested = parse_adrg_dis_opt_sheet(&fs::read(&nested_path).unwrap()).unwrap();
+        assert_eq!(flat, nested);
+    }
+
+    #[test]
+    fn test_operations_intersect() {
+        // 测试病例手术与手术表交集的判断, 结果应与is_disjoint一致
+        let case = DrgCase::new(
+            String::from("0001"),
+            String::from("G12.900"),
+            String::from("52.8000"),
+            vec![],
+            vec![String::from("55.6901")],
+            1,
+            20.0,
+            2288
+        );
+        let small: HashSet<String> = HashSet::from([String::from("55.6901")]);
+        let large = read_member_icd_list(&DirSource::new(DATA_DIR), "all_opt_sheet.txt", false).unwrap();
+        let other: HashSet<String> = HashSet::from([String::from("33.6x00")]);
+        assert!(case.operations_intersect(&small));
+        assert!(case.operations_intersect(&large));
+        assert!(!case.operations_intersect(&other));
+        assert_eq!(!large.is_disjoint(case.opt.all()), case.operations_intersect(&large));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_operations_intersect() {
+        // 在真实的分组方案上对比operations_intersect与is_disjoint的耗时
+        // 运行方式: cargo test --release bench_operations_intersect -- --ignored --nocapture
+        let adrg_dis_opt = parse_adrg_dis_opt_sheet(&fs::read("data/adrg_dis_opt_sheet.json").unwrap()).unwrap();
+        let case = DrgCase::new(
+            String::from("0001"),
+            String::from("I50.900x018"),
+            String::from("96.7101"),
+            vec![String::from("E16.200"), String::from("E87.201")],
+            vec![String::from("38.9302"), String::from("96.0400")],
+            1,
+            12.0,
+            1203
+        );
+        let sets: Vec<&HashSet<String>> = adrg_dis_opt.values().flat_map(|t| t.values()).collect();
+        let rounds = 2000;
+
+        let start = std::time::Instant::now();
+        let mut disjoint_hits = 0;
+        for _ in 0..rounds {
+            disjoint_hits += sets.iter().filter(|s| !s.is_disjoint(case.opt.all())).count();
+        }
+        let disjoint_cost = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut intersect_hits = 0;
+        for _ in 0..rounds {
+            intersect_hits += sets.iter().filter(|s| case.operations_intersect(s)).count();
+        }
+        let intersect_cost = start.elapsed();
+
+        println!("is_disjoint: {:?}, operations_intersect: {:?}", disjoint_cost, intersect_cost);
+        assert_eq!(disjoint_hits, intersect_hits);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_process_drg_ccmcc() {
+        // 对比每个病例临时构建DRG索引与读取时预先计算的耗时, 以及CCMCC扫描的耗时
+        // 运行方式: cargo test --release bench_process_drg_ccmcc -- --ignored --nocapture
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let other_dis: Vec<String> = scheme.ccmcc_sheet.keys().take(30).cloned().collect();
+        let case = DrgCase::new(
+            String::from("0001"),
+            String::from("I50.900x018"),
+            String::from(""),
+            other_dis,
+            vec![],
+            1,
+            60.0,
+            0
+        );
+        let rounds = 20000;
+
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            let drg_wait_dict: HashMap<i32, String> = scheme.adrg_drg_name_sheet["FR2"]
+                .iter()
+                .map(|x| (x.chars().last().unwrap_or_default().to_digit(10).unwrap() as i32, x.to_string()))
+                .collect();
+            assert_eq!(2, drg_wait_dict.len());
+        }
+        let per_case_cost = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            assert_eq!(2, scheme.adrg_drg_levels["FR2"].len());
+        }
+        let precomputed_cost = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut drg = String::new();
+        for _ in 0..rounds {
+            drg = process_drg(&case, String::from("FR2"), &scheme.ccmcc_sheet, &scheme.exclude_sheet, &scheme.adrg_drg_levels, &scheme.severity_endings, CcmccScope::OtherDis).unwrap();
+        }
+        let scan_cost = start.elapsed();
+
+        println!("per-case index: {:?}, precomputed index: {:?}, process_drg with 30 other_dis: {:?}", per_case_cost, precomputed_cost, scan_cost);
+        assert!(drg.starts_with("FR2"));
+    }
+
+    #[test]
+    fn test_split_output_path() {
+        // 测试拆分输出的文件命名
+        let path = split_output_path("result/out.csv", "MDCA");
+        assert_eq!(Path::new("result/out_MDCA.csv"), path.as_path());
+    }
+
+    #[test]
+    fn test_pre_mdc_precedence() {
+        // 测试先期分组的MDC入组后仍然判断主诊断所在的MDC, 之后判断的MDC的结果覆盖之前的结果
+        // 新生儿的主手术满足MDCP的PB1, 主诊断所在的MDCF的结果(FQY)覆盖了PB1
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let newborn = DrgCase::new(
+            String::from("0001"),
+            String::from("I50.900x018"),
+            String::from("34.0200x003"),
+            vec![],
+            vec![],
+            1,
+            0.01,
+            3000
+        );
+        let (result, trace) = scheme.group_with_trace(&newborn, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCF", "FQY"), (result.mdc.as_str(), result.adrg.as_str()));
+        let steps: Vec<(&str, &str)> = trace.mdc_order.iter().map(|x| (x.mdc.as_str(), x.result.as_str())).collect();
+        assert!(steps.contains(&("MDCP", "overridden")), "{:?}", steps);
+        assert_eq!(Some(&("MDCF", "grouped")), steps.last());
+        // 同样的诊断和手术不是新生儿时在MDCF入组
+        let adult = DrgCase::new(
+            String::from("0002"),
+            String::from("I50.900x018"),
+            String::from("34.0200x003"),
+            vec![],
+            vec![],
+            1,
+            60.0,
+            0
+        );
+        let result = scheme.group(&adult, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCF", "FQY"), (result.mdc.as_str(), result.adrg.as_str()));
+    }
+
+    #[test]
+    fn test_batch_split_by_mdc() {
+        // 测试按MDC拆分输出, 各文件的病例数之和应等于输入的病例数
+        let tmp = TestDir::new("test_batch_split_by_mdc");
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let case_count = cases_vec.len();
+        let out_dir = tmp.join("drg_split_by_mdc");
+        fs::create_dir_all(&out_dir).unwrap();
+        let out_file_path = out_dir.join("out.csv");
+        let options = CliOptions { split_by: Some(String::from("mdc")), ..Default::default() };
+        batch_drg_process(cases_vec, out_file_path.to_str().unwrap(), &options).unwrap();
+
+        let mut row_count = 0;
+        for entry in fs::read_dir(&out_dir).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(name.starts_with("out_MDC") || name == "out_KBBZ.csv");
+            row_count += csv::Reader::from_path(out_dir.join(&name)).unwrap().records().count();
+        }
+        assert_eq!(case_count, row_count);
+    }
+
+    #[test]
+    fn test_strict_missing_main_dis() {
+        // 主诊断不在主诊表中: 默认进入KBBZ, 严格模式下报错并注明病例ID和缺失的键
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::new(
+            String::from("0001"),
+            String::from("NOT.IN.SHEET"),
+            String::from(""),
+            vec![],
+            vec![],
+            1,
+            20.0,
+            2288
+        );
+        let res = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("KBBZ", res.drg);
+
+        let strict = GroupOptions { strict: true, ..Default::default() };
+        let err = scheme.group(&case, &strict).unwrap_err().to_string();
+        assert!(err.contains("0001"));
+        assert!(err.contains("NOT.IN.SHEET"));
+    }
+
+    #[test]
+    fn test_null_main_dis_policy() {
+        // 主诊断列为空的行: --null-as-kbbz(默认)进入KBBZ, --null-as-error报错并注明病例ID
+        let tmp = TestDir::new("test_null_main_dis_policy");
+        let in_file_path = tmp.join("drg_null_main_dis.csv");
+        fs::write(&in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+            0001,,,E16.200,,1,60,0\n").unwrap();
+        let cases = read_csv(in_file_path.to_str().unwrap()).unwrap();
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+
+        let args: Vec<String> = ["drg", "--null-as-kbbz"].iter().map(|x| x.to_string()).collect();
+        let (_, options) = parse_cli_options(&args).unwrap();
+        assert_eq!("KBBZ", scheme.group(&cases[0], &options.group_options).unwrap().drg);
+
+        let args: Vec<String> = ["drg", "--null-as-error"].iter().map(|x| x.to_string()).collect();
+        let (_, options) = parse_cli_options(&args).unwrap();
+        let err = scheme.group(&cases[0], &options.group_options).unwrap_err().to_string();
+        assert!(err.contains("0001"));
+        assert!(err.contains("empty main diagnosis"));
+    }
+
+    #[test]
+    fn test_strict_missing_adrg_table() {
+        // ADRG诊断手术表缺少AA1: 默认跳过该ADRG继续分组, 严格模式下报错
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.adrg_dis_opt.remove("AA1");
+        let case = DrgCase::new(
+            String::from("0002"),
+            String::from("I50.900x018"),
+            String::from("96.7101"),
+            vec![String::from("E16.200")],
+            vec![],
+            1,
+            12.0,
+            1203
+        );
+        assert!(scheme.group(&case, &GroupOptions::default()).is_ok());
+
+        let strict = GroupOptions { strict: true, ..Default::default() };
+        let err = scheme.group(&case, &strict).unwrap_err().to_string();
+        assert!(err.contains("0002"));
+        assert!(err.contains("AA1"));
+    }
+
+    #[test]
+    fn test_review_flags() {
+        // 测试普通MDC中无手术操作且只有一个CC的病例带有复核标记
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0003").main_dis("J18.900").other_dis(["A01.200"]).sex(1).age(60.0).build();
+        assert_eq!(valid_ccmcc(&case, &scheme.ccmcc_sheet, &scheme.exclude_sheet, CcmccScope::OtherDis).len(), 1);
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("ES33", result.drg);
+        assert_eq!(vec!["no_operation_support", "single_cc_only"], result.review_flags);
+        // FR2只有FR21和FR25, 一个CC不改变DRG, 没有single_cc_only
+        let case = DrgCase::builder().id("0004").main_dis("I50.900x018").other_dis(["A02.000"]).sex(1).age(60.0).build();
+        assert_eq!(valid_ccmcc(&case, &scheme.ccmcc_sheet, &scheme.exclude_sheet, CcmccScope::OtherDis).len(), 1);
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR25", result.drg);
+        assert_eq!(vec!["no_operation_support"], result.review_flags);
+
+        // 批量输出只在--emit-review-flags时有review_flags列
+        let tmp = TestDir::new("test_review_flags");
+        let out_file_path = tmp.join("drg_review_flags_out.csv");
+        let cases = || vec![
+            DrgCase::builder().id("0003").main_dis("J18.900").other_dis(["A01.200"]).sex(1).age(60.0).build(),
+            DrgCase::builder().id("0004").main_dis("I50.900x018").other_dis(["A02.000"]).sex(1).age(60.0).build(),
+        ];
+        batch_drg_process(cases(), out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        assert!(!rdr.headers().unwrap().iter().any(|h| h == "review_flags"));
+        let cli = CliOptions { emit_review_flags: true, ..Default::default() };
+        batch_drg_process(cases(), out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let col = rdr.headers().unwrap().iter().position(|h| h == "review_flags").unwrap();
+        let rows: Vec<String> = rdr.records().map(|x| x.unwrap()[col].to_string()).collect();
+        assert_eq!(vec!["no_operation_support|single_cc_only", "no_operation_support"], rows);
+    }
+
+    #[test]
+    fn test_warn_cross_mdc() {
+        // 测试分组方案把NS1错误地列在MDCF下时, 在MDCF中进入NS1的病例被标记
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.main_dis_sheet.insert(String::from("N70.000"), vec![String::from("MDCF")]);
+        scheme.mdc_sub_adrg.get_mut("MDCF").unwrap().insert(0, String::from("NS1"));
+        let case = DrgCase::builder().id("0001").main_dis("N70.000").sex(0).age(30.0).build();
+        let warn = GroupOptions { warn_cross_mdc: true, ..Default::default() };
+        let result = scheme.group(&case, &warn).unwrap();
+        assert_eq!(("MDCF", "NS1"), (result.mdc.as_str(), result.adrg.as_str()));
+        assert!(result.review_flags.contains(&String::from("cross_mdc")));
+        // 默认不检查
+        assert!(!scheme.group(&case, &GroupOptions::default()).unwrap().review_flags.contains(&String::from("cross_mdc")));
+        // 正常的病例不标记
+        let normal = DrgCase::builder().id("0002").main_dis("I50.900x018").sex(1).age(60.0).build();
+        assert!(!scheme.group(&normal, &warn).unwrap().review_flags.contains(&String::from("cross_mdc")));
+        assert!(adrg_in_mdc("FR2", "MDCF"));
+        assert!(!adrg_in_mdc("NS1", "MDCF"));
+    }
+
+    #[test]
+    fn test_group_with_codes_only() {
+        // 测试只提供诊断和手术编码时使用默认的人口学信息分组
+        let args = ["--main-dis", "I50.900x018", "--main-opt", "96.7101"]
+            .iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        let case = parse_group_case(&args, &CliOptions::default()).unwrap();
+        assert_eq!(UNKNOWN_SEX, case.sex);
+        assert_eq!(DEFAULT_WEIGHT, case.weight);
+        assert!(case.dis.secondary().is_empty());
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_ne!("MDCP", result.mdc);
+        assert_ne!("KBBZ", result.drg);
+        assert!(parse_group_case(&args[2..], &CliOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_all_drg_codes() {
+        // 测试全部DRG编码列表包含已知DRG且没有重复
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let codes = scheme.all_drg_codes();
+        assert!(codes.contains(&String::from("FR25")));
+        assert!(codes.contains(&String::from("KBBZ")));
+        assert!(codes.contains(&String::from("FQY")));
+        let unique: HashSet<&String> = codes.iter().collect();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn test_read_csv_with_column_map() {
+        // 测试中文表头的CSV通过列名映射读取为病例结构
+        let tmp = TestDir::new("test_read_csv_with_column_map");
+        let in_file_path = tmp.join("chinese_header_cases.csv");
+        fs::write(
+            &in_file_path,
+            "病案号,主诊断,主手术,其他诊断,其他手术,性别,年龄,体重\n\
+             0001,I50.900x018,96.7101,E16.200|E87.201,38.9302|96.0400,1,12,1203\n",
+        )
+        .unwrap();
+        let column_map = read_column_map(
+            r#"{"病案号":"id","主诊断":"main_dis","主手术":"main_opt","其他诊断":"other_dis",
+                "其他手术":"other_opt","性别":"sex","年龄":"age","体重":"weight"}"#,
+        )
+        .unwrap();
+        let options = CliOptions { column_map, ..Default::default() };
+        let cases_vec = read_csv_mapped(in_file_path.to_str().unwrap(), &options).unwrap();
+        assert_eq!(1, cases_vec.len());
+        assert_eq!("0001", cases_vec[0].id);
+        assert_eq!("I50.900x018", cases_vec[0].dis.principal());
+        assert_eq!(vec![String::from("E16.200"), String::from("E87.201")], cases_vec[0].dis.secondary());
+        assert_eq!(1203, cases_vec[0].weight);
+
+        // 不做映射时中文表头无法对应到内部字段
+        assert!(read_csv(in_file_path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_strip_redundant_main_codes() {
+        // 测试其他诊断中重复的主诊断被去除, --keep-redundant时保留
+        let tmp = TestDir::new("test_strip_redundant_main_codes");
+        let in_file_path = tmp.join("redundant_cases.csv");
+        fs::write(
+            &in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+             0001,I50.900x018,96.7101,I50.900x018|E16.200,96.7101,1,12,1203\n",
+        )
+        .unwrap();
+        let cases_vec = read_csv(in_file_path.to_str().unwrap()).unwrap();
+        assert_eq!(vec![String::from("E16.200")], cases_vec[0].dis.secondary());
+        assert!(cases_vec[0].opt.secondary().is_empty());
+        let grouped = DrgCaseGrouped::new(cases_vec.into_iter().next().unwrap(), String::from("KBBZ"));
+        assert_eq!("E16.200", grouped.other_dis);
+
+        let options = CliOptions { keep_redundant: true, ..Default::default() };
+        let cases_vec = read_csv_mapped(in_file_path.to_str().unwrap(), &options).unwrap();
+        assert_eq!(2, cases_vec[0].dis.secondary().len());
+    }
+
+    #[test]
+    fn test_age_unit() {
+        // 测试29天的新生儿按天数读入时满足MDCP的条件, 29岁的成人按年读入时不满足
+        let tmp = TestDir::new("test_age_unit");
+        let in_file_path = tmp.join("age_unit_cases.csv");
+        fs::write(
+            &in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+             0001,Q86.000,,,,1,29,3000\n",
+        )
+        .unwrap();
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+
+        let days = CliOptions { age_unit: Some(String::from("days")), ..Default::default() };
+        let cases_vec = read_csv_mapped(in_file_path.to_str().unwrap(), &days).unwrap();
+        assert_eq!("MDCP", is_mdcp(&cases_vec[0], &scheme.main_dis_sheet, String::from("MDCP")));
+
+        let years = CliOptions { age_unit: Some(String::from("years")), ..Default::default() };
+        let cases_vec = read_csv_mapped(in_file_path.to_str().unwrap(), &years).unwrap();
+        assert_eq!(29.0, cases_vec[0].age);
+        assert_eq!("KBBZ", is_mdcp(&cases_vec[0], &scheme.main_dis_sheet, String::from("MDCP")));
+
+        // 无法区分天数和年数的年龄只计数, 运行结束时汇总提示一次; 其他测试可能同时计数, 这里只比较增加的个数
+        let before = AMBIGUOUS_AGES.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(1.0, normalize_age(365.0, Some("auto")));
+        assert_eq!(12.0, normalize_age(12.0, Some("auto")));
+        assert_eq!(12.0, normalize_age(12.0, Some("auto")));
+        assert!(take_ambiguous_ages() >= before + 2);
+    }
+
+    #[test]
+    fn test_infer_age_unit() {
+        // 测试年龄列为天数的文件被整体识别为天数, 其中的新生儿满足MDCP的条件; 指定--age-unit时不再推断
+        let tmp = TestDir::new("test_infer_age_unit");
+        let in_file_path = tmp.join("infer_age_unit_cases.csv");
+        fs::write(
+            &in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+             0001,Q86.000,,,,1,29,3000\n\
+             0002,I50.900x018,,,,1,21900,0\n\
+             0003,I50.900x018,,,,0,14600,0\n",
+        )
+        .unwrap();
+        assert_eq!("days", infer_age_unit([29.0, 21900.0, 14600.0].into_iter()));
+        assert_eq!("years", infer_age_unit([29.0, 60.0, 0.0, 200.0].into_iter()));
+        let cases_vec = read_input_cases(in_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        assert_eq!(60.0, cases_vec[1].age);
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert_eq!("MDCP", is_mdcp(&cases_vec[0], &scheme.main_dis_sheet, String::from("MDCP")));
+
+        let years = CliOptions { age_unit: Some(String::from("years")), ..Default::default() };
+        let cases_vec = read_input_cases(in_file_path.to_str().unwrap(), &years).unwrap();
+        assert_eq!(29.0, cases_vec[0].age);
+    }
+
+    #[test]
+    fn test_blank_age_in_days() {
+        // 测试年龄为天数(--age-unit days或推断为天数)时, 空白或缺少的年龄仍为成人年龄, 不会换算成新生儿进入MDCP
+        let tmp = TestDir::new("test_blank_age_in_days");
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let csv_path = tmp.join("blank_age_days.csv");
+        fs::write(
+            &csv_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+             0001,Q86.000,,,,1,29,3000\n\
+             0002,Q86.000,,,,1,,3000\n\
+             0003,I50.900x018,,,,1,21900,0\n\
+             0004,I50.900x018,,,,0,14600,0\n",
+        )
+        .unwrap();
+        let csv_path = csv_path.to_str().unwrap();
+        let days = CliOptions { age_unit: Some(String::from("days")), ..Default::default() };
+        for options in [&days, &CliOptions::default(), &CliOptions { fast_csv: true, ..Default::default() }] {
+            let cases_vec = read_input_cases(csv_path, options).unwrap();
+            assert_eq!("MDCP", is_mdcp(&cases_vec[0], &scheme.main_dis_sheet, String::from("MDCP")));
+            assert_eq!(DEFAULT_ADULT_AGE, cases_vec[1].age);
+            assert_eq!("KBBZ", is_mdcp(&cases_vec[1], &scheme.main_dis_sheet, String::from("MDCP")));
+        }
+
+        // 流式分组和--join-input同样先推断单位, 空白的年龄不换算
+        let out_path = tmp.join("blank_age_days_out.csv");
+        let out = out_path.to_str().unwrap();
+        let stream = CliOptions { emit_stages: true, max_memory_mb: Some(0), ..Default::default() };
+        batch_stream_process(csv_path, out, &stream).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_path).unwrap();
+        let age_col = rdr.headers().unwrap().iter().position(|h| h == "age").unwrap();
+        let code_col = rdr.headers().unwrap().iter().position(|h| h == "code").unwrap();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(DEFAULT_ADULT_AGE.to_string(), rows[1][age_col]);
+        assert_eq!("60", &rows[2][age_col]);
+        let stream_codes: Vec<String> = rows.iter().map(|x| x[code_col].to_string()).collect();
+        batch_join_process(csv_path, out, &CliOptions { join_input: true, ..Default::default() }).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_path).unwrap();
+        let drg_col = rdr.headers().unwrap().iter().position(|h| h == "drg").unwrap();
+        let join_codes: Vec<String> = rdr.records().map(|x| x.unwrap()[drg_col].to_string()).collect();
+        assert_eq!(stream_codes, join_codes);
+
+        // NDJSON缺少年龄的病例不参与推断, 构造病例时为成人年龄
+        let ndjson_path = tmp.join("blank_age_days.ndjson");
+        fs::write(
+            &ndjson_path,
+            "{\"id\":\"0001\",\"main_dis\":\"Q86.000\",\"sex\":1,\"age\":29}\n\
+             {\"id\":\"0002\",\"main_dis\":\"Q86.000\",\"sex\":1}\n\
+             {\"id\":\"0003\",\"main_dis\":\"I50.900x018\",\"age\":21900}\n\
+             {\"id\":\"0004\",\"main_dis\":\"I50.900x018\",\"age\":null}\n\
+             {\"id\":\"0005\",\"main_dis\":\"I50.900x018\",\"age\":14600}\n",
+        )
+        .unwrap();
+        let cases_vec = read_input_cases(ndjson_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        assert_eq!("MDCP", is_mdcp(&cases_vec[0], &scheme.main_dis_sheet, String::from("MDCP")));
+        assert_eq!(60.0, cases_vec[2].age);
+        assert_eq!(DEFAULT_ADULT_AGE, cases_vec[1].age);
+        assert_eq!(DEFAULT_ADULT_AGE, cases_vec[3].age);
+    }
+
+    #[test]
+    fn test_ccmcc_age_condition() {
+        // 测试MCC带有年龄条件时, 年龄不在范围内的病例不计入该MCC
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::new(
+            String::from("0004"),
+            String::from("I50.900x018"),
+            String::from(""),
+            vec![String::from("A02.100")],
+            vec![],
+            1,
+            60.0,
+            0
+        );
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR21", result.drg);
+
+        scheme.ccmcc_sheet.get_mut("A02.100").unwrap().push(String::from("age_max:1"));
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR25", result.drg);
+    }
+
+    #[test]
+    fn test_batch_append() {
+        // 测试两次追加写入同一个文件只有一行表头且包含两批病例
+        let tmp = TestDir::new("test_batch_append");
+        let out_file_path = tmp.join("drg_append_out.csv");
+        let options = CliOptions { append: true, ..Default::default() };
+        let case_count = read_csv("case_data/test_case_data.csv").unwrap().len();
+        for _ in 0..2 {
+            let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+            batch_drg_process(cases_vec, out_file_path.to_str().unwrap(), &options).unwrap();
+        }
+        let content = fs::read_to_string(&out_file_path).unwrap();
+        assert_eq!(1, content.lines().filter(|x| x.starts_with("id,")).count());
+        let row_count = csv::Reader::from_path(&out_file_path).unwrap().records().count();
+        assert_eq!(case_count * 2, row_count);
+    }
+
+    #[test]
+    fn test_trace_file() {
+        // 测试判断路径文件每个病例一行JSON, 且记录的结果与分组结果一致
+        let tmp = TestDir::new("test_trace_file");
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let case_count = cases_vec.len();
+        let out_file_path = tmp.join("drg_trace_out.csv");
+        let trace_file_path = tmp.join("drg_trace.jsonl");
+        let options = CliOptions {
+            trace_file: Some(trace_file_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        batch_drg_process(cases_vec, out_file_path.to_str().unwrap(), &options).unwrap();
+
+        let content = fs::read_to_string(&trace_file_path).unwrap();
+        assert_eq!(case_count, content.lines().count());
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let code = rdr.headers().unwrap().iter().position(|h| h == "code").unwrap();
+        let traces: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        for (trace, row) in traces.iter().zip(rdr.records()) {
+            let row = row.unwrap();
+            assert_eq!(row[0].to_string(), trace["id"]);
+            assert_eq!(row[code].to_string(), trace["drg"]);
+        }
+
+        // 第二个病例(E11.600x051, 无手术, 两个CC)的完整判断路径
+        let trace = &traces[1];
+        assert_eq!("450800G0000539036390", trace["id"]);
+        assert_eq!(
+            serde_json::json!([
+                {"mdc": "MDCA", "result": "KBBZ"},
+                {"mdc": "MDCP", "result": "KBBZ"},
+                {"mdc": "MDCY", "result": "KBBZ"},
+                {"mdc": "MDCZ", "result": "KBBZ"},
+            ]),
+            trace["pre_mdc_checks"]
+        );
+        let tried: Vec<(&str, &str, bool)> = trace["adrg_tried"].as_array().unwrap().iter()
+            .map(|x| (x["mdc"].as_str().unwrap(), x["adrg"].as_str().unwrap(), x["matched"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("MDCK", "KB1", false),
+                ("MDCK", "KC1", false),
+                ("MDCK", "KD1", false),
+                ("MDCK", "KD2", false),
+                ("MDCK", "KE1", false),
+                ("MDCK", "KJ1", false),
+                ("MDCK", "KR1", false),
+                ("MDCK", "KS1", true),
+            ],
+            tried
+        );
+        assert_eq!("is_contain_main_dis", trace["adrg_tried"][7]["condition"]);
+        assert_eq!(("MDCK", "KS1", "KS1"), (trace["mdc"].as_str().unwrap(), trace["pre_qy_adrg"].as_str().unwrap(), trace["adrg"].as_str().unwrap()));
+        assert_eq!(false, trace["qy_adjusted"]);
+        assert_eq!(serde_json::json!([["E77.801", "CC"], ["E87.600", "CC"]]), trace["ccmcc"]);
+        assert_eq!("CC", trace["severity"]);
+        assert_eq!("KS13", trace["drg"]);
+        let order: Vec<(&str, &str)> = trace["mdc_order"].as_array().unwrap().iter()
+            .map(|x| (x["mdc"].as_str().unwrap(), x["result"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![("MDCA", "not_entered"), ("MDCP", "not_entered"), ("MDCY", "not_entered"), ("MDCZ", "not_entered"), ("MDCK", "grouped")],
+            order
+        );
+    }
+
+    #[test]
+    fn test_drg_case_builder() {
+        // 测试只设置必填字段时构造器使用默认值
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").build();
+        assert_eq!("0001", case.id);
+        assert_eq!(UNKNOWN_SEX, case.sex);
+        assert_eq!(DEFAULT_WEIGHT, case.weight);
+        assert!(case.dis.secondary().is_empty());
+        assert!(case.dis.all().contains("I50.900x018"));
+
+        let case = DrgCase::builder()
+            .main_dis("I50.900x018")
+            .main_opt("96.7101")
+            .other_dis(["E16.200", "E87.201"])
+            .sex(1)
+            .build();
+        assert_eq!(3, case.dis.all().len());
+        assert!(case.opt.all().contains("96.7101"));
+    }
+
+    #[test]
+    fn test_join_input_round_trip() {
+        // 测试输出文件中原始的列和单元格内容与输入完全一致, 末尾追加分组结果列
+        let tmp = TestDir::new("test_join_input_round_trip");
+        let in_file_path = tmp.join("join_input_cases.csv");
+        fs::write(
+            &in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight,备注\n\
+             0001,I50.900x018,96.7101,E16.200|E87.201,38.9302|96.0400,1,12.50,1203,门诊转入\n\
+             0002,I50.900x018,,,,0,060,0,\n",
+        )
+        .unwrap();
+        let out_file_path = tmp.join("join_input_out.csv");
+        batch_join_process(in_file_path.to_str().unwrap(), out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+
+        let mut in_rdr = csv::Reader::from_path(&in_file_path).unwrap();
+        let mut out_rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let in_headers = in_rdr.headers().unwrap().clone();
+        let out_headers = out_rdr.headers().unwrap().clone();
+        assert_eq!(in_headers.len() + 5, out_headers.len());
+        assert_eq!(vec!["mdc", "adrg", "drg", "rw", "severity"], out_headers.iter().skip(in_headers.len()).collect::<Vec<_>>());
+        let in_rows: Vec<csv::StringRecord> = in_rdr.records().map(|x| x.unwrap()).collect();
+        let out_rows: Vec<csv::StringRecord> = out_rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(in_rows.len(), out_rows.len());
+        for (in_row, out_row) in in_rows.iter().zip(out_rows.iter()) {
+            for (i, cell) in in_row.iter().enumerate() {
+                assert_eq!(cell.as_bytes(), out_row[i].as_bytes());
+            }
+        }
+
+        // 年龄为天数的文件与--batch一样推断为天数, 4天的新生儿进入MDCP(主诊断只属于MDCN, 男性不进入, 不覆盖MDCP的结果), 输出的age列仍为原始值
+        let days_file_path = tmp.join("join_input_days.csv");
+        fs::write(
+            &days_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+             0001,N70.000,34.0200x003,,,1,4,3000\n\
+             0002,I50.900x018,,,,1,21900,0\n\
+             0003,I50.900x018,,,,0,14600,0\n",
+        )
+        .unwrap();
+        let days_path = days_file_path.to_str().unwrap();
+        batch_join_process(days_path, out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        let batch_out_path = tmp.join("join_input_batch_out.csv");
+        batch_drg_process(read_input_cases(days_path, &CliOptions::default()).unwrap(), batch_out_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        let column = |path: &PathBuf, name: &str| -> Vec<String> {
+            let mut rdr = csv::Reader::from_path(path).unwrap();
+            let i = rdr.headers().unwrap().iter().position(|x| x == name).unwrap();
+            rdr.records().map(|x| x.unwrap()[i].to_string()).collect()
+        };
+        let joined = column(&out_file_path, "drg");
+        assert_eq!(column(&batch_out_path, "code"), joined);
+        assert_eq!("MDCP", column(&out_file_path, "mdc")[0]);
+        assert_eq!(vec!["4", "21900", "14600"], column(&out_file_path, "age"));
+        // 按年处理时结果不同
+        let years = CliOptions { age_unit: Some(String::from("years")), ..Default::default() };
+        batch_join_process(days_path, out_file_path.to_str().unwrap(), &years).unwrap();
+        assert_ne!(joined, column(&out_file_path, "drg"));
+    }
+
+    #[test]
+    fn test_is_contain_dis_main_or_other() {
+        // 测试主诊断或其他诊断之一在表中即可进入ADRG
+        let adrg_dis_opt = parse_adrg_dis_opt_sheet(&fs::read(Path::new(DATA_DIR).join("adrg_dis_opt_sheet.json")).unwrap()).unwrap();
+        let main_only = DrgCase::builder().main_dis("P07.200").other_dis(["E16.200"]).age(0.01).build();
+        assert_eq!("PS4", is_contain_dis(&main_only, &adrg_dis_opt, String::from("PS4")).unwrap());
+
+        let other_only = DrgCase::builder().main_dis("E16.200").other_dis(["P07.200"]).age(0.01).build();
+        assert_eq!("PS4", is_contain_dis(&other_only, &adrg_dis_opt, String::from("PS4")).unwrap());
+
+        let neither = DrgCase::builder().main_dis("E16.200").other_dis(["E87.201"]).age(0.01).build();
+        assert_eq!("KBBZ", is_contain_dis(&neither, &adrg_dis_opt, String::from("PS4")).unwrap());
+    }
+
+    #[test]
+    fn test_qy_with_other_operation_only() {
+        // 测试没有主手术但其他手术有效时, 默认不判定为QY, --qy-all-ops时内科ADRG判定为QY
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder()
+            .id("0005")
+            .main_dis("I50.900x018")
+            .other_opt(["96.7101"])
+            .sex(1)
+            .age(60.0)
+            .build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR2", result.adrg);
+
+        let all_ops = GroupOptions { qy_all_ops: true, ..Default::default() };
+        let result = scheme.group(&case, &all_ops).unwrap();
+        assert_eq!("FQY", result.adrg);
+        assert!(result.review_flags.contains(&String::from("qy_adjusted")));
+    }
+
+    #[test]
+    fn test_no_qy() {
+        // 测试--no-qy时QY病例保留原ADRG并按原ADRG进入DRG
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder()
+            .id("0006")
+            .main_dis("I50.900x018")
+            .main_opt("78.6201")
+            .sex(1)
+            .age(60.0)
+            .build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FQY", result.drg);
+
+        let no_qy = GroupOptions { no_qy: true, ..Default::default() };
+        let result = scheme.group(&case, &no_qy).unwrap();
+        assert_eq!("FR2", result.adrg);
+        assert!(result.drg.starts_with("FR2"));
+        assert!(!result.review_flags.contains(&String::from("qy_adjusted")));
+    }
+
+    #[test]
+    fn test_diagnoses_in_mdc() {
+        // 测试主诊表的反向索引
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert!(scheme.diagnoses_in_mdc("MDCF").binary_search(&String::from("I50.900x018")).is_ok());
+        // 同时属于MDCM和MDCN的诊断在两个MDC下都能查到
+        let both = scheme.main_dis_sheet.iter().find(|(_, v)| v.len() > 1).unwrap();
+        for mdc in both.1 {
+            assert!(scheme.diagnoses_in_mdc(mdc).contains(both.0));
+        }
+        assert!(scheme.diagnoses_in_mdc("NOT_A_MDC").is_empty());
+    }
+
+    #[test]
+    fn test_emit_counts() {
+        // 测试--emit-counts时输出诊断数和手术数, 默认不输出
+        let tmp = TestDir::new("test_emit_counts");
+        let case = DrgCase::builder()
+            .id("0001")
+            .main_dis("I50.900x018")
+            .other_dis(["E16.200", "E87.201"])
+            .other_opt(["96.7101"])
+            .build();
+        let out_file_path = tmp.join("drg_counts_out.csv");
+        let options = CliOptions { emit_counts: true, ..Default::default() };
+        batch_drg_process(vec![case], out_file_path.to_str().unwrap(), &options).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let row = rdr.records().next().unwrap().unwrap();
+        let n_dis = headers.iter().position(|h| h == "n_dis").unwrap();
+        let n_opt = headers.iter().position(|h| h == "n_opt").unwrap();
+        assert_eq!("3", &row[n_dis]);
+        assert_eq!("1", &row[n_opt]);
+
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").build();
+        batch_drg_process(vec![case], out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        assert!(!rdr.headers().unwrap().iter().any(|h| h == "n_dis"));
+    }
+
+    #[test]
+    fn test_require_columns() {
+        // 测试表头检查列出找到的、缺少的和多余的列
+        let tmp = TestDir::new("test_require_columns");
+        let in_file_path = tmp.join("require_columns_cases.csv");
+        fs::write(&in_file_path, "病案号,main_dis,main_opt,other_dis,sex,age,weight,备注\n").unwrap();
+        let column_map = HashMap::from([(String::from("病案号"), String::from("id"))]);
+        let check = check_columns(in_file_path.to_str().unwrap(), &column_map).unwrap();
+        assert!(check.matched.contains(&(String::from("病案号"), String::from("id"))));
+        assert_eq!(vec![String::from("other_opt")], check.missing);
+        assert_eq!(vec![String::from("备注")], check.extra);
+        let err = require_columns(in_file_path.to_str().unwrap(), &column_map).unwrap_err().to_string();
+        assert!(err.contains("other_opt"));
+
+        assert!(require_columns("case_data/test_case_data.csv", &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_progress_line() {
+        // 测试进度行的内容
+        let mut progress = Progress::new(4);
+        progress.tick();
+        progress.tick();
+        assert!(progress.line().starts_with("2/4 (50%)"));
+        assert!(progress.line().contains("ETA"));
+    }
+
+    #[test]
+    fn test_empty_mdc_list_in_main_dis_sheet() {
+        // 主诊表中对应空MDC列表的诊断: 读取时检查报错, 分组时进入KBBZ而不会panic
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.main_dis_sheet.insert(String::from("I50.900x018"), vec![]);
+        assert!(validate_main_dis_sheet(&scheme.main_dis_sheet).is_err());
+
+        let case = DrgCase::builder().id("0007").main_dis("I50.900x018").sex(0).age(60.0).build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("KBBZ", result.drg);
+        assert_eq!("KBBZ", is_common_mdc(&case, &scheme.main_dis_sheet, String::from("MDCF")));
+        assert_eq!("KBBZ", is_mdcn(&case, &scheme.main_dis_sheet, String::from("MDCN")));
+
+        let strict = GroupOptions { strict: true, ..Default::default() };
+        assert!(scheme.group(&case, &strict).unwrap_err().to_string().contains("empty MDC list"));
+    }
+
+    #[test]
+    fn test_diff_schemes() {
+        // 测试对比两个版本的分组方案
+        let old = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let mut new = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert!(diff_schemes(&old, &new).added_adrgs.is_empty());
+
+        new.adrg_type_dict.remove("FR2");
+        new.adrg_drg_name_sheet.remove("FR2");
+        new.adrg_type_dict.insert(String::from("FR9"), String::from("is_contain_main_dis"));
+        new.adrg_drg_name_sheet.insert(String::from("FR9"), vec![String::from("FR91"), String::from("FR95")]);
+        new.adrg_type_dict.insert(String::from("AA1"), String::from("is_contain_main_dis"));
+        new.main_dis_sheet.insert(String::from("I50.900x018"), vec![String::from("MDCE")]);
+
+        let diff = diff_schemes(&old, &new);
+        assert_eq!(vec![String::from("FR9")], diff.added_adrgs);
+        assert_eq!(vec![String::from("FR2")], diff.removed_adrgs);
+        assert_eq!("AA1", diff.changed_conditions[0].0);
+        assert_eq!(vec![String::from("MDCE")], diff.moved_diagnoses[0].2);
+        assert_eq!(vec![String::from("FR91"), String::from("FR95")], diff.added_drgs);
+        assert_eq!(vec![String::from("FR21"), String::from("FR25")], diff.removed_drgs);
+    }
+
+    #[test]
+    fn test_distinct_simultaneous_opt() {
+        // 测试唯一的手术同时在两个手术表中时, 严格的解释下不能入组
+        let mut adrg_dis_opt = parse_adrg_dis_opt_sheet(&fs::read(Path::new(DATA_DIR).join("adrg_dis_opt_sheet.json")).unwrap()).unwrap();
+        let shared = adrg_table(&adrg_dis_opt, "FB1", "normal_list").unwrap().iter().next().unwrap().to_string();
+        adrg_dis_opt.get_mut("FB1").unwrap().get_mut("other_list").unwrap().insert(shared.clone());
+        let case = DrgCase::builder().main_dis("I50.900x018").main_opt(shared.as_str()).build();
+
+        let lenient = GroupOptions::default();
+        assert_eq!("FB1", is_contain_opt_simultaneously(&case, &adrg_dis_opt, String::from("FB1"), &lenient).unwrap());
+        let distinct = GroupOptions { distinct_simultaneous_opt: true, ..Default::default() };
+        assert_eq!("KBBZ", is_contain_opt_simultaneously(&case, &adrg_dis_opt, String::from("FB1"), &distinct).unwrap());
+
+        // 再加上一个第二个表中的手术即可入组
+        let other = adrg_table(&adrg_dis_opt, "FB1", "other_list").unwrap().iter().find(|x| **x != shared).unwrap().to_string();
+        let case = DrgCase::builder().main_dis("I50.900x018").main_opt(shared.as_str()).other_opt([other]).build();
+        assert_eq!("FB1", is_contain_opt_simultaneously(&case, &adrg_dis_opt, String::from("FB1"), &distinct).unwrap());
+    }
+
+    #[test]
+    fn test_group_batch_with_stats() {
+        // 测试批量分组的汇总统计与逐个病例的结果一致
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let (results, stats) = group_batch_with_stats(&cases_vec, &scheme, &GroupOptions::default()).unwrap();
+        assert_eq!(cases_vec.len(), results.len());
+        assert_eq!(cases_vec.len(), stats.total);
+        assert_eq!(stats.total, stats.drg_counts.values().sum::<usize>());
+        assert_eq!(stats.total, stats.mdc_counts.values().sum::<usize>());
+        assert_eq!(results.iter().filter(|x| x.drg == "KBBZ").count(), stats.kbbz_count);
+        for (case, result) in cases_vec.iter().zip(results.iter()) {
+            assert_eq!(scheme.group(case, &GroupOptions::default()).unwrap().drg, result.drg);
+        }
+    }
+
+    #[test]
+    fn test_limit_mdc_loop() {
+        // 测试MDC下的ADRG列表过长时, 超过上限后进入KBBZ
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let mut huge: Vec<String> = (0..100000).map(|i| format!("F{}", i)).collect();
+        huge.extend(scheme.mdc_sub_adrg["MDCF"].iter().cloned());
+        scheme.mdc_sub_adrg.insert(String::from("MDCF"), huge);
+        let case = DrgCase::builder().id("0008").main_dis("I50.900x018").sex(1).age(60.0).build();
+
+        let limited = GroupOptions { max_candidates: Some(500), ..Default::default() };
+        let result = scheme.group(&case, &limited).unwrap();
+        assert_eq!("KBBZ", result.drg);
+        assert_eq!(vec![String::from("too_many_candidates")], result.review_flags);
+
+        // 没有上限时最终仍能进入真实的ADRG
+        let (result, trace) = scheme.group_with_trace(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR2", result.adrg);
+        // 判断路径中记录了扫描大量ADRG的耗时
+        assert!(trace.which_adrg_us > 0);
+
+        // 上限正好等于尝试的ADRG数时不触发
+        let exact = GroupOptions { max_candidates: Some(trace.adrg_tried.len()), ..Default::default() };
+        assert_eq!("FR2", scheme.group(&case, &exact).unwrap().adrg);
+    }
+
+    #[test]
+    fn test_trace_matched_tables() {
+        // 测试判断路径中列出入组ADRG命中的表及编码
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0009").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        let (result, trace) = scheme.group_with_trace(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FR2", result.adrg);
+        let hit = trace.matched_tables.iter().find(|x| x.codes.contains(&String::from("I50.900x018"))).unwrap();
+        assert!(hit.key.starts_with("FR2"));
+        assert!(!hit.codes.contains(&String::from("")));
+        let json = serde_json::to_string(&trace).unwrap();
+        assert!(json.contains("\"which_adrg_us\"") && json.contains("\"process_drg_us\""));
+    }
+
+    #[test]
+    fn test_explain_format() {
+        // 测试判断路径的文本格式和JSON格式的固定字段
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0009").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        let (_, trace) = scheme.group_with_trace(&case, &GroupOptions::default()).unwrap();
+        let text = trace.render("text").unwrap();
+        assert!(text.starts_with("case 0009"));
+        assert!(text.contains("\n  MDC: MDCF"));
+        assert!(text.contains("\n  ADRG: FR2"));
+        assert!(text.contains("    MDCA: "));
+
+        let json: serde_json::Value = serde_json::from_str(&trace.render("json").unwrap()).unwrap();
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        let expected = ["id", "pre_mdc_checks", "adrg_tried", "mdc", "pre_qy_adrg", "adrg", "qy_adjusted", "ccmcc",
+            "severity", "drg", "matched_tables", "which_adrg_us", "process_drg_us", "local_override", "complications", "adrg_alternatives", "mdc_order"];
+        let mut sorted_expected: Vec<&str> = expected.to_vec();
+        sorted_expected.sort();
+        assert_eq!(sorted_expected, keys.iter().map(|x| x.as_str()).collect::<Vec<&str>>());
+        assert_eq!("FR2", json["adrg"]);
+    }
+
+    #[test]
+    fn test_malformed_ccmcc_entry() {
+        // 测试CC/MCC条目只有一项时报错而不会panic
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.ccmcc_sheet.insert(String::from("A02.100"), vec![String::from("MCC")]);
+        assert!(validate_ccmcc_sheet(&scheme.ccmcc_sheet).unwrap_err().to_string().contains("A02.100"));
+
+        let case = DrgCase::builder().id("0010").main_dis("I50.900x018").other_dis(["A02.100"]).sex(1).age(60.0).build();
+        let err = scheme.group(&case, &GroupOptions::default()).unwrap_err().to_string();
+        assert!(err.contains("0010"));
+        assert!(err.contains("A02.100"));
+    }
+
+    #[test]
+    fn test_parse_json_case() {
+        // 测试从JSON字符串解析单个病例
+        let case = parse_json_case(
+            r#"{"id":"0001","main_dis":"I50.900x018","main_opt":"96.7101","other_dis":["E16.200"],"sex":1,"age":12,"weight":1203}"#,
+            &CliOptions::default(),
+        )
+        .unwrap();
+        assert_eq!("0001", case.id);
+        assert_eq!(vec![String::from("E16.200")], case.dis.secondary());
+        assert!(case.opt.all().contains("96.7101"));
+        assert_eq!(12.0, case.age);
+
+        let case = parse_json_case(r#"{"main_dis":"I50.900x018"}"#, &CliOptions::default()).unwrap();
+        assert_eq!(UNKNOWN_SEX, case.sex);
+        assert!(parse_json_case(r#"{"id":"0001"}"#, &CliOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_strict_age_for_mdcp() {
+        // 测试年龄为0.01但没有新生儿佐证的病例在--strict-age-for-mdcp时不进入MDCP
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        // 主诊断所在的MDCI在之后判断, 分组结果由MDCI给出, 这里按判断路径检查是否进入了MDCP
+        let mdcp_result = |case: &DrgCase, options: &GroupOptions| {
+            let (_, trace) = scheme.group_with_trace(case, options).unwrap();
+            trace.mdc_order.into_iter().find(|x| x.mdc == "MDCP").unwrap().result
+        };
+        let case = DrgCase::builder().id("0011").main_dis("Q86.000").sex(1).age(0.01).weight(0).build();
+        assert_ne!("not_entered", mdcp_result(&case, &GroupOptions::default()));
+
+        let strict_age = GroupOptions { strict_age_for_mdcp: true, ..Default::default() };
+        assert_eq!("not_entered", mdcp_result(&case, &strict_age));
+
+        // 有出生体重时仍进入MDCP
+        let newborn = DrgCase::builder().id("0012").main_dis("Q86.000").sex(1).age(0.01).weight(3000).build();
+        assert_ne!("not_entered", mdcp_result(&newborn, &strict_age));
+    }
+
+    #[test]
+    fn test_code_list_semantics() {
+        // 主编码为空时不计入all(), secondary_set()与secondary()一致
+        let opt = Operations::new(String::new(), vec![String::from("00.4000"), String::from("00.4000")]);
+        assert_eq!("", opt.principal());
+        assert!(opt.no_principal());
+        assert_eq!(2, opt.secondary().len());
+        assert_eq!(1, opt.secondary_set().len());
+        assert_eq!(1, opt.all().len());
+        assert!(!opt.all().contains(""));
+
+        let case = DrgCase::new(
+            String::from("0001"),
+            String::from("I50.900x018"),
+            String::new(),
+            vec![String::from("E16.200"), String::from("I50.900x018")],
+            vec![],
+            1,
+            60.0,
+            0,
+        );
+        assert_eq!("I50.900x018", case.dis.principal());
+        assert_eq!(vec![String::from("E16.200")], case.dis.secondary());
+        assert!(case.dis.secondary_set().contains("E16.200"));
+        assert_eq!(2, case.dis.all().len());
+        assert!(case.no_surgery());
+        assert!(case.opt.all().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "zip")]
+    fn test_load_scheme_from_zip() {
+        let files: Vec<(String, Vec<u8>)> = SCHEME_FILES.iter()
+            .map(|x| (format!("scheme_1.1/{}", x), fs::read(Path::new(DATA_DIR).join(x)).unwrap()))
+            .collect();
+        let from_zip = GroupingScheme::load_zip_bytes(&stored_zip(&files)).unwrap();
+        let from_dir = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert_eq!(from_dir.all_drg_codes(), from_zip.all_drg_codes());
+        let case = DrgCase::new(
+            String::from("0001"), String::from("I50.900x018"), String::new(),
+            vec![String::from("E16.200")], vec![], 1, 60.0, 0,
+        );
+        let options = GroupOptions::default();
+        assert_eq!(from_dir.group(&case, &options).unwrap().drg, from_zip.group(&case, &options).unwrap().drg);
+
+        // 缺少文件时报出缺少的文件名
+        let err = GroupingScheme::load_zip_bytes(&stored_zip(&files[1..])).err().unwrap().to_string();
+        assert!(err.contains(SCHEME_FILES[0]));
+    }
+
+    #[test]
+    fn test_adrg_categories() {
+        // 测试重新划分ADRG类型后, 外科ADRG按内科处理并触发QY调整
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0010").main_dis("Z47.001").main_opt("78.6201").sex(1).age(26.0).build();
+        let options = GroupOptions::default();
+        assert_eq!("IF1", scheme.group(&case, &options).unwrap().adrg);
+        assert_eq!(Some("surgery"), scheme.adrg_categories.drg_type("IF1"));
+        assert_eq!(None, scheme.adrg_categories.drg_type("KBBZ"));
+
+        scheme.adrg_categories = AdrgCategories {
+            surgery: String::from("ABCDEGHIJ"),
+            operation: String::from("KLMNOPQ"),
+            internal: String::from("FRSTUVWXYZ"),
+        };
+        let result = scheme.group(&case, &options).unwrap();
+        assert_eq!("IQY", result.adrg);
+        assert!(result.review_flags.contains(&String::from("qy_adjusted")));
+        assert!(scheme.all_drg_codes().contains(&String::from("IQY")));
+    }
+
+    #[test]
+    fn test_drg_type_from_scheme() {
+        // 测试ADRG类型来自分组方案的adrg_categories.json, 把B划为内科后FB2按内科处理
+        let members = fixture_members();
+        let default_scheme = GroupingScheme::load(&EmbeddedSource::new(&members)).unwrap();
+        assert_eq!("surgery", DrgFunc::drg_type("FB2", &default_scheme.adrg_categories));
+        let mut members = fixture_members();
+        members.push(("adrg_categories.json", br#"{"surgery": "ACDEFGHIJ", "operation": "KLMNOPQ", "internal": "BRSTUVWXYZ"}"#));
+        let scheme = GroupingScheme::load(&EmbeddedSource::new(&members)).unwrap();
+        assert_eq!("internal", DrgFunc::drg_type("FB2", &scheme.adrg_categories));
+        assert_eq!("internal", DrgFunc::drg_type("FR2", &scheme.adrg_categories));
+        assert_eq!("KBBZ", DrgFunc::drg_type("KBBZ", &scheme.adrg_categories));
+        assert_eq!("QY", DrgFunc::drg_type("FQY", &scheme.adrg_categories));
+        // 划分中没有的字母
+        let partial = AdrgCategories { surgery: String::from("B"), operation: String::new(), internal: String::from("R") };
+        assert_eq!("other", DrgFunc::drg_type("FM1", &partial));
+    }
+
+    #[test]
+    fn test_missing_adrg_drg_list() {
+        // 测试入组ADRG没有DRG列表时进入KBBZ而不会panic, 严格模式下报错, 读取时能检查出来
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.adrg_drg_name_sheet.remove("FR2");
+        scheme.adrg_drg_levels.remove("FR2");
+        let err = validate_adrg_drg_lists(&scheme.adrg_type_dict, &scheme.adrg_drg_name_sheet).unwrap_err().to_string();
+        assert!(err.contains("FR2"));
+
+        let case = DrgCase::builder().id("0012").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("KBBZ", result.drg);
+        assert_eq!(vec![String::from("no_drg_list_for_adrg")], result.review_flags);
+
+        let strict = GroupOptions { strict: true, ..Default::default() };
+        let err = scheme.group(&case, &strict).unwrap_err().to_string();
+        assert!(err.contains("0012") && err.contains("FR2"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_mdc_adrg_list() {
+        // 测试主诊断所在MDC没有ADRG列表时进入KBBZ而不会panic, 读取时能检查出来
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.mdc_sub_adrg.remove("MDCF");
+        let err = validate_mdc_sub_adrg(&scheme.main_dis_sheet, &scheme.mdc_sub_adrg).unwrap_err().to_string();
+        assert!(err.contains("MDCF"));
+
+        let case = DrgCase::builder().id("0011").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("KBBZ", result.drg);
+        assert_eq!(vec![String::from("no_adrg_list_for_mdc")], result.review_flags);
+        let strict = GroupOptions { strict: true, ..Default::default() };
+        assert!(scheme.group(&case, &strict).is_err());
+    }
+
+    #[test]
+    fn test_merge_outputs() {
+        // 测试合并分组输出: 列顺序不同也能合并, ID重复时报错或保留最后一行, 列不同时报错
+        let tmp = TestDir::new("test_merge_outputs");
+        let dir = tmp.path();
+        let a = dir.join("merge_a.csv");
+        let b = dir.join("merge_b.csv");
+        let c = dir.join("merge_c.csv");
+        let out = dir.join("merge_out.csv");
+        fs::write(&a, "id,main_dis,code\n0001,I50.900x018,FR25\n0002,K92.208,GS15\n").unwrap();
+        fs::write(&b, "code,id,main_dis\nKBBZ,0002,K92.208\nIF19,0003,Z47.001\n").unwrap();
+        fs::write(&c, "id,code\n0004,FR25\n").unwrap();
+        let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+
+        let err = merge_outputs(&[path(&a), path(&b)], out.to_str().unwrap(), false).unwrap_err();
+        assert!(err.to_string().contains("duplicate case id 0002"));
+
+        let collisions = merge_outputs(&[path(&a), path(&b)], out.to_str().unwrap(), true).unwrap();
+        assert_eq!(vec![String::from("0002")], collisions);
+        assert_eq!(
+            "id,main_dis,code\n0001,I50.900x018,FR25\n0002,K92.208,KBBZ\n0003,Z47.001,IF19\n",
+            fs::read_to_string(&out).unwrap()
+        );
+
+        // 合并失败时已有的输出文件不变, 也不留下临时文件
+        let merged = fs::read_to_string(&out).unwrap();
+        assert!(merge_outputs(&[path(&a), path(&c)], out.to_str().unwrap(), true).is_err());
+        assert_eq!(merged, fs::read_to_string(&out).unwrap());
+        assert!(fs::read_dir(dir).unwrap().all(|x| !x.unwrap().file_name().to_string_lossy().ends_with(".tmp")));
+
+        // 输出文件也可以是输入之一, 读完全部输入后才替换
+        merge_outputs(&[path(&a), path(&out)], out.to_str().unwrap(), true).unwrap();
+        assert_eq!(merged, fs::read_to_string(&out).unwrap());
+    }
+
+    #[test]
+    fn test_case_hash() {
+        // 测试哈希不受其他诊断顺序和病例ID影响, 分组相关的字段变化时哈希变化
+        let tmp = TestDir::new("test_case_hash");
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200", "E87.201"]).sex(1).age(60.0).build();
+        let reordered = DrgCase::builder().id("0002").main_dis("I50.900x018").other_dis(["E87.201", "E16.200"]).sex(1).age(60.0).build();
+        let newborn = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200", "E87.201"]).sex(1).age(0.05).build();
+        let swapped = DrgCase::builder().id("0001").main_dis("E16.200").other_dis(["I50.900x018", "E87.201"]).sex(1).age(60.0).build();
+        assert_eq!(16, case.case_hash(&scheme, false).len());
+        assert_eq!(case.case_hash(&scheme, false), reordered.case_hash(&scheme, false));
+        assert_ne!(case.case_hash(&scheme, false), newborn.case_hash(&scheme, false));
+        assert_ne!(case.case_hash(&scheme, false), swapped.case_hash(&scheme, false));
+
+        // 同一年龄分段和体重分段内的年龄和体重哈希相同, 跨过新生儿的年龄上限时不同
+        let older = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200", "E87.201"]).sex(1).age(61.5).build();
+        assert_eq!(case.case_hash(&scheme, false), older.case_hash(&scheme, false));
+        let hash_at = |scheme: &GroupingScheme, age: f64, weight: i32| DrgCase::builder().id("0003").main_dis("P22.000").sex(1).age(age).weight(weight).build().case_hash(scheme, false);
+        assert_eq!(hash_at(&scheme, 0.01, 2288), hash_at(&scheme, 0.07, 3100));
+        assert_eq!(hash_at(&scheme, 0.08, 2288), hash_at(&scheme, 0.5, 2288));
+        assert_ne!(hash_at(&scheme, 0.07, 2288), hash_at(&scheme, 0.08, 2288));
+        assert_ne!(hash_at(&scheme, 0.07, 2288), hash_at(&scheme, 0.07, 0));
+        let expected = case.case_hash(&scheme, false);
+        // ADRG的年龄范围也是分界, 范围内外的年龄哈希不同
+        scheme.adrg_dis_opt.get_mut("BR1").unwrap().insert(String::from("age_range"), HashSet::from([String::from("0-18")]));
+        scheme.age_thresholds = age_thresholds(&scheme.adrg_dis_opt, &scheme.ccmcc_sheet);
+        assert_eq!(vec![0.0, NEWBORN_MAX_AGE, 18.0], scheme.age_thresholds);
+        assert_eq!(hash_at(&scheme, 10.0, 0), hash_at(&scheme, 17.9, 0));
+        assert_ne!(hash_at(&scheme, 17.9, 0), hash_at(&scheme, 18.0, 0));
+
+        let out_file_path = tmp.join("drg_hash_out.csv");
+        let options = CliOptions { emit_hash: true, ..Default::default() };
+        batch_drg_process(vec![case], out_file_path.to_str().unwrap(), &options).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let col = rdr.headers().unwrap().iter().position(|h| h == "case_hash").unwrap();
+        assert_eq!(expected, rdr.records().next().unwrap().unwrap()[col]);
+    }
+
+    #[test]
+    fn test_suggest_main_swap() {
+        // 测试主诊断无法入组时, 给出换用其他诊断作为主诊断后能入组的DRG, 原分组结果不变
+        let tmp = TestDir::new("test_suggest_main_swap");
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let case = DrgCase::builder().id("0012").main_dis("X99.999").other_dis(["I50.900x018"]).sex(1).age(60.0).build();
+        assert_eq!("KBBZ", scheme.group(&case, &options).unwrap().drg);
+        let suggestions = scheme.suggest_main_swap(&case, &options).unwrap();
+        assert_eq!(1, suggestions.len());
+        assert_eq!("I50.900x018", suggestions[0].0);
+        assert!(suggestions[0].1.starts_with("FR2"));
+
+        let grouped = DrgCase::builder().id("0013").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let out_file_path = tmp.join("drg_swap_out.csv");
+        let cli = CliOptions { suggest_main_swap: true, ..Default::default() };
+        batch_drg_process(vec![case, grouped], out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let col = headers.iter().position(|h| h == "main_swap_suggestion").unwrap();
+        let code = headers.iter().position(|h| h == "code").unwrap();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!("KBBZ", &rows[0][code]);
+        assert!(rows[0][col].starts_with("I50.900x018:FR2"));
+        assert_eq!("", &rows[1][col]);
+    }
+
+    #[test]
+    fn test_mdca_operation_entry() {
+        // MDCA没有主诊表, 只按手术入组: 主诊断用一个与MDCA无关的诊断
+        // 每个ADRG给出一个能入组的病例(主手术, 其他手术, 其他诊断)和一个不能入组的病例
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let mdca = |main_opt: &str, other_opt: &[&str], other_dis: &[&str]| {
+            let case = DrgCase::builder()
+                .id("MDCA")
+                .main_dis("I50.900x018")
+                .main_opt(main_opt)
+                .other_opt(other_opt.iter().copied())
+                .other_dis(other_dis.iter().copied())
+                .sex(1)
+                .age(60.0)
+                .build();
+            is_mdca(&case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdc_sub_adrg, String::from("MDCA"), &options).unwrap()
+        };
+        // 主手术入组的ADRG: 主手术在表中时进入, 同样的手术只作为其他手术时不进入
+        let main_opt_cases = [
+            ("AA1", "33.6x00"),
+            ("AA2", "37.5100"),
+            ("AB1", "50.5100"),
+            ("AD1", "52.8000"),
+            ("AE1", "55.6100"),
+            ("AF1", "33.5000"),
+            ("AG1", "41.0200"),
+            ("AG2", "41.0300"),
+            ("AG3", "41.0000"),
+            ("AH1", "37.5200x001"),
+        ];
+        for (adrg, opt) in main_opt_cases {
+            assert_eq!(adrg, mdca(opt, &[], &[]), "{} should enter with main operation {}", adrg, opt);
+            assert_eq!("KBBZ", mdca("00.0000", &[opt], &[]), "{} should not enter with other operation {}", adrg, opt);
+        }
+        // AC1需要同时有两个表中的手术, 只有一个时进入AD1
+        assert_eq!("AC1", mdca("52.8000", &["55.6100"], &[]));
+        assert_eq!("AD1", mdca("52.8000", &[], &[]));
+        // AH2需要其他诊断Z93.000或手术表1中的手术, 同时需要手术表2中的手术
+        assert_eq!("AH2", mdca("96.7201", &[], &["Z93.000"]));
+        assert_eq!("KBBZ", mdca("96.7201", &[], &[]));
+        // 没有主手术时不进入MDCA, 即使其他手术符合
+        assert_eq!("KBBZ", mdca("", &["33.6x00"], &[]));
+    }
+
+    #[test]
+    fn test_mdca_list_from_scheme() {
+        // 测试分组方案在MDCA下增加ADRG后, is_mdca会判断新的ADRG
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0014").main_dis("I50.900x018").main_opt("99.9999").sex(1).age(60.0).build();
+        let options = GroupOptions::default();
+        let mdca = |scheme: &GroupingScheme| {
+            is_mdca(&case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, &scheme.mdc_sub_adrg, String::from("MDCA"), &options).unwrap()
+        };
+        assert_eq!("KBBZ", mdca(&scheme));
+
+        let tables = HashMap::from([(DEFAULT_TABLE.to_string(), HashSet::from([String::from("99.9999")]))]);
+        scheme.adrg_dis_opt.insert(String::from("AZ1"), tables);
+        scheme.adrg_type_dict.insert(String::from("AZ1"), String::from("is_contain_main_opt"));
+        assert_eq!("KBBZ", mdca(&scheme));
+        scheme.mdc_sub_adrg.get_mut("MDCA").unwrap().push(String::from("AZ1"));
+        assert_eq!("AZ1", mdca(&scheme));
+    }
+
+    #[test]
+    fn test_parallel_batch_matches_serial() {
+        // 测试多线程分组与单线程分组的输出文件逐字节相同
+        let tmp = TestDir::new("test_parallel_batch_matches_serial");
+        let in_file_path = "case_data/test_case_data.csv";
+        let serial_path = tmp.join("drg_serial_out.csv");
+        let parallel_path = tmp.join("drg_parallel_out.csv");
+        let options = CliOptions { emit_counts: true, ..Default::default() };
+        batch_drg_process(read_csv(in_file_path).unwrap(), serial_path.to_str().unwrap(), &options).unwrap();
+        let parallel = CliOptions { threads: 4, ..options };
+        batch_drg_process(read_csv(in_file_path).unwrap(), parallel_path.to_str().unwrap(), &parallel).unwrap();
+        assert_eq!(fs::read(&serial_path).unwrap(), fs::read(&parallel_path).unwrap());
+    }
+
+    #[test]
+    fn test_sample_cases_seed() {
+        // 测试相同的种子抽到相同的病例, 抽中的病例保持输入顺序
+        let ids = |cases: Vec<DrgCase>| cases.into_iter().map(|x| x.id).collect::<Vec<String>>();
+        let first = ids(sample_cases(read_csv("case_data/test_case_data.csv").unwrap(), 4, 42));
+        let second = ids(sample_cases(read_csv("case_data/test_case_data.csv").unwrap(), 4, 42));
+        assert_eq!(4, first.len());
+        assert_eq!(first, second);
+        let all = ids(read_csv("case_data/test_case_data.csv").unwrap());
+        let positions: Vec<usize> = first.iter().map(|x| all.iter().position(|y| y == x).unwrap()).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(10, sample_cases(read_csv("case_data/test_case_data.csv").unwrap(), 100, 42).len());
+    }
+
+    #[test]
+    fn test_is_valid_surgery() {
+        // 测试有效手术的判断与QY判断使用同一个列表
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert!(scheme.is_valid_surgery("03.9202"));
+        assert!(!scheme.is_valid_surgery("99.0401"));
+        let case = DrgCase::builder().main_dis("I50.900x018").main_opt("03.9202").build();
+        assert_eq!(scheme.is_valid_surgery("03.9202"), case.is_vaild_surgrey(&scheme.all_opt_list));
+    }
+
+    #[test]
+    fn test_sex_mdc_warning() {
+        // 测试同时属于MDCM和MDCN的诊断会给出警告
+        let mut main_dis_sheet: HashMap<String, Vec<String>> = HashMap::new();
+        main_dis_sheet.insert(String::from("N40.x00"), vec![String::from("MDCM")]);
+        assert!(sex_mdc_warning(&main_dis_sheet).is_none());
+        main_dis_sheet.insert(String::from("D36.700x021"), vec![String::from("MDCM"), String::from("MDCN")]);
+        let warning = sex_mdc_warning(&main_dis_sheet).unwrap();
+        assert!(warning.contains("D36.700x021"));
+        assert!(!warning.contains("N40.x00"));
+        // 读取分组方案时不直接输出警告, 而是返回给调用方, 与validate-scheme给出的警告相同
+        let source = DirSource::new(DATA_DIR);
+        let scheme = GroupingScheme::load(&source).unwrap();
+        assert_eq!(1, scheme.warnings.len());
+        assert!(scheme.warnings[0].starts_with("14 diagnoses in main_dis_sheet are mapped to both MDCM and MDCN"), "{}", scheme.warnings[0]);
+        assert_eq!(scheme.warnings, scheme_warnings(&source));
+        assert!(fixture_scheme().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_baseline_changed_only() {
+        // 测试只输出与基线不同的病例, 并标注新旧分组编码
+        let tmp = TestDir::new("test_baseline_changed_only");
+        let in_file_path = "case_data/test_case_data.csv";
+        let baseline_path = tmp.join("drg_baseline.csv");
+        batch_drg_process(read_csv(in_file_path).unwrap(), baseline_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        // 修改基线中一个病例的分组编码
+        let baseline = fs::read_to_string(&baseline_path).unwrap().replacen(",XJ15\n", ",XJ13\n", 1);
+        fs::write(&baseline_path, baseline).unwrap();
+
+        let out_file_path = tmp.join("drg_baseline_out.csv");
+        let options = CliOptions { baseline: Some(baseline_path.to_str().unwrap().to_string()), ..Default::default() };
+        batch_drg_process(read_csv(in_file_path).unwrap(), out_file_path.to_str().unwrap(), &options).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(1, rows.len());
+        let old_code = headers.iter().position(|h| h == "old_code").unwrap();
+        let new_code = headers.iter().position(|h| h == "new_code").unwrap();
+        assert_eq!("450800G0000539163478", &rows[0][0]);
+        assert_eq!("XJ13", &rows[0][old_code]);
+        assert_eq!("XJ15", &rows[0][new_code]);
+    }
+
+    #[test]
+    fn test_parse_i32() {
+        // 测试千分位逗号, 小数部分为0的数字和超出范围的数字
+        assert_eq!(Ok(2288), custom_deserializer::parse_i32("2,288"));
+        assert_eq!(Ok(2288), custom_deserializer::parse_i32("2288.0"));
+        assert_eq!(Ok(-1), custom_deserializer::parse_i32(" -1 "));
+        assert!(custom_deserializer::parse_i32("2288.5").unwrap_err().contains("not an integer"));
+        let err = custom_deserializer::parse_i32("99999999999").unwrap_err();
+        assert!(err.contains("out of range") && err.contains("99999999999"));
+        assert!(custom_deserializer::parse_i32("abc").is_err());
+    }
+
+    #[test]
+    fn test_local_rules() {
+        // 测试本地规则改写符合条件的病例的DRG, 并记录原DRG
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let case = DrgCase::builder().id("0015").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let other = DrgCase::builder().id("0016").main_dis("Z47.001").main_opt("78.6201").sex(1).age(26.0).build();
+        let original = scheme.group(&case, &options).unwrap();
+        let other_original = scheme.group(&other, &options).unwrap();
+        assert!(original.local_override.is_none());
+
+        scheme.local_rules = serde_json::from_str(&format!(
+            r#"[{{"name": "audit-1", "drg": "{}", "main_dis": ["I50.900x018"], "set_drg": "FR23"}}]"#,
+            original.drg
+        )).unwrap();
+        let result = scheme.group(&case, &options).unwrap();
+        assert_eq!("FR23", result.drg);
+        assert!(result.review_flags.contains(&String::from("local_override")));
+        let local_override = result.local_override.unwrap();
+        assert_eq!("audit-1", local_override.rule);
+        assert_eq!(original.drg, local_override.original_drg);
+
+        // 不符合条件的病例不受影响
+        let result = scheme.group(&other, &options).unwrap();
+        assert_eq!(other_original.drg, result.drg);
+        assert!(result.local_override.is_none());
+    }
+
+    #[test]
+    fn test_ndjson_count_only() {
+        // 测试流式读取大量NDJSON病例时按DRG计数
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let heart = r#"{"id":"h","main_dis":"I50.900x018","sex":1,"age":60}"#;
+        let unknown = r#"{"id":"u","main_dis":"X99.999"}"#;
+        let mut input = String::new();
+        for i in 0..3000 {
+            input.push_str(if i % 3 == 0 { unknown } else { heart });
+            input.push('\n');
+        }
+        input.push('\n'); // 末尾的空行跳过
+        let expected_drg = scheme.group(&parse_json_case(heart, &CliOptions::default()).unwrap(), &GroupOptions::default()).unwrap().drg;
+
+        let options = CliOptions { count_only: true, ..Default::default() };
+        let mut out: Vec<u8> = Vec::new();
+        stream_ndjson(std::io::Cursor::new(input.clone()), &mut out, &scheme, &options).unwrap();
+        let mut expected = vec![format!("{}\t2000", expected_drg), String::from("KBBZ\t1000")];
+        expected.sort();
+        assert_eq!(expected, String::from_utf8(out).unwrap().lines().collect::<Vec<&str>>());
+
+        // 不计数时每个病例一行结果
+        let mut out: Vec<u8> = Vec::new();
+        stream_ndjson(std::io::Cursor::new(input), &mut out, &scheme, &CliOptions::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(3000, out.lines().count());
+        let first: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        assert_eq!("KBBZ", first["drg"]);
+    }
+
+    #[test]
+    fn test_fast_csv_matches_serde() {
+        // 测试按列号读取与serde读取得到相同的病例
+        let in_file_path = "case_data/test_case_data.csv";
+        let options = CliOptions { fast_csv: true, ..Default::default() };
+        let fast = read_csv_mapped(in_file_path, &options).unwrap();
+        let serde = read_csv(in_file_path).unwrap();
+        assert_eq!(serde.len(), fast.len());
+        for (a, b) in serde.iter().zip(fast.iter()) {
+            assert_eq!(a.id, b.id);
+            assert_eq!(a.dis.principal(), b.dis.principal());
+            assert_eq!(a.dis.secondary(), b.dis.secondary());
+            assert_eq!(a.opt.principal(), b.opt.principal());
+            assert_eq!(a.opt.secondary(), b.opt.secondary());
+            assert_eq!((a.sex, a.age, a.weight), (b.sex, b.age, b.weight));
         }
-        _ => { println!("wrong input please check your input!!!") }
     }
-    Ok(())
-}
 
-// 功能测试=======================================
-#[cfg(test)]
-mod tests {
-    use std::{process::CommandArgs};
-    use super::*;
+    #[test]
+    #[ignore]
+    fn bench_fast_csv() {
+        // 对比大文件serde读取和按列号读取的耗时
+        // 运行方式: cargo test --release bench_fast_csv -- --ignored --nocapture
+        let tmp = TestDir::new("bench_fast_csv");
+        let rows = fs::read_to_string("case_data/test_case_data.csv").unwrap();
+        let mut lines = rows.lines();
+        let header = format!("{},{}", lines.next().unwrap(), (0..200).map(|i| format!("extra{}", i)).collect::<Vec<_>>().join(","));
+        let extra = vec!["x"; 200].join(",");
+        let body: Vec<String> = lines.map(|x| format!("{},{}", x, extra)).collect();
+        let in_file_path = tmp.join("drg_wide_cases.csv");
+        let mut contents = header + "\n";
+        for _ in 0..5000 {
+            for line in &body {
+                contents.push_str(line);
+                contents.push('\n');
+            }
+        }
+        fs::write(&in_file_path, contents).unwrap();
+        let path = in_file_path.to_str().unwrap();
+        let start = std::time::Instant::now();
+        let n = read_csv(path).unwrap().len();
+        println!("serde: {} cases in {:?}", n, start.elapsed());
+        let start = std::time::Instant::now();
+        let n = read_csv_mapped(path, &CliOptions { fast_csv: true, ..Default::default() }).unwrap().len();
+        println!("fast-csv: {} cases in {:?}", n, start.elapsed());
+    }
 
-    // #[test]
-    // fn read_adrg_dis_opt() {
-    //     // 测试读取ADRG诊断手术表是否正常
-    //     let res: HashMap<String, HashSet<String>> =
-    //         read_json_file("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     // res.expect("Reading File wrong???");
-    //     // println!("{:?}", &res["K85.001"]);
-    //     assert_eq!(true, res["AA1"].contains("33.6x00"));
-    // }
+    #[test]
+    fn test_normalize_codes() {
+        // 测试全角句号和全角空格的编码规范化后与分组方案中的半角编码一致
+        assert_eq!("I50.900x018", normalize_code("\u{3000}I50\u{FF0E}900x018 "));
+        assert_eq!("E16.200", normalize_code("Ｅ16。200"));
+
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0001").main_dis("I50\u{FF0E}900x018\u{3000}").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        assert_eq!("KBBZ", scheme.group(&case, &GroupOptions::default()).unwrap().drg);
+
+        // 分组方案中的全角编码同样被规范化
+        scheme.all_dis_list.insert(String::from("\u{FF3A}99.999"));
+        scheme.normalize_codes();
+        assert!(scheme.all_dis_list.contains("Z99.999"));
+        let options = GroupOptions { normalize_codes: true, ..Default::default() };
+        let result = scheme.group(&case, &options).unwrap();
+        assert_eq!("MDCF", result.mdc);
+        assert_eq!("FR2", result.adrg);
+    }
 
-    // #[test]
-    // fn read_adrg_to_drg() {
-    //     // 测试读取ADRG下的DRG分组列表
-    //     let res: HashMap<String, HashSet<String>> = read_json_file("data\\adrg_drg_name_sheet.json").unwrap();
-    //     assert_eq!(true, res["AA2"].contains("AA29"));
-    // }
+    #[test]
+    fn test_case_validate() {
+        // 测试分组前的病例检查, 每种问题一个病例
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let ok = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        assert!(ok.validate(&scheme).is_empty());
+
+        let empty = DrgCase::builder().id("0002").main_dis("").sex(1).age(60.0).build();
+        assert_eq!(vec![CaseIssue::EmptyMainDiagnosis], empty.validate(&scheme));
+        assert_eq!(IssueSeverity::Error, CaseIssue::EmptyMainDiagnosis.severity());
+
+        let unknown = DrgCase::builder().id("0003").main_dis("I50.900x018").other_dis(["X99.999"]).main_opt("99.9999x999").sex(1).age(60.0).build();
+        let issues = unknown.validate(&scheme);
+        assert_eq!(vec![CaseIssue::UnknownDiagnosis(String::from("X99.999")), CaseIssue::UnknownOperation(String::from("99.9999x999"))], issues);
+        assert_eq!("warning:unknown_diagnosis:X99.999", issues[0].to_string());
+
+        let swapped = DrgCase::builder().id("0008").main_dis("I50.900x018").other_dis(["96.7101"]).main_opt("E16.200").sex(1).age(60.0).build();
+        let issues = swapped.validate(&scheme);
+        assert_eq!(vec![CaseIssue::OperationAsDiagnosis(String::from("96.7101")), CaseIssue::DiagnosisAsOperation(String::from("E16.200"))], issues);
+        assert_eq!("warning:operation_as_diagnosis:96.7101", issues[0].to_string());
+
+        let sex = DrgCase::builder().id("0004").main_dis("I50.900x018").sex(3).age(60.0).build();
+        let issues = sex.validate(&scheme);
+        assert_eq!(vec![CaseIssue::InvalidSex(3)], issues);
+        assert_eq!(IssueSeverity::Error, issues[0].severity());
+
+        let conflict = DrgCase::builder().id("0005").main_dis("A18.100x019+N77.1*").sex(1).age(30.0).build();
+        let issue = CaseIssue::SexConflict { dis: String::from("A18.100x019+N77.1*"), mdc: String::from("MDCN") };
+        assert_eq!(vec![issue], conflict.validate(&scheme));
+
+        let newborn = DrgCase::builder().id("0006").main_dis("Q86.000").sex(1).age(0.01).weight(65000).build();
+        let issues = newborn.validate(&scheme);
+        assert_eq!(vec![CaseIssue::NewbornWeight(65000)], issues);
+        assert_eq!(IssueSeverity::Warning, issues[0].severity());
+        let normal = DrgCase::builder().id("0007").main_dis("Q86.000").sex(1).age(0.01).weight(3000).build();
+        assert!(normal.validate(&scheme).is_empty());
+    }
 
-    // #[test]
-    // fn read_all_icd9_and_10() {
-    //     // 测试读取所有诊断表或手术表是否正常
-    //     let res: HashSet<String> = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    //     println!("length of the file is {}", res.len());
-    //     let shit: Vec<String> = res.clone().iter().map(|x| x.to_string()).collect();
-    //     println!("the second element is {}", shit[1]);
-    //     // let test_verb = &shit[2];
-    //     let test_verb = String::from("A84.000x001");
-    //     assert_eq!(true, res.contains(&test_verb));
-    // }
+    #[test]
+    fn test_load_subset() {
+        // 测试只读取部分MDC的分组方案, 其他MDC的病例进入KBBZ
+        let scheme = GroupingScheme::load_subset(&DirSource::new(DATA_DIR), &["MDCB", "MDCC"]).unwrap();
+        assert!(scheme.mdc_sub_adrg["MDCF"].is_empty());
+        assert!(scheme.adrg_dis_opt.keys().all(|x| x.starts_with('B') || x.starts_with('C')));
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("KBBZ", result.drg);
+        assert!(!result.review_flags.contains(&String::from("no_adrg_list_for_mdc")));
+
+        // 保留的MDC与完整方案分组结果相同
+        let full = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let mdcb_dis = full.diagnoses_in_mdc("MDCB")[0].to_string();
+        let case = DrgCase::builder().id("0002").main_dis(mdcb_dis).sex(1).age(60.0).build();
+        let expected = full.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("MDCB", expected.mdc);
+        assert_eq!(expected.drg, scheme.group(&case, &GroupOptions::default()).unwrap().drg);
+
+        // 命令行的--only-mdcs按同样的方式读取, 不能与--scheme-cache同时使用
+        let args: Vec<String> = ["drg", "--only-mdcs", "mdcb, MDCC"].iter().map(|x| x.to_string()).collect();
+        let (_, options) = parse_cli_options(&args).unwrap();
+        assert_eq!(Some(vec![String::from("MDCB"), String::from("MDCC")]), options.only_mdcs);
+        let from_cli = load_scheme(&options).unwrap();
+        assert!(from_cli.mdc_sub_adrg["MDCF"].is_empty());
+        assert_eq!(scheme.adrg_dis_opt.len(), from_cli.adrg_dis_opt.len());
+        let args: Vec<String> = ["drg", "--only-mdcs", "MDCB", "--scheme-cache", "cache.bin"].iter().map(|x| x.to_string()).collect();
+        let err = load_scheme(&parse_cli_options(&args).unwrap().1).err().unwrap().to_string();
+        assert_eq!("--only-mdcs cannot be combined with --scheme-zip or --scheme-cache", err);
+    }
 
-    // #[test]
-    // fn read_mdc_main_dis() {
-    //     // 测试读取MDC主诊断表
-    //     let res = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let test_verb = "A00.100x001";
-    //     println!("target mdc is {}", res[test_verb][0]);
-    //     assert_eq!(true, res[test_verb][0] == "MDCG");
-    // }
+    #[test]
+    fn test_bundled_scheme() {
+        // 测试--bundled-scheme读取编译进程序的分组方案, 分组结果和scheme_version与data目录相同; 没有bundled特性时报错
+        let args: Vec<String> = ["drg", "--bundled-scheme", "--scheme-cache", "cache.bin"].iter().map(|x| x.to_string()).collect();
+        let err = load_scheme(&parse_cli_options(&args).unwrap().1).err().unwrap().to_string();
+        assert_eq!("--bundled-scheme cannot be combined with --scheme-zip or --scheme-cache", err);
+        let args: Vec<String> = ["drg", "--bundled-scheme"].iter().map(|x| x.to_string()).collect();
+        let (_, options) = parse_cli_options(&args).unwrap();
+        assert!(options.bundled_scheme);
+        #[cfg(not(feature = "bundled"))]
+        assert_eq!("--bundled-scheme needs the bundled feature", load_scheme(&options).err().unwrap().to_string());
+        #[cfg(feature = "bundled")]
+        {
+            assert_eq!(SCHEME_FILES.to_vec(), BUNDLED_SCHEME.iter().map(|(name, _)| *name).collect::<Vec<&str>>());
+            let bundled = load_scheme(&options).unwrap();
+            let dir = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+            for case in read_csv("case_data/test_case_data.csv").unwrap() {
+                assert_eq!(dir.group(&case, &GroupOptions::default()).unwrap().drg, bundled.group(&case, &GroupOptions::default()).unwrap().drg);
+            }
+            assert_eq!(scheme_version(&CliOptions::default()).unwrap(), scheme_version(&options).unwrap());
+            let subset = load_scheme(&CliOptions { only_mdcs: Some(vec![String::from("MDCB")]), ..options }).unwrap();
+            assert!(subset.mdc_sub_adrg["MDCF"].is_empty());
+        }
+    }
 
-    // #[test]
-    // fn read_exclude_sheet() {
-    //     // 读取主诊断排除表
-    //     let res = read_file_as_str_to_str("data\\exclude_sheet.json").unwrap();
-    //     assert_eq!(true, res["A01.000x014"] == "表6-3-1");
-    // }
+    #[test]
+    fn test_group_stream() {
+        // 测试惰性分组: 只取前两个结果时只读取两个病例, 结果与逐个分组一致
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let options = GroupOptions::default();
+        let mut pulled = 0;
+        let drgs: Vec<String> = scheme
+            .group_stream(cases_vec.iter().inspect(|_| pulled += 1), &options)
+            .take(2)
+            .map(|x| x.unwrap().drg)
+            .collect();
+        assert_eq!(2, pulled);
+        for (case, drg) in cases_vec.iter().zip(drgs.iter()) {
+            assert_eq!(&scheme.group(case, &options).unwrap().drg, drg);
+        }
 
-    // #[test]
-    // fn mdcz_group_test() {
-    //     // 进入MDC测试
+        // 也可以传入病例本身, 与过滤器串联
+        let kbbz = scheme.group_stream(cases_vec, &options).filter(|x| x.as_ref().is_ok_and(|r| r.drg == "KBBZ")).count();
+        assert_eq!(kbbz, group_batch_with_stats(&read_csv("case_data/test_case_data.csv").unwrap(), &scheme, &options).unwrap().1.kbbz_count);
+    }
 
-    //     // 读取数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    #[test]
+    fn test_repeat_latency() {
+        // 测试重复分组的耗时统计
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build();
+        let (mean, p99) = repeat_latency(&scheme, &case, &GroupOptions::default(), 200).unwrap();
+        assert!(mean > 0.0);
+        assert!(p99 > 0.0);
+        assert!(repeat_latency(&scheme, &case, &GroupOptions::default(), 0).is_err());
+    }
 
-    //     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-    //     // 初始化病例
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("03.9202"),
-    //         vec![String::from("B20.700x001"), String::from("S21.100x002")],
-    //         vec![],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
+    #[test]
+    fn test_severity_excluded_mcc_valid_cc() {
+        // G41.801(MCC)与主诊断G45.000同属排除表6-3-64被排除, A01.200(CC)有效, 并发症等级为CC
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0001").main_dis("G45.000").other_dis(["G41.801", "A01.200"]).sex(1).age(60.0).build();
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("BR2", result.adrg);
+        assert_eq!("CC", result.severity);
+        assert_eq!("BR23", result.drg);
+
+        // 只有被排除的MCC时没有并发症, 顺序不影响结果
+        let excluded = DrgCase::builder().id("0002").main_dis("G45.000").other_dis(["G41.801"]).sex(1).age(60.0).build();
+        assert_eq!("BR25", scheme.group(&excluded, &GroupOptions::default()).unwrap().drg);
+        let reversed = DrgCase::builder().id("0003").main_dis("G45.000").other_dis(["A01.200", "G41.801"]).sex(1).age(60.0).build();
+        assert_eq!("BR23", scheme.group(&reversed, &GroupOptions::default()).unwrap().drg);
+
+        // 判断路径中列出每个CC/MCC的排除判断
+        let (_, trace) = scheme.group_with_trace(&case, &GroupOptions::default()).unwrap();
+        let decisions: Vec<(&str, &str, bool, &str)> = trace.complications.iter()
+            .map(|x| (x.code.as_str(), x.severity.as_str(), x.excluded, x.exclusion_table.as_str()))
+            .collect();
+        assert_eq!(vec![("G41.801", "MCC", true, "表6-3-64"), ("A01.200", "CC", false, "表6-3-1")], decisions);
+        assert!(trace.to_text().contains("G41.801 MCC [表6-3-64]: excluded"));
+    }
 
-    //     let res = is_mdcz(
-    //         &case,
-    //         &adrg_dis_opt,
-    //         &all_opt_list,
-    //         &adrg_type_dict,
-    //         &mdcz_dis_sheet,
-    //         String::from("MDCZ")
-    //     );
+    #[test]
+    fn test_input_format_auto() {
+        // 测试按扩展名选择读取方式, 各种格式读取的病例相同
+        let tmp = TestDir::new("test_input_format_auto");
+        let dir = tmp.path();
+        let files = [
+            ("drg_input_format.csv", "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n0001,I50.900x018,,E16.200,,1,60,0\n"),
+            ("drg_input_format.tsv", "id\tmain_dis\tmain_opt\tother_dis\tother_opt\tsex\tage\tweight\n0001\tI50.900x018\t\tE16.200\t\t1\t60\t0\n"),
+            ("drg_input_format.ndjson", "{\"id\":\"0001\",\"main_dis\":\"I50.900x018\",\"other_dis\":[\"E16.200\"],\"sex\":1,\"age\":60}\n\n"),
+            ("drg_input_format.json", "[{\"id\":\"0001\",\"main_dis\":\"I50.900x018\",\"other_dis\":[\"E16.200\"],\"sex\":1,\"age\":60}]"),
+        ];
+        let expected = ["csv", "tsv", "ndjson", "json"];
+        for ((name, contents), format) in files.iter().zip(expected) {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            assert_eq!(Some(format), input_format_by_extension(path.to_str().unwrap()));
+            let cases = read_input_cases(path.to_str().unwrap(), &CliOptions::default()).unwrap();
+            assert_eq!(1, cases.len(), "{}", name);
+            assert_eq!(("0001", "I50.900x018", 1, 60.0), (cases[0].id.as_str(), cases[0].dis.principal(), cases[0].sex, cases[0].age));
+            assert_eq!(&[String::from("E16.200")], cases[0].dis.secondary());
+        }
+        assert_eq!(Some("xlsx"), input_format_by_extension("cases.XLSX"));
+        let xlsx = dir.join("drg_input_format.xlsx");
+        fs::write(&xlsx, b"PK").unwrap();
+        assert!(read_input_cases(xlsx.to_str().unwrap(), &CliOptions::default()).unwrap_err().to_string().contains("xlsx"));
+
+        // 没有扩展名(如stdin)时按内容判断
+        assert_eq!(None, input_format_by_extension("-"));
+        assert_eq!("json", sniff_input_format(b"  [{\"main_dis\":\"A00.100\"}]"));
+        assert_eq!("ndjson", sniff_input_format(b"\xef\xbb\xbf{\"main_dis\":\"A00.100\"}\n"));
+        assert_eq!("tsv", sniff_input_format(b"id\tmain_dis\n"));
+        assert_eq!("csv", sniff_input_format(b"id,main_dis\n"));
+    }
 
-    //     println!("all dis is {:?}", case.all_dis);
-    //     println!("all opt is {:?}", case.all_opt);
-    //     println!("{}", res);
-    //     assert_eq!(true, res == String::from("MDCZ"));
-    // }
+    #[test]
+    fn test_max_errors() {
+        // 测试跳过解析失败的行, 失败的行达到--max-errors时提前停止
+        let tmp = TestDir::new("test_max_errors");
+        let in_file_path = tmp.join("drg_max_errors.csv");
+        fs::write(&in_file_path,
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+            0001,I50.900x018,,E16.200,,1,60,0\n\
+            0002,I50.900x018,,,,x,60,0\n\
+            0003,I50.900x018,,,,1,sixty,0\n\
+            0004,I50.900x018,,,,1,60,0\n\
+            0005,I50.900x018,,,,1,60,heavy\n").unwrap();
+        let path = in_file_path.to_str().unwrap();
+        assert!(read_input_cases(path, &CliOptions::default()).is_err());
+
+        let skip = CliOptions { skip_bad_rows: true, ..Default::default() };
+        let cases = read_input_cases(path, &skip).unwrap();
+        assert_eq!(vec!["0001", "0004"], cases.iter().map(|x| x.id.as_str()).collect::<Vec<&str>>());
+
+        for fast_csv in [false, true] {
+            let limited = CliOptions { max_errors: Some(2), fast_csv, ..Default::default() };
+            let err = read_input_cases(path, &limited).unwrap_err().to_string();
+            assert!(err.contains("aborted after 2 bad rows"), "{}", err);
+            // 汇总行加两个失败的行, 第5个病例所在的行没有读到
+            assert_eq!(3, err.lines().count(), "{}", err);
+            assert!(!err.contains("heavy"), "{}", err);
+        }
 
-    // #[test]
-    // fn mdcy_group_test() {
-    //     // 进入MDC测试
+        // --join-input同样跳过解析失败的行, 输出的原始行中没有这些行
+        let out_file_path = tmp.join("drg_max_errors_join.csv");
+        let out = out_file_path.to_str().unwrap();
+        assert!(batch_join_process(path, out, &CliOptions { join_input: true, ..Default::default() }).is_err());
+        let skip = CliOptions { join_input: true, skip_bad_rows: true, ..Default::default() };
+        assert_eq!(2, batch_join_process(path, out, &skip).unwrap());
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let ids: Vec<String> = rdr.records().map(|x| x.unwrap()[0].to_string()).collect();
+        assert_eq!(vec!["0001", "0004"], ids);
+        let limited = CliOptions { join_input: true, max_errors: Some(2), ..Default::default() };
+        let err = batch_join_process(path, out, &limited).unwrap_err().to_string();
+        assert!(err.contains("aborted after 2 bad rows"), "{}", err);
+        assert_eq!(3, err.lines().count(), "{}", err);
+    }
 
-    //     // 读取数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    #[test]
+    fn test_scheme_stats() {
+        // 测试分组方案统计与分组方案数据一致
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let stats = scheme_stats(&scheme);
+        assert_eq!(scheme.adrg_type_dict.len(), stats.adrgs);
+        assert_eq!(stats.adrgs, stats.adrgs_per_condition.values().sum::<usize>());
+        assert_eq!(scheme.all_drg_codes().len(), stats.drgs);
+        assert!(stats.adrgs_per_condition["is_contain_main_dis"] > 0);
+        assert_eq!(scheme.mdc_sub_adrg["MDCA"].len(), stats.adrgs_per_mdc["MDCA"]);
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&stats).unwrap()).unwrap();
+        assert_eq!(stats.ccmcc_entries, json["ccmcc_entries"].as_u64().unwrap() as usize);
+        assert!(stats.to_table().contains("adrgs per condition"));
+
+        // 只读取部分MDC时ADRG表明显减少
+        let subset = scheme_stats(&GroupingScheme::load_subset(&DirSource::new(DATA_DIR), &["MDCB"]).unwrap());
+        assert!(subset.adrg_tables < stats.adrg_tables);
+    }
 
-    //     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-    //     // 初始化病例
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("03.9202"),
-    //         vec![String::from("B20.000x001"), String::from("S21.100x002")],
-    //         vec![],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
+    #[test]
+    #[cfg(feature = "parquet")]
+    fn test_write_parquet() {
+        // 测试分组结果写为Parquet之后用parquet读取器读回, 列类型和内容不变
+        let tmp = TestDir::new("test_write_parquet");
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let n = cases_vec.len().min(20);
+        let drgcases: Vec<DrgCaseGrouped> = cases_vec.into_iter().take(n)
+            .map(|x| { let code = String::from("FR25"); let mut c = DrgCaseGrouped::new(x, code); c.case_hash = Some(String::from("00ff")); c })
+            .collect();
+        let expected = grouped_parquet_columns(&drgcases).unwrap();
+        let path = tmp.join("drg_write_parquet.parquet");
+        write_parquet(&expected, n, path.to_str().unwrap()).unwrap();
+        let (num_rows, columns) = read_parquet(&path);
+        assert_eq!(n as i64, num_rows);
+        assert_eq!(expected, columns);
+        assert_eq!("case_hash", columns.last().unwrap().0);
+        let age = columns.iter().find(|(name, _)| name == "age").unwrap();
+        assert_eq!(ParquetValues::Double(drgcases.iter().map(|x| x.age.parse().unwrap()).collect()), age.1);
+        assert!(matches!(columns[5].1, ParquetValues::Int32(_)));
+    }
 
-    //     let res = is_mdcy(
-    //         &case, 
-    //         &adrg_type_dict, 
-    //         &mdcy_dis_sheet, 
-    //         String::from("MDCY")
-    //     );
-    //     println!("all dis is {:?}", case.all_dis);
-    //     println!("all opt is {:?}", case.all_opt);
-    //     let c = mdcy_dis_sheet.intersection(&case.all_dis);
-    //     let f = mdcy_dis_sheet.is_disjoint(&case.all_dis);
-    //     println!("{}", f);
-    //     println!("{:?}", c.into_iter().map(|x| x.to_string()).collect::<Vec<String>>().len());
-    //     println!("{}", res);
-    //     assert_eq!(true, res == String::from("MDCY"));
-    // }
+    #[test]
+    fn test_suggest_main_opt() {
+        // 测试QY病例换用其他手术作为主手术后能进入外科ADRG, 原分组结果不变
+        let tmp = TestDir::new("test_suggest_main_opt");
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let case = DrgCase::builder().id("0014").main_dis("I50.900x018").main_opt("78.6201").other_opt(["36.0601"]).sex(1).age(60.0).build();
+        assert_eq!("FQY", scheme.group(&case, &options).unwrap().adrg);
+        let (opt, drg) = scheme.suggest_main_opt(&case, &options).unwrap().unwrap();
+        assert_eq!("36.0601", opt);
+        assert!(drg.starts_with("FM3"), "{}", drg);
+
+        // 不是QY的病例没有建议
+        let grouped = DrgCase::builder().id("0015").main_dis("I50.900x018").sex(1).age(60.0).build();
+        assert_eq!(None, scheme.suggest_main_opt(&grouped, &options).unwrap());
+
+        let out_file_path = tmp.join("drg_main_opt_out.csv");
+        let cli = CliOptions { suggest_main_opt: true, ..Default::default() };
+        batch_drg_process(vec![case, grouped], out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let col = headers.iter().position(|h| h == "main_opt_suggestion").unwrap();
+        let code = headers.iter().position(|h| h == "code").unwrap();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!("FQY", &rows[0][code]);
+        assert!(rows[0][col].starts_with("36.0601:FM3"));
+        assert_eq!("", &rows[1][col]);
+    }
 
-    // #[test]
-    // fn mdcp_group_test() {
-    //     // 进入MDC测试
+    #[test]
+    fn test_emit_reason_always() {
+        // 测试每一行都输出分组状态, 包括正常入组, QY调整和各种未入组原因
+        let tmp = TestDir::new("test_emit_reason_always");
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let ok = DrgCase::builder().id("0016").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let qy = DrgCase::builder().id("0017").main_dis("I50.900x018").main_opt("78.6201").sex(1).age(60.0).build();
+        let no_main_dis = DrgCase::builder().id("0018").sex(1).age(60.0).build();
+        let unknown = DrgCase::builder().id("0019").main_dis("ZZZ.999").sex(1).age(60.0).build();
+        let status = |case: &DrgCase| scheme.group_status(case, &scheme.group(case, &options).unwrap(), &case.validate(&scheme));
+        assert_eq!("ok", status(&ok));
+        assert_eq!("qy_adjusted", status(&qy));
+        assert_eq!("kbbz:no_main_dis", status(&no_main_dis));
+        assert_eq!("kbbz:unknown_main_dis", status(&unknown));
+
+        let out_file_path = tmp.join("drg_status_out.csv");
+        let cli = CliOptions { emit_reason_always: true, ..Default::default() };
+        batch_drg_process(vec![ok, qy, no_main_dis, unknown], out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let col = rdr.headers().unwrap().iter().position(|h| h == "status").unwrap();
+        let rows: Vec<String> = rdr.records().map(|x| x.unwrap()[col].to_string()).collect();
+        assert_eq!(vec!["ok", "qy_adjusted", "kbbz:no_main_dis", "kbbz:unknown_main_dis"], rows);
+    }
 
-    //     // 读取数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    #[test]
+    fn test_ccmcc_scope() {
+        // 测试其他诊断中与主诊断相同的编码不算作CC/MCC, 不论诊断范围如何设置
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert_eq!("MCC", scheme.ccmcc_sheet["S10.801"][1]);
+        assert_ne!(Some(&scheme.ccmcc_sheet["S10.801"][0]), scheme.exclude_sheet.get("S10.801"));
+        let plain = DrgCase::builder().id("0020").main_dis("S10.801").sex(1).age(40.0).build();
+        let repeated = DrgCase::builder().id("0021").main_dis("S10.801").other_dis(["S10.801"]).sex(1).age(40.0).build();
+        for scope in [CcmccScope::OtherDis, CcmccScope::AllDis] {
+            let options = GroupOptions { ccmcc_scope: scope, ..Default::default() };
+            assert!(valid_ccmcc(&repeated, &scheme.ccmcc_sheet, &scheme.exclude_sheet, scope).is_empty());
+            let result = scheme.group(&repeated, &options).unwrap();
+            assert_eq!("none", result.severity);
+            assert_eq!(scheme.group(&plain, &options).unwrap().drg, result.drg);
+        }
 
-    //     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-    //     // 初始化病例
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("03.9202"),
-    //         vec![String::from("B20.000x001"), String::from("S21.100x002")],
-    //         vec![],
-    //         1,
-    //         0.05,
-    //         2288
-    //     );
+        // 重复录入的CC在other_dis范围各算一次, 在all_dis范围只算一次
+        let case = DrgCase::builder().id("0022").main_dis("G45.000").other_dis(["A01.200", "A01.200"]).sex(1).age(40.0).build();
+        assert_eq!(2, valid_ccmcc(&case, &scheme.ccmcc_sheet, &scheme.exclude_sheet, CcmccScope::OtherDis).len());
+        assert_eq!(1, valid_ccmcc(&case, &scheme.ccmcc_sheet, &scheme.exclude_sheet, CcmccScope::AllDis).len());
+        let all_dis = GroupOptions { ccmcc_scope: CcmccScope::AllDis, ..Default::default() };
+        assert!(scheme.group(&case, &all_dis).unwrap().review_flags.contains(&String::from("single_cc_only")));
+        assert_eq!(scheme.group(&case, &GroupOptions::default()).unwrap().drg, scheme.group(&case, &all_dis).unwrap().drg);
+        assert!(!scheme.group(&case, &GroupOptions::default()).unwrap().review_flags.contains(&String::from("single_cc_only")));
+    }
 
-    //     let res = is_mdcp(
-    //         &case,
-    //         &main_dis_sheet,
-    //         String::from("MDCP")
-    //     );
-    //     println!("all dis is {:?}", case.all_dis);
-    //     println!("all opt is {:?}", case.all_opt);
-    //     let c = case.age <= 0.0795;
-    //     println!("{}", res);
-    //     println!("{}", case.age);
-    //     println!("{}", c);
-    //     assert_eq!(true, res == String::from("MDCP"));
-    // }
+    #[test]
+    fn test_main_dis_list() {
+        // 测试主诊断条件使用专门的主诊断表, 与ADRG默认表区分
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert_eq!("is_contain_main_dis", scheme.adrg_type_dict["BR1"]);
+        assert_eq!(adrg_table(&scheme.adrg_dis_opt, "BR1", DEFAULT_TABLE).unwrap(), adrg_table(&scheme.adrg_dis_opt, "BR1", "main_dis_list").unwrap());
+
+        let tables = scheme.adrg_dis_opt.get_mut("BR1").unwrap();
+        tables.insert(String::from(DEFAULT_TABLE), HashSet::from([String::from("I63.900")]));
+        tables.insert(String::from("main_dis_list"), HashSet::from([String::from("I61.900")]));
+        let default_only = DrgCase::builder().main_dis("I63.900").build();
+        let main_dis_only = DrgCase::builder().main_dis("I61.900").build();
+        let options = GroupOptions::default();
+        let check = |case: &DrgCase, scheme: &GroupingScheme| {
+            process_adrg(case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, String::from("BR1"), &options).unwrap()
+        };
+        assert_eq!("BR1", is_contain_main_dis(&default_only, &scheme.adrg_dis_opt, String::from("BR1")).unwrap());
+        assert_eq!("KBBZ", is_contain_main_dis_list(&default_only, &scheme.adrg_dis_opt, String::from("BR1")).unwrap());
+        assert_eq!("KBBZ", check(&default_only, &scheme));
+        assert_eq!("BR1", check(&main_dis_only, &scheme));
+
+        // 没有主诊断表时is_contain_main_dis使用默认表, is_contain_main_dis_list需要主诊断表
+        scheme.adrg_dis_opt.get_mut("BR1").unwrap().remove("main_dis_list");
+        assert_eq!("BR1", check(&default_only, &scheme));
+        assert!(is_contain_main_dis_list(&default_only, &scheme.adrg_dis_opt, String::from("BR1")).is_err());
+    }
 
-    // #[test]
-    // fn mdcp_group_test() {
-    //     // 进入MDC测试
+    #[test]
+    fn test_repl() {
+        // 测试通过管道逐个发送病例, 每个病例的结果在输入结束之前就能读到, 错误的行不会中止
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let (case_rx, mut case_tx) = std::io::pipe().unwrap();
+        let (result_rx, mut result_tx) = std::io::pipe().unwrap();
+        let repl = std::thread::spawn(move || run_repl(BufReader::new(case_rx), &mut result_tx, &scheme, &CliOptions::default()).unwrap());
+        let mut results = std::io::BufRead::lines(BufReader::new(result_rx));
+
+        writeln!(case_tx, r#"{{"id":"0001","main_dis":"I50.900x018","sex":1,"age":60}}"#).unwrap();
+        let first: serde_json::Value = serde_json::from_str(&results.next().unwrap().unwrap()).unwrap();
+        assert_eq!("0001", first["id"]);
+        assert!(first["drg"].as_str().unwrap().starts_with("FR2"));
+
+        writeln!(case_tx, "not json").unwrap();
+        writeln!(case_tx, r#"{{"id":"0002","main_dis":"I50.900x018","main_opt":"78.6201","sex":1,"age":60}}"#).unwrap();
+        drop(case_tx);
+        let error: serde_json::Value = serde_json::from_str(&results.next().unwrap().unwrap()).unwrap();
+        assert_eq!(2, error["line"]);
+        let second: serde_json::Value = serde_json::from_str(&results.next().unwrap().unwrap()).unwrap();
+        assert_eq!("FQY", second["drg"]);
+        assert!(results.next().is_none());
+        repl.join().unwrap();
+    }
 
-    //     // 读取数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    #[test]
+    fn test_coverage_out() {
+        // 测试批量分组后输出全部ADRG的入组病例数, 没有病例的ADRG为0
+        let tmp = TestDir::new("test_coverage_out");
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let cases = vec![
+            DrgCase::builder().id("0023").main_dis("I50.900x018").sex(1).age(60.0).build(),
+            DrgCase::builder().id("0024").main_dis("I50.900x018").sex(0).age(70.0).build(),
+            DrgCase::builder().id("0025").sex(1).age(60.0).build(),
+        ];
+        let out_file_path = tmp.join("drg_coverage_out.csv");
+        let coverage_path = tmp.join("drg_coverage.csv");
+        let cli = CliOptions { coverage_out: Some(coverage_path.to_str().unwrap().to_string()), ..Default::default() };
+        batch_drg_process(cases, out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&coverage_path).unwrap();
+        let coverage: HashMap<String, usize> = rdr.records()
+            .map(|x| x.unwrap())
+            .map(|x| (x[0].to_string(), x[1].parse().unwrap()))
+            .collect();
+        assert_eq!(2, coverage["FR2"]);
+        assert_eq!(1, coverage["KBBZ"]);
+        assert_eq!(0, coverage["AA1"]);
+        let universe: HashSet<&String> = scheme.mdc_sub_adrg.values().flatten().collect();
+        assert!(universe.iter().all(|x| coverage.contains_key(*x)));
+        assert_eq!(3, coverage.values().sum::<usize>());
+    }
 
-    //     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-    //     // 初始化病例
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("03.9202"),
-    //         vec![String::from("B20.000x001"), String::from("S21.100x002")],
-    //         vec![],
-    //         1,
-    //         0.05,
-    //         2288
-    //     );
+    #[test]
+    fn test_weight_band() {
+        // 测试出生体重分段的边界, 体重未知时没有分段
+        let tmp = TestDir::new("test_weight_band");
+        let band = |weight: i32| DrgCase::builder().main_dis("P07.100").weight(weight).build().weight_band();
+        assert_eq!(None, band(0));
+        assert_eq!(Some("<1000"), band(999));
+        assert_eq!(Some("1000-1499"), band(1000));
+        assert_eq!(Some("1000-1499"), band(1499));
+        assert_eq!(Some("1500-2499"), band(1500));
+        assert_eq!(Some("1500-2499"), band(2499));
+        assert_eq!(Some(">=2500"), band(2500));
+
+        let out_file_path = tmp.join("drg_weight_band_out.csv");
+        let cases = vec![
+            DrgCase::builder().id("0026").main_dis("P07.100").age(0.01).weight(1200).build(),
+            DrgCase::builder().id("0027").main_dis("I50.900x018").age(60.0).build(),
+        ];
+        let cli = CliOptions { emit_weight_band: true, ..Default::default() };
+        batch_drg_process(cases, out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let col = rdr.headers().unwrap().iter().position(|h| h == "weight_band").unwrap();
+        let rows: Vec<String> = rdr.records().map(|x| x.unwrap()[col].to_string()).collect();
+        assert_eq!(vec!["1000-1499", ""], rows);
+    }
 
-    //     let res = is_mdcp(
-    //         &case,
-    //         &main_dis_sheet,
-    //         String::from("MDCP")
-    //     );
-    //     println!("all dis is {:?}", case.all_dis);
-    //     println!("all opt is {:?}", case.all_opt);
-    //     let c = case.age <= 0.0795;
-    //     println!("{}", res);
-    //     println!("{}", case.age);
-    //     println!("{}", c);
-    //     assert_eq!(true, res == String::from("MDCP"));
-    // }
+    #[test]
+    fn test_validate_scheme() {
+        // 测试完整的分组方案没有问题, 有多处错误的分组方案列出全部问题
+        let tmp = TestDir::new("test_validate_scheme");
+        let source = DirSource::new(DATA_DIR);
+        assert!(validate_scheme(&source).is_empty(), "{:?}", validate_scheme(&source));
+
+        let dir = tmp.join("drg_validate_scheme");
+        fs::create_dir_all(&dir).unwrap();
+        for entry in fs::read_dir(DATA_DIR).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+        let mut conditions = read_member_json::<HashMap<String, String>>(&DirSource::new(&dir), "adrg_in_condition.json").unwrap();
+        conditions.insert(String::from("AA1"), String::from("is_contain_something"));
+        conditions.remove("BR1");
+        fs::write(dir.join("adrg_in_condition.json"), serde_json::to_string(&conditions).unwrap()).unwrap();
+        let mut drgs = read_member_json::<HashMap<String, Vec<String>>>(&DirSource::new(&dir), "adrg_drg_name_sheet.json").unwrap();
+        drgs.insert(String::from("FR2"), Vec::new());
+        fs::write(dir.join("adrg_drg_name_sheet.json"), serde_json::to_string(&drgs).unwrap()).unwrap();
+        fs::remove_file(dir.join("mdcy_dis_sheet.txt")).unwrap();
+
+        let problems = validate_scheme(&DirSource::new(&dir));
+        assert_eq!(4, problems.len(), "{:?}", problems);
+        assert!(problems.iter().any(|x| x.starts_with("mdcy_dis_sheet.txt")));
+        assert!(problems.iter().any(|x| x.contains("unknown entry conditions") && x.contains("AA1")));
+        assert!(problems.iter().any(|x| x.contains("without an entry condition") && x.contains("BR1")));
+        assert!(problems.iter().any(|x| x.contains("empty DRG lists") && x.contains("FR2")));
+
+        // 读取时会报错的可选文件同样检查出来, 每个问题只有一次文件名
+        let mut members = fixture_members();
+        assert!(validate_scheme(&EmbeddedSource::new(&members)).is_empty(), "{:?}", validate_scheme(&EmbeddedSource::new(&members)));
+        members.push(("pre_mdc_order.json", br#"["MDCF"]"#));
+        members.push(("drg_weights.json", br#"{"FR25": "heavy"}"#));
+        members.push(("local_rules.json", b"{"));
+        members.push(("adrg_categories.json", br#"{"surgery": "A"}"#));
+        let embedded = EmbeddedSource::new(&members);
+        assert!(GroupingScheme::load(&embedded).is_err());
+        let problems = validate_scheme(&embedded);
+        assert_eq!(4, problems.len(), "{:?}", problems);
+        for name in ["pre_mdc_order.json", "drg_weights.json", "local_rules.json", "adrg_categories.json"] {
+            assert!(problems.iter().any(|x| x.starts_with(name) && !x[name.len()..].contains(name)), "{} {:?}", name, problems);
+        }
+        #[cfg(feature = "zip")]
+        {
+            let zip_path = tmp.join("scheme.zip");
+            let files: Vec<(String, Vec<u8>)> = members.iter().map(|(name, data)| (name.to_string(), data.to_vec())).collect();
+            fs::write(&zip_path, stored_zip(&files)).unwrap();
+            assert_eq!(problems, validate_scheme(&ZipSource::open(&zip_path).unwrap()));
+        }
+    }
 
-    // #[test]
-    // fn mdcp_group_test() {
-    //     // 读取分组方案数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
+    #[test]
+    fn test_parse_flat_case() {
+        // 测试按默认和自定义的字段位置解析单个字符串表示的病例
+        let options = CliOptions::default();
+        let case = parse_flat_case("0001|I50.900x018|| E16.200;I10.x00 ||1|60|0\n", &FlatLayout::default(), &options).unwrap();
+        assert_eq!("0001", case.id);
+        assert_eq!("I50.900x018", case.dis.principal());
+        assert_eq!(&[String::from("E16.200"), String::from("I10.x00")], case.dis.secondary());
+        assert!(case.no_surgery());
+        assert_eq!((1, 60.0, 0), (case.sex, case.age, case.weight));
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        assert!(scheme.group(&case, &GroupOptions::default()).unwrap().drg.starts_with("FR2"));
+
+        // 缺少的字段取默认值
+        let short = parse_flat_case("0002|I50.900x018", &FlatLayout::default(), &options).unwrap();
+        assert_eq!((UNKNOWN_SEX, DEFAULT_ADULT_AGE, DEFAULT_WEIGHT), (short.sex, short.age, short.weight));
+        assert!(parse_flat_case("0003||78.6201", &FlatLayout::default(), &options).is_err());
+
+        // 数字字段与CSV输入一样允许千分位逗号和小数部分为0的整数
+        let numbers = parse_flat_case("0004|P22.000||||1.0|0.02|2,288", &FlatLayout::default(), &options).unwrap();
+        assert_eq!((1, 0.02, 2288), (numbers.sex, numbers.age, numbers.weight));
+        let numbers = parse_flat_case("0005|P22.000||||1|0.02|2288.0", &FlatLayout::default(), &options).unwrap();
+        assert_eq!(2288, numbers.weight);
+        let err = parse_flat_case("0006|P22.000||||1|0.02|2288.5", &FlatLayout::default(), &options).unwrap_err().to_string();
+        assert!(err.starts_with("flat case 0006 weight: "), "{}", err);
+
+        let layout: FlatLayout = serde_json::from_str(r#"{"separator":",","sub_separator":"/","id":null,"main_dis":0,"main_opt":1,"other_dis":null,"other_opt":2,"sex":3,"age":4,"weight":null}"#).unwrap();
+        let case = parse_flat_case("I50.900x018,78.6201,36.0601/88.5600,1,60", &layout, &options).unwrap();
+        assert_eq!("0", case.id);
+        assert_eq!("78.6201", case.opt.principal());
+        assert_eq!(2, case.opt.secondary().len());
+        assert_eq!(60.0, case.age);
+    }
 
-    //     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-    //     // 初始化病例
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("41.0100"),
-    //         vec![String::from("B20.000x001"), String::from("S21.100x002")],
-    //         vec![String::from("52.8000"), String::from("55.6901")],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
+    #[test]
+    fn test_drg_only() {
+        // 测试--drg-only的输出只有id和code两列
+        let tmp = TestDir::new("test_drg_only");
+        let out_file_path = tmp.join("drg_only_out.csv");
+        let cases = vec![
+            DrgCase::builder().id("0028").main_dis("I50.900x018").other_dis(["E16.200"]).sex(1).age(60.0).build(),
+            DrgCase::builder().id("0029").sex(0).age(30.0).build(),
+        ];
+        let cli = CliOptions { drg_only: true, emit_counts: true, validate: true, ..Default::default() };
+        batch_drg_process(cases, out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        assert_eq!(vec!["id", "code"], rdr.headers().unwrap().iter().collect::<Vec<&str>>());
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(2, rows.len());
+        assert_eq!("0028", &rows[0][0]);
+        assert!(rows[0][1].starts_with("FR2"));
+        assert_eq!(vec!["0029", "KBBZ"], rows[1].iter().collect::<Vec<&str>>());
+    }
 
-    //     let res = is_mdca(
-    //         &case,
-    //         &adrg_dis_opt,
-    //         &all_opt_list,
-    //         &adrg_type_dict,
-    //         String::from("MDCA")
-    //     );
-    //     println!("all dis is {:?}", case.all_dis);
-    //     println!("all opt is {:?}", case.all_opt);
-    //     println!("{}", res);
-    //     assert_eq!(true, res == String::from("MDCA"));
-    // }
+    #[test]
+    fn test_severity_endings() {
+        // 测试按分组方案的约定选择DRG结尾数字
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let endings: SeverityEndings = serde_json::from_str(r#"{"mcc": 2, "cc": 4, "none": 6}"#).unwrap();
+        assert_eq!(9, endings.single);
+        scheme.severity_endings = endings;
+        scheme.adrg_drg_levels.insert(
+            String::from("BR2"),
+            HashMap::from([(2, String::from("BR22")), (4, String::from("BR24")), (6, String::from("BR26"))]),
+        );
+        let options = GroupOptions::default();
+        let group = |other_dis: &[&str]| {
+            let case = DrgCase::builder().main_dis("G45.000").other_dis(other_dis.iter().copied()).sex(1).age(60.0).build();
+            scheme.group(&case, &options).unwrap()
+        };
+        assert_eq!("BR22", group(&["S10.801"]).drg);
+        let cc = group(&["A01.200"]);
+        assert_eq!("BR24", cc.drg);
+        assert!(cc.review_flags.contains(&String::from("single_cc_only")));
+        assert_eq!("BR26", group(&[]).drg);
+
+        // 只有MCC和无两个DRG时, CC进入不伴并发症的DRG
+        let two = HashMap::from([(2, String::from("BR22")), (6, String::from("BR26"))]);
+        assert_eq!("BR26", resolve_drg_ending("BR2", "CC", &two, &scheme.severity_endings).unwrap());
+        assert_eq!("BR22", resolve_drg_ending("BR2", "MCC", &two, &scheme.severity_endings).unwrap());
+        let single = HashMap::from([(9, String::from("BR29"))]);
+        assert_eq!("BR29", resolve_drg_ending("BR2", "MCC", &single, &scheme.severity_endings).unwrap());
+    }
 
-    // #[test]
-    // fn mdcp_group_test() {
-    //     // 读取分组方案数据
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-//     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-//     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-//     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-//     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-//     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-
-//     let test_other_dis: Vec<String> = vec!["S35.200x005", "S21.100x002"].iter().map(|x| x.to_string()).collect();
-//     // 初始化病例
-//     let case = DrgCase::new(
-//         String::from("0001"),
-//         String::from("G12.900"),
-//         String::from("41.0100"),
-//         vec![String::from("B20.000x001"), String::from("S21.100x002")],
-//         vec![String::from("52.8000"), String::from("55.6901")],
-//         1,
-//         20.0,
-//         2288
-//     );
-
-//     let res = is_mdca(
-//         &case,
-//         &adrg_dis_opt,
-//         &all_opt_list,
-//         &adrg_type_dict,
-//         String::from("MDCA")
-//     );
-//     println!("all dis is {:?}", case.all_dis);
-//     println!("all opt is {:?}", case.all_opt);
-//     println!("{}", res);
-//     assert_eq!(true, res == String::from("AC1"));
-// }
+    #[test]
+    fn test_missing_drg_ending() {
+        // 测试ADRG下没有分组方案约定的"无并发症"结尾时, 分组返回错误而不会panic
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let case = DrgCase::builder().id("0030").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let options = GroupOptions::default();
+        assert_eq!("FR2", scheme.group(&case, &options).unwrap().adrg);
+        let levels = scheme.adrg_drg_levels.get_mut("FR2").unwrap();
+        // 把"无并发症"的FR25换成CC的FR23, 保持两个DRG
+        levels.remove(&scheme.severity_endings.none);
+        levels.insert(scheme.severity_endings.cc, String::from("FR23"));
+        let err = scheme.group(&case, &options).unwrap_err().to_string();
+        assert!(err.contains("ADRG FR2 has no DRG ending in 5 for severity none"), "{}", err);
+        // 两个DRG时同样检查
+        let two = HashMap::from([(1, String::from("BR21")), (3, String::from("BR23"))]);
+        let err = resolve_drg_ending("BR2", "none", &two, &SeverityEndings::default()).unwrap_err().to_string();
+        assert_eq!("ADRG BR2 has no DRG ending in 5 for severity none (DRGs: BR21|BR23)", err);
+        assert_eq!("BR21", resolve_drg_ending("BR2", "MCC", &two, &SeverityEndings::default()).unwrap());
+    }
 
-    // #[test]
-    // fn test_adrg() {
-    //     // 读取分组方案数据
+    #[test]
+    fn test_validate_drg_endings() {
+        // 测试读取时检查DRG结尾数字与severity_endings.json一致, 不一致时报出ADRG
+        let mut members = fixture_members();
+        assert!(GroupingScheme::load(&EmbeddedSource::new(&members)).is_ok());
+        members.push(("severity_endings.json", br#"{"mcc": 2, "cc": 4, "none": 6}"#));
+        let err = GroupingScheme::load(&EmbeddedSource::new(&members)).err().unwrap().to_string();
+        assert!(err.contains("severity_endings (mcc 2, cc 4, none 6)"), "{}", err);
+        assert!(err.contains("FR2 => FR21|FR23|FR25"), "{}", err);
+        // 只有一个DRG的ADRG不限结尾
+        assert!(!err.contains("AA1"), "{}", err);
+
+        let endings = SeverityEndings::default();
+        let levels = |endings: &[i32]| -> DrgLevelSheet {
+            HashMap::from([(String::from("FR2"), endings.iter().map(|x| (*x, format!("FR2{}", x))).collect())])
+        };
+        for ok in [&[9][..], &[1, 5], &[3, 5], &[1, 3, 5]] {
+            assert!(validate_drg_endings(&levels(ok), &endings).is_ok(), "{:?}", ok);
+        }
+        // 没有无并发症的DRG, 有约定以外的结尾, 三个DRG中没有MCC
+        for bad in [&[1, 3][..], &[1, 5, 7], &[3, 5, 9]] {
+            assert!(validate_drg_endings(&levels(bad), &endings).unwrap_err().to_string().contains("FR2 =>"), "{:?}", bad);
+        }
+    }
 
-    //     // ADRG内涵诊断和手术操作表
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     // 所有手术操作列表
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     // 所有诊断列表
-    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    //     // 各个MDC的主诊表
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     // MDCY的诊断表
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     // MDCZ的诊断表
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     // 各个ADRG组进入的判断条件
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-    //     // 读取MDC下的ADRG列表
-    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
+    #[test]
+    fn test_benchmark_scheme_load() {
+        // 测试逐个文件统计读取耗时, 列出全部必须的文件并按耗时从大到小排列
+        let tmp = TestDir::new("test_benchmark_scheme_load");
+        let times = benchmark_scheme_load(&DirSource::new(DATA_DIR)).unwrap();
+        let files: HashSet<&str> = times.iter().map(|x| x.file.as_str()).collect();
+        assert!(files.contains("adrg_dis_opt_sheet.json"));
+        assert!(files.contains("mdcz_dis_sheet.json"));
+        assert!(!files.contains("local_rules.json"));
+        assert_eq!(11, times.len());
+        assert!(times.iter().all(|x| x.bytes > 0));
+        assert!(times.windows(2).all(|x| x[0].parse_us >= x[1].parse_us));
+        assert!(benchmark_scheme_load(&DirSource::new(tmp.join("drg_no_such_scheme"))).unwrap().is_empty());
+
+        // 可选的文件与load_mdcs一样解析, 不在方案文件列表中的文件不计时
+        let mut members = fixture_members();
+        members.push(("local_rules.json", br#"[]"#));
+        members.push(("pre_mdc_order.json", br#"["MDCA", "MDCP", "MDCY", "MDCZ"]"#));
+        members.push(("notes.json", br#"{"not": "a scheme file"}"#));
+        let times = benchmark_scheme_load(&EmbeddedSource::new(&members)).unwrap();
+        let files: HashSet<&str> = times.iter().map(|x| x.file.as_str()).collect();
+        assert_eq!(13, times.len());
+        assert!(files.contains("local_rules.json") && files.contains("pre_mdc_order.json"));
+        assert!(!files.contains("notes.json"));
+        let (_, pre_mdc_order) = members.iter_mut().find(|(name, _)| *name == "pre_mdc_order.json").unwrap();
+        *pre_mdc_order = br#"["MDCA", "MDCA"]"#;
+        let err = benchmark_scheme_load(&EmbeddedSource::new(&members)).unwrap_err().to_string();
+        assert!(err.starts_with("pre_mdc_order.json: "), "{}", err);
+    }
+
+    #[test]
+    fn test_scheme_cache() {
+        // 测试第二次读取时使用缓存, 结果与解析源文件相同, 源文件变化后缓存失效
+        let tmp = TestDir::new("test_scheme_cache");
+        let dir = tmp.join("drg_scheme_cache");
+        fs::create_dir_all(&dir).unwrap();
+        for entry in fs::read_dir(DATA_DIR).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+        let cache_path = dir.join("scheme.cache");
+
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none());
+        let parsed = GroupingScheme::load_or_cache(&dir, &cache_path).unwrap();
+        let cached = GroupingScheme::read_cache(&dir, &cache_path).unwrap().expect("cache is used on the second load");
+        assert_eq!(parsed.adrg_dis_opt, cached.adrg_dis_opt);
+        assert_eq!(parsed.main_dis_sheet, cached.main_dis_sheet);
+        assert_eq!(parsed.ccmcc_sheet, cached.ccmcc_sheet);
+        assert_eq!(parsed.mdc_dis_index, cached.mdc_dis_index);
+        assert_eq!(parsed.adrg_drg_levels, cached.adrg_drg_levels);
+        let case = DrgCase::builder().main_dis("G45.000").other_dis(["A01.200"]).sex(1).age(60.0).build();
+        let options = GroupOptions::default();
+        assert_eq!(parsed.group(&case, &options).unwrap().drg, cached.group(&case, &options).unwrap().drg);
+
+        // 可选的文件不在缓存中, 读取缓存时与解析源文件一样从方案目录读取
+        fs::write(dir.join("drg_weights.json"), r#"{"FR25": 0.85}"#).unwrap();
+        fs::write(dir.join("pre_mdc_order.json"), r#"["MDCZ", "MDCA"]"#).unwrap();
+        let parsed = GroupingScheme::load(&DirSource::new(&dir)).unwrap();
+        let cached = GroupingScheme::read_cache(&dir, &cache_path).unwrap().expect("optional files do not invalidate the cache");
+        assert_eq!(parsed.drg_weights, cached.drg_weights);
+        assert_eq!(parsed.pre_mdc_order, cached.pre_mdc_order);
+        assert_eq!(Some(0.85), cached.relative_weight("FR25"));
+        fs::write(dir.join("pre_mdc_order.json"), r#"["MDCA", "MDCA"]"#).unwrap();
+        let err = GroupingScheme::read_cache(&dir, &cache_path).err().expect("invalid pre_mdc_order.json fails").to_string();
+        assert!(err.starts_with("pre_mdc_order.json: "), "{}", err);
+        fs::remove_file(dir.join("pre_mdc_order.json")).unwrap();
+
+        // 源文件变化后重新解析并更新缓存
+        let mut file = fs::OpenOptions::new().append(true).open(dir.join("mdc_sub_adrg.json")).unwrap();
+        writeln!(file).unwrap();
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none());
+        GroupingScheme::load_or_cache(&dir, &cache_path).unwrap();
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_scheme_cache_corrupt() {
+        // 测试缓存被截断、损坏或版本不一致时视为失效, 重新解析源文件并更新缓存
+        let tmp = TestDir::new("test_scheme_cache_corrupt");
+        let dir = tmp.join("drg_scheme_cache_corrupt");
+        fs::create_dir_all(&dir).unwrap();
+        for entry in fs::read_dir(DATA_DIR).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+        let cache_path = dir.join("scheme.cache");
+        GroupingScheme::load_or_cache(&dir, &cache_path).unwrap();
+        let data = fs::read(&cache_path).unwrap();
+
+        for len in [0, SCHEME_CACHE_MAGIC.len(), SCHEME_CACHE_MAGIC.len() + 3, data.len() / 2, data.len() - 1] {
+            fs::write(&cache_path, &data[..len]).unwrap();
+            assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none(), "truncated to {}", len);
+        }
+        // 末尾多出的字节
+        fs::write(&cache_path, [data.as_slice(), b"x"].concat()).unwrap();
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none());
+        // 字符串长度被改成很大的值
+        let mut corrupt = data.clone();
+        corrupt[SCHEME_CACHE_MAGIC.len() + 8..SCHEME_CACHE_MAGIC.len() + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&cache_path, &corrupt).unwrap();
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none());
+        // 文件头包含程序版本, 其他版本写入的缓存失效
+        assert!(data.starts_with(format!("DRGCACHE1 {}\n", env!("CARGO_PKG_VERSION")).as_bytes()));
+        let old_version = [b"DRGCACHE1 0.0.0\n".as_slice(), &data[SCHEME_CACHE_MAGIC.len()..]].concat();
+        fs::write(&cache_path, &old_version).unwrap();
+        assert!(GroupingScheme::read_cache(&dir, &cache_path).unwrap().is_none());
+
+        // 截断后的缓存会被重新写入
+        fs::write(&cache_path, &data[..data.len() / 2]).unwrap();
+        let rebuilt = GroupingScheme::load_or_cache(&dir, &cache_path).unwrap();
+        let cached = GroupingScheme::read_cache(&dir, &cache_path).unwrap().expect("cache is rebuilt");
+        assert_eq!(rebuilt.adrg_dis_opt, cached.adrg_dis_opt);
+        assert_eq!(rebuilt.adrg_drg_name_sheet, cached.adrg_drg_name_sheet);
+    }
+
+    #[test]
+    fn test_adrg_priority() {
+        // 同一MDC内外科手术ADRG与内科ADRG同时满足条件时, --adrg-priority选择外科手术ADRG
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        // 把内科的FR2放到MDCF列表的最前面, 默认取第一个满足条件的ADRG
+        let mut adrg_list = scheme.mdc_sub_adrg["MDCF"].clone();
+        adrg_list.retain(|x| x != "FR2");
+        adrg_list.insert(0, String::from("FR2"));
+        scheme.mdc_sub_adrg.insert(String::from("MDCF"), adrg_list);
+        let case = DrgCase::builder().id("0020").main_dis("I50.900x018").main_opt("35.0101").sex(1).age(60.0).build();
+
+        // 默认进入FR2, 又因为主手术属于本MDC的外科手术而调整为FQY
+        let result = scheme.group(&case, &GroupOptions::default()).unwrap();
+        assert_eq!("FQY", result.adrg);
+
+        let options = GroupOptions { adrg_priority: Some(default_adrg_priority()), ..Default::default() };
+        let (result, trace) = scheme.group_with_trace(&case, &options).unwrap();
+        assert_eq!("FB2", result.adrg);
+        let alternatives: Vec<(&str, &str, &str)> =
+            trace.adrg_alternatives.iter().map(|x| (x.adrg.as_str(), x.adrg_type.as_str(), x.chosen.as_str())).collect();
+        assert_eq!(vec![("FR2", "internal", "FB2")], alternatives);
+        assert!(trace.to_text().contains("    MDCF/FR2 [internal]: not chosen, FB2 preferred"));
+
+        // 自定义顺序把内科放在前面
+        let order = vec![String::from("internal"), String::from("surgery")];
+        let options = GroupOptions { adrg_priority: Some(order), no_qy: true, ..Default::default() };
+        assert_eq!("FR2", scheme.group(&case, &options).unwrap().adrg);
+
+        // 排序和explain中的类型都按分组方案的adrg_categories, 把B划为内科、R划为外科后默认优先级选择FR2
+        scheme.adrg_categories = AdrgCategories {
+            surgery: String::from("ACDEFGHIJR"),
+            operation: String::from("KLMNOPQ"),
+            internal: String::from("BSTUVWXYZ"),
+        };
+        let options = GroupOptions { adrg_priority: Some(default_adrg_priority()), no_qy: true, ..Default::default() };
+        let (result, trace) = scheme.group_with_trace(&case, &options).unwrap();
+        assert_eq!("FR2", result.adrg);
+        let alternatives: Vec<(&str, &str, &str)> =
+            trace.adrg_alternatives.iter().map(|x| (x.adrg.as_str(), x.adrg_type.as_str(), x.chosen.as_str())).collect();
+        assert_eq!(vec![("FB2", "internal", "FR2")], alternatives);
+    }
+
+    #[test]
+    fn test_run_meta() {
+        // 测试运行记录: 相同的输入、选项和运行时间得到相同的运行ID, 时间按UTC输出
+        let tmp = TestDir::new("test_run_meta");
+        assert_eq!("2026-10-14T08:30:00Z", utc_timestamp(1791966600));
+        assert_eq!("2000-02-29T00:00:00Z", utc_timestamp(951782400));
+
+        let in_file_path = tmp.join("drg_run_meta_in.csv");
+        let out_file_path = tmp.join("drg_run_meta_out.csv");
+        fs::write(&in_file_path, "id,main_dis\n0001,I50.900x018\n").unwrap();
+        let (in_path, out_path) = (in_file_path.to_str().unwrap(), out_file_path.to_str().unwrap());
+        let args: Vec<String> = ["DrgGrouper", "--batch", in_path, out_path, "--threads", "2", "--strict"]
+            .iter().map(|x| x.to_string()).collect();
+        let (_, options) = parse_cli_options(&args).unwrap();
+        assert_eq!(vec!["--threads", "2", "--strict"], options.flags);
+
+        let meta = RunMeta::new(in_path, out_path, 1, &options, 1791966600).unwrap();
+        let again = RunMeta::new(in_path, out_path, 1, &options, 1791966600).unwrap();
+        let later = RunMeta::new(in_path, out_path, 1, &options, 1791966601).unwrap();
+        let default_options = RunMeta::new(in_path, out_path, 1, &CliOptions::default(), 1791966600).unwrap();
+        assert_eq!(16, meta.run_id.len());
+        assert_eq!(meta.run_id, again.run_id);
+        assert_ne!(meta.run_id, later.run_id);
+        assert_ne!(meta.run_id, default_options.run_id);
+        assert_eq!(meta.scheme_version, default_options.scheme_version);
+
+        // 代替方案中文件的--local-rules和--drg-weights按文件内容计入分组方案版本
+        let weights_path = tmp.join("drg_run_meta_weights.json");
+        fs::write(&weights_path, r#"{"FR25": 0.85}"#).unwrap();
+        let with_weights = CliOptions { drg_weights: Some(weights_path.to_str().unwrap().to_string()), ..Default::default() };
+        let version = scheme_version(&with_weights).unwrap();
+        assert_ne!(meta.scheme_version, version);
+        assert_eq!(version, scheme_version(&with_weights).unwrap());
+        fs::write(&weights_path, r#"{"FR25": 0.9}"#).unwrap();
+        assert_ne!(version, scheme_version(&with_weights).unwrap());
+        let rules_path = tmp.join("drg_run_meta_rules.json");
+        fs::write(&rules_path, "[]").unwrap();
+        let with_rules = CliOptions { local_rules: Some(rules_path.to_str().unwrap().to_string()), ..Default::default() };
+        let rules_version = scheme_version(&with_rules).unwrap();
+        assert_ne!(meta.scheme_version, rules_version);
+        fs::write(&rules_path, r#"[{"name": "x", "when": {}, "drg": "FR21"}]"#).unwrap();
+        assert_ne!(rules_version, scheme_version(&with_rules).unwrap());
+        let with_subset = CliOptions { only_mdcs: Some(vec![String::from("MDCB")]), ..Default::default() };
+        assert_ne!(meta.scheme_version, scheme_version(&with_subset).unwrap());
+
+        write_run_meta(&meta, out_path).unwrap();
+        let read: RunMeta = read_json_file(run_meta_path(out_path)).unwrap();
+        assert_eq!(meta.run_id, read.run_id);
+        assert_eq!("2026-10-14T08:30:00Z", read.created_at);
+        assert_eq!(1, read.rows);
+    }
+
+    #[test]
+    fn test_main_dis_with_age() {
+        // 测试按年龄区分的ADRG: 儿童进入年龄范围内的ADRG, 成人不进入
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        scheme.adrg_type_dict.insert(String::from("BR1"), String::from("is_contain_main_dis_with_age"));
+        let tables = scheme.adrg_dis_opt.get_mut("BR1").unwrap();
+        tables.insert(String::from("age_range"), HashSet::from([String::from("0-18")]));
+        let main_dis = tables[DEFAULT_TABLE].iter().next().unwrap().to_string();
+        let child = DrgCase::builder().main_dis(&main_dis).sex(1).age(6.0).build();
+        let adult = DrgCase::builder().main_dis(&main_dis).sex(1).age(40.0).build();
+        let options = GroupOptions::default();
+        let check = |case: &DrgCase, scheme: &GroupingScheme| {
+            process_adrg(case, &scheme.adrg_dis_opt, &scheme.all_opt_list, &scheme.adrg_type_dict, String::from("BR1"), &options).unwrap()
+        };
+        assert_eq!("BR1", check(&child, &scheme));
+        assert_eq!("KBBZ", check(&adult, &scheme));
+
+        // 任一端可以不限, 上限不包括在内
+        let mut sheet = scheme.adrg_dis_opt.clone();
+        let set_range = |sheet: &mut AdrgSheet, range: &str| {
+            sheet.get_mut("BR1").unwrap().insert(String::from("age_range"), HashSet::from([range.to_string()]));
+        };
+        set_range(&mut sheet, "18-");
+        assert_eq!((Some(18.0), None), adrg_age_range(&sheet, "BR1").unwrap());
+        assert_eq!("BR1", is_contain_main_dis_with_age(&adult, &sheet, String::from("BR1")).unwrap());
+        set_range(&mut sheet, "-6");
+        assert_eq!("KBBZ", is_contain_main_dis_with_age(&child, &sheet, String::from("BR1")).unwrap());
+        set_range(&mut sheet, "zero-18");
+        assert!(adrg_age_range(&sheet, "BR1").is_err());
+        // 分组方案有误时按宽松模式进入KBBZ
+        scheme.adrg_dis_opt = sheet;
+        assert_eq!("KBBZ", check(&child, &scheme));
+    }
+
+    #[test]
+    fn test_mdc_order() {
+        // 测试判断路径中MDC的完整判断顺序及各自的结果
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let order = |case: &DrgCase| {
+            let (_, trace) = scheme.group_with_trace(case, &GroupOptions::default()).unwrap();
+            let steps: Vec<(String, String, String)> = trace.mdc_order.iter().map(|x| (x.mdc.clone(), x.source.clone(), x.result.clone())).collect();
+            (steps, trace.to_text())
+        };
+        let step = |mdc: &str, source: &str, result: &str| (mdc.to_string(), source.to_string(), result.to_string());
+
+        // 新生儿进入MDCP但没有满足条件的ADRG, 在主诊断所在的MDCF入组
+        let newborn = DrgCase::builder().id("0021").main_dis("I50.900x018").sex(1).age(0.01).weight(3000).build();
+        let (steps, text) = order(&newborn);
+        assert_eq!(vec![
+            step("MDCA", "pre_mdc", "not_entered"),
+            step("MDCP", "pre_mdc", "no_adrg_matched"),
+            step("MDCY", "pre_mdc", "not_entered"),
+            step("MDCZ", "pre_mdc", "not_entered"),
+            step("MDCF", "main_dis", "grouped"),
+        ], steps);
+        assert!(text.contains("\n    2. MDCP (pre-MDC): entered, no ADRG matched\n"));
+        assert!(text.contains("\n    5. MDCF (main diagnosis): <= grouped here\n"));
+
+        // 主诊断同时属于MDCM和MDCN, 男性进入MDCM, 之后的MDCN不满足进入条件, 不覆盖MDCM的结果
+        let male = DrgCase::builder().id("0022").main_dis("Q56.000").sex(1).age(30.0).build();
+        let (steps, _) = order(&male);
+        assert_eq!(vec!["MDCA", "MDCP", "MDCY", "MDCZ", "MDCM", "MDCN"], steps.iter().map(|x| x.0.as_str()).collect::<Vec<&str>>());
+        assert_eq!(step("MDCM", "main_dis", "grouped"), steps[4]);
+        assert_eq!(step("MDCN", "main_dis", "not_entered"), steps[5]);
+        // 性别未知时两者都不进入, 没有入组
+        let kbbz = DrgCase::builder().id("0022").main_dis("Q56.000").sex(9).age(30.0).build();
+        let (steps, text) = order(&kbbz);
+        assert!(steps.iter().all(|x| x.2 != "grouped"));
+        assert_eq!(step("MDCN", "main_dis", "not_entered"), steps[5]);
+        assert!(text.contains("    all MDCs failed, case goes to KBBZ"));
+
+        // 主诊断不在主诊表中时没有判断任何MDC
+        let unknown = DrgCase::builder().id("0023").main_dis("ZZZ.999").build();
+        let (steps, text) = order(&unknown);
+        assert!(steps.is_empty());
+        assert!(text.contains("    none evaluated: main diagnosis missing or not in main_dis_sheet"));
+    }
 
-    //     // 初始化病例结构
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("03.9202"),
-    //         vec![String::from("M41.900")],
-    //         vec![],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
-    //     // MDC列表
-    //     let mdc_list = vec![
-    //         "MDCA", "MDCP", "MDCY", "MDCZ", "MDCB", "MDCC", "MDCD", 
-    //         "MDCE", "MDCF", "MDCG", "MDCH", "MDCI", "MDCJ", "MDCK", "MDCL", 
-    //         "MDCM", "MDCN", "MDCO", "MDCQ", "MDCR", "MDCS", "MDCT", "MDCU", 
-    //         "MDCV", "MDCW", "MDCX"].iter_mut().map(|x| x.to_string()).collect::<Vec<String>>();
+    #[test]
+    fn test_read_icd9_bom() {
+        // 测试编码列表文件开头的BOM和结尾的换行不影响第一个和最后一个编码
+        let tmp = TestDir::new("test_read_icd9_bom");
+        fs::write(tmp.join("all_opt_sheet.txt"), "\u{feff}36.1001, 36.1002,,36.1003\r\n").unwrap();
+        let codes = read_member_icd_list(&DirSource::new(tmp.path()), "all_opt_sheet.txt", false).unwrap();
+        assert_eq!(HashSet::from([String::from("36.1001"), String::from("36.1002"), String::from("36.1003")]), codes);
+        let first = DrgCase::builder().main_dis("I50.900x018").main_opt("36.1001").build();
+        let last = DrgCase::builder().main_dis("I50.900x018").main_opt("36.1003").build();
+        assert!(first.is_vaild_surgrey(&codes));
+        assert!(last.is_vaild_surgrey(&codes));
+    }
 
-    //     // 无效主诊断，病例进入KBBZ
-    //     if case.no_main_diagnosis() {
-    //         println!("No main dis no adrg group in result is {}", "KBBZ");
-    //     }
+    #[test]
+    fn test_field_swap_policy() {
+        // 测试诊断手术填错列的病例: 默认照常分组, --reject-field-swaps时拒绝分组并注明原因
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let swapped = DrgCase::builder().id("0024").main_dis("I50.900x018").other_dis(["96.7101"]).sex(1).age(60.0).build();
+        let issues = swapped.validate(&scheme);
+        assert_eq!(vec![CaseIssue::OperationAsDiagnosis(String::from("96.7101"))], issues);
+        assert!(issues[0].is_field_swap());
+
+        let warn = scheme.group(&swapped, &GroupOptions::default()).unwrap();
+        assert_eq!("FR2", warn.adrg);
+        assert!(!warn.review_flags.contains(&String::from("field_swap")));
+
+        let args: Vec<String> = ["DrgGrouper", "--operations-as-diagnoses-ok", "--reject-field-swaps"].iter().map(|x| x.to_string()).collect();
+        let (_, cli) = parse_cli_options(&args).unwrap();
+        assert_eq!(FieldSwapPolicy::Reject, cli.group_options.field_swap_policy);
+        let reject = scheme.group(&swapped, &cli.group_options).unwrap();
+        assert_eq!("KBBZ", reject.drg);
+        assert_eq!(vec![String::from("field_swap")], reject.review_flags);
+        assert_eq!("rejected:operation_as_diagnosis:96.7101", scheme.group_status(&swapped, &reject, &issues));
+        // 已经检查过的病例问题直接传入, 分组时不再检查
+        assert_eq!(reject.review_flags, scheme.group_with_issues(&swapped, &cli.group_options, &issues).unwrap().review_flags);
+        assert_eq!("FR2", scheme.group_with_issues(&swapped, &cli.group_options, &[]).unwrap().adrg);
+
+        // 没有填错列的病例不受影响
+        let ok = DrgCase::builder().id("0025").main_dis("I50.900x018").sex(1).age(60.0).build();
+        assert_eq!("FR2", scheme.group(&ok, &cli.group_options).unwrap().adrg);
+    }
 
-    //     // 主诊断所在的MDC
-    //     let mut target_mdc_list = main_dis_sheet[&case.main_dis].clone();
-    //     let pre_mdc = vec![String::from("MDCA"), String::from("MDCP"), String::from("MDCY"), String::from("MDCZ")];
-    //     target_mdc_list = [pre_mdc, target_mdc_list].concat();
-    //     println!("{:?}", target_mdc_list);
-        
-    //     let mut pred_adrg = String::from("KBBZ");
-    //     let mut pred_mdc = String::from("KBBZ");
-    //     for mdc in target_mdc_list {
-    //         if mdc == String::from("MDCA") {
-    //             // 优先判断MDCA
-    //             pred_adrg = is_mdca( &case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, String::from("MDCA"));
-    //             if pred_adrg != String::from("KBBZ") {
-    //                 // 如果在MDCA中找到ADRG入组
-    //                 println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
-    //                 break
-    //             }
-    //         }
-    //         else if mdc == String::from("MDCP") {
-    //             // 判断MDCP
-    //             pred_mdc = is_mdcp(&case, &main_dis_sheet, String::from("MDCP"));
-    //             if pred_mdc == String::from("MDCP") {
-    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
-    //                     if pred_adrg != "KBBZ".to_string() {
-    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
-    //                         break
-    //                     }
-    //                 }
-            
-    //             }
-    //         }
-    //         else if mdc == String::from("MDCY") {
-    //             // 判断MDCY
-    //             pred_mdc = is_mdcy(&case, &adrg_type_dict, &mdcy_dis_sheet, String::from("MDCY"));
-    //             if pred_mdc == String::from("MDCY") {
-    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
-    //                     if pred_adrg != "KBBZ".to_string() {
-    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
-    //                         break
-    //                     }
-    //                 }
-                
-    //             }
-    //         }
-    //         else if mdc == String::from("MDCZ") {
-    //             // 判断MDCZ
-    //             pred_mdc = is_mdcz(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, &mdcz_dis_sheet, String::from("MDCZ"));
-    //             if pred_mdc == String::from("MDCZ") {
-    //                 // 判断MDC内的ADRG入组
-    //                 for adrg in mdc_sub_adrg[&pred_mdc].clone() {
-    //                     pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
-    //                     if pred_adrg != "KBBZ".to_string() {
-    //                         println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
-    //                         break
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //         else {
-    //             // 处理其他MDC
-    //             for adrg in mdc_sub_adrg[&mdc].clone() {
-    //                 pred_adrg = process_adrg(&case, &adrg_dis_opt, &all_opt_list, &adrg_type_dict, adrg); 
-    //                 if pred_adrg != String::from("KBBZ") {
-    //                     println!("predict mdc is {} and is adrg is {}", mdc, pred_adrg);
-    //                     break
-    //                 }
-    //             }
-    //         }
-    //     }
-    //     println!("The final predict adrg is {}", pred_adrg);
-    //     assert_eq!(true, pred_adrg == "AH1");
-    // }
+    #[test]
+    fn test_emit_stages() {
+        // 测试输出分组各阶段的结果: QY调整的病例QY判断前后的ADRG不同
+        let tmp = TestDir::new("test_emit_stages");
+        let ok = DrgCase::builder().id("0026").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let qy = DrgCase::builder().id("0027").main_dis("I50.900x018").main_opt("78.6201").sex(1).age(60.0).build();
+        let unknown = DrgCase::builder().id("0028").main_dis("ZZZ.999").sex(1).age(60.0).build();
+        let out_file_path = tmp.join("drg_stages_out.csv");
+        let cli = CliOptions { emit_stages: true, ..Default::default() };
+        batch_drg_process(vec![ok, qy, unknown], out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let names = ["stage_mdc", "stage_adrg_pre_qy", "stage_adrg_post_qy", "stage_drg", "code"];
+        let cols: Vec<usize> = names.iter().map(|x| headers.iter().position(|h| h == *x).unwrap()).collect();
+        let rows: Vec<Vec<String>> = rdr.records().map(|x| {
+            let record = x.unwrap();
+            cols.iter().map(|&i| record[i].to_string()).collect()
+        }).collect();
+        assert_eq!(vec!["MDCF", "FR2", "FR2", "FR25", "FR25"], rows[0]);
+        assert_eq!(vec!["MDCF", "FR2", "FQY"], rows[1][..3].to_vec());
+        assert_eq!(rows[1][3], rows[1][4]);
+        assert_eq!(vec!["KBBZ"; 5], rows[2]);
+
+        // 默认不输出
+        batch_drg_process(vec![DrgCase::builder().id("0029").main_dis("I50.900x018").build()], out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        assert!(!rdr.headers().unwrap().iter().any(|h| h.starts_with("stage_")));
+    }
 
-    // #[test]
-    // fn test_adrg() {        
-    //     // ADRG内涵诊断和手术操作表
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     // 所有手术操作列表
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     // 所有诊断列表
-    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    //     // 各个MDC的主诊表
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     // MDCY的诊断表
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     // MDCZ的诊断表
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     // 各个ADRG组进入的判断条件
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-    //     // 读取MDC下的ADRG列表
-    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
-    //     // 初始化病例结构
+    #[test]
+    fn test_blank_demographics() {
+        // 测试性别年龄体重为空或缺列时取默认值, 而不是解析失败
+        let tmp = TestDir::new("test_blank_demographics");
+        let case = DrgCase::default();
+        assert_eq!(("", "", UNKNOWN_SEX, 0.0, 0), (case.id.as_str(), case.dis.principal(), case.sex, case.age, case.weight));
+        assert!(case.dis.secondary().is_empty() && case.opt.all().is_empty());
+
+        let in_file_path = tmp.join("drg_blank_demographics.csv");
+        fs::write(&in_file_path, "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n0001,I50.900x018,,,,,60,\n").unwrap();
+        for fast_csv in [false, true] {
+            let options = CliOptions { fast_csv, ..Default::default() };
+            let cases = read_csv_mapped(in_file_path.to_str().unwrap(), &options).unwrap();
+            assert_eq!((UNKNOWN_SEX, 60.0, 0), (cases[0].sex, cases[0].age, cases[0].weight));
+        }
 
-    //     // 初始化病例结构
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("31.7400x0001"),
-    //         vec![String::from("M41.900")],
-    //         vec![],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
+        // 没有人口学信息的列或年龄为空时按成人处理, 与group模式分组结果相同, 不进入MDCP
+        let scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let group_case = DrgCase::builder().id("1").main_dis("I50.900x018").main_opt("34.0200x003").build();
+        let expected = scheme.group(&group_case, &GroupOptions::default()).unwrap().drg;
+        assert!(!expected.starts_with('P'), "{}", expected);
+        for content in [
+            "id,main_dis,main_opt,other_dis,other_opt\n1,I50.900x018,34.0200x003,,\n",
+            "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n1,I50.900x018,34.0200x003,,,,,\n",
+        ] {
+            fs::write(&in_file_path, content).unwrap();
+            for fast_csv in [false, true] {
+                let options = CliOptions { fast_csv, ..Default::default() };
+                let cases = read_csv_mapped(in_file_path.to_str().unwrap(), &options).unwrap();
+                assert_eq!((UNKNOWN_SEX, DEFAULT_ADULT_AGE, 0), (cases[0].sex, cases[0].age, cases[0].weight));
+                assert_eq!(expected, scheme.group(&cases[0], &GroupOptions::default()).unwrap().drg);
+            }
+        }
 
-    //     // 判断最终属于的ADRG
-    //     let result_adrg = which_adrg(
-    //         &case, 
-    //         &adrg_dis_opt, 
-    //         &all_opt_list, 
-    //         &all_dis_list, 
-    //         &main_dis_sheet, 
-    //         &adrg_type_dict, 
-    //         &mdcz_dis_sheet, &mdcy_dis_sheet, 
-    //         &mdc_sub_adrg
-    //     ).unwrap();
-    //     println!("result adrg is {}", result_adrg);
-    //     assert_eq!(true, result_adrg == String::from("BU2"));
-    // }
+        // 不是数字的值仍然报错
+        fs::write(&in_file_path, "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n0003,I50.900x018,,,,male,60,\n").unwrap();
+        assert!(read_csv(in_file_path.to_str().unwrap()).is_err());
+    }
 
-    // #[test]
-    // fn test_drg() {        
-    //     // ADRG内涵诊断和手术操作表
-    //     let adrg_dis_opt = read_file_as_str_to_set("data\\adrg_dis_opt_sheet.json").unwrap();
-    //     // 所有手术操作列表
-    //     let all_opt_list = read_icd9_to_vec("data\\all_opt_sheet.txt").unwrap();
-    //     // 所有诊断列表
-    //     let all_dis_list = read_icd9_to_vec("data\\all_dis_sheet.txt").unwrap();
-    //     // 各个MDC的主诊表
-    //     let main_dis_sheet = read_file_as_str_to_tuple("data\\main_dis_sheet.json").unwrap();
-    //     // MDCY的诊断表
-    //     let mdcy_dis_sheet = read_icd9_to_vec("data\\mdcy_dis_sheet.txt").unwrap();
-    //     // MDCZ的诊断表
-    //     let mdcz_dis_sheet = read_file_as_str_to_set("data\\mdcz_dis_sheet.json").unwrap();
-    //     // 各个ADRG组进入的判断条件
-    //     let adrg_type_dict = read_file_as_str_to_str("data\\adrg_in_condition.json").unwrap();
-    //     // 读取MDC下的ADRG列表
-    //     let mdc_sub_adrg = read_file_as_str_to_set("data\\mdc_sub_adrg.json").unwrap();
-    //     // 读取CCMCC列表
-    //     let ccmcc_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\ccmcc_sheet.json").unwrap();
-    //     // 读取排除表
-    //     let exclude_sheet = read_file_as_str_to_str("D:\\MyScript\\rust\\DrgGrouper\\data\\exclude_sheet.json").unwrap();
-    //     // 读取ADRG下的DRG
-    //     let adrg_drg_name_sheet = read_file_as_str_to_tuple("D:\\MyScript\\rust\\DrgGrouper\\data\\adrg_drg_name_sheet.json").unwrap();
+    #[test]
+    fn test_max_memory_streaming() {
+        // 测试--max-memory很小时改为流式分组, 输出与全部读入内存时相同
+        let tmp = TestDir::new("test_max_memory_streaming");
+        let in_file_path = tmp.join("drg_stream_in.csv");
+        fs::write(&in_file_path, "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+            0030,I50.900x018,,E16.200,,1,60,0\n\
+            0031,I50.900x018,78.6201,,,1,60,0\n\
+            0032,ZZZ.999,,,,1,60,0\n").unwrap();
+        let in_path = in_file_path.to_str().unwrap();
+        let buffered_path = tmp.join("drg_stream_buffered.csv");
+        let streamed_path = tmp.join("drg_stream_streamed.csv");
+        let cli = CliOptions { emit_stages: true, max_memory_mb: Some(0), ..Default::default() };
+        assert!(use_streaming(in_path, &cli).unwrap());
+        assert!(!use_streaming(in_path, &CliOptions { max_memory_mb: Some(1024), ..Default::default() }).unwrap());
+        assert!(!use_streaming(in_path, &CliOptions::default()).unwrap());
+        // 流式分组不支持拆分输出文件
+        assert!(!use_streaming(in_path, &CliOptions { max_memory_mb: Some(0), split_by: Some(String::from("mdc")), ..Default::default() }).unwrap());
+
+        assert_eq!(3, batch_stream_process(in_path, streamed_path.to_str().unwrap(), &cli).unwrap());
+        batch_drg_process(read_input_cases(in_path, &cli).unwrap(), buffered_path.to_str().unwrap(), &cli).unwrap();
+        assert_eq!(fs::read_to_string(&buffered_path).unwrap(), fs::read_to_string(&streamed_path).unwrap());
+
+        // 年龄为天数的文件同样推断为天数并换算为年
+        let days_file_path = tmp.join("drg_stream_days.csv");
+        fs::write(&days_file_path, "id,main_dis,main_opt,other_dis,other_opt,sex,age,weight\n\
+            0033,I50.900x018,,E16.200,,1,21900,0\n\
+            0034,I50.900x018,,,,1,14600,0\n\
+            0035,I50.900x018,,,,1,29,0\n").unwrap();
+        let days_path = days_file_path.to_str().unwrap();
+        assert!(csv_ages_in_days(days_path, &cli).unwrap());
+        assert!(!csv_ages_in_days(in_path, &cli).unwrap());
+        assert!(!csv_ages_in_days(days_path, &CliOptions { age_unit: Some(String::from("years")), ..Default::default() }).unwrap());
+        assert_eq!(3, batch_stream_process(days_path, streamed_path.to_str().unwrap(), &cli).unwrap());
+        let cases = read_input_cases(days_path, &cli).unwrap();
+        assert_eq!(60.0, cases[0].age);
+        batch_drg_process(cases, buffered_path.to_str().unwrap(), &cli).unwrap();
+        let streamed = fs::read_to_string(&streamed_path).unwrap();
+        assert_eq!(fs::read_to_string(&buffered_path).unwrap(), streamed);
+        assert!(!streamed.contains("21900"));
+    }
 
+    #[test]
+    fn test_warn_mdcz_override() {
+        // 测试进入MDCZ的病例在主诊断的MDC中也能进入外科ADRG FB2时给出提示
+        let mut scheme = fixture_scheme();
+        // 主手术同时属于MDCZ的ZB1和外科ADRG FB2
+        scheme.adrg_dis_opt.get_mut("ZB1").unwrap().get_mut(DEFAULT_TABLE).unwrap().insert(String::from("35.0101"));
+        let case = DrgCase::builder().id("0001").main_dis("I50.900").main_opt("35.0101").other_dis(["S36.000", "S22.000"]).sex(1).age(20.0).build();
+        let warn = GroupOptions { warn_mdcz_override: true, ..Default::default() };
+        // 主诊断所在的MDCF在MDCZ之后判断, 结果覆盖ZB1, 最终不在MDCZ中, 不提示
+        assert_eq!(Some((String::from("MDCF"), String::from("FB2"))), scheme.mdcz_surgical_alternative(&case, &warn).unwrap());
+        let result = scheme.group(&case, &warn).unwrap();
+        assert_eq!(("MDCF", "FB2"), (result.mdc.as_str(), result.adrg.as_str()));
+        assert!(!result.review_flags.contains(&String::from("mdcz_override")));
+
+        // 主诊断只属于MDCN时男性病例不进入MDCN, 停留在MDCZ, 而MDCN中能进入外科ADRG
+        scheme.main_dis_sheet.insert(String::from("I50.900"), vec![String::from("MDCN")]);
+        scheme.mdc_sub_adrg.insert(String::from("MDCN"), vec![String::from("FB2")]);
+        assert_eq!(Some((String::from("MDCN"), String::from("FB2"))), scheme.mdcz_surgical_alternative(&case, &warn).unwrap());
+        let result = scheme.group(&case, &warn).unwrap();
+        assert_eq!(("MDCZ", "ZB1"), (result.mdc.as_str(), result.adrg.as_str()));
+        assert!(result.review_flags.contains(&String::from("mdcz_override")));
+        // 默认不检查
+        assert!(!scheme.group(&case, &GroupOptions::default()).unwrap().review_flags.contains(&String::from("mdcz_override")));
+
+        // 主诊断的MDC中只能进入内科ADRG时不提示
+        let internal = DrgCase::builder().id("0002").main_dis("I50.900").main_opt("79.3500").other_dis(["S36.000", "S22.000"]).sex(1).age(20.0).build();
+        assert_eq!(None, scheme.mdcz_surgical_alternative(&internal, &warn).unwrap());
+        let result = scheme.group(&internal, &warn).unwrap();
+        assert_eq!("MDCZ", result.mdc);
+        assert!(!result.review_flags.contains(&String::from("mdcz_override")));
+    }
 
-    //     // 初始化病例结构
-    //     let case = DrgCase::new(
-    //         String::from("0001"),
-    //         String::from("G12.900"),
-    //         String::from("31.7400x0001"),
-    //         vec![String::from("M41.900")],
-    //         vec![],
-    //         1,
-    //         20.0,
-    //         2288
-    //     );
+    #[test]
+    fn test_scheme_sources() {
+        // 测试同一套分组方案从目录、zip压缩包和内存读取的结果相同
+        let tmp = TestDir::new("test_scheme_sources");
+        let case = DrgCase::builder().id("0001").main_dis("I50.900").main_opt("35.0101").sex(1).age(60.0).build();
+        let options = GroupOptions::default();
+        let members = fixture_members();
+        let embedded = EmbeddedSource::new(&members);
+        assert!(embedded.has_member("mdc_sub_adrg.json"));
+        assert!(!embedded.has_member("perinatal_dis.txt"));
+        assert!(embedded.read_member("perinatal_dis.txt").is_err());
+        let expected = GroupingScheme::load(&embedded).unwrap().group(&case, &options).unwrap();
+        assert_eq!(("MDCF", "FB2"), (expected.mdc.as_str(), expected.adrg.as_str()));
+
+        let dir = tmp.join("drg_scheme_source");
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in FIXTURE_FILES {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        let dir_source = DirSource::new(&dir);
+        assert_eq!(FIXTURE_FILES[0].1.as_bytes(), dir_source.read_member(FIXTURE_FILES[0].0).unwrap().as_slice());
+        assert!(!dir_source.has_member("local_rules.json"));
+        // 缺少必须的文件时报错中有文件路径
+        let err = dir_source.read_member("missing.json").err().unwrap().to_string();
+        assert!(err.contains("missing.json"));
+        let from_dir = GroupingScheme::load(&dir_source).unwrap();
+        assert_eq!(expected.drg, from_dir.group(&case, &options).unwrap().drg);
+
+        #[cfg(feature = "zip")]
+        {
+            let files: Vec<(String, Vec<u8>)> = FIXTURE_FILES.iter().map(|(name, contents)| (format!("scheme/{}", name), contents.as_bytes().to_vec())).collect();
+            let zip_source = ZipSource::from_bytes(&stored_zip(&files)).unwrap();
+            assert!(zip_source.has_member("adrg_in_condition.json"));
+            let from_zip = GroupingScheme::load(&zip_source).unwrap();
+            assert_eq!(expected.drg, from_zip.group(&case, &options).unwrap().drg);
+        }
+    }
 
-    //     // 判断最终属于的ADRG
-    //     let result_adrg = which_adrg(
-    //         &case, 
-    //         &adrg_dis_opt, 
-    //         &all_opt_list, 
-    //         &all_dis_list, 
-    //         &main_dis_sheet, 
-    //         &adrg_type_dict, 
-    //         &mdcz_dis_sheet, &mdcy_dis_sheet, 
-    //         &mdc_sub_adrg
-    //     ).unwrap();
-    //     println!("result adrg is {}", result_adrg);
+    #[test]
+    fn test_format_age() {
+        // 测试29天新生儿的年龄输出后能还原出天数, 整岁输出为整数
+        let tmp = TestDir::new("test_format_age");
+        let newborn = normalize_age(29.0, Some("days"));
+        for decimals in [None, Some(2)] {
+            let rendered = format_age(newborn, decimals);
+            assert_eq!(29.0, (rendered.parse::<f64>().unwrap() * 365.0).round());
+            assert_ne!("0.08", rendered);
+        }
+        assert_eq!("60", format_age(60.0, None));
+        assert_eq!("60", format_age(60.0, Some(2)));
+        assert_eq!("45.68", format_age(45.678, Some(2)));
+        assert_eq!("45.5", format_age(45.5, Some(2)));
+        assert_eq!("46", format_age(45.678, Some(0)));
+
+        // 批量输出的age列
+        let case = DrgCase::builder().id("0001").main_dis("P22.000").sex(1).age(newborn).weight(3000).build();
+        let out_file_path = tmp.join("drg_age_format_out.csv");
+        let cli = CliOptions { age_decimals: Some(1), ..Default::default() };
+        batch_drg_process(vec![case], out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let age_col = rdr.headers().unwrap().iter().position(|h| h == "age").unwrap();
+        let record = rdr.records().next().unwrap().unwrap();
+        assert_eq!(29.0, (record[age_col].parse::<f64>().unwrap() * 365.0).round());
+    }
 
-    //     let result_drg = process_drg(
-    //         &case,
-    //         result_adrg,
-    //         &ccmcc_sheet,
-    //         &exclude_sheet,
-    //         &adrg_drg_name_sheet
-    //     ).unwrap();
+    #[test]
+    fn test_cmi() {
+        // 测试按已知DRG和权重计算CMI, 没有权重的DRG按0计入, 可以不计入KBBZ和xQY
+        let tmp = TestDir::new("test_cmi");
+        let mut scheme = fixture_scheme();
+        scheme.drg_weights = HashMap::from([(String::from("FR25"), 0.8), (String::from("FB21"), 2.0)]);
+        let drg_counts = BTreeMap::from([
+            (String::from("FR25"), 3), (String::from("FB21"), 1), (String::from("FR21"), 1),
+            (String::from("KBBZ"), 2), (String::from("FQY"), 1),
+        ]);
+        let report = casemix_index(&scheme, &drg_counts, false);
+        assert_eq!(8, report.cases);
+        assert!((report.total_weight - 4.4).abs() < 1e-9);
+        assert!((report.cmi() - 0.55).abs() < 1e-9);
+        assert_eq!(1, report.missing_weight);
+        let report = casemix_index(&scheme, &drg_counts, true);
+        assert_eq!(5, report.cases);
+        assert!((report.cmi() - 0.88).abs() < 1e-9);
+        assert!(!report.drgs.contains_key("KBBZ"));
+
+        // 批量分组时按--drg-weights计算并写入各DRG的权重合计
+        let weights_path = tmp.join("drg_cmi_weights.json");
+        let out_file_path = tmp.join("drg_cmi_out.csv");
+        let cmi_path = tmp.join("drg_cmi.csv");
+        let cases = vec![
+            DrgCase::builder().id("0001").main_dis("I50.900x018").sex(1).age(60.0).build(),
+            DrgCase::builder().id("0002").main_dis("I50.900x018").sex(0).age(70.0).build(),
+            DrgCase::builder().id("0003").sex(1).age(60.0).build(),
+        ];
+        let full = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let drg = full.group(&cases[0], &GroupOptions::default()).unwrap().drg;
+        fs::write(&weights_path, format!(r#"{{"{}": 1.5}}"#, drg)).unwrap();
+        let cli = CliOptions {
+            drg_weights: Some(weights_path.to_str().unwrap().to_string()),
+            cmi_out: Some(cmi_path.to_str().unwrap().to_string()),
+            cmi_exclude_ungrouped: true,
+            ..Default::default()
+        };
+        batch_drg_process(cases, out_file_path.to_str().unwrap(), &cli).unwrap();
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&cmi_path).unwrap().records().map(|x| x.unwrap()).collect();
+        assert_eq!(1, rows.len());
+        assert_eq!(vec![drg.as_str(), "2", "1.5", "3"], rows[0].iter().collect::<Vec<&str>>());
+    }
 
-    //     println!("result drg is {}", result_drg);
-    //     assert_eq!(true, result_drg == String::from("BU25"));
-    // }
+    #[test]
+    fn test_check_positional_args() {
+        // 测试--batch缺少输出文件时给出用法, 而不是越界panic
+        let args = |x: &[&str]| parse_cli_options(&x.iter().map(|x| x.to_string()).collect::<Vec<String>>()).unwrap().0;
+        let err = check_positional_args(&args(&["DrgGrouper", "--batch", "in.csv"])).err().unwrap().to_string();
+        assert!(err.contains("--batch expects 2 arguments, got 1"));
+        assert!(err.contains("usage: --batch <in_file.csv> <out_file.csv>"));
+        // 无法识别的选项留在位置参数中, 报错中列出
+        let err = check_positional_args(&args(&["DrgGrouper", "--batch", "in.csv", "--typo", "out.csv", "--threads", "2"])).err().unwrap().to_string();
+        assert!(err.contains("unknown options: --typo"));
+        assert!(check_positional_args(&args(&["DrgGrouper", "--batch", "in.csv", "out.csv", "--threads", "2"])).is_ok());
+
+        let single = ["DrgGrouper", "--single", "0001", "I50.900x018", "", "", "", "1", "60", "0"];
+        assert!(check_positional_args(&args(&single)).is_ok());
+        let err = check_positional_args(&args(&single[..9])).err().unwrap().to_string();
+        assert!(err.contains("--single expects 8 arguments, got 7"));
+        assert!(check_positional_args(&args(&["DrgGrouper", "--single", "--json", "{}"])).is_ok());
+        assert!(check_positional_args(&args(&["DrgGrouper", "--single", "--json"])).is_err());
+        assert!(check_positional_args(&args(&["DrgGrouper"])).is_err());
+        // 其他模式不检查
+        assert!(check_positional_args(&args(&["DrgGrouper", "group", "--main-dis", "I50.900x018"])).is_ok());
+    }
 
-    // #[test]
-    // fn test_read_csv() {
-        // 测试读取CSV文件
-        // let cases_vec = read_csv("D:\\MyScript\\rust\\DrgGrouper\\case_data\\test_case_data.csv").unwrap();
-        // for drg_case in &cases_vec {
-            // println!("{:?}", drg_case);
-        // }
-        // assert_eq!(true, cases_vec[0].main_dis == "I50.900x08".to_string());
-    // }
+    #[test]
+    fn test_scheme_stats_format() {
+        // 测试scheme-stats的--format不被批量输出的--output-format占用
+        let to_args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        let (args, options) = parse_cli_options(&to_args(&["DrgGrouper", "scheme-stats", "--format", "json"])).unwrap();
+        assert_eq!(None, options.output_format);
+        assert_eq!("json", scheme_stats_format(&args[2..]).unwrap());
+        let (args, _) = parse_cli_options(&to_args(&["DrgGrouper", "scheme-stats"])).unwrap();
+        assert_eq!("table", scheme_stats_format(&args[2..]).unwrap());
+        let (args, _) = parse_cli_options(&to_args(&["DrgGrouper", "scheme-stats", "--format", "parquet"])).unwrap();
+        assert!(scheme_stats_format(&args[2..]).is_err());
+        let (_, options) = parse_cli_options(&to_args(&["DrgGrouper", "--batch", "in.csv", "out.parquet", "--output-format", "parquet"])).unwrap();
+        assert_eq!(Some("parquet"), options.output_format.as_deref());
+        assert!(parse_cli_options(&to_args(&["DrgGrouper", "--batch", "in.csv", "out.csv", "--output-format", "json"])).is_err());
+    }
+
+    #[test]
+    fn test_main_dis_alt() {
+        // 测试按备选主诊断分组得到不同的DRG, 权重较高时选择备选主诊断, 并在输出中注明两个结果
+        let tmp = TestDir::new("test_main_dis_alt");
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        let options = GroupOptions::default();
+        let case = DrgCase::builder().id("0001").main_dis("I50.900x018").sex(1).age(60.0).build();
+        let main = scheme.group(&case, &options).unwrap();
+        let alt_case = DrgCase::builder().id("0001").main_dis("E16.200").other_dis(vec!["I50.900x018"]).sex(1).age(60.0).build();
+        let alt_drg = scheme.group(&alt_case, &options).unwrap().drg;
+        assert_ne!(main.drg, alt_drg);
+
+        scheme.drg_weights = HashMap::from([(main.drg.to_string(), 0.8), (alt_drg.to_string(), 1.6)]);
+        let choice = scheme.choose_main_dis(&case, &main, "E16.200", MainDisPreference::Rw, &options).unwrap();
+        assert!(choice.alt_chosen);
+        assert_eq!(alt_drg, choice.chosen().drg);
+        assert_eq!(main.drg, choice.main.drg);
+        // 原主诊断能入组时grouped不换主诊断
+        let choice = scheme.choose_main_dis(&case, &main, "E16.200", MainDisPreference::Grouped, &options).unwrap();
+        assert!(!choice.alt_chosen);
+        scheme.drg_weights = HashMap::from([(main.drg.to_string(), 1.6), (alt_drg.to_string(), 0.8)]);
+        let choice = scheme.choose_main_dis(&case, &main, "E16.200", MainDisPreference::Rw, &options).unwrap();
+        assert!(!choice.alt_chosen);
+        assert_eq!(main.drg, choice.chosen().drg);
+
+        // 批量分组时按main_dis_alt列选择, 没有备选主诊断的病例不变
+        let weights_path = tmp.join("drg_main_dis_alt_weights.json");
+        let out_file_path = tmp.join("drg_main_dis_alt_out.csv");
+        fs::write(&weights_path, format!(r#"{{"{}": 0.8, "{}": 1.6}}"#, main.drg, alt_drg)).unwrap();
+        let mut with_alt = DrgCase::builder().id("0001").main_dis("I50.900x018").sex(1).age(60.0).build();
+        with_alt.main_dis_alt = String::from("E16.200");
+        let cases = vec![with_alt, DrgCase::builder().id("0002").main_dis("I50.900x018").sex(1).age(60.0).build()];
+        let cli = CliOptions {
+            drg_weights: Some(weights_path.to_str().unwrap().to_string()),
+            best_main_dis: true,
+            emit_hash: true,
+            ..Default::default()
+        };
+        batch_drg_process(cases, out_file_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&out_file_path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let col = |row: &csv::StringRecord, name: &str| row[headers.iter().position(|x| x == name).unwrap()].to_string();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(alt_drg, col(&rows[0], "code"));
+        assert_eq!(main.drg, col(&rows[0], "main_dis_drg"));
+        assert_eq!(alt_drg, col(&rows[0], "main_dis_alt_drg"));
+        assert_eq!("E16.200", col(&rows[0], "chosen_main_dis"));
+        assert_eq!(main.drg, col(&rows[1], "code"));
+        assert_eq!("", col(&rows[1], "main_dis_alt_drg"));
+        assert_eq!("I50.900x018", col(&rows[1], "chosen_main_dis"));
+        // 两个病例只有备选主诊断不同, 分组结果不同, 哈希也不同; 没有备选主诊断的病例哈希与不加--best-main-dis时相同
+        assert_ne!(col(&rows[0], "case_hash"), col(&rows[1], "case_hash"));
+        let plain = DrgCase::builder().id("0002").main_dis("I50.900x018").sex(1).age(60.0).build();
+        assert_eq!(plain.case_hash(&load_scheme(&cli).unwrap(), false), col(&rows[1], "case_hash"));
+
+        // JSON输入中的main_dis_alt字段与CSV的main_dis_alt列相同
+        let json = r#"[{"id": "0001", "main_dis": "I50.900x018", "main_dis_alt": " E16.200 ", "sex": 1, "age": 60},
+                       {"id": "0002", "main_dis": "I50.900x018", "sex": 1, "age": 60}]"#;
+        let cases = read_cases_from_bytes(json.as_bytes(), "json", &cli).unwrap();
+        assert_eq!("E16.200", cases[0].main_dis_alt);
+        assert_eq!("", cases[1].main_dis_alt);
+        let ndjson_case = parse_json_case(r#"{"id": "0001", "main_dis": "I50.900x018", "main_dis_alt": "E16.200"}"#, &cli).unwrap();
+        assert_eq!("E16.200", ndjson_case.main_dis_alt);
+        let json_out_path = tmp.join("drg_main_dis_alt_json_out.csv");
+        batch_drg_process(cases, json_out_path.to_str().unwrap(), &cli).unwrap();
+        let mut rdr = csv::Reader::from_path(&json_out_path).unwrap();
+        let json_rows: Vec<csv::StringRecord> = rdr.records().map(|x| x.unwrap()).collect();
+        assert_eq!(alt_drg, col(&json_rows[0], "code"));
+        assert_eq!("E16.200", col(&json_rows[0], "chosen_main_dis"));
+        assert_eq!(main.drg, col(&json_rows[1], "code"));
+
+        // 单病例的--explain输出选择的主诊断的判断路径, 返回的DRG与不加--explain时相同
+        let single = CliOptions {
+            drg_weights: Some(weights_path.to_str().unwrap().to_string()),
+            main_dis_alt: Some(String::from("E16.200")),
+            explain_format: Some(String::from("json")),
+            ..Default::default()
+        };
+        assert_eq!(alt_drg, single_drg_process(DrgCase::builder().id("0001").main_dis("I50.900x018").sex(1).age(60.0).build(), &single).unwrap());
+        let explain = CliOptions { explain: true, ..single };
+        assert_eq!(alt_drg, single_drg_process(case, &explain).unwrap());
+    }
+
+    // 序列化时按需出错的行, 用于模拟写入中途失败
+    struct FailingRow {
+        id: &'static str,
+        fail: bool,
+    }
+
+    impl Serialize for FailingRow {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeStruct;
+            if self.fail {
+                return Err(serde::ser::Error::custom("simulated write error"));
+            }
+            let mut row = serializer.serialize_struct("FailingRow", 1)?;
+            row.serialize_field("id", self.id)?;
+            row.end()
+        }
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        // 测试写入中途出错时已有的输出文件保持不变, 也不留下临时文件
+        let tmp = TestDir::new("test_atomic_write");
+        let out_file_path = tmp.join("drg_atomic_out.csv");
+        let out = out_file_path.to_str().unwrap();
+        let no_tmp_files = || fs::read_dir(tmp.path()).unwrap().all(|x| !x.unwrap().file_name().to_string_lossy().ends_with(".tmp"));
+        fs::write(&out_file_path, "id\nold\n").unwrap();
+        let rows = vec![FailingRow { id: "0001", fail: false }, FailingRow { id: "0002", fail: true }];
+        assert!(write_csv(rows, out, false).is_err());
+        assert_eq!("id\nold\n", fs::read_to_string(&out_file_path).unwrap());
+        assert!(no_tmp_files());
+        let rows = vec![FailingRow { id: "0001", fail: false }, FailingRow { id: "0002", fail: true }];
+        assert!(write_csv(rows, out, true).is_err());
+        assert_eq!("id\nold\n", fs::read_to_string(&out_file_path).unwrap());
+
+        // 成功时替换或追加
+        write_csv(vec![FailingRow { id: "0001", fail: false }], out, true).unwrap();
+        assert_eq!("id\nold\n0001\n", fs::read_to_string(&out_file_path).unwrap());
+        write_csv(vec![FailingRow { id: "0002", fail: false }], out, false).unwrap();
+        assert_eq!("id\n0002\n", fs::read_to_string(&out_file_path).unwrap());
+        assert!(no_tmp_files());
+
+        let json_path = tmp.join("drg_atomic_out.json");
+        fs::write(&json_path, "{}").unwrap();
+        assert!(write_json(&vec![FailingRow { id: "0001", fail: true }], json_path.to_str().unwrap()).is_err());
+        assert_eq!("{}", fs::read_to_string(&json_path).unwrap());
+        write_json(&BTreeMap::from([("id", "0001")]), json_path.to_str().unwrap()).unwrap();
+        assert_eq!(BTreeMap::from([(String::from("id"), String::from("0001"))]),
+            serde_json::from_str::<BTreeMap<String, String>>(&fs::read_to_string(&json_path).unwrap()).unwrap());
+        // 目录中只剩下两个输出文件
+        let mut names: Vec<String> = fs::read_dir(tmp.path()).unwrap().map(|x| x.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(vec!["drg_atomic_out.csv", "drg_atomic_out.json"], names);
+
+        // 同时写入同一目标文件时各自使用不同的临时文件, 不覆盖已有的同名.tmp文件
+        let other_tmp = tmp.join("drg_atomic_out.csv.tmp");
+        fs::write(&other_tmp, "other").unwrap();
+        let mut first = AtomicFile::create(&out_file_path, true).unwrap();
+        let mut second = AtomicFile::create(&out_file_path, false).unwrap();
+        assert_ne!(first.tmp_path, second.tmp_path);
+        for file in [&first, &second] {
+            assert_eq!(tmp.path(), file.tmp_path.parent().unwrap());
+            assert!(file.tmp_path.to_string_lossy().contains(&format!(".{}.", std::process::id())));
+        }
+        first.write_all(b"0003\n").unwrap();
+        second.write_all(b"id\n0004\n").unwrap();
+        first.commit().unwrap();
+        assert_eq!("id\n0002\n0003\n", fs::read_to_string(&out_file_path).unwrap());
+        second.commit().unwrap();
+        assert_eq!("id\n0004\n", fs::read_to_string(&out_file_path).unwrap());
+        assert_eq!("other", fs::read_to_string(&other_tmp).unwrap());
+    }
+
+    #[test]
+    fn test_pre_mdc_order() {
+        // 测试把MDCN列在先期分组中时, 女性病例先于主诊断所在的普通MDC判断MDCN
+        // 之后判断的MDC的结果覆盖之前的结果, 因此MDCN提前判断时由之后的MDCF给出结果
+        let mut scheme = GroupingScheme::load(&DirSource::new(DATA_DIR)).unwrap();
+        // 主诊断同时属于MDCF和MDCN, 在MDCF中也能进入FR2
+        scheme.main_dis_sheet.insert(String::from("N70.000"), vec![String::from("MDCF"), String::from("MDCN")]);
+        for table in scheme.adrg_dis_opt.get_mut("FR2").unwrap().values_mut() {
+            table.insert(String::from("N70.000"));
+        }
+        let female = DrgCase::builder().id("0001").main_dis("N70.000").sex(0).age(30.0).build();
+        let male = DrgCase::builder().id("0002").main_dis("N70.000").sex(1).age(30.0).build();
+        // 默认在主诊断所在的MDC中按MDCF, MDCN的顺序判断, MDCN的结果覆盖MDCF
+        let result = scheme.group(&female, &GroupOptions::default()).unwrap();
+        assert_eq!(("MDCN", "NS1"), (result.mdc.as_str(), result.adrg.as_str()));
+
+        let order = check_pre_mdc_order(["MDCA", "MDCN", "MDCP", "MDCY", "MDCZ"].iter().map(|x| x.to_string()).collect()).unwrap();
+        let options = GroupOptions { pre_mdc_order: Some(order.clone()), ..Default::default() };
+        let result = scheme.group(&female, &options).unwrap();
+        assert_eq!(("MDCF", "FR2"), (result.mdc.as_str(), result.adrg.as_str()));
+        let (_, trace) = scheme.group_with_trace(&female, &options).unwrap();
+        assert_eq!(vec!["MDCA", "MDCN"], trace.mdc_order.iter().take(2).map(|x| x.mdc.as_str()).collect::<Vec<&str>>());
+        assert_eq!("overridden", trace.mdc_order[1].result);
+        assert_eq!(5, trace.pre_mdc_checks.len());
+        // 男性病例不进入MDCN, 仍在MDCF入组, MDCN不再重复判断
+        let result = scheme.group(&male, &options).unwrap();
+        assert_eq!(("MDCF", "FR2"), (result.mdc.as_str(), result.adrg.as_str()));
+        let (_, trace) = scheme.group_with_trace(&male, &options).unwrap();
+        assert_eq!(1, trace.mdc_order.iter().filter(|x| x.mdc == "MDCN").count());
+
+        // 分组方案中的顺序同样生效, 命令行的顺序优先
+        scheme.pre_mdc_order = order;
+        assert_eq!("MDCF", scheme.group(&female, &GroupOptions::default()).unwrap().mdc);
+        let options = GroupOptions { pre_mdc_order: Some(default_pre_mdc_order()), ..Default::default() };
+        assert_eq!("MDCN", scheme.group(&female, &options).unwrap().mdc);
+
+        assert!(check_pre_mdc_order(vec![String::from("MDCF")]).is_err());
+        assert!(check_pre_mdc_order(vec![String::from("MDCN"), String::from("MDCN")]).is_err());
+        let args: Vec<String> = ["DrgGrouper", "group", "--pre-mdc-order", "mdca,MDCN"].iter().map(|x| x.to_string()).collect();
+        let (_, cli) = parse_cli_options(&args).unwrap();
+        assert_eq!(Some(vec![String::from("MDCA"), String::from("MDCN")]), cli.group_options.pre_mdc_order);
+    }
 
     #[test]
     fn test_write_csv() {
         // 测试写入CSV文件
-        let cases_vec = read_csv("D:\\MyScript\\rust\\DrgGrouper\\case_data\\test_case_data.csv").unwrap();
-        let out_file_path = "D:\\MyScript\\rust\\DrgGrouper\\case_data\\test_result.csv";
-        batch_drg_process(cases_vec, out_file_path).unwrap();
+        let tmp = TestDir::new("test_write_csv");
+        let cases_vec = read_csv("case_data/test_case_data.csv").unwrap();
+        let out_file_path = tmp.join("test_result.csv");
+        batch_drg_process(cases_vec, out_file_path.to_str().unwrap(), &CliOptions::default()).unwrap();
         assert_eq!(true, true);
     }
     
@@ -1965,18 +9651,208 @@ mod tests {
 // DONE: 写读取CSV文件批量结构化病例的函数
 // DONE: 测试CSV文件的读取
 // DONE: 终端的命令行参数控制单个病例分组或者导入表格进行分组
+// TODO: Excel输入按批(如每批1万行)分块读取, 分组与写入交替进行以控制内存, 与--stream的CSV/NDJSON共用逐批分组; 需要先有Excel读取(read_xlsx/calamine), 在此之前--input-format xlsx报错
 
 
 // NOTE 各种不同的读取
 /*
-1. 读取ADRG诊断手术列表(adrg_dis_opt_sheet) => read_file_as_str_to_set
-2. 读取所有诊断列表和手术列表(all_dis_sheet | all_opt_sheet) => read_icd9_to_vec
-3. 读取MDC主诊断列表(main_dis_sheet) => read_file_as_str_to_tuple
-4. 读取ADRG下的DRG分组编码列表(adrg_drg_name_sheet) => read_json_file
-5. 读取CCMCC列表(ccmcc_sheet) => read_json_file
-6. 读取主诊断排除表(exclude_sheet) => read_file_as_str_to_str
-7. 读取ADRG入组条件列表(adrg_in_condition) => read_file_as_str_to_str
-8. 读取MDCY的诊断列表(mdcy_dis_sheet) => read_icd9_to_vec
-9. 读取MDCZ的诊断列表(mdcz_dis_sheet) => read_file_as_str_to_set
-10. 读取病案CSV数据 => read_csv
+GroupingScheme::load通过SchemeSource按文件名读取各表: DirSource => 方案目录, ZipSource => zip压缩包(--scheme-zip), EmbeddedSource => 内存中的数据(--bundled-scheme编译进程序的分组方案, 以及测试)
+validate-scheme, benchmark-scheme-load和分组方案缓存(读取可选的文件)使用相同的SchemeSource和读取函数
+1. 读取ADRG诊断手术列表(adrg_dis_opt_sheet) => parse_adrg_dis_opt_sheet(嵌套格式直接解析, 扁平格式解析后用flat_to_nested_adrg_sheet转换)
+2. 读取所有诊断列表和手术列表(all_dis_sheet | all_opt_sheet) => read_member_icd_list
+3. 读取MDC主诊断列表(main_dis_sheet) => read_member_json
+4. 读取ADRG下的DRG分组编码列表(adrg_drg_name_sheet) => read_member_json
+5. 读取CCMCC列表(ccmcc_sheet) => read_member_json
+6. 读取主诊断排除表(exclude_sheet) => read_member_json
+7. 读取ADRG入组条件列表(adrg_in_condition) => read_member_json
+   入组条件为is_contain_main_dis的ADRG没有主诊断表(如"BR1_main_dis_list")时, 用ADRG默认表补充 => populate_main_dis_lists
+   入组条件is_contain_main_dis_with_age在主诊断条件之外还需要年龄在ADRG的年龄范围内, 如"BR3_age_range": ["0-18"]表示0 <= 年龄 < 18,
+   用于普通MDC中按年龄区分的儿童/成人ADRG => is_contain_main_dis_with_age
+8. 读取MDCY的诊断列表(mdcy_dis_sheet) => read_member_icd_list
+9. 读取MDCZ的诊断列表(mdcz_dis_sheet) => read_member_json
+10. 读取可选的文件(perinatal_dis, adrg_categories, severity_endings, local_rules, drg_weights, pre_mdc_order) => read_optional_tables
+    文件不存在时为默认值 => read_optional_member_json, read_member_icd_list(optional)
+11. 读取病案CSV数据 => read_csv
+*/
+
+
+// NOTE 命令行用法
+/*
+1. 单病例分组: --single <id> <main_dis> <main_opt> <other_dis> <other_opt> <sex> <age> <weight>
+   --single --json '{"id":"0001","main_dis":"I50.900x018","other_dis":["E16.200"],"sex":1,"age":60}'
+     => 病例以JSON字符串给出, 只有main_dis必填, 其余字段缺省时与group模式的默认值一致
+   --single --flat '0001|I50.900x018||E16.200;I10.x00||1|60|0'
+     => 病例以一个分隔的字符串给出(旧系统的定长格式), 默认字段顺序为id|main_dis|main_opt|other_dis|other_opt|sex|age|weight
+     其他诊断和其他手术的多个编码用";"分隔, 缺少或空白的字段取group模式的默认值
+     性别、年龄和体重与CSV输入一样允许千分位逗号和小数部分为0的整数(如"2,288", "2288.0")
+     --flat-layout <json> => 字段位置(从0开始)和分隔符, 如'{"separator":",","sub_separator":"/","main_dis":0,"id":null}', 没有给出的项取默认值
+   --main-dis-alt <code> => 再用备选主诊断(原主诊断改为其他诊断)分组, 输出两个主诊断的DRG和权重, 返回按--main-dis-prefer选择的DRG
+     --main-dis-prefer rw => 选择相对权重较高的DRG(默认), 没有权重的DRG按0比较, 权重相同时保留原主诊断
+     --main-dis-prefer grouped => 原主诊断未入组(KBBZ或xQY)而备选主诊断能入组时选择备选主诊断, 否则保留原主诊断
+     group模式同样适用
+2. 批量分组: --batch <in_file.csv> <out_file.csv>
+   --split-by mdc  => 按MDC拆分输出为out_file_MDCA.csv, out_file_MDCB.csv等, 未入组的病例写入out_file_KBBZ.csv
+   --split-by adrg => 按ADRG拆分输出为out_file_AA1.csv, out_file_BQY.csv等, 未入组的病例同样写入out_file_KBBZ.csv
+   输入文件的sex, age, weight列可以为空或没有这一列, 性别为未知(-1), 年龄为成人(18), 体重为0, 与group模式相同, 不会进入MDCP
+   --column-map <json> => 输入文件的列名映射, 如'{"病案号":"id","主诊断":"main_dis"}', 也可以是JSON文件的路径
+   --input-format auto|csv|tsv|ndjson|json|xlsx => 输入文件的格式, 默认auto按扩展名(.csv, .tsv, .ndjson/.jsonl, .json, .xlsx)判断
+     in_file为-时从stdin读取, 无法按扩展名判断时按内容判断: "["开头为JSON数组, "{"开头为NDJSON, 第一行只有制表符为TSV, 其他为CSV
+     JSON/NDJSON的病例字段与--single --json相同, xlsx暂不支持(需要另存为CSV)
+   --output-format csv|parquet => 输出文件的格式, 默认csv; parquet(需要parquet特性, 默认开启)的性别体重为INT32, 年龄为DOUBLE, 其他列为字符串
+     只写一个行组, 不压缩, 不能与--append同时使用, --join-input的输出总是CSV
+   --skip-bad-rows => 跳过解析失败的行(CSV/TSV/NDJSON, 包括--join-input)并在stderr提示, 默认第一个解析失败的行即报错
+   --max-errors <n> => 跳过的行达到n行时停止, 报错中列出已经失败的行, 默认不限, 给出时同时启用--skip-bad-rows
+   --emit-review-flags => 输出中增加review_flags列(默认不输出), 为需要人工复核的标记, 用"|"分隔:
+     single_cc_only => DRG的并发症等级只来自于一个CC/MCC(按--ccmcc-scope的范围计数, 与主诊断相同的编码不算作并发症)
+     qy_adjusted => ADRG经过QY判断调整
+     no_operation_support => 普通MDC的病例没有任何手术操作
+     cross_mdc => 入组的ADRG编码首字母与判断时所在的MDC不一致, 按--warn-cross-mdc标记
+     local_override => DRG被本地规则(--local-rules)改写
+     field_swap => 诊断字段中有手术编码或手术字段中有诊断编码, 按--reject-field-swaps拒绝分组
+     mdcz_override => 进入MDCZ的病例在主诊断所在的MDC中也能进入外科ADRG, 按--warn-mdcz-override标记
+   --warn-cross-mdc => 入组的ADRG编码首字母与判断时所在的MDC不一致(如在MDCF中进入NS1, 说明mdc_sub_adrg有误)时,
+     在stderr提示病例ID、ADRG和MDC, 并在review_flags中增加cross_mdc, 不改变分组结果
+   --warn-mdcz-override => 进入MDCZ(多发严重创伤)的病例再按主诊断所在的MDC判断一次ADRG(外科优先),
+     能进入外科ADRG时在stderr逐个提示病例ID、MDCZ的ADRG和外科ADRG, 并在review_flags中增加mdcz_override, 不改变分组结果
+   --reject-field-swaps => 诊断手术填错列(同--validate的operation_as_diagnosis/diagnosis_as_operation)的病例不分组, 直接进入KBBZ,
+     --emit-reason-always的status为rejected:operation_as_diagnosis:96.7101等; 默认照常分组, 批量分组结束时在stderr提示这类病例的数目
+   --operations-as-diagnoses-ok => 填错列的病例照常分组(默认), 同时给出时以后一个为准
+   分组成功后写入运行记录out_file.csv.meta.json: run_id, created_at(UTC), created_at_unix, scheme_version, options, input, output, rows
+     run_id为输入文件内容、scheme_version(分组方案各文件内容的哈希, 包括--local-rules和--drg-weights指定的文件)、命令行选项和运行时间(UNIX时间戳)的哈希, 与时区无关
+   输出的age列整岁为整数, 不足一岁的年龄保留全部精度(出生天数/365, 乘以365后四舍五入即为天数, 如29天为0.07945205479452055)
+   --age-decimals <n> => 一岁以上的年龄四舍五入到n位小数(去掉末尾的0), 不足一岁的年龄不受影响, 默认保留全部精度
+   --max-memory <MB> => 按输入文件大小估计全部读入内存需要的内存(约为文件大小的8倍), 超过时改为逐行读取、分组和写入, 输出相同
+     只支持CSV文件输入和CSV输出, 与--split-by、--sample-random、--output-format parquet同时使用或从stdin读取时仍然全部读入内存
+     没有--age-unit时先读取一遍age列, 与全部读入内存时一样推断年龄的单位; 逐行分组时不显示--progress进度
+3. 快速分组: group --main-dis <main_dis> [--main-opt <main_opt>] [--other-dis <a|b>] [--other-opt <a|b>] [--id <id>] [--sex <sex>] [--age <age>] [--weight <weight>]
+   未提供时性别为未知(-1), 年龄为成人(18), 体重为0, 因此不会进入MDCP
+4. 列出全部DRG编码: list-drgs
+   列出MDC下的全部主诊断(已排序): list-diagnoses --mdc <MDC>, 每行一个诊断编码, 分组方案中没有该MDC时报错
+5. 对比分组方案: diff-scheme --old <dir> --new <dir>
+   以JSON输出增加/删除的ADRG, 入组条件变化的ADRG, 所在MDC变化的主诊断, 增加/删除的DRG
+6. NDJSON流式分组: --ndjson <in_file.ndjson|->
+   每行一个与--single --json相同格式的病例, -为从stdin读取, 逐行分组并在stdout输出一行JSON结果(id, mdc, adrg, drg, severity, review_flags)
+   --count-only => 只按DRG计数, 不保存病例, 读完之后输出按DRG编码排序的"DRG<tab>病例数"
+7. 查询有效手术: classify-operation --opt <code> [--opt <code> ...]
+   输出手术操作是否为有效手术(在data/all_opt_sheet.txt中), 有效手术进入内科ADRG时判定为QY
+8. 合并分组输出: merge --inputs <a.csv> <b.csv> ... --output <all.csv> [--on-conflict fail|keep-last]
+   各文件的列必须相同(顺序可以不同), 按第一个文件的列顺序输出
+   病例ID重复时默认报错, keep-last时保留最后出现的行并在stderr列出重复的ID
+9. 分组方案统计: scheme-stats [--format table|json]
+   输出MDC, ADRG, DRG, 诊断, 手术, CC/MCC条目等的数目以及各入组条件类型和各MDC下的ADRG数, 用于发现读取不完整的分组方案文件
+10. 交互模式: repl
+   分组方案只读取一次, 之后从stdin逐行读取与--single --json相同格式的病例, 每读到一行立即在stdout输出一行JSON结果(字段与NDJSON模式相同), 直到EOF
+   单个病例解析或分组失败时输出{"line": 行号, "error": 错误信息}并继续等待下一行, 供桌面程序等长期运行的调用方使用
+11. 检查分组方案: validate-scheme [--dir <dir>] [--scheme-zip <zip>]
+   不需要病例数据, 只检查分组方案目录(默认data)或压缩包的一致性并列出找到的全部问题, 有问题时以非零状态退出
+   与读取分组方案使用相同的读取函数, 包括读取时的各项检查(文件能否读取, 主诊断都有MDC, MDC都有ADRG列表, ADRG都有DRG列表, CC/MCC条目完整, DRG编码以数字结尾)
+   可选的perinatal_dis.txt, adrg_categories.json, severity_endings.json, local_rules.json, drg_weights.json和pre_mdc_order.json存在时同样检查
+   以及入组条件都是已知的, MDC下的ADRG都有入组条件和诊断手术表, ADRG列表, DRG列表和诊断手术表都不为空
+   读取时的警告(如同时属于MDCM和MDCN的诊断)以"warning: "开头列出, 不算作问题
+12. 分组方案读取耗时: benchmark-scheme-load [--dir <dir>] [--scheme-zip <zip>]
+   逐个读取分组方案目录(默认data)或压缩包中的文件, 按耗时从大到小输出每个文件的大小(字节)和读取加解析的耗时(微秒), 最后一行为合计
+   每个文件按读取分组方案时相同的方式解析, 解析失败时报错
+   用于找出冷启动时解析最慢的文件(通常是adrg_dis_opt_sheet.json), 可选的文件不存在时不列出
+13. 分组汇总统计: batch-stats <in_file>
+   读取与--batch相同格式的病例文件并分组, 不写分组结果, 只在stdout以JSON输出病例总数(total), 未入组(kbbz_count)和歧义组(qy_count)的病例数,
+   以及各DRG(drg_counts)和各MDC(mdc_counts)的病例数
+14. 通用选项:
+   --single和--batch的位置参数个数不对(如缺少输出文件, 或者有无法识别的选项)时报错并给出该模式的用法
+   输出文件(分组结果、--coverage-out、--cmi、merge的合并结果、运行记录、分组方案缓存)先写入同一目录下的<文件名>.<进程号>.<序号>.tmp, 写完后再替换目标文件
+     写入中途出错时删除临时文件, 已有的输出文件保持不变; --append时先复制已有文件再追加
+     (每次--append都会完整复制一遍已有文件, 需要同样大小的临时磁盘空间, 反复追加到很大的文件时较慢)
+   --trace-file <path> => 把每个病例的判断路径(先期分组MDC, 依次尝试的ADRG及入组条件, 命中的表, 并发症等级, QY调整)逐行写为JSON
+     which_adrg_us和process_drg_us为判断ADRG和判断DRG的耗时(微秒), 只在记录判断路径时计时
+   --coverage-out <path> => 批量分组后把各ADRG的入组病例数写为CSV(adrg,cases), 包括mdc_sub_adrg中没有任何病例的ADRG(为0)以及xQY和KBBZ
+     用于发现从未被使用的ADRG(入组条件失效或数据缺失), 按全部病例统计, 不受--baseline筛选的影响
+   --join-input => 输出原始的全部列(单元格内容不变), 末尾追加mdc,adrg,drg,rw,severity列, 分组方案中没有该DRG的权重时rw为空
+     没有--age-unit时与--batch一样按整个文件推断年龄的单位(先读取一遍age列), 输出的age列仍为原始值
+   --fast-csv => 读取CSV时按表头中的列号直接取值, 不经过serde反序列化, 适合列很多的大文件, 解析规则与默认方式相同
+   --emit-counts => 输出中增加n_dis(诊断数)和n_opt(手术数)列
+   --suggest-main-swap => 输出中增加main_swap_suggestion列, 对未入组(KBBZ)的病例依次把每个其他诊断作为主诊断重新分组
+     列出能入组的"其他诊断:DRG"(用"|"分隔), 只作为选择主诊断的参考, 不改变code列的分组结果
+   --best-main-dis => 输入中的main_dis_alt列(可选, JSON和NDJSON输入中为main_dis_alt字段)为备选主诊断, 有备选主诊断的病例按两个主诊断分别分组, code列为按--main-dis-prefer选择的结果
+     输出中增加main_dis_alt, main_dis_drg(原主诊断的DRG), main_dis_alt_drg(备选主诊断的DRG)和chosen_main_dis(选择的主诊断)列
+   --suggest-main-opt => 输出中增加main_opt_suggestion列, 对QY病例按录入顺序依次把每个其他手术作为主手术重新分组
+     给出第一个能进入外科或操作ADRG的"其他手术:DRG", 用于了解手术顺序对分组的影响, 不改变code列的分组结果
+   --emit-reason-always => 输出中增加status列, 每一行都注明分组状态: ok(正常入组), qy_adjusted(经过QY调整)或kbbz:<原因>(未入组)
+     未入组的原因为no_main_dis, unknown_main_dis, no_adrg_match, too_many_candidates, no_adrg_list_for_mdc或no_drg_list_for_adrg
+     --reject-field-swaps拒绝分组的病例为rejected:<类型>:<编码>
+   --validate => 输出中增加case_issues列, 列出分组前检查出的病例问题(用"|"分隔), 格式为"严重程度:类型[:详情]", 不改变分组结果
+     (--normalize-codes时检查规范化编码之后的病例, 与分组和--reject-field-swaps看到的编码一致)
+     error => empty_main_diagnosis(没有主诊断), invalid_sex(性别不是0, 1或-1)
+     warning => unknown_diagnosis/unknown_operation(编码不在all_dis_sheet/all_opt_sheet中), sex_conflict(主诊断属于另一性别的MDCM/MDCN),
+                newborn_weight(29天以内的新生儿体重超过6000克)
+                operation_as_diagnosis/diagnosis_as_operation(诊断字段中是手术编码或者相反, 按编码表和编码格式判断, 可能是填错了列)
+   --baseline <grouped.csv> => 与基线分组结果(按id对比code列)比较, 只输出分组结果变化的病例, 增加old_code和new_code列
+     基线中没有的病例也会输出, old_code为空
+   --threads <n> => 批量分组使用n个线程, 结果按输入顺序收集后再写出, 输出与单线程完全相同
+   --sample-random <n> [--seed <seed>] => 批量分组时随机抽取n个病例(保持输入顺序), 相同的种子总是抽到相同的病例
+     没有--seed时取当前时间作为种子并在stderr输出, 不能与--join-input同时使用
+   --emit-hash => 输出中增加case_hash列, 为主诊断、排序后的其他诊断、主手术、排序后的其他手术、性别、年龄分段和体重分段的哈希(FNV-1a), --best-main-dis时还包括备选主诊断
+     年龄分段由分组方案用到的年龄分界(新生儿的年龄上限, ADRG的age_range, CC/MCC条目的age_min/age_max)划分, 体重分段只区分有无出生体重
+     分组只依赖这些字段, 同一版本的分组方案和相同的分组选项下哈希相同的病例分组结果一定相同, 可用于增量分组时找出变化的病例
+   --drg-only => 输出只有id和code两列(CSV和parquet), 不含诊断、手术、性别、年龄和体重, 用于把分组结果提供给第三方
+     其他增加输出列的选项(--emit-counts, --validate等)被忽略, 不能与--join-input同时使用
+   --emit-weight-band => 输出中增加weight_band列, 出生体重分段: <1000, 1000-1499, 1500-2499, >=2500(克), 体重未知(0)时为空
+   --emit-stages => 输出中增加分组各阶段的结果列: stage_mdc(MDC), stage_adrg_pre_qy(QY判断之前的ADRG), stage_adrg_post_qy(QY判断之后的ADRG),
+     stage_drg(本地规则改写之前的DRG), 用于逐列比较两个版本的分组结果从哪一步开始不同, 未入组的病例各列都为KBBZ
+   --require-columns => 分组之前只读取表头, 按--column-map打印matched/missing/extra列的检查表, 缺少必须的列时报错
+   --progress => 在stderr显示批量分组进度(已完成/总数, 速度, 预计剩余时间), --quiet时不显示
+   --quiet => 不显示进度, 也不在stderr显示读取分组方案时的警告(如同时属于MDCM和MDCN的诊断), validate-scheme总是列出这些警告
+   --append => 追加写入已有的输出文件, 文件已有内容时不重复写表头
+   --keep-redundant => 保留其他诊断/其他手术中与主诊断/主手术重复的编码, 默认去除
+   --age-unit days|years|auto => 输入年龄的单位, 默认years(不足一岁以小数表示, 出生天数/365)
+     days => 年龄为出生天数, 除以365后使用
+     auto => 超过150的年龄按天数处理, 1到28之间的整数年龄无法区分天数和年数, 按年处理, 运行结束时在stderr汇总给出这类病例的个数
+     没有指定时--batch按整个文件推断: 非零年龄中超过一半大于150时整列按天数处理并在stderr给出警告, 否则按年处理
+     缺少或空白的年龄不换算单位, 也不参与推断, 总是按成人年龄(18岁)分组
+   --qy-all-ops => QY判断时主手术或任一其他手术有效即可, 默认只看主手术是否有效
+   --no-qy => 跳过QY判断, 有效手术进入内科ADRG的病例不再归为xQY而是按原ADRG分到DRG, 这类病例的结果会改变
+   --distinct-simultaneous-opt => is_contain_opt_simultaneously要求两个手术表分别由不同的手术满足, 默认一个同时在两个表中的手术即可
+   --limit-mdc-loop <n> => 单个病例最多尝试n个ADRG(MDCA内置的ADRG列表不计入), 超过时进入KBBZ, review_flags为too_many_candidates
+   --normalize-codes => 分组前规范化病例和分组方案中的编码: 全角ASCII字符和全角空格转为半角, 中文句号转为'.', 去掉首尾的空白字符(包括全角空格)
+   --null-as-kbbz | --null-as-error => 主诊断为空的病例进入KBBZ(默认), 或者报错并注明病例ID, 批量分组时整批失败, 用于发现上游数据主诊断列为空的问题
+   --ccmcc-scope other_dis|all_dis => 判断CC/MCC时检查的诊断范围, other_dis(默认)按录入顺序逐个检查其他诊断, 重复录入的编码各算一次
+     all_dis检查全部诊断去重后的编码, 重复录入只算一次(影响single_cc_only标记), 两者都不把主诊断算作自身的并发症
+     分组方案只按CC/MCC的最高等级决定DRG, 两种范围得到的DRG相同, 区别只在于single_cc_only标记和--explain列出的并发症
+     同时给出时以后一个为准
+   --explain => 单病例(--single/group)分组时输出判断路径, 包括入组ADRG的各个表(如AH1_main_opt_list1)中命中的编码, 有--main-dis-alt时为选择的主诊断的判断路径
+     以及MDC的完整判断顺序(MDCA, MDCP, MDCY, MDCZ, 然后是主诊断所在的MDC), 标出在哪个MDC入组或全部未入组; 之后判断的MDC的结果覆盖之前的结果(标为overridden), 只有MDCA入组后不再判断其他MDC
+   --explain-format json|text => 判断路径的输出格式, --explain默认text(缩进的树形文本), --trace-file默认json(每行一个病例)
+     json的字段固定为id, pre_mdc_checks[{mdc, result}], adrg_tried[{mdc, adrg, condition, matched}], mdc, pre_qy_adrg, adrg,
+     qy_adjusted, ccmcc, severity, drg, matched_tables[{key, codes}], which_adrg_us, process_drg_us, local_override,
+     complications[{code, severity, excluded, exclusion_table, condition_ok}](其他诊断中每个CC/MCC是否被主诊断排除),
+     adrg_alternatives[{mdc, adrg, adrg_type, chosen}](--adrg-priority时同一MDC内满足条件但未被选中的ADRG),
+     mdc_order[{mdc, source, result}](MDC的完整判断顺序: 先期分组的MDCA, MDCP, MDCY, MDCZ, 然后是主诊断所在的MDC;
+     result为grouped, overridden, no_adrg_matched, not_entered或not_evaluated), 新增字段只追加不改名
+   --adrg-priority => 同一MDC内多个ADRG满足入组条件时不再取mdc_sub_adrg中的第一个, 而是按ADRG类型优先: 外科手术 > 操作 > 内科,
+     ADRG类型按分组方案的adrg_categories.json划分, 同一类型按列表顺序; 该MDC的全部ADRG都会被尝试(计入--limit-mdc-loop), --explain中列出未被选中的ADRG
+   --adrg-priority-order surgery,operation,internal => 自定义类型的优先顺序(隐含--adrg-priority), 未列出的类型排在最后
+   --pre-mdc-order MDCA,MDCN,MDCP,MDCY,MDCZ => 先期分组MDC的判断顺序, 默认为MDCA,MDCP,MDCY,MDCZ, 也可以写在分组方案目录中可选的pre_mdc_order.json
+     如["MDCA", "MDCN", "MDCP", "MDCY", "MDCZ"], 命令行给出时以命令行为准; 可以包含MDCA, MDCP, MDCY, MDCZ, MDCN和MDCM, 不能重复
+     MDCN(女性)和MDCM(男性)按性别和主诊断判断是否进入, 列在先期分组中时在该位置判断, 不再在主诊断所在的MDC中重复判断
+     之后判断的MDC的结果覆盖之前的结果, 列在先期分组中的MDCN/MDCM的结果会被主诊断所在的其他MDC覆盖
+     同时属于MDCM和MDCN的主诊断按性别进入其中一个
+   --strict-age-for-mdcp => 进入MDCP除了年龄不超过29天外, 还需要主诊断为围产期诊断(data/perinatal_dis.txt, 没有该文件时为P00-P96)或体重大于0
+   --strict => 严格模式, 分组方案中缺失的键(adrg_dis_opt, adrg_type_dict, main_dis_sheet等)直接报错并注明病例ID, 默认进入KBBZ
+     非严格模式下病例进入的MDC在mdc_sub_adrg中没有ADRG列表时进入KBBZ, review_flags为no_adrg_list_for_mdc; 读取分组方案时会检查主诊表中的MDC都有ADRG列表
+     入组的ADRG在adrg_drg_name_sheet中没有DRG列表时同样进入KBBZ, review_flags为no_drg_list_for_adrg; 读取分组方案时会检查adrg_in_condition中的ADRG都有DRG列表
+   分组方案目录中可选的adrg_categories.json设置ADRG编码第二位字母对应的类型(surgery, operation, internal), 默认A-J为外科, K-Q为操作, R-Z为内科, QY判断按内科类型进行
+   分组方案目录中可选的severity_endings.json设置并发症等级对应的DRG结尾数字, 如{"mcc": 1, "cc": 3, "none": 5, "single": 9}(默认), 没有给出的项取默认值
+     读取时检查各ADRG下DRG的结尾数字: 有多个DRG时只能是mcc、cc和none的数字, 必须有none, 三个以上时必须有mcc, 否则报出不一致的ADRG
+   --local-rules <path> => 分组之后按本地政策改写DRG的规则(JSON数组), 默认读取分组方案目录中可选的local_rules.json
+     如[{"name": "audit-1", "drg": "FR25", "main_dis": ["I50.900x018"], "set_drg": "FR23"}], 条件drg, main_dis, any_dis, any_opt都是可选的
+     按顺序第一条条件全部满足的规则生效, 改写的病例review_flags中有local_override, 判断路径中记录规则名称和原DRG
+   分组方案目录中可选的drg_weights.json为各DRG的相对权重(RW), 如{"FR25": 0.85, "FR23": 1.12}, 用于--cmi和--join-input的rw列
+   --drg-weights <path> => 代替分组方案目录中drg_weights.json的权重文件
+   --cmi <path> => 批量分组后在stdout输出CMI(病例的平均权重), 并把各DRG的病例数和权重写为CSV(drg,cases,rw,weighted)
+     没有权重的DRG按0计入并在stderr提示病例数, 按全部病例计算, 不受--baseline筛选的影响, 不能与--join-input同时使用
+     --cmi-exclude-ungrouped => 不计入KBBZ和xQY的病例
+   --scheme-zip <path> => 从zip压缩包读取分组方案(需要zip特性, 默认开启), 压缩包中的文件与data目录中的同名, 可以在子目录中, 缺少文件时报错
+     只支持不压缩和deflate两种压缩方式, 不同子目录中有同名文件或文件CRC-32校验失败时报错
+   --bundled-scheme => 读取编译进程序的分组方案(需要bundled特性, 默认不开启, 为编译时data目录中的必需文件), 运行时不需要data目录
+     可以与--only-mdcs同时使用, 不能与--scheme-zip或--scheme-cache同时使用, 运行记录的scheme_version与同样内容的data目录相同
+   --scheme-cache <path> => 第一次读取data目录时把解析后的分组方案写为二进制缓存, 之后源文件的大小和修改时间都没有变化时直接读取缓存
+     任一源文件变化、缓存由其他版本的程序写入(文件头中的版本与程序版本不同)或缓存损坏(截断)时重新解析并覆盖缓存, 可选的perinatal_dis.txt, adrg_categories.json, severity_endings.json, local_rules.json, drg_weights.json和pre_mdc_order.json每次都重新读取
 */
@@ -0,0 +1,120 @@
+// 分组方案zip压缩包的读取(--scheme-zip)
+// 解压使用zip crate, 支持不压缩(stored)和deflate两种方式
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Cursor, Read};
+
+use zip::ZipArchive;
+
+// 读取zip压缩包中的全部文件, 键为去掉目录后的文件名
+// 不同目录下有同名文件时报错, 读出的内容按中央目录中的CRC-32校验, 不一致时报错
+pub(crate) fn read_zip_members(data: &[u8]) -> Result<HashMap<String, Vec<u8>>, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| format!("not a zip archive: {}", e))?;
+    let mut members: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut paths: HashMap<String, String> = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| format!("bad zip entry {}: {}", i, e))?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().map_err(|e| format!("bad zip entry {}: {}", i, e))?.to_string();
+        let mut contents = Vec::with_capacity(file.size() as usize);
+        // zip crate读到文件末尾时校验CRC-32
+        file.read_to_end(&mut contents).map_err(|e| format!("{}: {}", name, e))?;
+        let file_name = name.rsplit('/').next().unwrap_or(&name).to_string();
+        if let Some(previous) = paths.insert(file_name.clone(), name.clone()) {
+            return Err(format!("duplicate file name {} in zip archive ({} and {})", file_name, previous, name).into());
+        }
+        members.insert(file_name, contents);
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    // 按不压缩(stored)方式在内存中生成zip压缩包
+    pub(crate) fn stored_zip(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        zip_with_method(files, CompressionMethod::Stored)
+    }
+
+    // 在内存中生成zip压缩包, 文件内容按method压缩
+    fn zip_with_method(files: &[(String, Vec<u8>)], method: CompressionMethod) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(method);
+        for (name, contents) in files {
+            writer.start_file(name.as_str(), options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    const JSON_TEXT: &str = "{\"MDCB\": [\"BB1\", \"BB2\", \"BC1\", \"BD1\", \"BE1\", \"BJ1\", \"BL1\", \"BM1\", \"BR1\", \"BS1\", \"BT1\", \"BU1\", \"BV1\", \"BW1\", \"BX1\", \"BY1\", \"BZ1\"], \"MDCC\": [\"CB1\", \"CB2\", \"CC1\", \"CD1\", \"CJ1\", \"CR1\", \"CS1\", \"CT1\", \"CU1\", \"CV1\", \"CW1\", \"CX1\", \"CZ1\"]}";
+
+    fn sample_zip() -> Vec<u8> {
+        zip_with_method(&[
+            (String::from("scheme/"), Vec::new()),
+            (String::from("scheme/readme.txt"), b"KBBZ".to_vec()),
+            (String::from("scheme/mdc_sub_adrg.json"), JSON_TEXT.as_bytes().to_vec()),
+        ], CompressionMethod::Deflated)
+    }
+
+    #[test]
+    fn test_read_zip_members() {
+        let stored = read_zip_members(&stored_zip(&[(String::from("scheme/a.txt"), b"A00.100".to_vec())])).unwrap();
+        assert_eq!(Some(&b"A00.100".to_vec()), stored.get("a.txt"));
+        // deflate压缩的文件解压后不变, 目录不作为文件读出
+        let deflated = read_zip_members(&sample_zip()).unwrap();
+        assert_eq!(2, deflated.len());
+        assert_eq!(b"KBBZ".as_slice(), deflated["readme.txt"].as_slice());
+        assert_eq!(JSON_TEXT.as_bytes(), deflated["mdc_sub_adrg.json"].as_slice());
+    }
+
+    #[test]
+    fn test_duplicate_file_name() {
+        // 不同目录下的同名文件不能互相覆盖
+        let data = stored_zip(&[
+            (String::from("v1/mdc_sub_adrg.json"), b"{}".to_vec()),
+            (String::from("v2/mdc_sub_adrg.json"), JSON_TEXT.as_bytes().to_vec()),
+        ]);
+        let err = read_zip_members(&data).unwrap_err().to_string();
+        assert_eq!("duplicate file name mdc_sub_adrg.json in zip archive (v1/mdc_sub_adrg.json and v2/mdc_sub_adrg.json)", err);
+    }
+
+    #[test]
+    fn test_crc_mismatch() {
+        // 改动不压缩文件内容的一个字节, CRC-32校验失败, 报出文件名
+        let data = stored_zip(&[(String::from("scheme/a.txt"), b"A00.100".to_vec())]);
+        let pos = data.windows(7).position(|w| w == b"A00.100").unwrap();
+        let mut bad = data.clone();
+        bad[pos + 6] = b'9';
+        let err = read_zip_members(&bad).unwrap_err().to_string();
+        assert!(err.starts_with("scheme/a.txt: ") && err.contains("checksum"), "{}", err);
+    }
+
+    #[test]
+    fn test_truncated_zip() {
+        // 压缩包的任何前缀都报错而不是panic
+        let data = sample_zip();
+        for n in 0..data.len() {
+            assert!(read_zip_members(&data[..n]).is_err(), "prefix of {} bytes", n);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_zip() {
+        let err = read_zip_members(b"PK not really a zip archive").unwrap_err().to_string();
+        assert!(err.starts_with("not a zip archive"), "{}", err);
+        // 损坏压缩包的每一个字节, 读取可能成功也可能报错, 但不会panic
+        let data = sample_zip();
+        for i in 0..data.len() {
+            let mut bad = data.clone();
+            bad[i] ^= 0x55;
+            let _ = read_zip_members(&bad);
+        }
+    }
+}